@@ -1,5 +1,6 @@
 // This example illustrates how to generate a JWT
 // This method is the same to create a JWT to connect to the subscriptions server as it is to generate one for a user
+// If you just need a token, the `diana` command-line tool can do this for you (`diana token --publisher --exp 1w`)
 
 use diana::{create_jwt, decode_time_str, get_jwt_secret};
 use std::collections::HashMap;
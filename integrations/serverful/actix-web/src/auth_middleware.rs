@@ -3,50 +3,69 @@ use actix_web::{
     Error, HttpMessage, HttpResponse,
 };
 use async_graphql::{ObjectType, SubscriptionType};
-use diana::{AuthVerdict, DianaHandler};
+use diana::{
+    AuthFailure, AuthVerdict, DianaHandler, PublishAuth, UserClaims, PUBLISH_SIGNATURE_HEADER,
+};
 use futures::{
     future::{ok, Ready},
     Future,
 };
 use std::any::Any;
+use std::cell::RefCell;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::result::Result as StdResult;
 use std::task::{Context, Poll};
 
+use crate::routes::{blocked_response, get_auth_parts};
+
 // Create a factory for authentication middleware
 #[derive(Clone)]
-pub struct AuthCheck<C, Q, M, S>
+pub struct AuthCheck<C, Q, M, S, T>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
-    diana_handler: DianaHandler<C, Q, M, S>,
+    diana_handler: DianaHandler<C, Q, M, S, T>,
+    // Whether or not this is guarding the subscriptions server's publish endpoint, which is the only place minted publisher tokens work
+    for_publishing: bool,
 }
-impl<C, Q, M, S> AuthCheck<C, Q, M, S>
+impl<C, Q, M, S, T> AuthCheck<C, Q, M, S, T>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     // Initialises a new instance of the authentication middleware factory by cloning the given DianaHandler
-    pub fn new(diana_handler: &DianaHandler<C, Q, M, S>) -> Self {
+    pub fn new(diana_handler: &DianaHandler<C, Q, M, S, T>) -> Self {
         Self {
             diana_handler: diana_handler.clone(),
+            for_publishing: false,
+        }
+    }
+    // Initialises a new instance of the authentication middleware factory for the subscriptions server's publish endpoint
+    pub fn for_publishing(diana_handler: &DianaHandler<C, Q, M, S, T>) -> Self {
+        Self {
+            diana_handler: diana_handler.clone(),
+            for_publishing: true,
         }
     }
 }
 
 // This is what we'll actually call, all it does is create the middleware and define all its properties
-impl<C, Q, M, Sb, S> Transform<S> for AuthCheck<C, Q, M, Sb>
+impl<C, Q, M, Sb, T, S> Transform<S> for AuthCheck<C, Q, M, Sb, T>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     Sb: Clone + SubscriptionType + 'static,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    T: UserClaims,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
     S::Future: 'static,
 {
     // All the properties of the middleware need to be defined here
@@ -55,7 +74,7 @@ where
     type Response = ServiceResponse;
     type Error = Error;
     type InitError = ();
-    type Transform = AuthCheckMiddleware<C, Q, M, Sb, S>;
+    type Transform = AuthCheckMiddleware<C, Q, M, Sb, T, S>;
     type Future = Ready<StdResult<Self::Transform, Self::InitError>>;
 
     // This will be called internally by Actix Web to create our middleware
@@ -63,31 +82,36 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(AuthCheckMiddleware {
             diana_handler: self.diana_handler.clone(),
-            service,
+            for_publishing: self.for_publishing,
+            // The service has to be shared with the future we return, because authentication checks are asynchronous
+            service: Rc::new(RefCell::new(service)),
         })
     }
 }
 
 // The actual middleware
 #[derive(Clone)]
-pub struct AuthCheckMiddleware<C, Q, M, Sb, S>
+pub struct AuthCheckMiddleware<C, Q, M, Sb, T, S>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     Sb: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
-    diana_handler: DianaHandler<C, Q, M, Sb>,
-    service: S,
+    diana_handler: DianaHandler<C, Q, M, Sb, T>,
+    for_publishing: bool,
+    service: Rc<RefCell<S>>,
 }
 
-impl<C, Q, M, Sb, S> Service for AuthCheckMiddleware<C, Q, M, Sb, S>
+impl<C, Q, M, Sb, T, S> Service for AuthCheckMiddleware<C, Q, M, Sb, T, S>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     Sb: Clone + SubscriptionType + 'static,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    T: UserClaims,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error> + 'static,
     S::Future: 'static,
 {
     // More properties for Actix Web
@@ -99,56 +123,69 @@ where
     // Stock function for asynchronous operations
     // The context here has nothing to do with our app's internal context whatsoever!
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<StdResult<(), Self::Error>> {
-        self.service.poll_ready(cx)
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        // Get the HTTP `Authorization` header
-        let auth_header = req
-            .headers()
-            .get("AUTHORIZATION")
-            .map(|auth_header| {
-                // We convert to a string and handle the result, which gives us an Option inside an Option
-                let header_str = auth_header.to_str();
-                match header_str {
-                    Ok(header_str) => Some(header_str),
-                    Err(_) => None,
-                }
-            })
-            .flatten(); // Then we flatten the two Options into one Option
-                        // Get a verdict and match that to a middleware outcome
-        let verdict = self.diana_handler.is_authed(auth_header);
-        match verdict {
-            auth_verdict @ AuthVerdict::Allow(_) => {
-                // Insert the authentication verdict into the request extensions for later retrieval
-                req.extensions_mut().insert(auth_verdict);
-                // Move on from this middleware to the handler
-                let fut = self.service.call(req);
-                Box::pin(async move {
+        // Get everything the token could be in (headers and the query string), and the path (which can hold the tenant)
+        // We need owned data because authentication checks happen inside the future we return
+        // Any headers that aren't valid strings are ignored
+        let auth_parts = get_auth_parts(
+            req.headers(),
+            req.path(),
+            req.query_string(),
+            req.peer_addr(),
+        );
+        let diana_handler = self.diana_handler.clone();
+        let for_publishing = self.for_publishing;
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            // Signed publish requests can only be checked with their bodies, so we leave them to the handler (which will check them for
+            // the subscriptions server, and run the usual checks for anything else)
+            if matches!(diana_handler.opts.publish_auth, PublishAuth::Hmac(_))
+                && auth_parts.get_header(PUBLISH_SIGNATURE_HEADER).is_some()
+            {
+                let fut = service.borrow_mut().call(req);
+                let res = fut.await?;
+                return Ok(res);
+            }
+            // Get a verdict and match that to a middleware outcome
+            let verdict = match for_publishing {
+                true => diana_handler.is_authed_for_publishing(&auth_parts).await,
+                false => diana_handler.is_authed_from_parts(&auth_parts).await,
+            };
+            // If there's a policy, it might allow some blocked operations, so we can only decide once the request has been parsed
+            let has_policy = diana_handler.opts.auth_policy.is_some();
+            match verdict {
+                auth_verdict
+                    if matches!(auth_verdict, AuthVerdict::Allow(_))
+                        || (has_policy && !matches!(auth_verdict, AuthVerdict::Error(_))) =>
+                {
+                    // Insert the authentication verdict into the request extensions for later retrieval
+                    req.extensions_mut().insert(auth_verdict);
+                    // Move on from this middleware to the handler
+                    let fut = service.borrow_mut().call(req);
                     let res = fut.await?;
                     Ok(res)
-                })
-            }
-            AuthVerdict::Block => {
-                // Return a 403
-                Box::pin(async move {
-                    Ok(ServiceResponse::new(
-                        req.into_parts().0,                    // Eliminates the payload of the request
-                        HttpResponse::Unauthorized().finish(), // In the playground this will come up as bad JSON, it's a direct HTTP response
-                    ))
-                })
-            }
-            AuthVerdict::Error(_) => {
+                }
+                // Return a 401 (or a 400 for an invalid request) with the reason
+                AuthVerdict::Block(failure) => Ok(ServiceResponse::new(
+                    req.into_parts().0, // Eliminates the payload of the request
+                    blocked_response(failure),
+                )),
+                AuthVerdict::Revoked => Ok(ServiceResponse::new(
+                    req.into_parts().0, // Eliminates the payload of the request
+                    blocked_response(AuthFailure::Revoked),
+                )),
                 // Middleware failed, we shouldn't let this proceed to the request just in case
                 // This error could be triggered by a failure in transforming the token from base64, meaning the error can be caused forcefully by an attacker
                 // In that scenario, we can't allow the bypassing of this layer
-                Box::pin(async move {
-                    Ok(ServiceResponse::new(
-                        req.into_parts().0, // Eliminates the payload of the request
-                        HttpResponse::InternalServerError().finish(),
-                    ))
-                })
+                AuthVerdict::Error(_) => Ok(ServiceResponse::new(
+                    req.into_parts().0, // Eliminates the payload of the request
+                    HttpResponse::InternalServerError().finish(),
+                )),
             }
-        }
+        })
     }
 }
@@ -7,7 +7,7 @@ use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
     ObjectType, SubscriptionType,
 };
-use diana::{errors::*, AuthBlockLevel, DianaHandler, Options};
+use diana::{errors::*, AuthBlockLevel, DianaHandler, Options, UserClaims};
 use std::any::Any;
 
 use crate::auth_middleware::AuthCheck;
@@ -17,14 +17,15 @@ use crate::routes::graphql_without_subscriptions;
 /// quickly configure a new or existing Actix Web server to use Diana. For examples, see the book.
 /// This function is designed for development only, Diana should be used serverlessly for queries and mutations in a production environment.
 /// See the book for more information on how to do that.
-pub fn create_graphql_server<C, Q, M, S>(
-    opts: Options<C, Q, M, S>,
+pub fn create_graphql_server<C, Q, M, S, T>(
+    opts: Options<C, Q, M, S, T>,
 ) -> Result<impl FnOnce(&mut ServiceConfig) + Clone>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     // Create a new Diana handler (core logic primitive)
     let diana_handler = DianaHandler::new(opts.clone())?;
@@ -35,6 +36,8 @@ where
         AuthBlockLevel::AllowAll => AuthCheck::new(&diana_handler),
         AuthBlockLevel::AllowMissing => AuthCheck::new(&diana_handler),
         AuthBlockLevel::BlockUnauthenticated => AuthCheck::new(&diana_handler),
+        AuthBlockLevel::RequireJwt => AuthCheck::new(&diana_handler),
+        AuthBlockLevel::RequireApiKey => AuthCheck::new(&diana_handler),
     };
 
     let graphql_endpoint = opts.graphql_endpoint;
@@ -50,7 +53,7 @@ where
                 web::resource(&graphql_endpoint)
                     .guard(guard::Post()) // Should accept POST requests
                     .wrap(auth_middleware.clone())
-                    .to(graphql_without_subscriptions::<C, Q, M, S>), // The handler function it should use
+                    .to(graphql_without_subscriptions::<C, Q, M, S, T>), // The handler function it should use
             );
 
         // Define the closure for the GraphiQL endpoint
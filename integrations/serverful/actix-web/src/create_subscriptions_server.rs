@@ -7,7 +7,7 @@ use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
     ObjectType, SubscriptionType,
 };
-use diana::{errors::*, AuthBlockLevel, DianaHandler, Options};
+use diana::{errors::*, AuthBlockLevel, DianaHandler, Options, UserClaims};
 use std::any::Any;
 
 use crate::auth_middleware::AuthCheck;
@@ -17,24 +17,27 @@ use crate::routes::{graphql_for_subscriptions, graphql_ws};
 /// a new or existing Actix Web server to use Diana. For examples, see the book. This function should be used to create production servers.
 /// If your setup doesn't require subscriptions at all, don't configure anything in the [`Options`](diana::Options) and don't worry
 /// about this function, subscriptions will automatically be disabled.
-pub fn create_subscriptions_server<C, Q, M, S>(
-    opts: Options<C, Q, M, S>,
+pub fn create_subscriptions_server<C, Q, M, S, T>(
+    opts: Options<C, Q, M, S, T>,
 ) -> Result<impl FnOnce(&mut ServiceConfig) + Clone>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     // Create a new Diana handler (core logic primitive)
     let diana_handler = DianaHandler::new(opts.clone())?;
 
     // Get the appropriate authentication middleware set up with the JWT secret
-    // This will wrap the GraphQL endpoint itself
+    // This will wrap the GraphQL endpoint itself, which is only used for publishing (so it accepts minted publisher tokens)
     let auth_middleware = match opts.authentication_block_state {
-        AuthBlockLevel::AllowAll => AuthCheck::new(&diana_handler),
-        AuthBlockLevel::AllowMissing => AuthCheck::new(&diana_handler),
-        AuthBlockLevel::BlockUnauthenticated => AuthCheck::new(&diana_handler),
+        AuthBlockLevel::AllowAll => AuthCheck::for_publishing(&diana_handler),
+        AuthBlockLevel::AllowMissing => AuthCheck::for_publishing(&diana_handler),
+        AuthBlockLevel::BlockUnauthenticated => AuthCheck::for_publishing(&diana_handler),
+        AuthBlockLevel::RequireJwt => AuthCheck::for_publishing(&diana_handler),
+        AuthBlockLevel::RequireApiKey => AuthCheck::for_publishing(&diana_handler),
     };
 
     let graphql_endpoint = opts.graphql_endpoint;
@@ -50,14 +53,14 @@ where
                 web::resource(&graphql_endpoint)
                     .guard(guard::Post()) // Should accept POST requests
                     .wrap(auth_middleware.clone())
-                    .to(graphql_for_subscriptions::<C, Q, M, S>), // The handler function it should use
+                    .to(graphql_for_subscriptions::<C, Q, M, S, T>), // The handler function it should use
             )
             // The GraphQL endpoint for subscriptions over WebSockets
             .service(
                 web::resource(&graphql_endpoint)
                     .guard(guard::Get())
                     .guard(guard::Header("upgrade", "websocket"))
-                    .to(graphql_ws::<C, Q, M, S>),
+                    .to(graphql_ws::<C, Q, M, S, T>),
             );
 
         // Define the closure for the GraphiQL endpoint
@@ -1,16 +1,51 @@
-use actix_web::{web, HttpRequest, HttpResponse, Result as ActixResult};
-use async_graphql::{ObjectType, SubscriptionType};
+use actix_web::{
+    http::{HeaderMap, StatusCode},
+    web, HttpRequest, HttpResponse, Result as ActixResult,
+};
+use async_graphql::{Error as GQLError, ObjectType, SubscriptionType};
 use async_graphql_actix_web::WSSubscription; // Pre-built WebSocket logic
 use std::any::Any;
+use std::net::SocketAddr;
 
-use diana::{AuthVerdict, DianaHandler, DianaResponse};
+use diana::{AuthFailure, AuthRequestParts, AuthVerdict, DianaHandler, DianaResponse, UserClaims};
 
 // TODO reduce code duplication here
 
+// Gets everything the token and the tenant could be in from a request, along with the address it came from (for limiting logins)
+// Any headers that aren't valid strings are ignored
+pub fn get_auth_parts(
+    headers: &HeaderMap,
+    path: &str,
+    query: &str,
+    peer_addr: Option<SocketAddr>,
+) -> AuthRequestParts {
+    let parts = headers
+        .iter()
+        .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.as_str(), value)))
+        .fold(AuthRequestParts::new(), |parts, (name, value)| {
+            parts.with_header(name, value)
+        })
+        .with_path(path)
+        .with_query(query);
+    match peer_addr {
+        Some(peer_addr) => parts.with_client_ip(&peer_addr.ip().to_string()),
+        None => parts,
+    }
+}
+
+// Creates the response for a blocked request, which tells the client why with an RFC 6750 `WWW-Authenticate` header
+// In the playground this will come up as bad JSON, it's a direct HTTP response
+pub fn blocked_response(failure: AuthFailure) -> HttpResponse {
+    let status = StatusCode::from_u16(failure.status_code()).unwrap_or(StatusCode::UNAUTHORIZED);
+    HttpResponse::build(status)
+        .header("WWW-Authenticate", failure.www_authenticate())
+        .finish()
+}
+
 // The main GraphQL endpoint for queries and mutations with authentication support
 // This handler does not support subscriptions
-pub async fn graphql_without_subscriptions<C, Q, M, S>(
-    diana_handler: web::Data<DianaHandler<C, Q, M, S>>,
+pub async fn graphql_without_subscriptions<C, Q, M, S, T>(
+    diana_handler: web::Data<DianaHandler<C, Q, M, S, T>>,
     http_req: HttpRequest,
     body: String,
 ) -> HttpResponse
@@ -19,27 +54,34 @@ where
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     // Get the authorisation verdict from the request extensions if it exists (it would be set by the middleware)
     let extensions = http_req.extensions();
-    let auth_verdict = extensions.get::<AuthVerdict>().cloned();
+    let auth_verdict = extensions.get::<AuthVerdict<T>>().cloned();
 
-    // Run the query, stating that authentication checks don't need to be performed again
+    // Run the query, stating that authentication checks don't need to be performed again (the parts are still needed for the tenant)
+    let auth_parts = get_auth_parts(
+        http_req.headers(),
+        http_req.path(),
+        http_req.query_string(),
+        http_req.peer_addr(),
+    );
     let res = diana_handler
-        .run_stateless_without_subscriptions(body, Option::<String>::None, auth_verdict)
+        .run_stateless_without_subscriptions_from_parts(body, &auth_parts, auth_verdict)
         .await;
 
     // Transform the DianaResponse into an HttpResponse
     match res {
         DianaResponse::Success(res) => res.into(),
-        DianaResponse::Blocked => HttpResponse::Forbidden().finish(),
+        DianaResponse::Blocked(failure) => blocked_response(failure),
         DianaResponse::Error(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 // The main GraphQL endpoint for queries and mutations with authentication support
 // This handler does not support subscriptions, but is for use in the subscriptions system (which also needs query/mutation support)
-pub async fn graphql_for_subscriptions<C, Q, M, S>(
-    diana_handler: web::Data<DianaHandler<C, Q, M, S>>,
+pub async fn graphql_for_subscriptions<C, Q, M, S, T>(
+    diana_handler: web::Data<DianaHandler<C, Q, M, S, T>>,
     http_req: HttpRequest,
     body: String,
 ) -> HttpResponse
@@ -48,28 +90,35 @@ where
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     // Get the authorisation verdict from the request extensions if it exists (it would be set by the middleware)
     let extensions = http_req.extensions();
-    let auth_verdict = extensions.get::<AuthVerdict>().cloned();
+    let auth_verdict = extensions.get::<AuthVerdict<T>>().cloned();
 
-    // Run the query, stating that authentication checks don't need to be performed again
+    // Run the query, stating that authentication checks don't need to be performed again (the parts are still needed for the tenant)
+    let auth_parts = get_auth_parts(
+        http_req.headers(),
+        http_req.path(),
+        http_req.query_string(),
+        http_req.peer_addr(),
+    );
     let res = diana_handler
-        .run_stateless_for_subscriptions(body, Option::<String>::None, auth_verdict)
+        .run_stateless_for_subscriptions_from_parts(body, &auth_parts, auth_verdict)
         .await;
 
     // Transform the DianaResponse into an HttpResponse
     match res {
         DianaResponse::Success(res) => res.into(),
-        DianaResponse::Blocked => HttpResponse::Forbidden().finish(),
+        DianaResponse::Blocked(failure) => blocked_response(failure),
         DianaResponse::Error(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
 // The endpoint for GraphQL subscriptions
-// This doesn't use DianaHandler at all (except to extract the needed schema) because `async_graphql` provides practically pre-built integration for this
-pub async fn graphql_ws<C, Q, M, S>(
-    diana_handler: web::Data<DianaHandler<C, Q, M, S>>,
+// `async_graphql` provides practically pre-built integration for this, we just authenticate the connection and give it the resulting data
+pub async fn graphql_ws<C, Q, M, S, T>(
+    diana_handler: web::Data<DianaHandler<C, Q, M, S, T>>,
     http_req: HttpRequest,
     payload: web::Payload,
 ) -> ActixResult<HttpResponse>
@@ -78,7 +127,37 @@ where
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
-    let schema = &diana_handler.schema_for_subscriptions;
-    WSSubscription::start(schema.clone(), &http_req, payload)
+    let auth_parts = get_auth_parts(
+        http_req.headers(),
+        http_req.path(),
+        http_req.query_string(),
+        http_req.peer_addr(),
+    );
+    // If the upgrade request has credentials that aren't valid, there's no point in upgrading the connection at all
+    // Missing credentials could still be sent in the `connection_init` message though
+    match diana_handler.is_authed_from_parts(&auth_parts).await {
+        AuthVerdict::Block(AuthFailure::MissingToken) | AuthVerdict::Allow(_) => (),
+        AuthVerdict::Block(failure) => return Ok(blocked_response(failure)),
+        AuthVerdict::Revoked => return Ok(blocked_response(AuthFailure::Revoked)),
+        AuthVerdict::Error(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+
+    let schema = diana_handler.schema_for_subscriptions.clone();
+    // This is run when the client sends its `connection_init` message, and the data it returns is available to every subscription
+    let initializer = move |connection_params: serde_json::Value| async move {
+        match diana_handler
+            .is_authed_for_subscription(&auth_parts, &connection_params)
+            .await
+        {
+            AuthVerdict::Allow(auth_state) => {
+                Ok(diana_handler.get_subscription_data(auth_state, &auth_parts))
+            }
+            AuthVerdict::Block(failure) => Err(GQLError::new(failure.description())),
+            AuthVerdict::Revoked => Err(GQLError::new(AuthFailure::Revoked.description())),
+            AuthVerdict::Error(_) => Err(GQLError::new("Authentication failed")),
+        }
+    };
+    WSSubscription::start_with_initializer(schema, &http_req, payload, initializer)
 }
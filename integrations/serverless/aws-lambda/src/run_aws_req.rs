@@ -2,23 +2,60 @@
 
 use async_graphql::{ObjectType, SubscriptionType};
 use aws_lambda_events::encodings::Body;
+use netlify_lambda_http::request::RequestContext;
 use netlify_lambda_http::{Request, Response};
+use once_cell::sync::OnceCell;
 use std::any::Any;
 
-use diana::{DianaHandler, DianaResponse, Options};
+use diana::{AuthRequestParts, DianaHandler, DianaResponse, Options, UserClaims};
 
 /// A *very* generic error type that the deployment system will accept as a return type.
 pub type AwsError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+// The handler for this container, which is built on the first request and then reused for every request after that
+// Building it parses the schema and creates the key provider, so rebuilding it every time would mean fetching any remote JWKS again too
+// This can't be generic, so it holds the handler for whatever types the user has, which we get back with a downcast
+static DIANA_HANDLER: OnceCell<Box<dyn Any + Send + Sync>> = OnceCell::new();
+
+// Gets the handler for this container, building it from the given options if this is the first request
+fn get_diana_handler<C, Q, M, S, T>(
+    opts: Options<C, Q, M, S, T>,
+) -> Result<&'static DianaHandler<C, Q, M, S, T>, AwsError>
+where
+    C: Any + Send + Sync + Clone,
+    Q: Clone + ObjectType + 'static,
+    M: Clone + ObjectType + 'static,
+    S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
+{
+    let diana_handler = DIANA_HANDLER.get_or_try_init(|| {
+        DianaHandler::new(opts)
+            .map(|diana_handler| Box::new(diana_handler) as Box<dyn Any + Send + Sync>)
+            .map_err(|err| err.to_string())
+    })?;
+    // This can only fail if the same container is given options of different types
+    let diana_handler = diana_handler
+        .downcast_ref::<DianaHandler<C, Q, M, S, T>>()
+        .ok_or(
+            "The options given don't have the same types as the ones the handler was built with",
+        )?;
+
+    Ok(diana_handler)
+}
+
 // This allows us to propagate error HTTP responses more easily
 enum AwsReqData {
-    Valid((String, Option<String>)),
+    Valid((String, AuthRequestParts)),
     Invalid(Response<String>), // For some reason
 }
 
-// Gets the stringified body and authentication header from an AWS request
+// Gets the stringified body and everything the token (and the tenant) could be in from an AWS request
+// The given function says whether a header could hold a token, in which case it has to be readable
 // We use a generic error type rather than the crate's `error_chain` logic here for AWS' benefit
-fn get_data_from_aws_req(req: Request) -> Result<AwsReqData, AwsError> {
+fn get_data_from_aws_req(
+    req: Request,
+    is_token_header: impl Fn(&str) -> bool,
+) -> Result<AwsReqData, AwsError> {
     // Get the request body (query/mutation) as a string
     // Any errors are returned gracefully as HTTP responses
     let body = req.body();
@@ -46,26 +83,38 @@ fn get_data_from_aws_req(req: Request) -> Result<AwsReqData, AwsError> {
             return Ok(AwsReqData::Invalid(res));
         }
     };
-    // Get the authorisation header as a string
-    // Any errors are returned gracefully as HTTP responses
-    let auth_header = req.headers().get("Authorization");
-    let auth_header = match auth_header {
-        Some(auth_header) => {
-            let header_str = auth_header.to_str();
-            match header_str {
-                Ok(header_str) => Some(header_str.to_string()),
-                Err(_) => {
-                    let res = Response::builder()
-                        .status(400) // Invalid request
-                        .body("Couldn't parse authorization header as string".to_string())?;
-                    return Ok(AwsReqData::Invalid(res));
-                }
+    // Get everything the token could be in (headers and the query string)
+    // Headers that aren't valid strings are ignored, unless a token could be in them, in which case that's returned gracefully as an HTTP
+    // response
+    let mut auth_parts = AuthRequestParts::new();
+    for (name, value) in req.headers() {
+        let header_str = value.to_str();
+        match header_str {
+            Ok(header_str) => auth_parts = auth_parts.with_header(name.as_str(), header_str),
+            Err(_) if is_token_header(name.as_str()) => {
+                let res = Response::builder()
+                    .status(400) // Invalid request
+                    .body(format!("Couldn't parse {} header as string", name))?;
+                return Ok(AwsReqData::Invalid(res));
             }
+            Err(_) => continue,
         }
-        None => None,
+    }
+    auth_parts = auth_parts.with_path(req.uri().path());
+    if let Some(query) = req.uri().query() {
+        auth_parts = auth_parts.with_query(query);
+    }
+    // The address the request came from is only in the request context that API Gateway gives us (ALBs don't give us one)
+    let source_ip = match req.extensions().get::<RequestContext>() {
+        Some(RequestContext::ApiGatewayV2(ctx)) => Some(ctx.http.source_ip.as_str()),
+        Some(RequestContext::ApiGateway(ctx)) => Some(ctx.identity.source_ip.as_str()),
+        _ => None,
     };
+    if let Some(source_ip) = source_ip.filter(|source_ip| !source_ip.is_empty()) {
+        auth_parts = auth_parts.with_client_ip(source_ip);
+    }
 
-    Ok(AwsReqData::Valid((body, auth_header)))
+    Ok(AwsReqData::Valid((body, auth_parts)))
 }
 
 // Parses the response from `DianaHandler` into HTTP responses that AWS Lambda (or derivatives) can handle
@@ -74,9 +123,10 @@ fn parse_aws_res(res: DianaResponse) -> Result<Response<String>, AwsError> {
         DianaResponse::Success(gql_res_str) => Response::builder()
             .status(200) // GraphQL will handle any errors within it through JSON
             .body(gql_res_str)?,
-        DianaResponse::Blocked => Response::builder()
-            .status(403) // Unauthorised
-            .body("Request blocked due to invalid or insufficient authentication".to_string())?,
+        DianaResponse::Blocked(failure) => Response::builder()
+            .status(failure.status_code()) // Unauthorised (or an invalid request)
+            .header("WWW-Authenticate", failure.www_authenticate())
+            .body(failure.description().to_string())?,
         DianaResponse::Error(_) => Response::builder()
             .status(500) // Internal server error
             .body("An internal server error occurred".to_string())?,
@@ -89,32 +139,36 @@ fn parse_aws_res(res: DianaResponse) -> Result<Response<String>, AwsError> {
 /// This just takes the entire Lambda request and does all the processing for you, but it's really just a wrapper around
 /// [`DianaHandler`](diana::DianaHandler).
 /// You should use this function in your Lambda handler as shown in the book.
-pub async fn run_aws_req<C, Q, M, S>(
+/// The handler is built from the given options on the first request a container receives, and then reused for the rest of its lifetime (so
+/// remote key sets and anything else that's cached will be shared between requests). Options given on later requests are ignored.
+pub async fn run_aws_req<C, Q, M, S, T>(
     req: Request,
-    opts: Options<C, Q, M, S>,
+    opts: Options<C, Q, M, S, T>,
 ) -> Result<Response<String>, AwsError>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
-    // TODO cache the DianaHandler instance
-
-    // Create a new Diana handler (core logic primitive)
-    let diana_handler = DianaHandler::new(opts.clone()).map_err(|err| err.to_string())?;
+    // Get the Diana handler (core logic primitive) for this container
+    let diana_handler = get_diana_handler(opts)?;
+    let opts = &diana_handler.opts;
     // Process the request data into what's needed
-    let req_data = get_data_from_aws_req(req)?;
-    let (body, auth_header) = match req_data {
+    let req_data = get_data_from_aws_req(req, |name| {
+        opts.token_extractor.reads_header(name)
+            || matches!(&opts.api_key_authenticator, Some(api_key_authenticator) if api_key_authenticator.reads_header(name))
+    })?;
+    let (body, auth_parts) = match req_data {
         AwsReqData::Valid(data) => data,
         AwsReqData::Invalid(http_res) => return Ok(http_res), // Propagate any HTTP responses for errors
     };
 
-    // Run the serverless request with the extracted data and the user's given options
-    // We convert the Option<String> to Option<&str> with `.as_deref()`
-    // We explicitly state that authentication checks need to be run again
+    // Run authentication checks with everything the token could be in, then run the serverless request with that verdict
+    let auth_verdict = diana_handler.is_authed_from_parts(&auth_parts).await;
     let res = diana_handler
-        .run_stateless_without_subscriptions(body, auth_header.as_deref(), None)
+        .run_stateless_without_subscriptions_from_parts(body, &auth_parts, Some(auth_verdict))
         .await;
 
     // Convert the result to an appropriate HTTP response
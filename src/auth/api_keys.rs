@@ -0,0 +1,258 @@
+// This file contains the logic for API keys, which are long-lived credentials for machine-to-machine callers that are used instead of JWTs
+// API keys are opaque random strings, and only their hashes are stored, so a leaked store can't be used to authenticate
+// Because the keys are long and random, a single fast hash is enough (there's nothing to brute-force like there is with passwords)
+// A verified key produces the same authentication state as a JWT would, so the rest of the system doesn't need to know the difference
+
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::extraction::{AuthRequestParts, TokenExtraction, TokenExtractor, TokenSource};
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwt::{Claims, RegisteredClaims, UserClaims};
+use crate::errors::*;
+
+/// The record of an API key that's kept in an [`ApiKeyStore`]. The key itself is never stored, only its hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKeyRecord {
+    /// A human-readable name for the key, so you know which caller it belongs to.
+    pub name: String,
+    /// The claims that requests using this key will be authorised with.
+    pub claims: serde_json::Value,
+    /// The subject of the key, usually the ID of the service that uses it.
+    pub sub: Option<String>,
+    /// The expiry of the key, in seconds from Unix epoch, if it has one.
+    pub exp: Option<u64>,
+}
+
+/// A store of API keys. Diana provides [`MemoryApiKeyStore`] and [`FileApiKeyStore`], but you can implement this yourself to use something
+/// like a database. Keys are identified by their hashes (see [`hash_api_key`]), so the raw keys never reach the store.
+pub trait ApiKeyStore: fmt::Debug + Send + Sync {
+    /// Stores the record of a new API key.
+    fn insert_key(&self, key_hash: &str, record: ApiKeyRecord) -> Result<()>;
+    /// Gets the record of the given API key, if it exists.
+    fn get_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>>;
+    /// Removes the given API key, so it can't be used anymore. This returns whether or not the key existed.
+    fn remove_key(&self, key_hash: &str) -> Result<bool>;
+}
+
+/// An API key store that's held entirely in memory. Keys will be lost when the system restarts, so this is best for development or for
+/// keys that are created from configuration on startup.
+#[derive(Debug, Default)]
+pub struct MemoryApiKeyStore {
+    keys: RwLock<HashMap<String, ApiKeyRecord>>,
+}
+impl MemoryApiKeyStore {
+    /// Creates a new empty in-memory API key store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl ApiKeyStore for MemoryApiKeyStore {
+    fn insert_key(&self, key_hash: &str, record: ApiKeyRecord) -> Result<()> {
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("api_keys".to_string()))?;
+        keys.insert(key_hash.to_string(), record);
+
+        Ok(())
+    }
+    fn get_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>> {
+        let keys = self
+            .keys
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("api_keys".to_string()))?;
+
+        Ok(keys.get(key_hash).cloned())
+    }
+    fn remove_key(&self, key_hash: &str) -> Result<bool> {
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("api_keys".to_string()))?;
+
+        Ok(keys.remove(key_hash).is_some())
+    }
+}
+
+/// An API key store that's held in memory and written to a JSON file (of key hashes mapped to their records) whenever it changes, so keys
+/// survive restarts. This is fine for a single instance, but you should use a shared store (like a database) if you have several.
+#[derive(Debug)]
+pub struct FileApiKeyStore {
+    path: PathBuf,
+    keys: RwLock<HashMap<String, ApiKeyRecord>>,
+}
+impl FileApiKeyStore {
+    /// Creates a new file-backed API key store, reading any existing keys from the given file. The file will be created when the first key
+    /// is inserted if it doesn't exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let keys = match path.exists() {
+            true => serde_json::from_str(&fs::read_to_string(&path)?)?,
+            false => HashMap::new(),
+        };
+
+        Ok(Self {
+            path,
+            keys: RwLock::new(keys),
+        })
+    }
+    // Writes the given keys to the file, which should be done while the write lock is still held
+    fn persist(&self, keys: &HashMap<String, ApiKeyRecord>) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string(keys)?)?;
+
+        Ok(())
+    }
+}
+impl ApiKeyStore for FileApiKeyStore {
+    fn insert_key(&self, key_hash: &str, record: ApiKeyRecord) -> Result<()> {
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("api_keys".to_string()))?;
+        keys.insert(key_hash.to_string(), record);
+
+        self.persist(&keys)
+    }
+    fn get_key(&self, key_hash: &str) -> Result<Option<ApiKeyRecord>> {
+        let keys = self
+            .keys
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("api_keys".to_string()))?;
+
+        Ok(keys.get(key_hash).cloned())
+    }
+    fn remove_key(&self, key_hash: &str) -> Result<bool> {
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("api_keys".to_string()))?;
+        let existed = keys.remove(key_hash).is_some();
+        self.persist(&keys)?;
+
+        Ok(existed)
+    }
+}
+
+/// Generates a new random API key. You'll need to give this to the caller and store its hash (see [`hash_api_key`]), or you can just use
+/// `ApiKeyAuthenticator::create_key()`, which does both.
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+/// Hashes an API key for storage. You only need this if you're putting keys into an [`ApiKeyStore`] yourself.
+pub fn hash_api_key(key: &str) -> String {
+    base64::encode_config(Sha256::digest(key.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// The system that verifies API keys. You should create one of these with an [`ApiKeyStore`] and give it to the
+/// [`Options`](crate::Options), after which requests can be authorised with API keys as well as JWTs (see
+/// [`AuthBlockLevel`](crate::AuthBlockLevel) for controlling which are accepted). By default, keys are read from the `X-Api-Key` header.
+/// The type parameter is the type of the user's claims, which should be the same as in your options.
+#[derive(Debug, Clone)]
+pub struct ApiKeyAuthenticator<T: UserClaims = HashMap<String, String>> {
+    store: Arc<dyn ApiKeyStore>,
+    extractor: TokenExtractor,
+    clock: Arc<dyn Clock>,
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> ApiKeyAuthenticator<T> {
+    /// Creates a new API key authenticator with the given store.
+    pub fn new(store: Arc<dyn ApiKeyStore>) -> Self {
+        Self {
+            store,
+            extractor: TokenExtractor::new()
+                .with_source(TokenSource::Header("X-Api-Key".to_string())),
+            clock: Arc::new(SystemClock),
+            claims_type: PhantomData,
+        }
+    }
+    /// Sets where API keys will be looked for in requests. This should be somewhere different to where JWTs are looked for.
+    pub fn extractor(mut self, extractor: TokenExtractor) -> Self {
+        self.extractor = extractor;
+        self
+    }
+    /// Sets the clock that key expiries will be checked against. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Creates a new API key with the given name, claims, subject and expiry (in seconds from Unix epoch), storing its hash. The raw key is
+    /// returned, and can't be recovered later, so you should give it to the caller straight away.
+    pub fn create_key(
+        &self,
+        name: &str,
+        claims: T,
+        sub: Option<&str>,
+        exp: Option<u64>,
+    ) -> Result<String> {
+        let key = generate_api_key();
+        let record = ApiKeyRecord {
+            name: name.to_string(),
+            claims: serde_json::to_value(claims)?,
+            sub: sub.map(|sub| sub.to_string()),
+            exp,
+        };
+        self.store.insert_key(&hash_api_key(&key), record)?;
+
+        Ok(key)
+    }
+    /// Revokes the given API key, so it can't be used anymore. This returns whether or not the key existed.
+    pub fn revoke_key(&self, key: &str) -> Result<bool> {
+        self.store.remove_key(&hash_api_key(key))
+    }
+    /// Looks for an API key in the given request parts.
+    pub fn extract(&self, parts: &AuthRequestParts) -> TokenExtraction {
+        self.extractor.extract(parts)
+    }
+    /// Checks if API keys are looked for in the given header (matched case-insensitively).
+    pub fn reads_header(&self, name: &str) -> bool {
+        self.extractor.reads_header(name)
+    }
+    /// Gets the authentication state of the result of looking for an API key. Keys that don't exist, have expired, or have claims that
+    /// can't be deserialized into the user's claims type are treated as invalid tokens. Keys without an expiry will have the largest
+    /// possible `exp` in their claims.
+    pub fn get_key_state(&self, key: TokenExtraction) -> Result<AuthState<T>> {
+        let key = match key {
+            TokenExtraction::Found(key) => key,
+            TokenExtraction::Invalid => {
+                return Ok(AuthState::InvalidToken(AuthFailure::InvalidRequest))
+            }
+            TokenExtraction::Missing => return Ok(AuthState::NoToken),
+        };
+        let record = match self.store.get_key(&hash_api_key(&key))? {
+            Some(record) => record,
+            None => return Ok(AuthState::InvalidToken(AuthFailure::UnknownCredentials)),
+        };
+        let now = self.clock.timestamp();
+        if matches!(record.exp, Some(exp) if exp < now) {
+            return Ok(AuthState::InvalidToken(AuthFailure::Expired));
+        }
+        let claims: T = match serde_json::from_value(record.claims) {
+            Ok(claims) => claims,
+            Err(_) => return Ok(AuthState::InvalidToken(AuthFailure::InvalidClaims)),
+        };
+
+        Ok(AuthState::Authorised(AuthToken(
+            Claims {
+                exp: record.exp.unwrap_or(u64::MAX),
+                registered: RegisteredClaims {
+                    sub: record.sub,
+                    ..RegisteredClaims::default()
+                },
+                claims,
+            },
+            None,
+        )))
+    }
+}
@@ -1,36 +1,55 @@
 use std::collections::HashMap;
 
-use crate::auth::jwt::Claims;
+use crate::auth::claim_predicate::ClaimPredicate;
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwt::{Claims, UserClaims};
+use crate::auth::tenant::Tenant;
 use crate::errors::*;
 
-/// An authentication token with claims.
+/// An authentication token with claims, and the tenant it was verified for (if there are tenants and it's a JWT).
 #[derive(Debug, Clone)]
-pub struct AuthToken(pub Claims);
+pub struct AuthToken<T: UserClaims = HashMap<String, String>>(pub Claims<T>, pub Option<Tenant>);
+impl<T: UserClaims> From<Claims<T>> for AuthToken<T> {
+    fn from(claims: Claims<T>) -> Self {
+        Self(claims, None)
+    }
+}
 
-/// The three states authentication can be in at the token level.
+/// The states authentication can be in at the token level. This is generic over the type of the user's claims, which is a map of
+/// strings by default.
 #[derive(Debug, Clone)]
-pub enum AuthState {
+pub enum AuthState<T: UserClaims = HashMap<String, String>> {
     /// The user is authorized, authentication data is attached.
-    Authorised(AuthToken),
-    /// An invalid token was provided.
-    InvalidToken,
+    Authorised(AuthToken<T>),
+    /// An invalid token was provided, for the attached reason.
+    InvalidToken(AuthFailure),
+    /// A valid token was provided, but it has been revoked.
+    RevokedToken,
     /// No token was provided.
     NoToken,
 }
-impl AuthState {
+impl<T: UserClaims> AuthState<T> {
     /// Checks if the each key/value pair in the given `HashMap` is present in the token. This will return false if the token was invalid
-    /// or not provided.
+    /// or not provided. If you're using a custom claims type, this will check its serialized fields, and only string fields can match.
+    /// For anything more complex than exact matches, use `.satisfies()`.
     pub fn has_claims(&self, test_claims: HashMap<&str, &str>) -> bool {
-        if let Self::Authorised(AuthToken(Claims { claims, .. })) = self {
-            for (key, val) in &test_claims {
-                if claims.get(&key.to_string()) != Some(&val.to_string()) {
-                    return false;
-                }
+        let predicate = ClaimPredicate::All(
+            test_claims
+                .iter()
+                .map(|(key, val)| ClaimPredicate::Equals(key.to_string(), val.to_string()))
+                .collect(),
+        );
+        self.satisfies(&predicate)
+    }
+    /// Checks if the claims in the token satisfy the given predicate. This will return false if the token was invalid or not provided
+    /// (even if the predicate is a negation). You'll usually create the predicate with [`claim_predicate!`](crate::claim_predicate), or
+    /// you can just use [`is_authed!`](crate::is_authed).
+    pub fn satisfies(&self, predicate: &ClaimPredicate) -> bool {
+        match self {
+            Self::Authorised(AuthToken(Claims { claims, .. }, _)) => {
+                predicate.is_satisfied_by(claims)
             }
-
-            true // If we're here everything's passed
-        } else {
-            false
+            _ => false,
         }
     }
     /// Checks if the token is valid.
@@ -39,17 +58,30 @@ impl AuthState {
     }
     /// Checks if the token is invalid.
     pub fn is_invalid(&self) -> bool {
-        matches!(self, Self::InvalidToken)
+        matches!(self, Self::InvalidToken(_))
+    }
+    /// Checks if the token has been revoked.
+    pub fn is_revoked(&self) -> bool {
+        matches!(self, Self::RevokedToken)
     }
     /// Checks if the token is not present.
     pub fn has_no_token(&self) -> bool {
         matches!(self, Self::NoToken)
     }
     /// Gets a reference to the claims of the token (including metadata like expiry).
-    pub fn get_claims(&self) -> Result<&Claims> {
+    pub fn get_claims(&self) -> Result<&Claims<T>> {
         match self {
-            Self::Authorised(AuthToken(claims)) => Ok(claims),
+            Self::Authorised(AuthToken(claims, _)) => Ok(claims),
             _ => bail!(ErrorKind::Unauthorised),
         }
     }
+    /// Gets the tenant the token was verified for. This will be `None` if the token was invalid or not provided, if there are no tenants, or
+    /// if the request was authenticated with an API key (those aren't specific to tenants). Requests without valid tokens can still get
+    /// their tenant from the context with `ctx.data::<Tenant>()`.
+    pub fn tenant(&self) -> Option<&Tenant> {
+        match self {
+            Self::Authorised(AuthToken(_, tenant)) => tenant.as_ref(),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,190 @@
+// This file contains the trait that maps the credentials in a request to an authentication state, along with the implementations Diana provides
+// By default, Diana authenticates requests with JWTs, but this lets users plug in anything else (e.g. OAuth2 token introspection, mTLS
+// identities, or their own session services) without touching the rest of the system
+// This is asynchronous because most of those will need to do some kind of I/O
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::core::get_token_state;
+use crate::auth::extraction::{AuthRequestParts, TokenExtraction, TokenExtractor};
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwks::JWTKeyProvider;
+use crate::auth::jwt::{Claims, JWTValidation, RegisteredClaims, UserClaims};
+use crate::auth::revocation::RevocationStore;
+use crate::errors::*;
+
+/// A system that maps the credentials in a request to an authentication state. Diana uses [`JWTAuthenticator`] by default, but you can
+/// implement this yourself and give it to the [`OptionsBuilder`](crate::OptionsBuilder) to authenticate requests however you like.
+/// This is asynchronous, so it can do I/O (e.g. calling an external service). Any errors will result in an [`AuthVerdict::Error`](crate::AuthVerdict),
+/// so credentials that are simply invalid should produce `AuthState::InvalidToken` (with the reason) instead.
+/// The type parameter is the type of the user's claims, which should be the same as in your options.
+#[async_trait]
+pub trait Authenticator<T: UserClaims = HashMap<String, String>>: fmt::Debug + Send + Sync {
+    /// Gets the authentication state of the given request. If the request has no credentials at all, this should return
+    /// `AuthState::NoToken`.
+    async fn authenticate(&self, parts: &AuthRequestParts) -> Result<AuthState<T>>;
+}
+
+/// The default authenticator, which validates JWTs with the given keys. This will be created from the [`Options`](crate::Options) unless
+/// you give another authenticator, so you'll only need to create this yourself if you're wrapping it in your own authenticator.
+#[derive(Debug, Clone)]
+pub struct JWTAuthenticator<T: UserClaims = HashMap<String, String>> {
+    keys: JWTKeyProvider,
+    validation: JWTValidation,
+    revocation_store: Option<Arc<dyn RevocationStore>>,
+    token_extractor: TokenExtractor,
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> JWTAuthenticator<T> {
+    /// Creates a new JWT authenticator with the given keys and validation settings. By default, tokens will be found in the `Authorization`
+    /// header with the `Bearer` scheme, and revocation won't be checked.
+    pub fn new(keys: JWTKeyProvider, validation: JWTValidation) -> Self {
+        Self {
+            keys,
+            validation,
+            revocation_store: None,
+            token_extractor: TokenExtractor::default(),
+            claims_type: PhantomData,
+        }
+    }
+    /// Sets the store of revoked JWTs that will be checked whenever a token is validated.
+    pub fn revocation_store(mut self, revocation_store: Arc<dyn RevocationStore>) -> Self {
+        self.revocation_store = Some(revocation_store);
+        self
+    }
+    /// Sets where tokens will be looked for in requests.
+    pub fn token_extractor(mut self, token_extractor: TokenExtractor) -> Self {
+        self.token_extractor = token_extractor;
+        self
+    }
+}
+#[async_trait]
+impl<T: UserClaims> Authenticator<T> for JWTAuthenticator<T> {
+    async fn authenticate(&self, parts: &AuthRequestParts) -> Result<AuthState<T>> {
+        get_token_state(
+            self.token_extractor.extract(parts),
+            &self.keys,
+            &self.validation,
+            self.revocation_store.as_deref(),
+        )
+        .await
+    }
+}
+
+// The fields of an introspection response that are put into the registered claims rather than the user's claims
+const INTROSPECTION_REGISTERED_FIELDS: [&str; 8] =
+    ["active", "exp", "iss", "sub", "aud", "nbf", "iat", "jti"];
+
+/// An authenticator that checks opaque tokens with an OAuth2 token introspection endpoint (see
+/// [RFC 7662](https://datatracker.ietf.org/doc/html/rfc7662)). Every request with a token will make a request to the endpoint, so this
+/// is best for low-traffic APIs or tokens that can't be validated locally.
+/// The registered claims (`sub`, `iss`, etc.) in the introspection response will become the registered claims of the token, and all the
+/// other properties (e.g. `scope`, `client_id`) will be deserialized into the user's claims type. If that fails, the token will be
+/// treated as invalid. Tokens without an `exp` will have the largest possible expiry.
+#[derive(Debug, Clone)]
+pub struct IntrospectionAuthenticator<T: UserClaims = HashMap<String, String>> {
+    endpoint: String,
+    client_credentials: Option<(String, String)>,
+    token_extractor: TokenExtractor,
+    client: reqwest::Client,
+    clock: Arc<dyn Clock>,
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> IntrospectionAuthenticator<T> {
+    /// Creates a new introspection authenticator that will use the given endpoint. By default, tokens will be found in the `Authorization`
+    /// header with the `Bearer` scheme.
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.to_string(),
+            client_credentials: None,
+            token_extractor: TokenExtractor::default(),
+            client: reqwest::Client::new(),
+            clock: Arc::new(SystemClock),
+            claims_type: PhantomData,
+        }
+    }
+    /// Sets the client ID and secret that will be used to authenticate with the introspection endpoint (with HTTP basic authentication).
+    /// Most providers require this.
+    pub fn client_credentials(mut self, client_id: &str, client_secret: &str) -> Self {
+        self.client_credentials = Some((client_id.to_string(), client_secret.to_string()));
+        self
+    }
+    /// Sets where tokens will be looked for in requests.
+    pub fn token_extractor(mut self, token_extractor: TokenExtractor) -> Self {
+        self.token_extractor = token_extractor;
+        self
+    }
+    /// Sets the clock that the expiries in introspection responses will be checked against. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+#[async_trait]
+impl<T: UserClaims> Authenticator<T> for IntrospectionAuthenticator<T> {
+    async fn authenticate(&self, parts: &AuthRequestParts) -> Result<AuthState<T>> {
+        let token = match self.token_extractor.extract(parts) {
+            TokenExtraction::Found(token) => token,
+            TokenExtraction::Invalid => {
+                return Ok(AuthState::InvalidToken(AuthFailure::InvalidRequest))
+            }
+            TokenExtraction::Missing => return Ok(AuthState::NoToken),
+        };
+        let mut req = self.client.post(&self.endpoint).form(&[
+            ("token", token.as_str()),
+            ("token_type_hint", "access_token"),
+        ]);
+        if let Some((client_id, client_secret)) = &self.client_credentials {
+            req = req.basic_auth(client_id, Some(client_secret));
+        }
+        let res: serde_json::Value = req.send().await?.error_for_status()?.json().await?;
+
+        Ok(parse_introspection_response(res, self.clock.timestamp()))
+    }
+}
+
+// Turns the response from an introspection endpoint into an authentication state
+fn parse_introspection_response<T: UserClaims>(res: serde_json::Value, now: u64) -> AuthState<T> {
+    let mut fields = match res {
+        serde_json::Value::Object(fields) => fields,
+        _ => return AuthState::InvalidToken(AuthFailure::InvalidClaims),
+    };
+    if fields.get("active").and_then(|active| active.as_bool()) != Some(true) {
+        return AuthState::InvalidToken(AuthFailure::UnknownCredentials);
+    }
+    let exp = fields
+        .get("exp")
+        .and_then(|exp| exp.as_u64())
+        .unwrap_or(u64::MAX);
+    // The endpoint should never say an expired token is active, but we check anyway
+    if exp < now {
+        return AuthState::InvalidToken(AuthFailure::Expired);
+    }
+    let registered: RegisteredClaims =
+        match serde_json::from_value(serde_json::Value::Object(fields.clone())) {
+            Ok(registered) => registered,
+            Err(_) => return AuthState::InvalidToken(AuthFailure::InvalidClaims),
+        };
+    for field in INTROSPECTION_REGISTERED_FIELDS.iter() {
+        fields.remove(*field);
+    }
+    let claims: T = match serde_json::from_value(serde_json::Value::Object(fields)) {
+        Ok(claims) => claims,
+        Err(_) => return AuthState::InvalidToken(AuthFailure::InvalidClaims),
+    };
+
+    AuthState::Authorised(AuthToken(
+        Claims {
+            exp,
+            registered,
+            claims,
+        },
+        None,
+    ))
+}
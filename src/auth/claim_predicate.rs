@@ -0,0 +1,113 @@
+// This file contains the predicates that claims can be checked against, which are used by `is_authed!` and `AuthState::has_claims`
+// These work on the serialized claims so that they can be used with any claims type
+// You'll usually build these with the `claim_predicate!` macro rather than directly
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// A condition on the claims of a token. These can be combined with `All`, `Any` and `Not` to express more complex rules, and you'll usually
+/// create them with [`claim_predicate!`](crate::claim_predicate) (or directly in [`is_authed!`](crate::is_authed)) rather than by hand.
+/// These work on the serialized claims, so they can be used with any claims type. A claim that doesn't exist won't match anything except
+/// a negation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClaimPredicate {
+    /// The claim is a string equal to the given value.
+    Equals(String, String),
+    /// The claim is a string equal to any of the given values.
+    AnyOf(String, Vec<String>),
+    /// The claim is a list that contains the given value. If the claim is a string, it will be treated as a space-separated list (like an
+    /// OAuth2 `scope`).
+    Contains(String, String),
+    /// The claim is a string (or a list with an element) that matches the given pattern, in which `*` matches any sequence of characters.
+    Matches(String, String),
+    /// The claim is a string (or a list with an element) that starts with the given prefix.
+    StartsWith(String, String),
+    /// The claim exists, whatever its value.
+    Exists(String),
+    /// The given predicate is not satisfied.
+    Not(Box<ClaimPredicate>),
+    /// All of the given predicates are satisfied. This is satisfied if there are no predicates.
+    All(Vec<ClaimPredicate>),
+    /// Any of the given predicates are satisfied. This is not satisfied if there are no predicates.
+    Any(Vec<ClaimPredicate>),
+}
+impl ClaimPredicate {
+    /// Checks if the given claims satisfy this predicate. Claims that don't serialize to an object won't satisfy anything except a
+    /// negation.
+    pub fn is_satisfied_by<T: Serialize>(&self, claims: &T) -> bool {
+        let claims = match serde_json::to_value(claims) {
+            Ok(Value::Object(claims)) => claims,
+            _ => Map::new(),
+        };
+        self.evaluate(&claims)
+    }
+    // Checks the predicate against serialized claims
+    fn evaluate(&self, claims: &Map<String, Value>) -> bool {
+        match self {
+            Self::Equals(key, value) => get_str(claims, key) == Some(value.as_str()),
+            Self::AnyOf(key, values) => match get_str(claims, key) {
+                Some(claim) => values.iter().any(|value| value == claim),
+                None => false,
+            },
+            Self::Contains(key, value) => match claims.get(key) {
+                Some(Value::Array(elems)) => elems.iter().any(|elem| elem.as_str() == Some(value)),
+                Some(Value::String(claim)) => claim.split_whitespace().any(|word| word == value),
+                _ => false,
+            },
+            Self::Matches(key, pattern) => {
+                get_strs(claims, key).any(|claim| glob_matches(pattern, claim))
+            }
+            Self::StartsWith(key, prefix) => {
+                get_strs(claims, key).any(|claim| claim.starts_with(prefix.as_str()))
+            }
+            Self::Exists(key) => claims.contains_key(key),
+            Self::Not(predicate) => !predicate.evaluate(claims),
+            Self::All(predicates) => predicates
+                .iter()
+                .all(|predicate| predicate.evaluate(claims)),
+            Self::Any(predicates) => predicates
+                .iter()
+                .any(|predicate| predicate.evaluate(claims)),
+        }
+    }
+}
+
+// Gets the given claim if it's a string
+fn get_str<'a>(claims: &'a Map<String, Value>, key: &str) -> Option<&'a str> {
+    claims.get(key).and_then(|claim| claim.as_str())
+}
+// Gets the given claim if it's a string, or its string elements if it's a list
+fn get_strs<'a>(claims: &'a Map<String, Value>, key: &str) -> impl Iterator<Item = &'a str> {
+    let strs: Vec<&str> = match claims.get(key) {
+        Some(Value::String(claim)) => vec![claim.as_str()],
+        Some(Value::Array(elems)) => elems.iter().filter_map(|elem| elem.as_str()).collect(),
+        _ => Vec::new(),
+    };
+    strs.into_iter()
+}
+
+// Checks if the given value matches the given pattern, in which `*` matches any sequence of characters (including none)
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // There's always a first part, which has to be at the start (it'll be empty if the pattern starts with `*`)
+    let first = parts.next().unwrap_or("");
+    let mut remaining = match value.strip_prefix(first) {
+        Some(remaining) => remaining,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    // If there were no wildcards, the whole value must have been matched
+    let (last, middle) = match parts.split_last() {
+        Some((last, middle)) => (*last, middle),
+        None => return remaining.is_empty(),
+    };
+    // The parts between wildcards can be anywhere, and we take the earliest match to leave as much room as possible for the rest
+    for part in middle {
+        match remaining.find(part) {
+            Some(idx) => remaining = &remaining[idx + part.len()..],
+            None => return false,
+        }
+    }
+    // The last part has to be at the end
+    remaining.ends_with(last)
+}
@@ -0,0 +1,67 @@
+// This file contains the clocks that all time-dependent authentication logic reads the current time from
+// Using a fake clock makes it possible to test expiries without waiting for them
+
+use chrono::{prelude::Utc, DateTime, Duration};
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+/// A source of the current time. Everything in Diana that depends on the time (creating and validating tokens, revocation, refresh
+/// tokens, etc.) can be given one of these. You'll almost always want [`SystemClock`] (the default), but [`FakeClock`] lets you control
+/// time in your tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Gets the current datetime.
+    fn now(&self) -> DateTime<Utc>;
+    /// Gets the current datetime in seconds from Unix epoch, which is what JWTs use. Datetimes before January 1st 1970 will be clamped to
+    /// it.
+    fn timestamp(&self) -> u64 {
+        self.now().timestamp().max(0) as u64
+    }
+}
+
+/// A clock that reads the system time. This is the default everywhere.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when you tell it to, which is useful for testing expiries. This should be shared (in an `Arc`) between
+/// everything you're testing, and then moved forward with `.advance()`.
+#[derive(Debug)]
+pub struct FakeClock {
+    now: Mutex<DateTime<Utc>>,
+}
+impl FakeClock {
+    /// Creates a new fake clock stopped at the given datetime.
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+    /// Sets the clock to the given datetime, which can be in the past.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.lock() = now;
+    }
+    /// Moves the clock forward by the given duration (or backward, if it's negative).
+    pub fn advance(&self, duration: Duration) {
+        *self.lock() += duration;
+    }
+
+    // The lock only guards a copyable datetime, so we can ignore poisoning rather than making every time lookup fallible
+    fn lock(&self) -> MutexGuard<'_, DateTime<Utc>> {
+        self.now.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+impl Default for FakeClock {
+    /// Creates a new fake clock stopped at the present moment.
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.lock()
+    }
+}
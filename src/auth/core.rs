@@ -1,16 +1,24 @@
 // This file contains the core authentication logic that will be used regardless of integration
 
+use std::collections::HashMap;
+
 use crate::auth::auth_state::{AuthState, AuthToken};
-use crate::auth::jwt::{get_jwt_secret, validate_and_decode_jwt};
+use crate::auth::extraction::TokenExtraction;
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwks::JWTKeyProvider;
+use crate::auth::jwt::{JWTValidation, UserClaims};
+use crate::auth::revocation::{is_revoked, RevocationStore};
 use crate::errors::*;
 
-/// An enum for the level of blocking imposed on a particular endpoint.
+/// An enum for the level of blocking imposed on a particular endpoint, and which kinds of credentials it accepts.
+/// Unless otherwise stated, both JWTs and API keys are accepted (API keys only if you've set an [`ApiKeyAuthenticator`](crate::ApiKeyAuthenticator)).
 /// Your choice on this should be carefully evaluated based on your threat model. Please choose wisely!
 #[derive(Debug, Clone, Copy)]
 pub enum AuthBlockLevel {
     /// Allows anything through.
     /// - Valid token   -> allow
     /// - Invalid token -> allow
+    /// - Revoked token -> allow
     /// - Missing token -> allow
     AllowAll,
     /// Blocks eveything except requests with valid tokens.
@@ -18,79 +26,116 @@ pub enum AuthBlockLevel {
     /// and then this in production (see the book).
     /// - Valid token   -> allow
     /// - Invalid token -> block
+    /// - Revoked token -> block
     /// - Missing token -> block
     BlockUnauthenticated,
     /// Allows requests with valid tokens or no token at all. Only blocks requests that specify an invalid token.
     /// This is mostly useful for development to enable introspection in the GraphiQL playground (see the book).
     /// - Valid token   -> allow
     /// - Invalid token -> block
+    /// - Revoked token -> block
     /// - Missing token -> allow
     AllowMissing,
+    /// Like `BlockUnauthenticated`, but only accepts JWTs (or whatever your [`Authenticator`](crate::Authenticator) checks, if you've set
+    /// one). Requests with API keys will be treated as having invalid tokens.
+    /// - Valid JWT     -> allow
+    /// - API key       -> block
+    /// - Invalid token -> block
+    /// - Revoked token -> block
+    /// - Missing token -> block
+    RequireJwt,
+    /// Like `BlockUnauthenticated`, but only accepts API keys. Requests with JWTs will be treated as having invalid tokens.
+    /// - Valid API key -> allow
+    /// - JWT           -> block
+    /// - Invalid token -> block
+    /// - Missing token -> block
+    RequireApiKey,
+}
+impl AuthBlockLevel {
+    /// Checks whether or not JWTs (or the credentials checked by a custom authenticator) are accepted at this block level.
+    pub fn accepts_jwts(&self) -> bool {
+        !matches!(self, AuthBlockLevel::RequireApiKey)
+    }
+    /// Checks whether or not API keys are accepted at this block level.
+    pub fn accepts_api_keys(&self) -> bool {
+        !matches!(self, AuthBlockLevel::RequireJwt)
+    }
 }
 
-// Extracts an authentication state from the given Option<String> token
+// Extracts an authentication state from the result of looking for a token in a request
 // This is exposed as a primitive for serverful and serverless authentication logic
-pub fn get_token_state_from_header(
-    auth_header: Option<&str>,
-    secret_str: String,
-) -> Result<AuthState> {
-    // Get the bearer token from the header if it exists
-    let bearer_token = match auth_header {
-        Some(header) => header
-            .split("Bearer")
-            .collect::<Vec<&str>>()
-            .get(1) // Get everything apart from that first element
-            .map(|token| token.trim()),
-        None => None,
-    };
-
-    // Decode the bearer token into an authentication state
-    match bearer_token {
-        Some(token) => {
-            let jwt_secret = get_jwt_secret(secret_str)?;
-            let decoded_jwt = validate_and_decode_jwt(&token, &jwt_secret);
+// This is asynchronous because the keys may need to be fetched from a remote JWKS
+// Tokens with claims that can't be deserialized into the user's claims type are treated as invalid
+// Revocation is only checked for tokens that are otherwise valid, so the store isn't hit for garbage tokens
+pub async fn get_token_state<T: UserClaims>(
+    token: TokenExtraction,
+    jwt_keys: &JWTKeyProvider,
+    jwt_validation: &JWTValidation,
+    revocation_store: Option<&dyn RevocationStore>,
+) -> Result<AuthState<T>> {
+    // Decode the token into an authentication state
+    match token {
+        TokenExtraction::Found(token) => {
+            let decoded_jwt = jwt_keys
+                .validate_and_decode_jwt_with_reason(&token, jwt_validation)
+                .await?;
 
             match decoded_jwt {
-                Some(claims) => Ok(AuthState::Authorised(AuthToken(claims))),
-                None => Ok(AuthState::InvalidToken), // The token is invalid
+                Ok(claims) => match revocation_store {
+                    Some(store) if is_revoked(store, &claims)? => Ok(AuthState::RevokedToken),
+                    _ => Ok(AuthState::Authorised(AuthToken(claims, None))),
+                },
+                Err(failure) => Ok(AuthState::InvalidToken(failure)), // The token is invalid
             }
         }
-        None => Ok(AuthState::NoToken), // No token exists
+        // The request was invalid (e.g. it failed a CSRF check)
+        TokenExtraction::Invalid => Ok(AuthState::InvalidToken(AuthFailure::InvalidRequest)),
+        TokenExtraction::Missing => Ok(AuthState::NoToken), // No token exists
     }
 }
 
 /// This represents the decision as to whether or not a use is allowed through to an endpoint. You should only have to deal with this if you're
 /// developing middleware for a custom integration.
 #[derive(Clone, Debug)]
-pub enum AuthVerdict {
+pub enum AuthVerdict<T: UserClaims = HashMap<String, String>> {
     /// The user should be allowed through, and their decoded authentication data (JWT payload without metadata) is attached.
-    Allow(AuthState),
-    /// The user should be blocked.
-    Block,
+    Allow(AuthState<T>),
+    /// The user should be blocked, for the attached reason.
+    Block(AuthFailure),
+    /// The user should be blocked because their token has been revoked. This should be treated in the same way as `Block`, but is separate
+    /// so that it can be reported differently.
+    Revoked,
     /// Some internal error occurred, the body of which is attached.
     Error(String),
 }
 
 // Compares the given token's authentication state (as a raw result) to a given block-level to arrive at a verdict
-pub fn get_auth_verdict(
-    token_state: Result<AuthState>,
+pub fn get_auth_verdict<T: UserClaims>(
+    token_state: Result<AuthState<T>>,
     block_state: AuthBlockLevel,
-) -> AuthVerdict {
+) -> AuthVerdict<T> {
     match token_state {
         // We hold `token_state` as the AuthState variant so we don't pointlessly insert a Result into the request extensions
         Ok(token_state @ AuthState::Authorised(_)) => AuthVerdict::Allow(token_state),
-        Ok(token_state @ AuthState::InvalidToken) => {
+        Ok(AuthState::InvalidToken(failure)) => {
+            if let AuthBlockLevel::AllowAll = block_state {
+                AuthVerdict::Allow(AuthState::InvalidToken(failure))
+            } else {
+                AuthVerdict::Block(failure)
+            }
+        }
+        Ok(token_state @ AuthState::RevokedToken) => {
             if let AuthBlockLevel::AllowAll = block_state {
                 AuthVerdict::Allow(token_state)
             } else {
-                AuthVerdict::Block
+                AuthVerdict::Revoked
             }
         }
         Ok(token_state @ AuthState::NoToken) => {
             if let AuthBlockLevel::AllowAll | AuthBlockLevel::AllowMissing = block_state {
                 AuthVerdict::Allow(token_state)
             } else {
-                AuthVerdict::Block
+                AuthVerdict::Block(AuthFailure::MissingToken)
             }
         }
         Err(err) => AuthVerdict::Error(err.to_string()),
@@ -0,0 +1,252 @@
+// This file contains the logic for parsing durations like `1w` or `P1M2DT3H`, which are mostly used for token expiries
+// Months and years are fixed lengths by default (for compatibility), but they can optionally be added according to the calendar
+
+use chrono::{prelude::Utc, DateTime, Datelike, Duration, NaiveDate, TimeZone};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use crate::auth::clock::{Clock, SystemClock};
+use crate::errors::*;
+
+const SECONDS_IN_MINUTE: i64 = 60;
+const SECONDS_IN_HOUR: i64 = 60 * SECONDS_IN_MINUTE;
+const SECONDS_IN_DAY: i64 = 24 * SECONDS_IN_HOUR;
+const SECONDS_IN_WEEK: i64 = 7 * SECONDS_IN_DAY;
+
+/// A duration parsed from a timestring. Two formats are accepted:
+///
+/// - Shorthand strings of the form 'xXyYzZ...', where the lower-case letters are numbers meaning a number of the intervals X/Y/Z (e.g.
+///   `1M4d` -- one month four days). The available intervals are `s` (second), `m` (minute), `h` (hour), `d` (day), `w` (week), `M` (month)
+///   and `y` (year).
+/// - ISO 8601 durations, like `P1Y2M3W4DT5H6M7S`. The designators must be in that order, and fractions aren't supported.
+///
+/// By default, a month is 30 days and a year is 365 days (so 12 months ≠ 1 year!), which means this can be converted into a `chrono` or
+/// `std` duration. If you call [`calendar_aware`](ParsedDuration::calendar_aware), months and years will instead be added according to
+/// the calendar when this is added to a datetime (so one month after January 31st is the end of February).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParsedDuration {
+    years: i64,
+    months: i64,
+    seconds: i64,
+    calendar_aware: bool,
+}
+impl ParsedDuration {
+    /// Parses the given timestring, which may be in either shorthand or ISO 8601 format (ISO 8601 durations always start with `P`). The
+    /// errors returned will contain the position of the problem in the string.
+    pub fn parse(time_str: &str) -> Result<Self> {
+        match time_str.strip_prefix('P') {
+            Some(designators) => Self::parse_iso(designators),
+            None => Self::parse_shorthand(time_str),
+        }
+    }
+    /// Sets whether or not months and years should be added according to the calendar rather than as fixed numbers of days. This is
+    /// `false` by default.
+    pub fn calendar_aware(mut self, calendar_aware: bool) -> Self {
+        self.calendar_aware = calendar_aware;
+        self
+    }
+    /// Converts this into a `chrono` duration. This always uses the fixed lengths of months and years, even if the duration is
+    /// calendar-aware, because a calendar-aware duration has no length until it's added to a datetime.
+    pub fn to_chrono(&self) -> Result<Duration> {
+        let seconds = self.fixed_seconds().ok_or(ErrorKind::DurationOutOfRange)?;
+        seconds_to_chrono(seconds)
+    }
+    /// Converts this into a `std` duration. This works in the same way as [`to_chrono`](ParsedDuration::to_chrono).
+    pub fn to_std(&self) -> Result<std::time::Duration> {
+        let seconds = self.fixed_seconds().ok_or(ErrorKind::DurationOutOfRange)?;
+        // Durations can't be negative, so this will always work
+        Ok(std::time::Duration::from_secs(seconds as u64))
+    }
+    /// Gets the datetime this duration after the given one, adding months and years according to the calendar if this is calendar-aware.
+    pub fn after(&self, start: DateTime<Utc>) -> Result<DateTime<Utc>> {
+        let (start, duration) = if self.calendar_aware {
+            let months = self
+                .years
+                .checked_mul(12)
+                .and_then(|months| months.checked_add(self.months))
+                .ok_or(ErrorKind::DurationOutOfRange)?;
+            (add_months(start, months)?, seconds_to_chrono(self.seconds)?)
+        } else {
+            (start, self.to_chrono()?)
+        };
+        start
+            .checked_add_signed(duration)
+            .ok_or_else(|| ErrorKind::DurationOutOfRange.into())
+    }
+    /// Gets the datetime this duration after the present moment. This is what you'll usually want for token expiries.
+    pub fn from_now(&self) -> Result<DateTime<Utc>> {
+        self.from_now_with_clock(&SystemClock)
+    }
+    /// Gets the datetime this duration after the present moment according to the given clock.
+    pub fn from_now_with_clock(&self, clock: &dyn Clock) -> Result<DateTime<Utc>> {
+        self.after(clock.now())
+    }
+
+    // Parses shorthand timestrings like `1w2d`, in which intervals can be in any order and repeated
+    fn parse_shorthand(time_str: &str) -> Result<Self> {
+        let intervals = split_intervals(time_str, 0, "smhdwMy")?;
+        if intervals.is_empty() {
+            bail!(ErrorKind::EmptyDuration)
+        }
+        let mut duration = Self::default();
+        for (length, indicator, _) in intervals {
+            match indicator {
+                's' => duration.add_seconds(length, 1)?,
+                'm' => duration.add_seconds(length, SECONDS_IN_MINUTE)?,
+                'h' => duration.add_seconds(length, SECONDS_IN_HOUR)?,
+                'd' => duration.add_seconds(length, SECONDS_IN_DAY)?,
+                'w' => duration.add_seconds(length, SECONDS_IN_WEEK)?,
+                'M' => duration.months = checked_add(duration.months, length)?,
+                // This can only be a year, `split_intervals` checks the indicators for us
+                _ => duration.years = checked_add(duration.years, length)?,
+            }
+        }
+
+        Ok(duration)
+    }
+    // Parses ISO 8601 durations (without the leading `P`), in which each designator can appear once, in order
+    // `M` means months before the `T` and minutes after it
+    fn parse_iso(designators: &str) -> Result<Self> {
+        let (date_part, time_part) = match designators.split_once('T') {
+            Some((date_part, time_part)) => (date_part, Some(time_part)),
+            None => (designators, None),
+        };
+        // Positions are relative to the whole string, including the `P` and `T`
+        let date_offset = 1;
+        let time_offset = date_offset + date_part.chars().count() + 1;
+        let date_intervals = split_intervals(date_part, date_offset, "YMWDHS")?;
+        let time_intervals = match time_part {
+            // A `T` must be followed by at least one time interval
+            Some("") => bail!(ErrorKind::MisplacedDurationDesignator(
+                "T".to_string(),
+                time_offset - 1
+            )),
+            Some(time_part) => split_intervals(time_part, time_offset, "HMSYWD")?,
+            None => Vec::new(),
+        };
+        if date_intervals.is_empty() && time_intervals.is_empty() {
+            bail!(ErrorKind::EmptyDuration)
+        }
+
+        let mut duration = Self::default();
+        for (intervals, order) in [(date_intervals, "YMWD"), (time_intervals, "HMS")] {
+            // The index in the order of the last designator we saw, so we can make sure they're in order with no repeats
+            let mut last_idx = None;
+            for (length, designator, position) in intervals {
+                let idx = match order.find(designator) {
+                    // `None` is less than any index, so the first designator is always fine
+                    Some(idx) if last_idx < Some(idx) => idx,
+                    _ => bail!(ErrorKind::MisplacedDurationDesignator(
+                        designator.to_string(),
+                        position
+                    )),
+                };
+                last_idx = Some(idx);
+                match (order, designator) {
+                    ("YMWD", 'Y') => duration.years = length,
+                    ("YMWD", 'M') => duration.months = length,
+                    ("YMWD", 'W') => duration.add_seconds(length, SECONDS_IN_WEEK)?,
+                    ("YMWD", _) => duration.add_seconds(length, SECONDS_IN_DAY)?,
+                    (_, 'H') => duration.add_seconds(length, SECONDS_IN_HOUR)?,
+                    (_, 'M') => duration.add_seconds(length, SECONDS_IN_MINUTE)?,
+                    (_, _) => duration.add_seconds(length, 1)?,
+                }
+            }
+        }
+
+        Ok(duration)
+    }
+    // Adds the given number of intervals of the given length in seconds
+    fn add_seconds(&mut self, length: i64, interval_seconds: i64) -> Result<()> {
+        let seconds = length
+            .checked_mul(interval_seconds)
+            .ok_or(ErrorKind::DurationOutOfRange)?;
+        self.seconds = checked_add(self.seconds, seconds)?;
+        Ok(())
+    }
+    // Gets the total number of seconds in this duration, assuming months are 30 days and years are 365 days
+    fn fixed_seconds(&self) -> Option<i64> {
+        self.years
+            .checked_mul(365 * SECONDS_IN_DAY)?
+            .checked_add(self.months.checked_mul(30 * SECONDS_IN_DAY)?)?
+            .checked_add(self.seconds)
+    }
+}
+impl FromStr for ParsedDuration {
+    type Err = Error;
+
+    fn from_str(time_str: &str) -> Result<Self> {
+        Self::parse(time_str)
+    }
+}
+
+// Splits the given string into intervals of a length, an indicator and the indicator's position (offset by the given amount)
+// Only the given indicators are allowed, and they must all have numbers before them
+fn split_intervals(
+    time_str: &str,
+    offset: usize,
+    indicators: &str,
+) -> Result<Vec<(i64, char, usize)>> {
+    let mut intervals = Vec::new();
+    // The position and digits of the number we're currently in, until we reach its indicator
+    let mut curr_number: Option<(usize, String)> = None;
+    for (idx, c) in time_str.chars().enumerate() {
+        let position = offset + idx;
+        if c.is_ascii_digit() {
+            curr_number
+                .get_or_insert_with(|| (position, String::new()))
+                .1
+                .push(c);
+        } else if indicators.contains(c) {
+            let (_, digits) = curr_number
+                .take()
+                .ok_or(ErrorKind::DurationMissingNumber(position))?;
+            // The number is only digits, so this can only fail if it's too large
+            let length = digits
+                .parse::<i64>()
+                .map_err(|_| ErrorKind::DurationOutOfRange)?;
+            intervals.push((length, c, position));
+        } else {
+            bail!(ErrorKind::InvalidDatetimeIntervalIndicator(
+                c.to_string(),
+                position
+            ))
+        }
+    }
+    if let Some((position, _)) = curr_number {
+        bail!(ErrorKind::DurationMissingIndicator(position))
+    }
+
+    Ok(intervals)
+}
+
+fn checked_add(a: i64, b: i64) -> Result<i64> {
+    a.checked_add(b)
+        .ok_or_else(|| ErrorKind::DurationOutOfRange.into())
+}
+
+// `Duration::seconds` panics on overflow, so we go through milliseconds (which can't overflow) ourselves
+fn seconds_to_chrono(seconds: i64) -> Result<Duration> {
+    let milliseconds = seconds
+        .checked_mul(1000)
+        .ok_or(ErrorKind::DurationOutOfRange)?;
+    Ok(Duration::milliseconds(milliseconds))
+}
+
+// Adds the given number of months to a datetime, going to the end of the month if the day doesn't exist in it
+fn add_months(start: DateTime<Utc>, months: i64) -> Result<DateTime<Utc>> {
+    let start = start.naive_utc();
+    let total_months = (start.year() as i64 * 12 + start.month0() as i64)
+        .checked_add(months)
+        .ok_or(ErrorKind::DurationOutOfRange)?;
+    let year =
+        i32::try_from(total_months.div_euclid(12)).map_err(|_| ErrorKind::DurationOutOfRange)?;
+    let month = total_months.rem_euclid(12) as u32 + 1;
+    // Going back from the original day gets us the end of the month if that day doesn't exist (e.g. February 31st)
+    let date = (1..=start.day())
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .ok_or(ErrorKind::DurationOutOfRange)?;
+
+    Ok(Utc.from_utc_datetime(&date.and_time(start.time())))
+}
@@ -0,0 +1,332 @@
+// This file contains the logic for finding the token in a request, which can be in a header, a cookie, the query string, or the payload
+// that a WebSocket client sends when it connects
+// This is deliberately independent of any HTTP library so that every integration can use it
+
+use std::collections::HashMap;
+
+/// The parts of a request that a token can be extracted from. Integrations should create this from each request with all its headers and
+/// its query string, and WebSocket connections can add the payload of their `connection_init` message.
+/// Header names are case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct AuthRequestParts {
+    headers: HashMap<String, String>,
+    query: Option<String>,
+    path: Option<String>,
+    client_ip: Option<String>,
+    init_payload: Option<serde_json::Value>,
+}
+impl AuthRequestParts {
+    /// Creates a new empty set of request parts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Creates a set of request parts with only an `Authorization` header, which is what Diana has always used.
+    pub fn from_auth_header(auth_header: Option<&str>) -> Self {
+        match auth_header {
+            Some(auth_header) => Self::new().with_header("Authorization", auth_header),
+            None => Self::new(),
+        }
+    }
+    /// Adds a header. If a header with the same name has already been added, the values will be joined with commas (or semicolons for
+    /// cookies), as they would be in HTTP/1.1.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        let name = name.to_lowercase();
+        let separator = match name.as_str() {
+            "cookie" => "; ",
+            _ => ", ",
+        };
+        self.headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(separator);
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+        self
+    }
+    /// Adds the query string of the request (everything after the `?`, not including it).
+    pub fn with_query(mut self, query: &str) -> Self {
+        self.query = Some(query.to_string());
+        self
+    }
+    /// Adds the path of the request (everything before the `?`), which is needed to find tenants by their path prefixes.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+    /// Adds the IP address the request came from, which is needed to limit login attempts by address.
+    pub fn with_client_ip(mut self, client_ip: &str) -> Self {
+        self.client_ip = Some(client_ip.to_string());
+        self
+    }
+    /// Adds the payload of a WebSocket client's `connection_init` message.
+    pub fn with_init_payload(mut self, init_payload: serde_json::Value) -> Self {
+        self.init_payload = Some(init_payload);
+        self
+    }
+    /// Gets the value of the given header, if it was provided.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|x| x.as_str())
+    }
+    /// Gets the value of the given cookie, if it was provided.
+    pub fn get_cookie(&self, name: &str) -> Option<&str> {
+        self.get_header("cookie")?
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie_name, _)| *cookie_name == name)
+            .map(|(_, value)| value.trim_matches('"'))
+    }
+    /// Gets the value of the given query string parameter, if it was provided. This will be percent-decoded.
+    pub fn get_query_param(&self, name: &str) -> Option<String> {
+        self.query
+            .as_ref()?
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(param_name, _)| *param_name == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+    /// Gets the path of the request, if it was provided.
+    pub fn get_path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+    /// Gets the IP address the request came from, if it was provided. Behind a proxy, this will be the address of the proxy.
+    pub fn get_client_ip(&self) -> Option<&str> {
+        self.client_ip.as_deref()
+    }
+    /// Gets the value of the given property of the WebSocket `connection_init` payload, if it was provided and is a string.
+    pub fn get_init_payload_property(&self, name: &str) -> Option<&str> {
+        self.init_payload.as_ref()?.get(name)?.as_str()
+    }
+}
+
+// Decodes a percent-encoded query string value (invalid escapes are left as they are)
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let hex_value = |byte: u8| (byte as char).to_digit(16).map(|digit| digit as u8);
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2]))
+            {
+                (Some(high), Some(low)) => {
+                    decoded.push(high * 16 + low);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// Percent-encodes everything in a value except the characters that never need it in a query string
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Compares two strings in constant time (for their length), so CSRF tokens can't be guessed byte-by-byte
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a_byte, b_byte)| acc | (a_byte ^ b_byte))
+            == 0
+}
+
+// Strips the given authentication scheme from the start of a value, case-insensitively
+fn strip_scheme<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+    let value = value.trim();
+    let (given_scheme, token) = value.split_once(' ')?;
+    if given_scheme.eq_ignore_ascii_case(scheme) {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// The settings for CSRF protection of cookie-based authentication, using the double-submit pattern. The client must read the CSRF cookie
+/// (which must not be `HttpOnly`) and send its value in the CSRF header as well, which a malicious site can't do.
+#[derive(Debug, Clone)]
+pub struct CsrfConfig {
+    /// The name of the cookie that holds the CSRF token.
+    pub cookie_name: String,
+    /// The name of the header that the client must copy the CSRF token into.
+    pub header_name: String,
+}
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: "csrf_token".to_string(),
+            header_name: "X-CSRF-Token".to_string(),
+        }
+    }
+}
+
+/// A place that a token can be found in a request.
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    /// The `Authorization` header with the given scheme (usually `Bearer`), which will be matched case-insensitively.
+    AuthorizationHeader(String),
+    /// A custom header that holds the raw token (e.g. `X-Api-Token`).
+    Header(String),
+    /// A cookie with the given name that holds the raw token. This should be `HttpOnly`. Unless you have some other form of CSRF protection,
+    /// you should give CSRF settings here, and requests whose CSRF header doesn't match their CSRF cookie will be treated as having an
+    /// invalid token.
+    Cookie {
+        /// The name of the cookie that holds the token.
+        name: String,
+        /// The settings for CSRF protection, if it should be used.
+        csrf: Option<CsrfConfig>,
+    },
+    /// A query string parameter that holds the raw token. Query strings often end up in logs, so this should only be used where nothing else
+    /// is possible (e.g. WebSocket connections from browsers).
+    QueryParam(String),
+    /// A property of the payload of a WebSocket `connection_init` message. This can hold the raw token, or the token with a `Bearer` scheme
+    /// (as many GraphQL clients send it).
+    InitPayload(String),
+}
+
+/// The result of looking for a token in a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenExtraction {
+    /// A token was found.
+    Found(String),
+    /// A token was found, but the request was invalid in some other way (e.g. it failed a CSRF check), so it should be treated as an invalid
+    /// token.
+    Invalid,
+    /// No token was found.
+    Missing,
+}
+
+/// The system that finds tokens in requests. This tries each of its sources in order, and uses the first token it finds. By default, it
+/// only looks at the `Authorization` header with the `Bearer` scheme.
+#[derive(Debug, Clone)]
+pub struct TokenExtractor {
+    sources: Vec<TokenSource>,
+}
+impl Default for TokenExtractor {
+    fn default() -> Self {
+        Self::new().with_source(TokenSource::AuthorizationHeader("Bearer".to_string()))
+    }
+}
+impl TokenExtractor {
+    /// Creates a new token extractor with no sources. You'll need to add some with `.with_source()`.
+    pub fn new() -> Self {
+        Self {
+            sources: Vec::new(),
+        }
+    }
+    /// Adds a source to look for tokens in. Sources are tried in the order they're added.
+    pub fn with_source(mut self, source: TokenSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+    /// Checks if any of this extractor's sources read the given header (matched case-insensitively). Integrations use this to reject requests
+    /// whose token-bearing headers can't be read, rather than silently treating them as having no token.
+    pub fn reads_header(&self, name: &str) -> bool {
+        self.sources.iter().any(|source| match source {
+            TokenSource::AuthorizationHeader(_) => name.eq_ignore_ascii_case("Authorization"),
+            TokenSource::Header(header_name) => name.eq_ignore_ascii_case(header_name),
+            TokenSource::Cookie { csrf, .. } => {
+                name.eq_ignore_ascii_case("Cookie")
+                    || matches!(csrf, Some(csrf) if name.eq_ignore_ascii_case(&csrf.header_name))
+            }
+            TokenSource::QueryParam(_) | TokenSource::InitPayload(_) => false,
+        })
+    }
+    /// Creates request parts that hold the given token wherever this extractor looks first, so that it will be found. This is for checking
+    /// tokens that didn't arrive in a request of their own (like ones sent to refresh a WebSocket connection) with an authenticator that
+    /// uses this extractor. If this extractor has no sources, the parts will be empty.
+    pub fn parts_with_token(&self, token: &str) -> AuthRequestParts {
+        let parts = AuthRequestParts::new();
+        match self.sources.first() {
+            Some(TokenSource::AuthorizationHeader(scheme)) => {
+                parts.with_header("Authorization", &format!("{} {}", scheme, token))
+            }
+            Some(TokenSource::Header(name)) => parts.with_header(name, token),
+            Some(TokenSource::Cookie { name, csrf }) => {
+                let parts = parts.with_header("Cookie", &format!("{}={}", name, token));
+                // This token didn't come from a browser, so there's nothing to forge, but it still has to pass the check
+                match csrf {
+                    Some(csrf) => parts
+                        .with_header("Cookie", &format!("{}=refresh", csrf.cookie_name))
+                        .with_header(&csrf.header_name, "refresh"),
+                    None => parts,
+                }
+            }
+            Some(TokenSource::QueryParam(name)) => {
+                parts.with_query(&format!("{}={}", name, percent_encode(token)))
+            }
+            Some(TokenSource::InitPayload(name)) => {
+                parts.with_init_payload(serde_json::json!({ name: token }))
+            }
+            None => parts,
+        }
+    }
+    /// Looks for a token in the given request parts.
+    pub fn extract(&self, parts: &AuthRequestParts) -> TokenExtraction {
+        for source in &self.sources {
+            let extraction = match source {
+                TokenSource::AuthorizationHeader(scheme) => parts
+                    .get_header("Authorization")
+                    .and_then(|header| strip_scheme(header, scheme))
+                    .map(|token| TokenExtraction::Found(token.to_string())),
+                TokenSource::Header(name) => parts
+                    .get_header(name)
+                    .map(|token| TokenExtraction::Found(token.trim().to_string())),
+                TokenSource::Cookie { name, csrf } => {
+                    parts.get_cookie(name).map(|token| match csrf {
+                        Some(csrf) => {
+                            let csrf_passed = match (
+                                parts.get_cookie(&csrf.cookie_name),
+                                parts.get_header(&csrf.header_name),
+                            ) {
+                                (Some(csrf_cookie), Some(csrf_header)) => {
+                                    !csrf_cookie.is_empty()
+                                        && constant_time_eq(csrf_cookie, csrf_header.trim())
+                                }
+                                _ => false,
+                            };
+                            match csrf_passed {
+                                true => TokenExtraction::Found(token.to_string()),
+                                false => TokenExtraction::Invalid,
+                            }
+                        }
+                        None => TokenExtraction::Found(token.to_string()),
+                    })
+                }
+                TokenSource::QueryParam(name) => {
+                    parts.get_query_param(name).map(TokenExtraction::Found)
+                }
+                TokenSource::InitPayload(name) => {
+                    parts.get_init_payload_property(name).map(|value| {
+                        // Many clients send the whole header value here
+                        let token = strip_scheme(value, "Bearer").unwrap_or(value);
+                        TokenExtraction::Found(token.trim().to_string())
+                    })
+                }
+            };
+            match extraction {
+                // An empty token is no token at all
+                Some(TokenExtraction::Found(token)) if token.is_empty() => continue,
+                Some(extraction) => return extraction,
+                None => continue,
+            }
+        }
+
+        TokenExtraction::Missing
+    }
+}
@@ -0,0 +1,118 @@
+// This file contains the reasons authentication can fail, which are carried through to the HTTP responses of the integrations
+// Clients need to know the difference between an expired token (refresh it) and a bad one (log in again), so we follow RFC 6750
+
+use std::fmt;
+
+/// The reason a request's credentials weren't accepted. This is attached to [`AuthState::InvalidToken`](crate::AuthState),
+/// [`AuthVerdict::Block`](crate::AuthVerdict) and [`DianaResponse::Blocked`](crate::DianaResponse), and can be turned into an RFC 6750
+/// `WWW-Authenticate` header with `.www_authenticate()`, which the integrations do for you.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// No credentials were provided, but they're required.
+    MissingToken,
+    /// The request was invalid (e.g. it failed a CSRF check, or the token was somewhere it wasn't allowed to be).
+    InvalidRequest,
+    /// The token couldn't be parsed at all.
+    Malformed,
+    /// The token's signature was invalid, or it was signed with the wrong algorithm.
+    InvalidSignature,
+    /// The token was signed with a key that isn't known.
+    UnknownKey,
+    /// The token (or API key) has expired.
+    Expired,
+    /// The token isn't valid yet (its `nbf` or `iat` is in the future).
+    NotYetValid,
+    /// The token was issued by an issuer that isn't accepted.
+    InvalidIssuer,
+    /// The token wasn't issued for an accepted audience.
+    InvalidAudience,
+    /// The token's claims were missing something required, or couldn't be deserialized into the user's claims type.
+    InvalidClaims,
+    /// The token has been revoked.
+    Revoked,
+    /// The credentials were of a kind that isn't accepted (e.g. an API key at an endpoint that requires JWTs).
+    WrongCredentialKind,
+    /// The credentials weren't recognised (e.g. an unknown API key, or a token an introspection endpoint says is inactive).
+    UnknownCredentials,
+    /// The credentials were valid, but their claims don't allow the operation (e.g. because of an [`AuthPolicy`](crate::AuthPolicy)).
+    InsufficientClaims,
+    /// The credentials have already been used and can't be used again (e.g. a signed publish request that was replayed).
+    Replayed,
+}
+impl AuthFailure {
+    /// Gets the RFC 6750 error code for this failure. This is `None` for a missing token, because the specification says clients that
+    /// didn't try to authenticate shouldn't be given an error code.
+    pub fn error_code(&self) -> Option<&'static str> {
+        match self {
+            Self::MissingToken => None,
+            Self::InvalidRequest => Some("invalid_request"),
+            Self::InsufficientClaims => Some("insufficient_scope"),
+            _ => Some("invalid_token"),
+        }
+    }
+    /// Gets a human-readable description of this failure, which is safe to send to clients.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::MissingToken => "No credentials were provided",
+            Self::InvalidRequest => "The request was malformed",
+            Self::Malformed => "The token is malformed",
+            Self::InvalidSignature => "The token signature is invalid",
+            Self::UnknownKey => "The token was signed with an unknown key",
+            Self::Expired => "The token has expired",
+            Self::NotYetValid => "The token is not valid yet",
+            Self::InvalidIssuer => "The token issuer is not accepted",
+            Self::InvalidAudience => "The token audience is not accepted",
+            Self::InvalidClaims => "The token claims are invalid",
+            Self::Revoked => "The token has been revoked",
+            Self::WrongCredentialKind => "This kind of credential is not accepted here",
+            Self::UnknownCredentials => "The credentials were not recognised",
+            Self::InsufficientClaims => "The credentials do not allow this operation",
+            Self::Replayed => "The credentials have already been used",
+        }
+    }
+    /// Gets the HTTP status code that should be returned for this failure. This is 400 for an invalid request, 403 for insufficient
+    /// claims and 401 otherwise.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::InvalidRequest => 400,
+            Self::InsufficientClaims => 403,
+            _ => 401,
+        }
+    }
+    /// Gets the value of the `WWW-Authenticate` header that should be returned for this failure, as defined in
+    /// [RFC 6750](https://datatracker.ietf.org/doc/html/rfc6750#section-3).
+    pub fn www_authenticate(&self) -> String {
+        match self.error_code() {
+            Some(error_code) => format!(
+                "Bearer error=\"{}\", error_description=\"{}\"",
+                error_code,
+                self.description()
+            ),
+            None => "Bearer".to_string(),
+        }
+    }
+}
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+impl From<&jsonwebtoken::errors::Error> for AuthFailure {
+    fn from(err: &jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind as JWTErrorKind;
+        match err.kind() {
+            JWTErrorKind::ExpiredSignature => Self::Expired,
+            JWTErrorKind::ImmatureSignature => Self::NotYetValid,
+            JWTErrorKind::InvalidIssuer => Self::InvalidIssuer,
+            JWTErrorKind::InvalidAudience => Self::InvalidAudience,
+            JWTErrorKind::InvalidSubject
+            | JWTErrorKind::MissingRequiredClaim(_)
+            | JWTErrorKind::Json(_) => Self::InvalidClaims,
+            JWTErrorKind::InvalidToken | JWTErrorKind::Base64(_) | JWTErrorKind::Utf8(_) => {
+                Self::Malformed
+            }
+            // Everything else means the signature couldn't be verified with the key we have
+            _ => Self::InvalidSignature,
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// This file contains the `async_graphql` guards that enforce claim requirements declaratively, so resolvers don't have to check by hand
+// Guards are constructed by `async_graphql` from attributes, so they can't know the user's claims type
+// That's why we insert a serialized copy of the authentication state into every request alongside the typed one
+
+use async_graphql::guard::Guard;
+use async_graphql::parser::types::Field;
+use async_graphql::registry::Registry;
+use async_graphql::resolver_utils::{resolve_container, ContainerType};
+use async_graphql::{
+    Context, ContextSelectionSet, ErrorExtensions, ObjectType, OutputType, Positioned,
+    ServerResult, Type, Value,
+};
+use std::borrow::Cow;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::claim_predicate::ClaimPredicate;
+use crate::auth::jwt::{Claims, UserClaims};
+use crate::errors::{ErrorKind, GQLError, GQLResult};
+
+/// The error code that will be in the `code` extension of every error caused by a lack of authorisation.
+pub const UNAUTHORISED_ERROR_CODE: &str = "UNAUTHORISED";
+
+/// Creates the GraphQL error that Diana uses whenever a user isn't authorised to do something. This has the `code` extension set to
+/// [`UNAUTHORISED_ERROR_CODE`], so clients can handle it consistently. You should use this in your own resolvers if you check
+/// authorisation by hand.
+pub fn unauthorised_error() -> GQLError {
+    GQLError::new(ErrorKind::Unauthorised.to_string())
+        .extend_with(|_, extensions| extensions.set("code", UNAUTHORISED_ERROR_CODE))
+}
+
+// The authentication state with its claims serialized, which is what guards read from the context
+// This is `None` if the user isn't authorised
+#[derive(Debug, Clone)]
+pub(crate) struct SerializedClaims(Option<serde_json::Value>);
+impl SerializedClaims {
+    pub(crate) fn from_auth_state<T: UserClaims>(auth_state: &AuthState<T>) -> Self {
+        match auth_state {
+            AuthState::Authorised(AuthToken(Claims { claims, .. }, _)) => {
+                Self(serde_json::to_value(claims).ok())
+            }
+            _ => Self(None),
+        }
+    }
+}
+
+// Gets the serialized claims from the context, treating their absence as a lack of authorisation
+fn get_serialized_claims<'a>(ctx: &'a Context<'_>) -> Option<&'a serde_json::Value> {
+    ctx.data_opt::<SerializedClaims>()
+        .and_then(|serialized_claims| serialized_claims.0.as_ref())
+}
+
+/// A guard that only allows users with valid tokens (or API keys) through, whatever their claims. This is mostly useful with
+/// `AuthBlockLevel::AllowMissing`, where some fields can be accessed anonymously and others can't.
+/// # Example
+/// ```rust,ignore
+/// #[graphql(guard(AuthenticatedGuard()))]
+/// async fn me(&self) -> User { /* ... */ }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AuthenticatedGuard {}
+#[async_trait::async_trait]
+impl Guard for AuthenticatedGuard {
+    async fn check(&self, ctx: &Context<'_>) -> GQLResult<()> {
+        match get_serialized_claims(ctx) {
+            Some(_) => Ok(()),
+            None => Err(unauthorised_error()),
+        }
+    }
+}
+
+/// A guard that only allows users whose claims satisfy the given predicate through. In an attribute, the predicate can be written with
+/// [`claim_predicate!`](crate::claim_predicate) in a raw string, which `async_graphql` will parse as an expression.
+/// # Example
+/// ```rust,ignore
+/// #[graphql(guard(ClaimsGuard(predicate = r#"claim_predicate!("role" in ["admin", "editor"])"#)))]
+/// async fn publish_article(&self, id: String) -> bool { /* ... */ }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClaimsGuard {
+    /// The predicate the user's claims must satisfy.
+    pub predicate: ClaimPredicate,
+}
+impl ClaimsGuard {
+    /// Creates a new guard with the given predicate.
+    pub fn new(predicate: ClaimPredicate) -> Self {
+        Self { predicate }
+    }
+}
+#[async_trait::async_trait]
+impl Guard for ClaimsGuard {
+    async fn check(&self, ctx: &Context<'_>) -> GQLResult<()> {
+        match get_serialized_claims(ctx) {
+            Some(claims) if self.predicate.is_satisfied_by(claims) => Ok(()),
+            _ => Err(unauthorised_error()),
+        }
+    }
+}
+
+/// A wrapper that applies a guard to every field of an object. `async_graphql` only supports guards on individual fields, so this is the
+/// easiest way to protect a whole object, like your query or mutation root.
+/// # Example
+/// ```rust,ignore
+/// let admin_mutations = Guarded::new(AdminMutation, ClaimsGuard::new(claim_predicate!("role" == "admin")));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Guarded<O, G> {
+    inner: O,
+    guard: G,
+}
+impl<O: ObjectType, G: Guard + Send + Sync> Guarded<O, G> {
+    /// Wraps the given object so that the given guard will be checked before any of its fields are resolved.
+    pub fn new(inner: O, guard: G) -> Self {
+        Self { inner, guard }
+    }
+    // Checks the guard, turning any failure into an error at the position of the field being resolved
+    async fn check_guard(&self, ctx: &Context<'_>) -> ServerResult<()> {
+        self.guard
+            .check(ctx)
+            .await
+            .map_err(|err| err.into_server_error(ctx.item.pos))
+    }
+}
+impl<O: ObjectType, G: Guard + Send + Sync> Type for Guarded<O, G> {
+    fn type_name() -> Cow<'static, str> {
+        O::type_name()
+    }
+    fn create_type_info(registry: &mut Registry) -> String {
+        O::create_type_info(registry)
+    }
+}
+#[async_trait::async_trait]
+impl<O: ObjectType, G: Guard + Send + Sync> ContainerType for Guarded<O, G> {
+    async fn resolve_field(&self, ctx: &Context<'_>) -> ServerResult<Option<Value>> {
+        self.check_guard(ctx).await?;
+        self.inner.resolve_field(ctx).await
+    }
+    // Federation resolves entities through the query root without going through `resolve_field`, so we have to check here too
+    async fn find_entity(&self, ctx: &Context<'_>, params: &Value) -> ServerResult<Option<Value>> {
+        self.check_guard(ctx).await?;
+        self.inner.find_entity(ctx, params).await
+    }
+}
+#[async_trait::async_trait]
+impl<O: ObjectType, G: Guard + Send + Sync> OutputType for Guarded<O, G> {
+    async fn resolve(
+        &self,
+        ctx: &ContextSelectionSet<'_>,
+        _field: &Positioned<Field>,
+    ) -> ServerResult<Value> {
+        // We resolve ourselves rather than delegating so that the guard is checked for every field
+        resolve_container(ctx, self).await
+    }
+}
+impl<O: ObjectType, G: Guard + Send + Sync> ObjectType for Guarded<O, G> {}
@@ -0,0 +1,379 @@
+// This file contains the logic for sets of JWT keys, which allow keys to be selected by the `kid` header of a token
+// This is what makes key rotation possible without downtime, as both old and new keys can be accepted at once
+
+use jsonwebtoken::{
+    decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey,
+};
+use reqwest::Client;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwt::{
+    validate_and_decode_jwt_with_reason, Claims, JWTKeyConfig, JWTSecret, JWTValidation,
+};
+use crate::errors::*;
+
+// How long we'll wait to connect to an identity provider, and for it to send the whole key set, before giving up on a fetch
+// Requests that have no cached keys to use have to wait for the fetch, so an identity provider that hangs mustn't hold them up forever
+const JWKS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A set of JWT secrets, each of which may be identified by a key ID. When a token is validated against a key set, the `kid` in its header
+/// is used to pick the right key. Tokens without a `kid` will be tried against every key in the set, which means that you can accept both an
+/// old and a new key during a rotation window.
+#[derive(Debug, Clone, Default)]
+pub struct JWTKeySet {
+    secrets: Vec<JWTSecret>,
+}
+impl JWTKeySet {
+    /// Creates a new, empty key set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a secret to the key set. If you want tokens to be able to select it with their `kid` header, you should give it a key ID with
+    /// [`JWTSecret::with_key_id`] first.
+    pub fn with_secret(mut self, secret: JWTSecret) -> Self {
+        self.secrets.push(secret);
+        self
+    }
+    /// Parses a key set from a JWKS (JSON Web Key Set) document, like the one an identity provider serves at `/.well-known/jwks.json`.
+    /// Keys that don't declare an algorithm with `alg` will use a default for their type (`RS256` for RSA, `ES256`/`ES384` for EC depending
+    /// on the curve, `EdDSA` for OKP, and `HS256` for shared secrets).
+    /// Key sets parsed from JWKS documents can only be used to verify tokens, not to create them.
+    pub fn from_jwks(jwks_json: &str) -> Result<Self> {
+        let jwk_set: JwkSet = serde_json::from_str(jwks_json)?;
+        let mut key_set = Self::new();
+        for jwk in &jwk_set.keys {
+            let mut secret = JWTSecret::new(
+                get_jwk_algorithm(jwk),
+                None, // We can only verify tokens with a JWKS
+                DecodingKey::from_jwk(jwk)?,
+            );
+            if let Some(key_id) = &jwk.common.key_id {
+                secret = secret.with_key_id(key_id);
+            }
+            key_set = key_set.with_secret(secret);
+        }
+
+        Ok(key_set)
+    }
+    /// Checks if this key set has a key that the given token could have been signed with. This will be `false` if the token names a key ID
+    /// that isn't in the set, which usually means that the set needs to be refreshed.
+    pub fn has_key_for_jwt(&self, jwt: &str) -> bool {
+        match self.get_candidates(jwt) {
+            Some(candidates) => !candidates.is_empty(),
+            None => false,
+        }
+    }
+    /// Checks if the given token names a key ID that isn't in this set, which usually means that the set needs to be refreshed. Tokens that
+    /// can't be decoded or that don't name a key ID will never be missing their key.
+    pub fn is_missing_key_id(&self, jwt: &str) -> bool {
+        match decode_header(jwt) {
+            Ok(header) => match header.kid {
+                Some(kid) => !self
+                    .secrets
+                    .iter()
+                    .any(|secret| secret.key_id() == Some(kid.as_str())),
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+    /// Validates a JWT against this key set and returns the payload. If the token names a key ID, only the secret with that key ID will be
+    /// used (or the secrets without key IDs if there isn't one). Otherwise, the token will be tried against every secret in the set.
+    pub fn validate_and_decode_jwt<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+        validation: &JWTValidation,
+    ) -> Option<Claims<T>> {
+        self.validate_and_decode_jwt_with_reason(jwt, validation)
+            .ok()
+    }
+    /// Validates a JWT against this key set and returns the payload, or the reason the token was rejected. This works in the same way as
+    /// `.validate_and_decode_jwt()`.
+    pub fn validate_and_decode_jwt_with_reason<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+        validation: &JWTValidation,
+    ) -> StdResult<Claims<T>, AuthFailure> {
+        let candidates = self.get_candidates(jwt).ok_or(AuthFailure::Malformed)?;
+        // If no key verified the signature, that's the failure we report, but if one did and the claims were wrong, that's more useful
+        let mut failure = AuthFailure::UnknownKey;
+        for secret in candidates {
+            match validate_and_decode_jwt_with_reason(jwt, secret, validation) {
+                Ok(claims) => return Ok(claims),
+                Err(AuthFailure::InvalidSignature) if failure != AuthFailure::UnknownKey => (),
+                Err(new_failure) => failure = new_failure,
+            }
+        }
+
+        Err(failure)
+    }
+
+    // Gets all the secrets that the given token could have been signed with
+    // This returns `None` if the token's header couldn't even be decoded
+    fn get_candidates(&self, jwt: &str) -> Option<Vec<&JWTSecret>> {
+        let header = decode_header(jwt).ok()?;
+        let candidates = match header.kid {
+            Some(kid) => {
+                let by_kid: Vec<&JWTSecret> = self
+                    .secrets
+                    .iter()
+                    .filter(|secret| secret.key_id() == Some(kid.as_str()))
+                    .collect();
+                // If we don't know about the key ID at all, we'll fall back to any keys that don't have IDs (e.g. one configured secret)
+                if by_kid.is_empty() {
+                    self.secrets
+                        .iter()
+                        .filter(|secret| secret.key_id().is_none())
+                        .collect()
+                } else {
+                    by_kid
+                }
+            }
+            None => self.secrets.iter().collect(),
+        };
+
+        Some(candidates)
+    }
+}
+
+// Works out which algorithm a JWK should be used with, falling back to defaults for the key type if it doesn't say
+fn get_jwk_algorithm(jwk: &Jwk) -> Algorithm {
+    match (jwk.common.algorithm, &jwk.algorithm) {
+        (Some(algorithm), _) => algorithm,
+        (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+        (None, AlgorithmParameters::EllipticCurve(params)) => match params.curve {
+            EllipticCurve::P384 => Algorithm::ES384,
+            _ => Algorithm::ES256,
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+        (None, AlgorithmParameters::OctetKey(_)) => Algorithm::HS256,
+    }
+}
+
+/// Where the keys used to verify JWTs should come from. You should set this in the [`Options`](crate::Options) if you need more than one
+/// key at a time (e.g. for rotation) or if your keys are managed by an external identity provider.
+#[derive(Debug, Clone)]
+pub enum JWTKeySource {
+    /// A single key, as configured with `.jwt_secret()` or `.jwt_public_key()`.
+    Config(JWTKeyConfig),
+    /// A fixed set of keys.
+    Static(JWTKeySet),
+    /// A JWKS document on the filesystem. This will be read once when the system starts up.
+    File(PathBuf),
+    /// A JWKS document served over HTTP(S). This will be fetched when first needed and cached. Fetches will be given up on if the identity
+    /// provider hasn't responded within 10 seconds.
+    Url {
+        /// The URL of the JWKS document.
+        url: String,
+        /// How long the fetched keys will be cached for before they're fetched again.
+        refresh_interval: Duration,
+        /// The minimum time between fetches. If a token names a key ID that isn't in the cached set, the keys will be fetched again early
+        /// (it has probably just been rotated), but never more often than this, which stops attackers from forcing a fetch on every request. Failed
+        /// fetches count towards this too, so an unreachable identity provider won't be retried on every request either.
+        min_refresh_interval: Duration,
+    },
+}
+
+/// The keys used to verify JWTs at runtime, created from a [`JWTKeySource`]. You should only need to touch this if you're building a
+/// custom integration.
+#[derive(Debug, Clone)]
+pub enum JWTKeyProvider {
+    /// A set of keys that will never change.
+    Static(JWTKeySet),
+    /// A set of keys fetched from a JWKS URL and cached.
+    Remote(RemoteJWKS),
+}
+impl JWTKeyProvider {
+    /// Creates a new key provider from the given source. This will parse any configured keys and read any JWKS files, but remote key sets
+    /// won't be fetched until they're first needed.
+    pub fn from_source(source: &JWTKeySource) -> Result<Self> {
+        let provider = match source {
+            JWTKeySource::Config(key_config) => {
+                Self::Static(JWTKeySet::new().with_secret(key_config.parse()?))
+            }
+            JWTKeySource::Static(key_set) => Self::Static(key_set.clone()),
+            JWTKeySource::File(path) => {
+                let jwks_json = fs::read_to_string(path)?;
+                Self::Static(JWTKeySet::from_jwks(&jwks_json)?)
+            }
+            JWTKeySource::Url {
+                url,
+                refresh_interval,
+                min_refresh_interval,
+            } => Self::Remote(RemoteJWKS::new(
+                url,
+                *refresh_interval,
+                *min_refresh_interval,
+            )?),
+        };
+
+        Ok(provider)
+    }
+    /// Validates a JWT against the provided keys and returns the payload. The outer result will only be an error if remote keys couldn't be
+    /// fetched at all, an invalid token will lead to `Ok(None)`.
+    pub async fn validate_and_decode_jwt<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+        validation: &JWTValidation,
+    ) -> Result<Option<Claims<T>>> {
+        Ok(self
+            .validate_and_decode_jwt_with_reason(jwt, validation)
+            .await?
+            .ok())
+    }
+    /// Validates a JWT against the provided keys and returns the payload, or the reason the token was rejected. The outer result will only
+    /// be an error if remote keys couldn't be fetched at all.
+    pub async fn validate_and_decode_jwt_with_reason<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+        validation: &JWTValidation,
+    ) -> Result<StdResult<Claims<T>, AuthFailure>> {
+        match self {
+            Self::Static(key_set) => {
+                Ok(key_set.validate_and_decode_jwt_with_reason(jwt, validation))
+            }
+            Self::Remote(remote_jwks) => {
+                let mut key_set = remote_jwks.get_key_set().await?;
+                // If the token names a key we don't have, the keys have probably been rotated (if it's just made up, it'll still be rejected)
+                if key_set.is_missing_key_id(jwt) {
+                    if let Some(refreshed_key_set) = remote_jwks.refresh_for_unknown_key().await? {
+                        key_set = refreshed_key_set;
+                    }
+                }
+
+                Ok(key_set.validate_and_decode_jwt_with_reason(jwt, validation))
+            }
+        }
+    }
+}
+
+// A key set along with when it was fetched
+#[derive(Debug)]
+struct CachedJWKS {
+    key_set: Arc<JWTKeySet>,
+    fetched_at: Instant,
+}
+
+/// A JWKS document that's fetched over HTTP(S) and cached. Clones of this share the same cache.
+#[derive(Debug, Clone)]
+pub struct RemoteJWKS {
+    url: String,
+    refresh_interval: Duration,
+    min_refresh_interval: Duration,
+    client: Client,
+    cache: Arc<RwLock<Option<CachedJWKS>>>,
+    // When the keys were last fetched (whether that worked or not), which is locked while fetching so that only one fetch happens at once
+    last_attempt: Arc<AsyncMutex<Option<Instant>>>,
+}
+impl RemoteJWKS {
+    /// Creates a new remote key set. Nothing will be fetched until the keys are first needed.
+    pub fn new(
+        url: &str,
+        refresh_interval: Duration,
+        min_refresh_interval: Duration,
+    ) -> Result<Self> {
+        let client = Client::builder()
+            .connect_timeout(JWKS_CONNECT_TIMEOUT)
+            .timeout(JWKS_FETCH_TIMEOUT)
+            .build()?;
+
+        Ok(Self {
+            url: url.to_string(),
+            refresh_interval,
+            min_refresh_interval,
+            client,
+            cache: Arc::new(RwLock::new(None)),
+            last_attempt: Arc::new(AsyncMutex::new(None)),
+        })
+    }
+    /// Gets the key set, fetching it if it hasn't been fetched yet or if the cached version is older than the refresh interval. If a refresh
+    /// fails but we have an older version cached, that will be used instead. This will only fail if the keys have never been fetched.
+    pub async fn get_key_set(&self) -> Result<Arc<JWTKeySet>> {
+        match self.get_cached()? {
+            Some((key_set, fetched_at)) if fetched_at.elapsed() < self.refresh_interval => {
+                Ok(key_set)
+            }
+            Some(_) => {
+                // If this fails, we'll just keep using the old keys
+                let _ = self.refresh().await;
+                self.get_cached_key_set()
+            }
+            None => {
+                self.refresh().await?;
+                self.get_cached_key_set()
+            }
+        }
+    }
+    /// Fetches the key set again because a token named a key ID that we don't have. This won't do anything if the keys were fetched (or a
+    /// fetch was attempted) more recently than the minimum refresh interval. If the fetch fails, the cached keys will be returned, and the
+    /// token will just be checked against those.
+    pub async fn refresh_for_unknown_key(&self) -> Result<Option<Arc<JWTKeySet>>> {
+        let _ = self.refresh().await;
+        Ok(self.get_cached()?.map(|(key_set, _)| key_set))
+    }
+
+    // Gets the cached key set and when it was fetched, if there is one
+    fn get_cached(&self) -> Result<Option<(Arc<JWTKeySet>, Instant)>> {
+        let cache = self
+            .cache
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("jwks_cache".to_string()))?;
+        Ok(cache
+            .as_ref()
+            .map(|cached| (Arc::clone(&cached.key_set), cached.fetched_at)))
+    }
+    // Gets the cached key set, failing if the keys have never been fetched
+    fn get_cached_key_set(&self) -> Result<Arc<JWTKeySet>> {
+        match self.get_cached()? {
+            Some((key_set, _)) => Ok(key_set),
+            None => bail!(ErrorKind::JWKSUnavailable),
+        }
+    }
+    // Fetches the key set and caches it, unless that was attempted more recently than the minimum refresh interval
+    // If someone else is already fetching, requests with cached keys just use those rather than queueing up behind the fetch
+    // Requests without any keys have to wait, but they'll then find it was just attempted, so they'll share its result rather than fetching again
+    async fn refresh(&self) -> Result<()> {
+        let mut last_attempt = match self.last_attempt.try_lock() {
+            Ok(last_attempt) => last_attempt,
+            Err(_) if self.get_cached()?.is_some() => return Ok(()),
+            Err(_) => self.last_attempt.lock().await,
+        };
+        if matches!(*last_attempt, Some(attempted_at) if attempted_at.elapsed() < self.min_refresh_interval)
+        {
+            return Ok(());
+        }
+        // This is recorded before fetching so that failed fetches count too
+        *last_attempt = Some(Instant::now());
+
+        let jwks_json = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let key_set = Arc::new(JWTKeySet::from_jwks(&jwks_json)?);
+
+        let mut cache = self
+            .cache
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("jwks_cache".to_string()))?;
+        *cache = Some(CachedJWKS {
+            key_set,
+            fetched_at: Instant::now(),
+        });
+
+        Ok(())
+    }
+}
@@ -1,42 +1,342 @@
-use chrono::{prelude::Utc, Duration};
+use chrono::Duration;
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::sync::Arc;
 
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::duration::ParsedDuration;
+use crate::auth::failure::AuthFailure;
 use crate::errors::*;
 
-/// The claims made by a JWT, including metadata.
+/// The requirements for a type to be used as the custom claims in a JWT. This is implemented automatically for anything that can be
+/// serialized, deserialized, cloned and sent between threads, so you should just derive `Serialize`, `Deserialize`, `Clone` and `Debug` on
+/// your own struct. The default is `HashMap<String, String>`.
+pub trait UserClaims:
+    Serialize + DeserializeOwned + Clone + fmt::Debug + Send + Sync + 'static
+{
+}
+impl<T> UserClaims for T where
+    T: Serialize + DeserializeOwned + Clone + fmt::Debug + Send + Sync + 'static
+{
+}
+
+/// The claims made by a JWT, including metadata. The user's own claims can be any type that implements [`UserClaims`], and are a simple
+/// map of strings by default.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct Claims {
+pub struct Claims<T = HashMap<String, String>> {
     /// The expiry of the JWT as a datetime in seconds from Unix epoch.
     pub exp: u64,
+    /// The other registered claims of the JWT (issuer, audience, etc.), all of which are optional.
+    #[serde(flatten)]
+    pub registered: RegisteredClaims,
     /// The claims made by the user (non-metadata).
-    pub claims: HashMap<String, String>,
+    pub claims: T,
+}
+
+/// The registered claims a JWT can make other than its expiry (see [RFC 7519](https://datatracker.ietf.org/doc/html/rfc7519#section-4.1)).
+/// All of these are optional, though you can require some of them to be present with [`JWTValidation`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegisteredClaims {
+    /// The issuer of the JWT (`iss`).
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub iss: Option<String>,
+    /// The subject of the JWT (`sub`), usually the ID of the user it was issued to.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub sub: Option<String>,
+    /// The audience(s) the JWT is intended for (`aud`). This will be serialized as a single string if there's only one.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        serialize_with = "serialize_audience",
+        deserialize_with = "deserialize_audience"
+    )]
+    pub aud: Option<Vec<String>>,
+    /// The datetime before which the JWT must not be accepted (`nbf`), in seconds from Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub nbf: Option<u64>,
+    /// The datetime at which the JWT was issued (`iat`), in seconds from Unix epoch. This will be set automatically by `create_jwt` if you
+    /// don't set it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub iat: Option<u64>,
+    /// A unique identifier for the JWT (`jti`).
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub jti: Option<String>,
+}
+
+// The `aud` claim can be either a single string or an array of strings
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+fn serialize_audience<S: Serializer>(
+    aud: &Option<Vec<String>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match aud.as_deref() {
+        Some([aud]) => aud.serialize(serializer),
+        aud => aud.serialize(serializer),
+    }
+}
+fn deserialize_audience<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Vec<String>>, D::Error> {
+    let aud = Option::<Audience>::deserialize(deserializer)?;
+    Ok(aud.map(|aud| match aud {
+        Audience::Single(aud) => vec![aud],
+        Audience::Multiple(aud) => aud,
+    }))
+}
+
+/// The settings used to validate the claims of JWTs beyond their signature and expiry. By default, any issuer and audience are accepted,
+/// there's no leeway for clock skew, and `nbf`/`iat` don't need to be present (though `nbf` will always be checked if it is).
+/// You should set this up in the [`Options`](crate::Options) so that tokens minted for one of your APIs can't be used on another.
+#[derive(Debug, Clone)]
+pub struct JWTValidation {
+    /// The issuers that will be accepted. If this is set, tokens must have an `iss` claim that's one of these.
+    pub issuers: Option<Vec<String>>,
+    /// The audiences that will be accepted. If this is set, tokens must have an `aud` claim that includes at least one of these.
+    pub audiences: Option<Vec<String>>,
+    /// The number of seconds of leeway to allow for clock skew when checking `exp`, `nbf` and `iat`.
+    pub leeway: u64,
+    /// Whether or not tokens must have an `nbf` claim.
+    pub require_nbf: bool,
+    /// Whether or not tokens must have an `iat` claim.
+    pub require_iat: bool,
+    /// The clock that `exp`, `nbf` and `iat` will be checked against. This is the system clock by default.
+    pub clock: Arc<dyn Clock>,
+}
+impl Default for JWTValidation {
+    fn default() -> Self {
+        Self {
+            issuers: None,
+            audiences: None,
+            leeway: 0,
+            require_nbf: false,
+            require_iat: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl JWTValidation {
+    // Creates the `jsonwebtoken` validation settings for tokens signed with the given algorithm
+    fn get_validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        // `jsonwebtoken` always uses the system clock, so we check the datetimes ourselves (it still makes sure `exp` is present)
+        validation.validate_exp = false;
+        validation.validate_nbf = false;
+        if let Some(issuers) = &self.issuers {
+            validation.set_issuer(issuers);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        if let Some(audiences) = &self.audiences {
+            validation.set_audience(audiences);
+            validation.required_spec_claims.insert("aud".to_string());
+        }
+        if self.require_nbf {
+            validation.required_spec_claims.insert("nbf".to_string());
+        }
+
+        validation
+    }
+    // Checks the claims that `jsonwebtoken` doesn't handle for us, which includes all the datetimes
+    fn check_extra_claims<T>(&self, claims: &Claims<T>) -> StdResult<(), AuthFailure> {
+        let now = self.clock.timestamp();
+        if claims.exp.saturating_add(self.leeway) < now {
+            return Err(AuthFailure::Expired);
+        }
+        // `nbf` is always checked if it's present, and a token can't have been issued in the future
+        let latest_valid_time = now.saturating_add(self.leeway);
+        if matches!(claims.registered.nbf, Some(nbf) if nbf > latest_valid_time)
+            || matches!(claims.registered.iat, Some(iat) if iat > latest_valid_time)
+        {
+            return Err(AuthFailure::NotYetValid);
+        }
+        if self.require_iat && claims.registered.iat.is_none() {
+            return Err(AuthFailure::InvalidClaims);
+        }
+
+        Ok(())
+    }
 }
 
-/// A parsed JWT secret. This should be created once with `get_jwt_secret` and then reused as much as possible. You may want to
-/// place it in your context under [`Options`](crate::Options).
+/// Raw key material for signing or verifying JWTs. This is what you provide in the [`Options`](crate::Options), and it will be parsed into
+/// a [`JWTSecret`] once when the system starts up.
 #[derive(Debug, Clone)]
-pub struct JWTSecret<'a> {
-    encoding_key: EncodingKey,
-    decoding_key: DecodingKey<'a>,
+pub enum JWTKeyMaterial {
+    /// A base64-encoded shared secret, for use with the `HS*` algorithms. This is used both to sign and to verify tokens.
+    Base64Secret(String),
+    /// A PEM-encoded key, for use with the `RS*`, `PS*`, `ES*` and `EdDSA` algorithms.
+    Pem(Vec<u8>),
+    /// A DER-encoded key, for use with the `RS*`, `PS*`, `ES*` and `EdDSA` algorithms.
+    Der(Vec<u8>),
+}
+
+/// The configuration of the keys used to verify and sign JWTs. With an asymmetric algorithm, the verification key is a public key, and the
+/// signing key (a private key) is only needed by systems that actually mint tokens. With a shared secret, the secret does both jobs.
+#[derive(Debug, Clone)]
+pub struct JWTKeyConfig {
+    /// The algorithm that tokens must be signed with. Tokens signed with any other algorithm will be rejected.
+    pub algorithm: Algorithm,
+    /// The key that will be used to verify tokens.
+    pub verification_key: JWTKeyMaterial,
+    /// The key that will be used to sign tokens, if this system needs to create them.
+    pub signing_key: Option<JWTKeyMaterial>,
+}
+impl JWTKeyConfig {
+    /// Parses this configuration into a [`JWTSecret`] that can be used for operations.
+    pub fn parse(&self) -> Result<JWTSecret> {
+        let decoding_key = get_decoding_key(self.algorithm, &self.verification_key)?;
+        let encoding_key = match (&self.signing_key, &self.verification_key) {
+            (Some(signing_key), _) => Some(get_encoding_key(self.algorithm, signing_key)?),
+            // A shared secret can sign tokens as well as verify them
+            (None, secret @ JWTKeyMaterial::Base64Secret(_)) => {
+                Some(get_encoding_key(self.algorithm, secret)?)
+            }
+            (None, _) => None,
+        };
+
+        Ok(JWTSecret::new(self.algorithm, encoding_key, decoding_key))
+    }
+}
+
+// The families of algorithms that `jsonwebtoken` supports, each of which needs a different kind of key
+enum KeyFamily {
+    Hmac,
+    Rsa,
+    Ec,
+    Ed,
+}
+impl From<Algorithm> for KeyFamily {
+    fn from(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => Self::Hmac,
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => Self::Rsa,
+            Algorithm::ES256 | Algorithm::ES384 => Self::Ec,
+            Algorithm::EdDSA => Self::Ed,
+        }
+    }
+}
+
+// Parses the given key material into a key for verifying tokens signed with the given algorithm
+fn get_decoding_key(algorithm: Algorithm, key: &JWTKeyMaterial) -> Result<DecodingKey> {
+    let decoding_key = match (KeyFamily::from(algorithm), key) {
+        (KeyFamily::Hmac, JWTKeyMaterial::Base64Secret(secret)) => {
+            DecodingKey::from_base64_secret(secret)?
+        }
+        (KeyFamily::Rsa, JWTKeyMaterial::Pem(pem)) => DecodingKey::from_rsa_pem(pem)?,
+        (KeyFamily::Rsa, JWTKeyMaterial::Der(der)) => DecodingKey::from_rsa_der(der),
+        (KeyFamily::Ec, JWTKeyMaterial::Pem(pem)) => DecodingKey::from_ec_pem(pem)?,
+        (KeyFamily::Ec, JWTKeyMaterial::Der(der)) => DecodingKey::from_ec_der(der),
+        (KeyFamily::Ed, JWTKeyMaterial::Pem(pem)) => DecodingKey::from_ed_pem(pem)?,
+        (KeyFamily::Ed, JWTKeyMaterial::Der(der)) => DecodingKey::from_ed_der(der),
+        _ => bail!(ErrorKind::InvalidJWTKeyForAlgorithm(format!(
+            "{:?}",
+            algorithm
+        ))),
+    };
+
+    Ok(decoding_key)
+}
+// Parses the given key material into a key for signing tokens with the given algorithm
+fn get_encoding_key(algorithm: Algorithm, key: &JWTKeyMaterial) -> Result<EncodingKey> {
+    let encoding_key = match (KeyFamily::from(algorithm), key) {
+        (KeyFamily::Hmac, JWTKeyMaterial::Base64Secret(secret)) => {
+            EncodingKey::from_base64_secret(secret)?
+        }
+        (KeyFamily::Rsa, JWTKeyMaterial::Pem(pem)) => EncodingKey::from_rsa_pem(pem)?,
+        (KeyFamily::Rsa, JWTKeyMaterial::Der(der)) => EncodingKey::from_rsa_der(der),
+        (KeyFamily::Ec, JWTKeyMaterial::Pem(pem)) => EncodingKey::from_ec_pem(pem)?,
+        (KeyFamily::Ec, JWTKeyMaterial::Der(der)) => EncodingKey::from_ec_der(der),
+        (KeyFamily::Ed, JWTKeyMaterial::Pem(pem)) => EncodingKey::from_ed_pem(pem)?,
+        (KeyFamily::Ed, JWTKeyMaterial::Der(der)) => EncodingKey::from_ed_der(der),
+        _ => bail!(ErrorKind::InvalidJWTKeyForAlgorithm(format!(
+            "{:?}",
+            algorithm
+        ))),
+    };
+
+    Ok(encoding_key)
 }
 
-/// Transforms a string JWT secret into a form in which it can be used for operations.
-pub fn get_jwt_secret<'a>(secret_str: String) -> Result<JWTSecret<'a>> {
-    let encoding_key = EncodingKey::from_base64_secret(&secret_str)?;
-    let decoding_key = DecodingKey::from_base64_secret(&secret_str)?;
+/// A parsed JWT secret. This should be created once with `get_jwt_secret` (or from a [`JWTKeyConfig`]) and then reused as much as possible.
+/// You may want to place it in your context under [`Options`](crate::Options).
+#[derive(Clone)]
+pub struct JWTSecret {
+    algorithm: Algorithm,
+    key_id: Option<String>,
+    // This will only be present if we have a private key (or a shared secret)
+    encoding_key: Option<EncodingKey>,
+    decoding_key: DecodingKey,
+}
+// The keys themselves should never be printed
+impl fmt::Debug for JWTSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JWTSecret")
+            .field("algorithm", &self.algorithm)
+            .field("key_id", &self.key_id)
+            .field("can_sign", &self.encoding_key.is_some())
+            .finish()
+    }
+}
+impl JWTSecret {
+    pub(crate) fn new(
+        algorithm: Algorithm,
+        encoding_key: Option<EncodingKey>,
+        decoding_key: DecodingKey,
+    ) -> Self {
+        Self {
+            algorithm,
+            key_id: None,
+            encoding_key,
+            decoding_key,
+        }
+    }
+    /// Attaches a key ID to this secret. Tokens created with it will have this in their `kid` header, and it will be used to select this
+    /// secret from a [`JWTKeySet`](crate::JWTKeySet).
+    pub fn with_key_id(mut self, key_id: &str) -> Self {
+        self.key_id = Some(key_id.to_string());
+        self
+    }
+    /// Gets the key ID of this secret, if it has one.
+    pub fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+    /// Gets the algorithm that this secret signs and verifies tokens with.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+    /// Checks if this secret can be used to create tokens (i.e. if it has a private key or a shared secret).
+    pub fn can_sign(&self) -> bool {
+        self.encoding_key.is_some()
+    }
+}
 
-    Ok(JWTSecret {
-        encoding_key,
-        decoding_key,
-    })
+/// Transforms a base64 string JWT secret into a form in which it can be used for operations. Tokens will be signed and verified with
+/// `HS512`. If you want to use a different algorithm or asymmetric keys, use [`JWTKeyConfig`] instead.
+pub fn get_jwt_secret(secret_str: String) -> Result<JWTSecret> {
+    JWTKeyConfig {
+        algorithm: Algorithm::HS512,
+        verification_key: JWTKeyMaterial::Base64Secret(secret_str),
+        signing_key: None,
+    }
+    .parse()
 }
 
 /// Decodes time strings like '1w' into actual datetimes from the present moment. If you've ever used NodeJS's [`jsonwebtoken`](https://www.npmjs.com/package/jsonwebtoken) module, this is
 /// very similar (based on Vercel's [`ms`](https://github.com/vercel/ms) module for JavaScript).
-/// Accepts strings of the form 'xXyYzZ...', where the lower-case letters are numbers meaning a number of the intervals X/Y/Z (e.g. 1m4d -- one month four days).
-/// The available intervals are:
+/// Accepts strings of the form 'xXyYzZ...', where the lower-case letters are numbers meaning a number of the intervals X/Y/Z (e.g. 1M4d -- one month four days),
+/// as well as ISO 8601 durations (e.g. `P1M4D`). The available intervals are:
 ///
 /// - s: second,
 /// - m: minute,
@@ -45,74 +345,151 @@ pub fn get_jwt_secret<'a>(secret_str: String) -> Result<JWTSecret<'a>> {
 /// - w: week,
 /// - M: month (30 days used here, 12M ≠ 1y!),
 /// - y: year (365 days always, leap years ignored, if you want them add them as days)
+///
+/// If you want calendar-aware months and years, or a `chrono`/`std` duration rather than a timestamp, use [`ParsedDuration`] directly.
 pub fn decode_time_str(time_str: &str) -> Result<u64> {
-    let mut duration_after_current = Duration::zero();
-    // Get the current datetime since Unix epoch, we'll add to that
-    let current = Utc::now();
-    // A working variable to store the '123' part of an interval until we reach the idnicator and can do the full conversion
-    let mut curr_duration_length = String::new();
-    // Iterate through the time string's characters to get each interval
-    for c in time_str.chars() {
-        // If we have a number, append it to the working cache
-        // If we have an indicator character, we'll match it to a duration
-        if c.is_numeric() {
-            curr_duration_length.push(c);
-        } else {
-            // Parse the working variable into an actual number
-            let interval_length = curr_duration_length.parse::<i64>().unwrap(); // It's just a string of numbers, we know more than the compiler
-            let duration = match c {
-                's' => Duration::seconds(interval_length),
-                'm' => Duration::minutes(interval_length),
-                'h' => Duration::hours(interval_length),
-                'd' => Duration::days(interval_length),
-                'w' => Duration::weeks(interval_length),
-                'M' => Duration::days(interval_length * 30), // Multiplying the number of months by 30 days (assumed length of a month)
-                'y' => Duration::days(interval_length * 365), // Multiplying the number of years by 365 days (assumed length of a year)
-                c => bail!(ErrorKind::InvalidDatetimeIntervalIndicator(c.to_string())),
-            };
-            duration_after_current = duration_after_current + duration;
-            // Reset that working variable
-            curr_duration_length = String::new();
-        }
+    decode_time_str_with_clock(time_str, &SystemClock)
+}
+/// Decodes time strings in the same way as [`decode_time_str`], but from the present moment according to the given clock rather than the
+/// system clock. This is mostly useful for testing with a [`FakeClock`](crate::FakeClock).
+pub fn decode_time_str_with_clock(time_str: &str, clock: &dyn Clock) -> Result<u64> {
+    let datetime = ParsedDuration::parse(time_str)?.from_now_with_clock(clock)?;
+    timestamp_to_u64(datetime.timestamp())
+}
+
+/// The expiry of a new JWT, which can be given to [`create_jwt`] as either a Unix timestamp or a duration from the present moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JWTExpiry {
+    /// The token will expire at the given Unix timestamp (e.g. from [`decode_time_str`]).
+    At(u64),
+    /// The token will expire this long after it's created (months and years will be calendar-aware if the duration is).
+    In(ParsedDuration),
+    /// The token will expire this long after it's created.
+    After(Duration),
+}
+impl JWTExpiry {
+    /// Gets the Unix timestamp at which a token created at the given clock's present moment should expire.
+    pub fn to_timestamp(&self, clock: &dyn Clock) -> Result<u64> {
+        let datetime = match self {
+            Self::At(timestamp) => return Ok(*timestamp),
+            Self::In(duration) => duration.after(clock.now())?,
+            Self::After(duration) => clock
+                .now()
+                .checked_add_signed(*duration)
+                .ok_or(ErrorKind::DurationOutOfRange)?,
+        };
+        timestamp_to_u64(datetime.timestamp())
+    }
+}
+impl From<u64> for JWTExpiry {
+    fn from(timestamp: u64) -> Self {
+        Self::At(timestamp)
     }
-    // Form the final duration by reducing the durations vector into one
-    let datetime = current + duration_after_current;
+}
+impl From<ParsedDuration> for JWTExpiry {
+    fn from(duration: ParsedDuration) -> Self {
+        Self::In(duration)
+    }
+}
+impl From<Duration> for JWTExpiry {
+    fn from(duration: Duration) -> Self {
+        Self::After(duration)
+    }
+}
 
-    Ok(datetime.timestamp() as u64) // As Unix timestamp in u64 because that's what the JWT demands (we can't have expiries before January 1st 1970, let me know if that's a problem!)
+// JWTs can't express expiries before January 1st 1970, let me know if that's a problem!
+fn timestamp_to_u64(timestamp: i64) -> Result<u64> {
+    u64::try_from(timestamp).map_err(|_| ErrorKind::DurationOutOfRange.into())
 }
 
 /// Creates a new JWT. You should use this to issue all client JWTs and create the initial JWT for communication with the subscriptions
-/// server (more information in the book).
-pub fn create_jwt(
-    user_claims: HashMap<String, String>,
+/// server (more information in the book). This will fail if the given secret only has a public key. If the secret has a key ID, it will be
+/// set as the `kid` header of the token. The expiry can be a Unix timestamp or a duration (see [`JWTExpiry`]).
+pub fn create_jwt<T: Serialize, E: Into<JWTExpiry>>(
+    user_claims: T,
+    secret: &JWTSecret,
+    exp: E,
+) -> Result<String> {
+    create_jwt_with_registered_claims(user_claims, RegisteredClaims::default(), secret, exp)
+}
+
+/// Creates a new JWT with the given registered claims (issuer, audience, etc.) as well as the user's claims. This works in the same way as
+/// [`create_jwt`], and `iat` will be set to the present moment if you don't set it.
+pub fn create_jwt_with_registered_claims<T: Serialize, E: Into<JWTExpiry>>(
+    user_claims: T,
+    registered_claims: RegisteredClaims,
+    secret: &JWTSecret,
+    exp: E,
+) -> Result<String> {
+    create_jwt_with_clock(user_claims, registered_claims, secret, exp, &SystemClock)
+}
+
+/// Creates a new JWT in the same way as [`create_jwt_with_registered_claims`], but using the given clock for the present moment (which
+/// `iat` and expiry durations are based on). This is mostly useful for testing with a [`FakeClock`](crate::FakeClock).
+pub fn create_jwt_with_clock<T: Serialize, E: Into<JWTExpiry>>(
+    user_claims: T,
+    mut registered_claims: RegisteredClaims,
     secret: &JWTSecret,
-    exp: u64,
+    exp: E,
+    clock: &dyn Clock,
 ) -> Result<String> {
+    let exp = exp.into().to_timestamp(clock)?;
+    if registered_claims.iat.is_none() {
+        registered_claims.iat = Some(clock.timestamp());
+    }
     // Create the claims
     let claims = Claims {
         exp,
+        registered: registered_claims,
         claims: user_claims,
     };
-    let token = encode(
-        &Header::new(Algorithm::HS512),
-        &claims,
-        &secret.encoding_key,
-    )?;
+    let encoding_key = secret
+        .encoding_key
+        .as_ref()
+        .ok_or(ErrorKind::MissingJWTSigningKey)?;
+    let mut header = Header::new(secret.algorithm);
+    header.kid = secret.key_id.clone();
+    let token = encode(&header, &claims, encoding_key)?;
 
     Ok(token)
 }
 
 /// Validates a JWT and returns the payload. All client JWTs are automatically validated and their payloads are sent (parsed) to your resolvers,
 /// but if you have a system on top of that you'll want to use this function (not required for normal Diana usage though).
-pub fn validate_and_decode_jwt(jwt: &str, secret: &JWTSecret) -> Option<Claims> {
-    let decoded = decode::<Claims>(
+/// Tokens signed with any algorithm other than the one the secret was created for will be rejected.
+/// This uses the default [`JWTValidation`] settings, which accept any issuer and audience. Tokens whose claims can't be deserialized into
+/// the given claims type will be rejected.
+pub fn validate_and_decode_jwt<T: DeserializeOwned>(
+    jwt: &str,
+    secret: &JWTSecret,
+) -> Option<Claims<T>> {
+    validate_and_decode_jwt_with_validation(jwt, secret, &JWTValidation::default())
+}
+
+/// Validates a JWT with the given validation settings and returns the payload. This works in the same way as [`validate_and_decode_jwt`],
+/// but also checks the token's registered claims (issuer, audience, etc.) against the given settings.
+pub fn validate_and_decode_jwt_with_validation<T: DeserializeOwned>(
+    jwt: &str,
+    secret: &JWTSecret,
+    validation: &JWTValidation,
+) -> Option<Claims<T>> {
+    validate_and_decode_jwt_with_reason(jwt, secret, validation).ok()
+}
+
+/// Validates a JWT with the given validation settings and returns the payload, or the reason the token was rejected. This works in the
+/// same way as [`validate_and_decode_jwt_with_validation`], but lets you tell an expired token from a forged one.
+pub fn validate_and_decode_jwt_with_reason<T: DeserializeOwned>(
+    jwt: &str,
+    secret: &JWTSecret,
+    validation: &JWTValidation,
+) -> StdResult<Claims<T>, AuthFailure> {
+    let decoded = decode::<Claims<T>>(
         jwt,
         &secret.decoding_key,
-        &Validation::new(Algorithm::HS512),
-    );
+        &validation.get_validation(secret.algorithm),
+    )
+    .map_err(|err| AuthFailure::from(&err))?;
+    validation.check_extra_claims(&decoded.claims)?;
 
-    match decoded {
-        Ok(decoded) => Some(decoded.claims),
-        Err(_) => None,
-    }
+    Ok(decoded.claims)
 }
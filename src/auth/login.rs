@@ -0,0 +1,453 @@
+// This file contains the logic for logging users in with a username and password, which is only compiled with the `login` feature
+// How users are stored is up to the user of Diana, we only hash and verify their passwords (with Argon2id) and issue the resulting tokens
+// Failed logins are counted per account and per IP address so that passwords can't be brute-forced, and both are locked out for a while
+// once they've failed too many times
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm as Argon2Algorithm, Argon2, Params, Version};
+use async_graphql::Object as GQLObject;
+use async_trait::async_trait;
+use rand::rngs::OsRng;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::extraction::AuthRequestParts;
+use crate::auth::jwt::{create_jwt_with_clock, JWTSecret, RegisteredClaims, UserClaims};
+use crate::errors::*;
+
+// The Argon2id parameters for new hashes (19 MiB of memory, 2 iterations, 1 lane), as recommended by OWASP
+// Existing hashes are verified with whatever parameters they were created with, so these can be raised later
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+// A hash with the same parameters as new hashes that no known password matches, which is verified against when a user doesn't exist so
+// that attackers can't find out which usernames exist by timing logins
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$ZGlhbmFkdW1teXNhbHQ$y3oLpobmr+y3/uQdmuzeeQ0ftwy5lOi0+TFIH6nU03k";
+
+// Creates the hasher that all new password hashes are made with
+fn get_hasher() -> Result<Argon2<'static>> {
+    let params = Params::new(
+        ARGON2_MEMORY_KIB,
+        ARGON2_ITERATIONS,
+        ARGON2_PARALLELISM,
+        None,
+    )
+    .map_err(|err| ErrorKind::PasswordHashingFailed(err.to_string()))?;
+
+    Ok(Argon2::new(
+        Argon2Algorithm::Argon2id,
+        Version::V0x13,
+        params,
+    ))
+}
+
+/// Hashes the given password with Argon2id and a random salt, returning the hash as a PHC string (which includes the salt and parameters).
+/// This is what you should store for your users, and what you should return from [`CredentialVerifier::find_credentials`]. Hashing is
+/// deliberately slow, so this will block the thread for a while, and you should use [`hash_password_async`] in async code.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = get_hasher()?
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| ErrorKind::PasswordHashingFailed(err.to_string()))?;
+
+    Ok(hash.to_string())
+}
+
+/// Checks if the given password matches the given hash (a PHC string from [`hash_password`]). This will return an error if the hash is
+/// malformed, but a wrong password is simply `false`. Like hashing, this will block the thread for a while, so you should use
+/// [`verify_password_async`] in async code.
+pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    let hash =
+        PasswordHash::new(hash).map_err(|err| ErrorKind::PasswordHashingFailed(err.to_string()))?;
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(_) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(err) => bail!(ErrorKind::PasswordHashingFailed(err.to_string())),
+    }
+}
+
+/// Hashes the given password in the same way as [`hash_password`], but on Tokio's blocking thread pool, so that other tasks can carry on
+/// in the meantime.
+pub async fn hash_password_async(password: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || hash_password(&password))
+        .await
+        .map_err(|err| ErrorKind::PasswordHashingFailed(err.to_string()))?
+}
+/// Checks if the given password matches the given hash in the same way as [`verify_password`], but on Tokio's blocking thread pool, so
+/// that other tasks can carry on in the meantime.
+pub async fn verify_password_async(password: String, hash: String) -> Result<bool> {
+    tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .map_err(|err| ErrorKind::PasswordHashingFailed(err.to_string()))?
+}
+
+/// The stored credentials of a user, which a [`CredentialVerifier`] finds from their username.
+#[derive(Debug, Clone)]
+pub struct StoredCredentials {
+    /// The ID of the user, which will be the `sub` claim of their tokens.
+    pub user_id: String,
+    /// The hash of the user's password, as created by [`hash_password`].
+    pub password_hash: String,
+    /// Anything else about the user that's needed to create their claims (e.g. their role).
+    pub data: serde_json::Value,
+}
+
+/// The system that finds users' credentials when they log in. You'll need to implement this yourself, usually by looking users up in your
+/// database. By default, passwords will be verified against the stored hash with [`verify_password`], but you can override `.verify()` to
+/// check them some other way (e.g. against a directory server).
+#[async_trait]
+pub trait CredentialVerifier: fmt::Debug + Send + Sync {
+    /// Finds the stored credentials of the user with the given username, returning `None` if there's no such user.
+    async fn find_credentials(&self, username: &str) -> Result<Option<StoredCredentials>>;
+    /// Checks the given username and password, returning the user's credentials if they're correct and `None` otherwise. Users that don't
+    /// exist take as long to check as those that do, so attackers can't find valid usernames by timing this.
+    async fn verify(&self, username: &str, password: &str) -> Result<Option<StoredCredentials>> {
+        let credentials = self.find_credentials(username).await?;
+        let password_hash = match &credentials {
+            Some(credentials) => credentials.password_hash.clone(),
+            None => DUMMY_PASSWORD_HASH.to_string(),
+        };
+        let is_valid = verify_password_async(password.to_string(), password_hash).await?;
+
+        Ok(credentials.filter(|_| is_valid))
+    }
+}
+
+/// A store of failed login attempts, which are counted by key (Diana uses keys for accounts and IP addresses). Diana provides
+/// [`MemoryLoginAttemptStore`], but you can implement this yourself to use something shared between instances (like Redis).
+/// Attempts are recorded as failures *before* the password is checked (and released again if it was right), so that an attacker can't get
+/// extra guesses by sending lots of them at once.
+pub trait LoginAttemptStore: fmt::Debug + Send + Sync {
+    /// Records an attempt for the given key as a failure in advance, unless the key already has at least the given number of failed
+    /// attempts that haven't expired, returning whether or not it was recorded. The attempt can be forgotten after the given expiry (in
+    /// seconds from Unix epoch). This must be atomic, otherwise racing attempts could all get through before any of them are counted.
+    fn try_reserve_attempt(&self, key: &str, exp: u64, max_failures: u32) -> Result<bool>;
+    /// Forgets a single attempt with the given expiry that was recorded for the given key by `.try_reserve_attempt()`, because it didn't
+    /// fail after all.
+    fn release_attempt(&self, key: &str, exp: u64) -> Result<()>;
+    /// Gets the number of failed attempts for the given key that haven't expired.
+    fn count_failures(&self, key: &str) -> Result<u32>;
+    /// Forgets all the failed attempts for the given key (e.g. after a successful login).
+    fn clear_failures(&self, key: &str) -> Result<()>;
+}
+
+/// A login attempt store that's held entirely in memory. Attempts won't be shared between instances, so an attacker could spread their
+/// guesses across them, which makes this best for development or single-instance servers.
+#[derive(Debug)]
+pub struct MemoryLoginAttemptStore {
+    // Keys mapped to the expiries of their failed attempts
+    failures: RwLock<HashMap<String, Vec<u64>>>,
+    clock: Arc<dyn Clock>,
+}
+impl MemoryLoginAttemptStore {
+    /// Creates a new empty in-memory login attempt store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the clock that's used to decide when failed attempts expire. This should be the same clock you give to the [`LoginManager`].
+    /// This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+impl Default for MemoryLoginAttemptStore {
+    fn default() -> Self {
+        Self {
+            failures: RwLock::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl LoginAttemptStore for MemoryLoginAttemptStore {
+    fn try_reserve_attempt(&self, key: &str, exp: u64, max_failures: u32) -> Result<bool> {
+        let mut failures = self
+            .failures
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("login_attempt_list".to_string()))?;
+        // Forget about the attempts that have expired (and the keys that have no attempts left)
+        let now = self.clock.timestamp();
+        failures.retain(|_, expiries| {
+            expiries.retain(|expiry| *expiry >= now);
+            !expiries.is_empty()
+        });
+        let expiries = failures.entry(key.to_string()).or_default();
+        if expiries.len() as u32 >= max_failures {
+            return Ok(false);
+        }
+        expiries.push(exp);
+
+        Ok(true)
+    }
+    fn release_attempt(&self, key: &str, exp: u64) -> Result<()> {
+        let mut failures = self
+            .failures
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("login_attempt_list".to_string()))?;
+        if let Some(expiries) = failures.get_mut(key) {
+            if let Some(idx) = expiries.iter().position(|expiry| *expiry == exp) {
+                expiries.remove(idx);
+            }
+            if expiries.is_empty() {
+                failures.remove(key);
+            }
+        }
+
+        Ok(())
+    }
+    fn count_failures(&self, key: &str) -> Result<u32> {
+        let failures = self
+            .failures
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("login_attempt_list".to_string()))?;
+        let now = self.clock.timestamp();
+        let count = failures.get(key).map_or(0, |expiries| {
+            expiries.iter().filter(|expiry| **expiry >= now).count()
+        });
+
+        Ok(count as u32)
+    }
+    fn clear_failures(&self, key: &str) -> Result<()> {
+        let mut failures = self
+            .failures
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("login_attempt_list".to_string()))?;
+        failures.remove(key);
+
+        Ok(())
+    }
+}
+
+// The function that creates a user's claims from their credentials once they've logged in
+type ClaimsCreator<T> = Arc<dyn Fn(&StoredCredentials) -> Result<T> + Send + Sync>;
+
+/// The system that logs users in. You should create one of these with your [`CredentialVerifier`], the secret to sign tokens with, and a
+/// function that creates a user's claims from their credentials, then give it to the [`Options`](crate::Options) if you want to use
+/// [`LoginMutation`]. You can also use it directly in your own login logic with `.login()`.
+///
+/// Every failed login counts against both the account and the IP address it came from, and once either has failed too many times within
+/// the lockout window, logins for it will be refused with [`ErrorKind::TooManyLoginAttempts`] until enough of those failures expire. Note
+/// that this means anyone can lock an account by failing to log in to it, which is a trade-off you should keep in mind when choosing limits.
+/// The last type parameter is the type of the user's claims, which should be the same as in your options.
+#[derive(Clone)]
+pub struct LoginManager<T: UserClaims = HashMap<String, String>> {
+    verifier: Arc<dyn CredentialVerifier>,
+    secret: JWTSecret,
+    create_claims: ClaimsCreator<T>,
+    attempt_store: Arc<dyn LoginAttemptStore>,
+    registered_claims: RegisteredClaims,
+    token_lifetime: Duration,
+    max_account_failures: u32,
+    max_ip_failures: u32,
+    lockout_window: Duration,
+    client_ip_header: Option<String>,
+    clock: Arc<dyn Clock>,
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> fmt::Debug for LoginManager<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginManager")
+            .field("verifier", &self.verifier)
+            .field("attempt_store", &self.attempt_store)
+            .field("registered_claims", &self.registered_claims)
+            .field("token_lifetime", &self.token_lifetime)
+            .field("max_account_failures", &self.max_account_failures)
+            .field("max_ip_failures", &self.max_ip_failures)
+            .field("lockout_window", &self.lockout_window)
+            .field("client_ip_header", &self.client_ip_header)
+            .finish()
+    }
+}
+impl<T: UserClaims> LoginManager<T> {
+    /// Creates a new login manager with the given credential verifier, secret (which must be able to sign tokens) and function to create a
+    /// user's claims from their credentials. By default, tokens will be valid for 15 minutes, failed attempts will be counted in memory for
+    /// 15 minutes, and accounts will be locked after 5 failures and IP addresses after 50.
+    pub fn new<F>(
+        verifier: Arc<dyn CredentialVerifier>,
+        secret: JWTSecret,
+        create_claims: F,
+    ) -> Self
+    where
+        F: Fn(&StoredCredentials) -> Result<T> + Send + Sync + 'static,
+    {
+        Self {
+            verifier,
+            secret,
+            create_claims: Arc::new(create_claims),
+            attempt_store: Arc::new(MemoryLoginAttemptStore::new()),
+            registered_claims: RegisteredClaims::default(),
+            token_lifetime: Duration::from_secs(15 * 60),
+            max_account_failures: 5,
+            max_ip_failures: 50,
+            lockout_window: Duration::from_secs(15 * 60),
+            client_ip_header: None,
+            clock: Arc::new(SystemClock),
+            claims_type: PhantomData,
+        }
+    }
+    /// Sets how long the tokens issued on login will be valid for.
+    pub fn token_lifetime(mut self, token_lifetime: Duration) -> Self {
+        self.token_lifetime = token_lifetime;
+        self
+    }
+    /// Sets the registered claims (issuer, audience, etc.) that will be put in every token. Any `sub` or `iat` set here will be overridden.
+    pub fn registered_claims(mut self, registered_claims: RegisteredClaims) -> Self {
+        self.registered_claims = registered_claims;
+        self
+    }
+    /// Sets the store that failed attempts are counted in. If you have more than one instance, this should be shared between them.
+    pub fn attempt_store(mut self, attempt_store: Arc<dyn LoginAttemptStore>) -> Self {
+        self.attempt_store = attempt_store;
+        self
+    }
+    /// Sets how many failed attempts an account can have within the lockout window before it's locked.
+    pub fn max_account_failures(mut self, max_account_failures: u32) -> Self {
+        self.max_account_failures = max_account_failures;
+        self
+    }
+    /// Sets how many failed attempts an IP address can have within the lockout window before it's locked. This should be higher than the
+    /// limit for accounts, because many users can share an address.
+    pub fn max_ip_failures(mut self, max_ip_failures: u32) -> Self {
+        self.max_ip_failures = max_ip_failures;
+        self
+    }
+    /// Sets how long failed attempts count towards the limits for.
+    pub fn lockout_window(mut self, lockout_window: Duration) -> Self {
+        self.lockout_window = lockout_window;
+        self
+    }
+    /// Sets a header to read clients' IP addresses from (the first address in it is used), instead of the address the integration saw the
+    /// request come from. You'll need this behind a proxy (usually with `X-Forwarded-For`), but you should only use it if the proxy sets
+    /// the header itself, otherwise clients could claim any address they like.
+    pub fn client_ip_header(mut self, client_ip_header: &str) -> Self {
+        self.client_ip_header = Some(client_ip_header.to_string());
+        self
+    }
+    /// Sets the clock that tokens will be issued and failed attempts will expire against. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Gets the IP address of the client that made the given request, from the header set with `.client_ip_header()` if there is one.
+    pub fn get_client_ip(&self, parts: &AuthRequestParts) -> Option<String> {
+        match &self.client_ip_header {
+            Some(header) => parts
+                .get_header(header)?
+                .split(',')
+                .next()
+                .map(|ip| ip.trim().to_string())
+                .filter(|ip| !ip.is_empty()),
+            None => parts.get_client_ip().map(|ip| ip.to_string()),
+        }
+    }
+    /// Logs the user with the given username and password in, returning a new token for them. This will fail with
+    /// [`ErrorKind::InvalidCredentials`] if the username or password is wrong, or with [`ErrorKind::TooManyLoginAttempts`] if the account or
+    /// the given IP address has been locked. If no IP address is given, only the account will be limited.
+    pub async fn login(
+        &self,
+        username: &str,
+        password: &str,
+        client_ip: Option<&str>,
+    ) -> Result<String> {
+        // Usernames are compared case-insensitively for counting attempts so attackers can't get more guesses by changing the case
+        let account_key = format!("account:{}", username.to_lowercase());
+        let ip_key = client_ip.map(|client_ip| format!("ip:{}", client_ip));
+        // We count this attempt as a failure before checking the password, so that locked accounts can't be used to check guesses, and so
+        // that guesses sent all at once are limited just like ones sent one after another
+        let exp = self.clock.timestamp() + self.lockout_window.as_secs();
+        if !self
+            .attempt_store
+            .try_reserve_attempt(&account_key, exp, self.max_account_failures)?
+        {
+            bail!(ErrorKind::TooManyLoginAttempts);
+        }
+        if let Some(ip_key) = &ip_key {
+            if !self
+                .attempt_store
+                .try_reserve_attempt(ip_key, exp, self.max_ip_failures)?
+            {
+                self.attempt_store.release_attempt(&account_key, exp)?;
+                bail!(ErrorKind::TooManyLoginAttempts);
+            }
+        }
+
+        let credentials = match self.verifier.verify(username, password).await {
+            Ok(Some(credentials)) => credentials,
+            // The failure has already been counted
+            Ok(None) => bail!(ErrorKind::InvalidCredentials),
+            // If we couldn't check the password at all, it wasn't a guess
+            Err(err) => {
+                self.attempt_store.release_attempt(&account_key, exp)?;
+                if let Some(ip_key) = &ip_key {
+                    self.attempt_store.release_attempt(ip_key, exp)?;
+                }
+                return Err(err);
+            }
+        };
+        // The IP address keeps its other failures, otherwise an attacker with one valid account could reset their own limit
+        self.attempt_store.clear_failures(&account_key)?;
+        if let Some(ip_key) = &ip_key {
+            self.attempt_store.release_attempt(ip_key, exp)?;
+        }
+
+        let user_claims = (self.create_claims)(&credentials)?;
+        let now = self.clock.timestamp();
+        let registered_claims = RegisteredClaims {
+            sub: Some(credentials.user_id),
+            iat: Some(now),
+            ..self.registered_claims.clone()
+        };
+        create_jwt_with_clock(
+            user_claims,
+            registered_claims,
+            &self.secret,
+            now + self.token_lifetime.as_secs(),
+            self.clock.as_ref(),
+        )
+    }
+}
+
+/// A GraphQL mutation root that lets users log in with their username and password. You can merge this into your own mutation root with
+/// `async_graphql::MergedObject`, and you'll need to give a [`LoginManager`] to the [`Options`](crate::Options) for it to work. You'll
+/// probably also want to allow it anonymously with an [`AuthPolicy`](crate::AuthPolicy).
+/// The type parameter is the type of the user's claims, which should be the same as in your options.
+#[derive(Clone)]
+pub struct LoginMutation<T: UserClaims = HashMap<String, String>> {
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> Default for LoginMutation<T> {
+    fn default() -> Self {
+        Self {
+            claims_type: PhantomData,
+        }
+    }
+}
+#[GQLObject]
+impl<T: UserClaims> LoginMutation<T> {
+    /// Logs in with the given username and password, returning a token that should be sent as a bearer token with requests.
+    async fn login(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        username: String,
+        password: String,
+    ) -> Result<String> {
+        let login_manager = raw_ctx
+            .data::<LoginManager<T>>()
+            .map_err(|_err| ErrorKind::GraphQLContextNotFound("login_manager".to_string()))?;
+        let client_ip = raw_ctx
+            .data::<AuthRequestParts>()
+            .ok()
+            .and_then(|parts| login_manager.get_client_ip(parts));
+
+        login_manager
+            .login(&username, &password, client_ip.as_deref())
+            .await
+    }
+}
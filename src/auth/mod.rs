@@ -1,3 +1,20 @@
+pub mod api_keys;
 pub mod auth_state;
+pub mod authenticator;
+pub mod claim_predicate;
+pub mod clock;
 pub mod core;
+pub mod duration;
+pub mod extraction;
+pub mod failure;
+pub mod guards;
+pub mod jwks;
 pub mod jwt;
+#[cfg(feature = "login")]
+pub mod login;
+pub mod policy;
+pub mod publish_auth;
+pub mod refresh;
+pub mod revocation;
+pub mod subscription_expiry;
+pub mod tenant;
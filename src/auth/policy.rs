@@ -0,0 +1,219 @@
+// This file contains per-operation authentication policies, which refine the endpoint-wide block level once a request has been parsed
+// Operation names are chosen by clients, so policies match the fields an operation selects at its root rather than its name
+
+use async_graphql::parser::{
+    parse_query,
+    types::{DocumentOperations, ExecutableDocument, OperationType, Selection, SelectionSet},
+};
+use std::collections::HashSet;
+
+use crate::auth::auth_state::AuthState;
+use crate::auth::claim_predicate::ClaimPredicate;
+use crate::auth::core::AuthVerdict;
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwt::UserClaims;
+
+/// The root fields that introspection queries select, which are allowed anonymously by `.allow_introspection()`.
+const INTROSPECTION_FIELDS: [&str; 3] = ["__schema", "__type", "__typename"];
+
+/// The kinds of GraphQL operations that policies can apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperationKind {
+    /// A query.
+    Query,
+    /// A mutation.
+    Mutation,
+    /// A subscription.
+    Subscription,
+}
+impl From<OperationType> for OperationKind {
+    fn from(ty: OperationType) -> Self {
+        match ty {
+            OperationType::Query => Self::Query,
+            OperationType::Mutation => Self::Mutation,
+            OperationType::Subscription => Self::Subscription,
+        }
+    }
+}
+
+/// The rule that an [`AuthPolicy`] applies to a kind of operation.
+#[derive(Debug, Clone, Default)]
+pub enum OperationRule {
+    /// The endpoint's block level decides, as if there were no policy. This is the default.
+    #[default]
+    Default,
+    /// Anyone can run the operation, even without a valid token (the [`AuthState`] they get will say why their token wasn't accepted).
+    Anonymous,
+    /// The operation can only be run with a valid token, whatever the endpoint's block level.
+    Authenticated,
+    /// The operation can only be run with a valid token whose claims satisfy the given predicate. Requests with valid tokens that don't
+    /// will be blocked with [`AuthFailure::InsufficientClaims`].
+    Claims(ClaimPredicate),
+}
+
+/// A policy that refines the endpoint's [`AuthBlockLevel`](crate::AuthBlockLevel) for each operation, once the request has been parsed.
+/// This can allow anonymous access to specific root fields (like `login` or introspection) while blocking everything else, or require
+/// specific claims for mutations while leaving queries open.
+///
+/// Operations are matched by the fields they select at their root rather than by their names, because clients can give their operations
+/// any name they like. An operation is only anonymous if *every* field it selects is.
+#[derive(Debug, Clone, Default)]
+pub struct AuthPolicy {
+    anonymous_fields: HashSet<(OperationKind, String)>,
+    queries: OperationRule,
+    mutations: OperationRule,
+}
+impl AuthPolicy {
+    /// Creates a new policy that doesn't change anything. You'll need to then call the other methods to set it up.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Allows anyone to run operations of the given kind that only select the given root fields (e.g. a `login` mutation).
+    pub fn anonymous_fields(mut self, kind: OperationKind, fields: &[&str]) -> Self {
+        self.anonymous_fields
+            .extend(fields.iter().map(|field| (kind, field.to_string())));
+        self
+    }
+    /// Allows anyone to run introspection queries (those that only select `__schema`, `__type` and `__typename`), which is needed for
+    /// the GraphiQL playground with `AuthBlockLevel::BlockUnauthenticated`.
+    pub fn allow_introspection(self) -> Self {
+        self.anonymous_fields(OperationKind::Query, &INTROSPECTION_FIELDS)
+    }
+    /// Sets the rule for queries that aren't anonymous because of their fields. By default, the endpoint's block level decides.
+    pub fn queries(mut self, rule: OperationRule) -> Self {
+        self.queries = rule;
+        self
+    }
+    /// Sets the rule for mutations that aren't anonymous because of their fields. By default, the endpoint's block level decides.
+    pub fn mutations(mut self, rule: OperationRule) -> Self {
+        self.mutations = rule;
+        self
+    }
+    /// Applies this policy to the verdict the endpoint's block level arrived at, given the request's query and the name of the operation
+    /// to run. If the query can't be parsed or doesn't contain that operation, the verdict will be returned unchanged (and `async_graphql`
+    /// will return an error if it's allowed).
+    pub fn apply<T: UserClaims>(
+        &self,
+        query: &str,
+        operation_name: Option<&str>,
+        verdict: AuthVerdict<T>,
+    ) -> AuthVerdict<T> {
+        let doc = match parse_query(query) {
+            Ok(doc) => doc,
+            Err(_) => return verdict,
+        };
+        let rule = match self.get_rule(&doc, operation_name) {
+            Some(rule) => rule,
+            None => return verdict,
+        };
+        match (rule, verdict) {
+            (OperationRule::Default, verdict) => verdict,
+            // Anonymous operations are allowed whatever the token, but they should still know what it was
+            (OperationRule::Anonymous, AuthVerdict::Block(AuthFailure::MissingToken)) => {
+                AuthVerdict::Allow(AuthState::NoToken)
+            }
+            (OperationRule::Anonymous, AuthVerdict::Block(failure)) => {
+                AuthVerdict::Allow(AuthState::InvalidToken(failure))
+            }
+            (OperationRule::Anonymous, AuthVerdict::Revoked) => {
+                AuthVerdict::Allow(AuthState::RevokedToken)
+            }
+            // Other rules need a valid token, even if the block level would let the request through without one
+            (OperationRule::Authenticated, AuthVerdict::Allow(auth_state))
+            | (OperationRule::Claims(_), AuthVerdict::Allow(auth_state))
+                if !auth_state.is_valid() =>
+            {
+                match auth_state {
+                    AuthState::InvalidToken(failure) => AuthVerdict::Block(failure),
+                    AuthState::RevokedToken => AuthVerdict::Revoked,
+                    _ => AuthVerdict::Block(AuthFailure::MissingToken),
+                }
+            }
+            (OperationRule::Claims(predicate), AuthVerdict::Allow(auth_state))
+                if !auth_state.satisfies(predicate) =>
+            {
+                AuthVerdict::Block(AuthFailure::InsufficientClaims)
+            }
+            (_, verdict) => verdict,
+        }
+    }
+
+    // Gets the rule that applies to the operation that will be run, if it can be found
+    fn get_rule(
+        &self,
+        doc: &ExecutableDocument,
+        operation_name: Option<&str>,
+    ) -> Option<&OperationRule> {
+        // This mirrors how `async_graphql` chooses the operation
+        let operation = match (&doc.operations, operation_name) {
+            (DocumentOperations::Single(operation), None) => &operation.node,
+            (DocumentOperations::Multiple(operations), Some(name)) => &operations.get(name)?.node,
+            (DocumentOperations::Multiple(operations), None) if operations.len() == 1 => {
+                &operations.values().next()?.node
+            }
+            _ => return None,
+        };
+        let kind = OperationKind::from(operation.ty);
+        let mut fields = HashSet::new();
+        collect_root_fields(
+            doc,
+            &operation.selection_set.node,
+            &mut fields,
+            &mut HashSet::new(),
+        );
+        // An operation that selects nothing we can see isn't anonymous
+        let is_anonymous = !fields.is_empty()
+            && fields
+                .iter()
+                .all(|field| self.anonymous_fields.contains(&(kind, field.to_string())));
+        let rule = match (is_anonymous, kind) {
+            (true, _) => &OperationRule::Anonymous,
+            (false, OperationKind::Query) => &self.queries,
+            (false, OperationKind::Mutation) => &self.mutations,
+            (false, OperationKind::Subscription) => &OperationRule::Default,
+        };
+        Some(rule)
+    }
+}
+
+// Collects the names of the fields selected at the root of an operation, including those in fragments
+// We keep track of the fragments we've already seen so that a (invalid) cycle of fragments can't loop forever
+fn collect_root_fields<'a>(
+    doc: &'a ExecutableDocument,
+    selection_set: &'a SelectionSet,
+    fields: &mut HashSet<&'a str>,
+    seen_fragments: &mut HashSet<&'a str>,
+) {
+    for selection in &selection_set.items {
+        match &selection.node {
+            Selection::Field(field) => {
+                fields.insert(field.node.name.node.as_str());
+            }
+            Selection::InlineFragment(fragment) => collect_root_fields(
+                doc,
+                &fragment.node.selection_set.node,
+                fields,
+                seen_fragments,
+            ),
+            Selection::FragmentSpread(spread) => {
+                let name = spread.node.fragment_name.node.as_str();
+                if !seen_fragments.insert(name) {
+                    continue;
+                }
+                match doc.fragments.get(name) {
+                    Some(fragment) => collect_root_fields(
+                        doc,
+                        &fragment.node.selection_set.node,
+                        fields,
+                        seen_fragments,
+                    ),
+                    // A fragment that doesn't exist could hide anything, so we make sure the operation can't be anonymous (no field can
+                    // have this name)
+                    None => {
+                        fields.insert("...");
+                    }
+                }
+            }
+        }
+    }
+}
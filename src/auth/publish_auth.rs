@@ -0,0 +1,381 @@
+// This file contains the logic for authenticating the requests the queries/mutations system makes to publish data to the subscriptions server
+// A bearer token can be replayed by anyone who gets hold of it, so requests can instead be signed with a shared secret over their body,
+// a timestamp and a random nonce, and the subscriptions server will only accept each signature once and only while it's fresh
+// Alternatively, the publisher can mint its own short-lived tokens, so there's never a long-lived one to leak
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::core::AuthVerdict;
+use crate::auth::extraction::AuthRequestParts;
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwt::{create_jwt_with_clock, Claims, JWTSecret, RegisteredClaims, UserClaims};
+use crate::errors::*;
+
+/// The audience that publisher tokens minted with [`MintedPublishAuth`] are issued for, which the subscriptions server requires them to have.
+pub const PUBLISH_TOKEN_AUDIENCE: &str = "diana:publish";
+/// The header that signed publish requests carry their timestamp (in seconds from Unix epoch) in.
+pub const PUBLISH_TIMESTAMP_HEADER: &str = "X-Diana-Timestamp";
+/// The header that signed publish requests carry their nonce in.
+pub const PUBLISH_NONCE_HEADER: &str = "X-Diana-Nonce";
+/// The header that signed publish requests carry their signature in.
+pub const PUBLISH_SIGNATURE_HEADER: &str = "X-Diana-Signature";
+
+/// How the queries/mutations system authenticates itself to the subscriptions server when it publishes data.
+#[derive(Debug, Clone, Default)]
+pub enum PublishAuth {
+    /// A bearer JWT with the `role=graphql_server` claim, set with `.jwt_to_connect_to_subscriptions_server()`. This is the default.
+    #[default]
+    Jwt,
+    /// A bearer JWT that the publisher mints for itself from your signing key and renews before it expires. See [`MintedPublishAuth`] for
+    /// more details.
+    MintedJwt(MintedPublishAuth),
+    /// An HMAC signature over each request's body, timestamp and nonce. See [`HmacPublishAuth`] for more details.
+    Hmac(HmacPublishAuth),
+}
+
+/// The settings for the publisher minting its own short-lived JWTs instead of using one you've created. The publisher will sign a token with
+/// the `role=graphql_server` claim and the [`PUBLISH_TOKEN_AUDIENCE`] audience, cache it, and mint a new one shortly before it expires. The
+/// subscriptions server will only let tokens with that audience and a lifetime no longer than the one set here publish, so a token that's
+/// been created by hand (or stolen from somewhere else) can't be used for long. Both systems should be given the same settings.
+#[derive(Debug, Clone)]
+pub struct MintedPublishAuth {
+    signing_key: Option<Arc<JWTSecret>>,
+    registered_claims: RegisteredClaims,
+    lifetime: Duration,
+    renew_before: Duration,
+    clock: Arc<dyn Clock>,
+    // The last token that was minted and its expiry, shared between clones
+    cached_token: Arc<RwLock<Option<(String, u64)>>>,
+}
+impl Default for MintedPublishAuth {
+    fn default() -> Self {
+        Self {
+            signing_key: None,
+            registered_claims: RegisteredClaims::default(),
+            lifetime: Duration::from_secs(5 * 60),
+            renew_before: Duration::from_secs(60),
+            clock: Arc::new(SystemClock),
+            cached_token: Arc::default(),
+        }
+    }
+}
+impl MintedPublishAuth {
+    /// Creates new settings for minting publisher tokens. By default, tokens will be signed with the key configured in the
+    /// [`Options`](crate::Options), be valid for 5 minutes, and be renewed a minute before they expire.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the key that tokens will be signed with. You only need this if the key in the [`Options`](crate::Options) can't sign tokens
+    /// (e.g. if it's a JWKS), and it has to be one that the subscriptions server will accept.
+    pub fn signing_key(mut self, signing_key: JWTSecret) -> Self {
+        self.signing_key = Some(Arc::new(signing_key));
+        self
+    }
+    /// Sets the registered claims (issuer, audience, etc.) that will be put into every token. If you've limited the accepted issuers or
+    /// audiences in the [`Options`](crate::Options), you'll need to set them here. The [`PUBLISH_TOKEN_AUDIENCE`] will be added to any
+    /// audiences you give.
+    pub fn registered_claims(mut self, registered_claims: RegisteredClaims) -> Self {
+        self.registered_claims = registered_claims;
+        self
+    }
+    /// Sets how long tokens will be valid for. The subscriptions server will reject publisher tokens that are valid for any longer than this.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+    /// Sets how long before its expiry a token will be replaced with a new one. This should allow for the time requests take to arrive and
+    /// any clock skew between your systems.
+    pub fn renew_before(mut self, renew_before: Duration) -> Self {
+        self.renew_before = renew_before;
+        self
+    }
+    /// Sets the clock that tokens are minted and checked with. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Gets the key that tokens will be signed with, if one has been set.
+    pub fn get_signing_key(&self) -> Option<&JWTSecret> {
+        self.signing_key.as_deref()
+    }
+
+    /// Gets a token to publish with. This will be the cached one unless it's about to expire, in which case a new one will be minted. This
+    /// will fail if there's no signing key.
+    pub fn get_token(&self) -> Result<String> {
+        let now = self.clock.timestamp();
+        let renew_before = self.renew_before.as_secs();
+        if let Some((token, exp)) = &*self
+            .cached_token
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("publisher_token".to_string()))?
+        {
+            if now.saturating_add(renew_before) < *exp {
+                return Ok(token.clone());
+            }
+        }
+        let signing_key = self
+            .signing_key
+            .as_deref()
+            .ok_or(ErrorKind::MissingJWTSigningKey)?;
+
+        let mut registered_claims = self.registered_claims.clone();
+        registered_claims
+            .aud
+            .get_or_insert_with(Vec::new)
+            .push(PUBLISH_TOKEN_AUDIENCE.to_string());
+        // The subscriptions server measures the lifetime from this, so it has to be now
+        registered_claims.iat = Some(now);
+        let exp = now + self.lifetime.as_secs();
+        let mut claims = HashMap::new();
+        claims.insert("role", "graphql_server");
+        let token = create_jwt_with_clock(
+            claims,
+            registered_claims,
+            signing_key,
+            exp,
+            self.clock.as_ref(),
+        )?;
+        // If another request minted a token at the same time, it doesn't matter which one is kept
+        *self
+            .cached_token
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("publisher_token".to_string()))? =
+            Some((token.clone(), exp));
+
+        Ok(token)
+    }
+    /// Checks that a verdict the subscriptions server has reached on a publish request is for a token that was minted for publishing and
+    /// isn't too long-lived. Verdicts for anything other than a valid token are left alone.
+    pub fn check_verdict<T: UserClaims>(&self, verdict: AuthVerdict<T>) -> AuthVerdict<T> {
+        let claims = match &verdict {
+            AuthVerdict::Allow(AuthState::Authorised(AuthToken(claims, _))) => claims,
+            _ => return verdict,
+        };
+        if !is_publish_token(claims) {
+            return AuthVerdict::Block(AuthFailure::InvalidAudience);
+        }
+        // Without an issue datetime we can't know how long the token was made to last
+        match claims.registered.iat {
+            Some(iat) if claims.exp.saturating_sub(iat) <= self.lifetime.as_secs() => verdict,
+            _ => AuthVerdict::Block(AuthFailure::InvalidClaims),
+        }
+    }
+}
+
+// Checks whether or not the given claims are for a token that was minted for publishing
+pub(crate) fn is_publish_token<T: UserClaims>(claims: &Claims<T>) -> bool {
+    matches!(&claims.registered.aud, Some(aud) if aud.iter().any(|aud| aud == PUBLISH_TOKEN_AUDIENCE))
+}
+// Blocks verdicts for tokens minted for publishing, which are scoped to the subscriptions server's publish endpoint and mustn't be usable
+// anywhere else (they have the publisher's claims, so they'd otherwise be accepted as ordinary user tokens)
+pub(crate) fn reject_publish_tokens<T: UserClaims>(verdict: AuthVerdict<T>) -> AuthVerdict<T> {
+    match &verdict {
+        AuthVerdict::Allow(AuthState::Authorised(AuthToken(claims, _)))
+            if is_publish_token(claims) =>
+        {
+            AuthVerdict::Block(AuthFailure::InvalidAudience)
+        }
+        _ => verdict,
+    }
+}
+
+/// A store of the nonces of signed publish requests that have already been seen, so that they can't be replayed. Diana provides
+/// [`MemoryNonceStore`], but you can implement this yourself to use something like Redis.
+pub trait NonceStore: fmt::Debug + Send + Sync {
+    /// Records the given nonce, returning `false` if it had already been recorded. This must be atomic, otherwise the same request could
+    /// be accepted twice by racing it. The nonce can be forgotten after the given expiry (in seconds from Unix epoch), because requests
+    /// with it will be rejected as stale by then anyway.
+    fn insert_nonce(&self, nonce: &str, exp: u64) -> Result<bool>;
+}
+
+/// A nonce store that's held entirely in memory. Nonces won't be shared between instances, which is fine as long as you only run one
+/// subscriptions server. If you run several behind a load balancer (e.g. with `RedisPubSub`), a request that one instance has already
+/// accepted can be replayed against another, so you should give them all a shared [`NonceStore`] instead.
+#[derive(Debug)]
+pub struct MemoryNonceStore {
+    // Nonces mapped to the datetimes after which they can be forgotten
+    nonces: RwLock<HashMap<String, u64>>,
+    clock: Arc<dyn Clock>,
+}
+impl MemoryNonceStore {
+    /// Creates a new empty in-memory nonce store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the clock that's used to decide when nonces can be forgotten. This should be the same clock you give to the
+    /// [`HmacPublishAuth`]. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+impl Default for MemoryNonceStore {
+    fn default() -> Self {
+        Self {
+            nonces: RwLock::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl NonceStore for MemoryNonceStore {
+    fn insert_nonce(&self, nonce: &str, exp: u64) -> Result<bool> {
+        let mut nonces = self
+            .nonces
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("nonce_list".to_string()))?;
+        // Forget about the nonces whose requests would be rejected as stale anyway
+        let now = self.clock.timestamp();
+        nonces.retain(|_, nonce_exp| *nonce_exp >= now);
+
+        Ok(nonces.insert(nonce.to_string(), exp).is_none())
+    }
+}
+
+/// The settings for signing publish requests with HMAC-SHA256 instead of sending a bearer JWT. Both systems need the same secret (which
+/// should be different from your JWT secret): the queries/mutations system signs every request's body along with the current time and a
+/// random nonce, and the subscriptions server rejects requests whose signatures don't match, whose timestamps are too far from its own
+/// time, or whose nonces it has already seen. That means a captured request can't be replayed, and there's no long-lived token to leak.
+#[derive(Clone)]
+pub struct HmacPublishAuth {
+    secret: Vec<u8>,
+    max_age: Duration,
+    nonce_store: Arc<dyn NonceStore>,
+    clock: Arc<dyn Clock>,
+}
+// The secret shouldn't end up in any logs
+impl fmt::Debug for HmacPublishAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HmacPublishAuth")
+            .field("max_age", &self.max_age)
+            .field("nonce_store", &self.nonce_store)
+            .finish()
+    }
+}
+impl HmacPublishAuth {
+    /// Creates new HMAC settings with the given base64-encoded secret (you can generate one with `diana secret`). By default, requests
+    /// will be accepted for 60 seconds either side of the subscriptions server's time, and nonces will be remembered in memory. That
+    /// in-memory store isn't shared between instances, so if you run several subscriptions servers you should set a shared one with
+    /// `.nonce_store()`, otherwise requests can be replayed against the instances that haven't seen them.
+    pub fn new(secret: &str) -> Result<Self> {
+        let secret = base64::decode(secret).map_err(|_err| ErrorKind::InvalidPublishSecret)?;
+        if secret.is_empty() {
+            bail!(ErrorKind::InvalidPublishSecret);
+        }
+
+        Ok(Self {
+            secret,
+            max_age: Duration::from_secs(60),
+            nonce_store: Arc::new(MemoryNonceStore::new()),
+            clock: Arc::new(SystemClock),
+        })
+    }
+    /// Sets how far a request's timestamp can be from the subscriptions server's time before it's rejected. This needs to allow for clock
+    /// skew between your systems and the time requests take to arrive, but the shorter it is, the less time an attacker has.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+    /// Sets the store that the subscriptions server remembers nonces in.
+    pub fn nonce_store(mut self, nonce_store: Arc<dyn NonceStore>) -> Self {
+        self.nonce_store = nonce_store;
+        self
+    }
+    /// Sets the clock that requests are timestamped and checked with. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Computes the signature of the given body with the given timestamp and nonce
+    fn get_mac(&self, body: &str, timestamp: &str, nonce: &str) -> Hmac<Sha256> {
+        // HMAC can take a key of any length, so this can't fail
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("hmac should accept keys of any length");
+        // The timestamp is always a number and the nonce is always URL-safe base64, so neither can contain the separator
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(nonce.as_bytes());
+        mac.update(b".");
+        mac.update(body.as_bytes());
+        mac
+    }
+    /// Signs the given request body, returning the headers (names and values) that should be sent with it.
+    pub fn sign(&self, body: &str) -> Vec<(&'static str, String)> {
+        let timestamp = self.clock.timestamp().to_string();
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let nonce = base64::encode_config(nonce, base64::URL_SAFE_NO_PAD);
+        let signature = self
+            .get_mac(body, &timestamp, &nonce)
+            .finalize()
+            .into_bytes();
+
+        vec![
+            (PUBLISH_TIMESTAMP_HEADER, timestamp),
+            (PUBLISH_NONCE_HEADER, nonce),
+            (
+                PUBLISH_SIGNATURE_HEADER,
+                base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
+            ),
+        ]
+    }
+    /// Verifies the signature on the given request body, returning a verdict that allows it with the `role=graphql_server` claim if it's
+    /// valid. Your claims type must be able to hold that claim, as it must for the JWT that would otherwise be used.
+    pub fn verify<T: UserClaims>(&self, body: &str, parts: &AuthRequestParts) -> AuthVerdict<T> {
+        let (timestamp, nonce, signature) = match (
+            parts.get_header(PUBLISH_TIMESTAMP_HEADER),
+            parts.get_header(PUBLISH_NONCE_HEADER),
+            parts.get_header(PUBLISH_SIGNATURE_HEADER),
+        ) {
+            (Some(timestamp), Some(nonce), Some(signature)) => (timestamp, nonce, signature),
+            _ => return AuthVerdict::Block(AuthFailure::MissingToken),
+        };
+        let request_time = match timestamp.parse::<u64>() {
+            Ok(request_time) => request_time,
+            Err(_) => return AuthVerdict::Block(AuthFailure::Malformed),
+        };
+        let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(signature) => signature,
+            Err(_) => return AuthVerdict::Block(AuthFailure::Malformed),
+        };
+        // We check the signature before anything else so that unsigned requests can't fill up the nonce store
+        if self
+            .get_mac(body, timestamp, nonce)
+            .verify(&signature)
+            .is_err()
+        {
+            return AuthVerdict::Block(AuthFailure::InvalidSignature);
+        }
+        let now = self.clock.timestamp();
+        let max_age = self.max_age.as_secs();
+        if request_time + max_age < now {
+            return AuthVerdict::Block(AuthFailure::Expired);
+        } else if request_time > now + max_age {
+            return AuthVerdict::Block(AuthFailure::NotYetValid);
+        }
+        match self.nonce_store.insert_nonce(nonce, request_time + max_age) {
+            Ok(true) => (),
+            Ok(false) => return AuthVerdict::Block(AuthFailure::Replayed),
+            Err(err) => return AuthVerdict::Error(err.to_string()),
+        }
+
+        // The request is from the queries/mutations system, so it gets the same claims as the JWT it would otherwise have sent
+        let user_claims = serde_json::json!({ "role": "graphql_server" });
+        match serde_json::from_value::<T>(user_claims) {
+            Ok(user_claims) => AuthVerdict::Allow(AuthState::Authorised(AuthToken::from(Claims {
+                exp: request_time + max_age,
+                registered: RegisteredClaims::default(),
+                claims: user_claims,
+            }))),
+            Err(err) => AuthVerdict::Error(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,393 @@
+// This file contains the logic for refresh tokens, which let clients get new short-lived access tokens without logging in again
+// Refresh tokens are opaque random strings (not JWTs), and only their hashes are stored, so a leaked store can't be used to mint tokens
+// Every refresh token belongs to a family that starts when the user logs in, and using one rotates it to the next token in that family
+// If a refresh token that's already been used is presented again, it's been stolen (or the client has been compromised), so we revoke the family
+
+use async_graphql::{Object as GQLObject, SimpleObject as GQLSimpleObject};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::jwt::{create_jwt_with_clock, JWTSecret, RegisteredClaims, UserClaims};
+use crate::auth::revocation::RevocationStore;
+use crate::errors::*;
+
+/// A short-lived access token (a JWT) and the refresh token that can be used to get the next one.
+#[derive(GQLSimpleObject, Serialize, Deserialize, Debug, Clone)]
+pub struct TokenPair {
+    /// The access token, which should be sent as a bearer token with requests.
+    pub access_token: String,
+    /// The expiry of the access token, in seconds from Unix epoch.
+    pub access_token_exp: u64,
+    /// The refresh token, which should be stored securely by the client and exchanged for a new pair before the access token expires.
+    pub refresh_token: String,
+    /// The expiry of the refresh token, in seconds from Unix epoch.
+    pub refresh_token_exp: u64,
+}
+
+/// The record of a refresh token that's kept in a [`RefreshTokenStore`]. The token itself is never stored, only its hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RefreshTokenRecord {
+    /// The ID of the family this token belongs to. A family is started every time a user logs in.
+    pub family_id: String,
+    /// The user's claims, which will be put into every access token in the family.
+    pub claims: serde_json::Value,
+    /// The subject of the tokens in the family, usually the ID of the user.
+    pub sub: Option<String>,
+    /// The expiry of this refresh token, in seconds from Unix epoch.
+    pub exp: u64,
+    /// Whether or not this refresh token has already been exchanged for a new pair.
+    pub used: bool,
+    /// The `jti` of the access token that was issued with this refresh token, so it can be revoked along with the family.
+    pub access_token_jti: String,
+    /// The expiry of the access token that was issued with this refresh token, in seconds from Unix epoch.
+    pub access_token_exp: u64,
+}
+
+/// A store of refresh tokens. Diana provides [`MemoryRefreshTokenStore`], but you can implement this yourself to use something like a
+/// database. Tokens are identified by their hashes, so the raw tokens never reach the store.
+pub trait RefreshTokenStore: fmt::Debug + Send + Sync {
+    /// Stores the record of a new refresh token.
+    fn insert_token(&self, token_hash: &str, record: RefreshTokenRecord) -> Result<()>;
+    /// Gets the record of the given refresh token without marking it as used. This should return `None` if the token doesn't exist.
+    fn get_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>>;
+    /// Marks the given refresh token as used and returns its record as it was before (so `used` will be `true` if it had already been used).
+    /// This must be atomic, otherwise the same token could be used twice by racing requests. This should return `None` if the token doesn't
+    /// exist.
+    fn use_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>>;
+    /// Revokes every token in the given family, returning the records of all the tokens that were in it.
+    fn revoke_family(&self, family_id: &str) -> Result<Vec<RefreshTokenRecord>>;
+    /// Checks if the given family has been revoked.
+    fn is_family_revoked(&self, family_id: &str) -> Result<bool>;
+}
+
+// The state of the in-memory refresh token store
+#[derive(Debug, Default)]
+struct RefreshTokenList {
+    tokens: HashMap<String, RefreshTokenRecord>,
+    // Revoked families mapped to the latest expiry of their tokens, after which they can be forgotten
+    revoked_families: HashMap<String, u64>,
+}
+impl RefreshTokenList {
+    // Forgets about the tokens that have expired anyway (we keep used ones until then so we can still detect reuse), and the revoked
+    // families that have no tokens left that could be used
+    fn prune(&mut self, now: u64) {
+        self.tokens.retain(|_, record| record.exp >= now);
+        self.revoked_families.retain(|_, exp| *exp >= now);
+    }
+}
+
+/// A refresh token store that's held entirely in memory. Refresh tokens will be lost when the system restarts (so users will have to log
+/// in again), and won't be shared between instances, so this is best for development or single-instance servers.
+#[derive(Debug)]
+pub struct MemoryRefreshTokenStore {
+    list: RwLock<RefreshTokenList>,
+    clock: Arc<dyn Clock>,
+}
+impl MemoryRefreshTokenStore {
+    /// Creates a new empty in-memory refresh token store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the clock that's used to decide when expired tokens can be forgotten. This should be the same clock you give to the
+    /// [`RefreshTokenManager`]. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+impl Default for MemoryRefreshTokenStore {
+    fn default() -> Self {
+        Self {
+            list: RwLock::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl RefreshTokenStore for MemoryRefreshTokenStore {
+    fn insert_token(&self, token_hash: &str, record: RefreshTokenRecord) -> Result<()> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("refresh_token_list".to_string()))?;
+        list.prune(self.clock.timestamp());
+        // If the family was revoked while this token was being issued, it has to stay revoked for as long as this token is valid
+        if let Some(family_exp) = list.revoked_families.get_mut(&record.family_id) {
+            *family_exp = (*family_exp).max(record.exp);
+        }
+        list.tokens.insert(token_hash.to_string(), record);
+
+        Ok(())
+    }
+    fn get_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>> {
+        let list = self
+            .list
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("refresh_token_list".to_string()))?;
+
+        Ok(list.tokens.get(token_hash).cloned())
+    }
+    fn use_token(&self, token_hash: &str) -> Result<Option<RefreshTokenRecord>> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("refresh_token_list".to_string()))?;
+        let record = list.tokens.get_mut(token_hash).map(|record| {
+            let old_record = record.clone();
+            record.used = true;
+            old_record
+        });
+
+        Ok(record)
+    }
+    fn revoke_family(&self, family_id: &str) -> Result<Vec<RefreshTokenRecord>> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("refresh_token_list".to_string()))?;
+        let now = self.clock.timestamp();
+        list.prune(now);
+        let family_records: Vec<RefreshTokenRecord> = list
+            .tokens
+            .values()
+            .filter(|record| record.family_id == family_id)
+            .cloned()
+            .collect();
+        // The family only needs to be remembered until its last token expires
+        let family_exp = family_records
+            .iter()
+            .map(|record| record.exp)
+            .max()
+            .unwrap_or(now);
+        let revoked_exp = list
+            .revoked_families
+            .entry(family_id.to_string())
+            .or_insert(family_exp);
+        *revoked_exp = (*revoked_exp).max(family_exp);
+
+        Ok(family_records)
+    }
+    fn is_family_revoked(&self, family_id: &str) -> Result<bool> {
+        let list = self
+            .list
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("refresh_token_list".to_string()))?;
+
+        Ok(list.revoked_families.contains_key(family_id))
+    }
+}
+
+// Generates a random URL-safe string from the given number of bytes of OS randomness
+fn generate_random_string(num_bytes: usize) -> String {
+    let mut bytes = vec![0u8; num_bytes];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+// Hashes a refresh token for storage
+fn hash_token(token: &str) -> String {
+    base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// The system that issues and rotates refresh tokens. You should create one of these with the secret you use to sign access tokens and a
+/// [`RefreshTokenStore`], then give it to the [`Options`](crate::Options) if you want to use [`RefreshMutation`]. You can use it directly
+/// in your login logic to start new token families with `.issue()`.
+/// The last type parameter is the type of the user's claims, which should be the same as in your options.
+#[derive(Debug, Clone)]
+pub struct RefreshTokenManager<T: UserClaims = HashMap<String, String>> {
+    secret: JWTSecret,
+    store: Arc<dyn RefreshTokenStore>,
+    revocation_store: Option<Arc<dyn RevocationStore>>,
+    registered_claims: RegisteredClaims,
+    access_token_lifetime: Duration,
+    refresh_token_lifetime: Duration,
+    clock: Arc<dyn Clock>,
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> RefreshTokenManager<T> {
+    /// Creates a new refresh token manager with the given secret (which must be able to sign tokens) and store. By default, access tokens
+    /// will be valid for 15 minutes and refresh tokens for 30 days.
+    pub fn new(secret: JWTSecret, store: Arc<dyn RefreshTokenStore>) -> Self {
+        Self {
+            secret,
+            store,
+            revocation_store: None,
+            registered_claims: RegisteredClaims::default(),
+            access_token_lifetime: Duration::from_secs(15 * 60),
+            refresh_token_lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+            clock: Arc::new(SystemClock),
+            claims_type: PhantomData,
+        }
+    }
+    /// Sets how long access tokens will be valid for. This should be short, as access tokens can only be revoked with a revocation store.
+    pub fn access_token_lifetime(mut self, access_token_lifetime: Duration) -> Self {
+        self.access_token_lifetime = access_token_lifetime;
+        self
+    }
+    /// Sets how long refresh tokens will be valid for. Every refresh produces a new refresh token with this lifetime, so this is effectively
+    /// how long a user can go without using your app before they have to log in again.
+    pub fn refresh_token_lifetime(mut self, refresh_token_lifetime: Duration) -> Self {
+        self.refresh_token_lifetime = refresh_token_lifetime;
+        self
+    }
+    /// Sets a revocation store that the access tokens of a family will be revoked in when the family is revoked. This should be the same
+    /// store that you give to the [`Options`](crate::Options). Without this, access tokens from a revoked family will be valid until they expire.
+    pub fn revocation_store(mut self, revocation_store: Arc<dyn RevocationStore>) -> Self {
+        self.revocation_store = Some(revocation_store);
+        self
+    }
+    /// Sets the registered claims (issuer, audience, etc.) that will be put in every access token. Any `sub`, `jti` or `iat` set here will be
+    /// overridden.
+    pub fn registered_claims(mut self, registered_claims: RegisteredClaims) -> Self {
+        self.registered_claims = registered_claims;
+        self
+    }
+    /// Sets the clock that tokens will be issued and checked against. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Starts a new token family for the given claims and subject (usually the ID of the user) and returns its first pair of tokens. This
+    /// should be called when a user logs in.
+    pub fn issue(&self, user_claims: T, sub: Option<&str>) -> Result<TokenPair> {
+        let family_id = generate_random_string(16);
+        self.issue_in_family(
+            family_id,
+            serde_json::to_value(user_claims)?,
+            sub.map(|sub| sub.to_string()),
+        )
+    }
+    /// Exchanges the given refresh token for a new pair of tokens. The given refresh token can't be used again after this, and if it is,
+    /// the whole family will be revoked and an error will be returned. This will also fail if the refresh token is unknown or has expired,
+    /// or if its family has been revoked.
+    pub fn refresh(&self, refresh_token: &str) -> Result<TokenPair> {
+        let token_hash = hash_token(refresh_token);
+        // Tokens that couldn't be used anyway mustn't be marked as used, otherwise presenting one twice would look like reuse
+        let record = self
+            .store
+            .get_token(&token_hash)?
+            .ok_or(ErrorKind::InvalidRefreshToken)?;
+        let now = self.clock.timestamp();
+        if record.exp < now || self.store.is_family_revoked(&record.family_id)? {
+            bail!(ErrorKind::InvalidRefreshToken);
+        }
+        let record = self
+            .store
+            .use_token(&token_hash)?
+            .ok_or(ErrorKind::InvalidRefreshToken)?;
+        if record.used {
+            // This token has already been rotated, so someone other than the legitimate client has it
+            self.revoke_family(&record.family_id)?;
+            bail!(ErrorKind::RefreshTokenReused);
+        }
+
+        self.issue_in_family(record.family_id, record.claims, record.sub)
+    }
+    /// Revokes the family that the given refresh token belongs to, which should be done when a user logs out. Every refresh token in the
+    /// family will stop working, and so will its access tokens if there's a revocation store.
+    pub fn revoke(&self, refresh_token: &str) -> Result<()> {
+        let record = self
+            .store
+            .use_token(&hash_token(refresh_token))?
+            .ok_or(ErrorKind::InvalidRefreshToken)?;
+
+        self.revoke_family(&record.family_id)
+    }
+
+    // Creates the next pair of tokens in the given family
+    fn issue_in_family(
+        &self,
+        family_id: String,
+        claims: serde_json::Value,
+        sub: Option<String>,
+    ) -> Result<TokenPair> {
+        let now = self.clock.timestamp();
+        let access_token_exp = now + self.access_token_lifetime.as_secs();
+        let refresh_token_exp = now + self.refresh_token_lifetime.as_secs();
+        let access_token_jti = generate_random_string(16);
+
+        // We go through the user's claims type to make sure the claims still fit it
+        let user_claims: T = serde_json::from_value(claims.clone())?;
+        let registered_claims = RegisteredClaims {
+            sub: sub.clone(),
+            jti: Some(access_token_jti.clone()),
+            iat: Some(now),
+            ..self.registered_claims.clone()
+        };
+        let access_token = create_jwt_with_clock(
+            user_claims,
+            registered_claims,
+            &self.secret,
+            access_token_exp,
+            self.clock.as_ref(),
+        )?;
+
+        let refresh_token = generate_random_string(32);
+        self.store.insert_token(
+            &hash_token(&refresh_token),
+            RefreshTokenRecord {
+                family_id,
+                claims,
+                sub,
+                exp: refresh_token_exp,
+                used: false,
+                access_token_jti,
+                access_token_exp,
+            },
+        )?;
+
+        Ok(TokenPair {
+            access_token,
+            access_token_exp,
+            refresh_token,
+            refresh_token_exp,
+        })
+    }
+    // Revokes a family and all the access tokens issued in it
+    fn revoke_family(&self, family_id: &str) -> Result<()> {
+        let family_records = self.store.revoke_family(family_id)?;
+        if let Some(revocation_store) = &self.revocation_store {
+            for record in family_records {
+                revocation_store.revoke_token(&record.access_token_jti, record.access_token_exp)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A GraphQL mutation root that lets clients exchange refresh tokens for new pairs of tokens. You can merge this into your own mutation root
+/// with `async_graphql::MergedObject`, and you'll need to give a [`RefreshTokenManager`] to the [`Options`](crate::Options) for it to work.
+/// The type parameter is the type of the user's claims, which should be the same as in your options.
+#[derive(Clone)]
+pub struct RefreshMutation<T: UserClaims = HashMap<String, String>> {
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> Default for RefreshMutation<T> {
+    fn default() -> Self {
+        Self {
+            claims_type: PhantomData,
+        }
+    }
+}
+#[GQLObject]
+impl<T: UserClaims> RefreshMutation<T> {
+    /// Exchanges a refresh token for a new access token and refresh token. The given refresh token can't be used again after this.
+    async fn refresh_token(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        refresh_token: String,
+    ) -> Result<TokenPair> {
+        let refresh_token_manager = raw_ctx.data::<RefreshTokenManager<T>>().map_err(|_err| {
+            ErrorKind::GraphQLContextNotFound("refresh_token_manager".to_string())
+        })?;
+
+        refresh_token_manager.refresh(&refresh_token)
+    }
+}
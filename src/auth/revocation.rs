@@ -0,0 +1,211 @@
+// This file contains the logic for revoking JWTs before they expire
+// Diana's authentication is otherwise stateless, so this is the only way to invalidate a leaked token or log a user out everywhere
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::jwt::Claims;
+use crate::errors::*;
+
+/// A store of revoked JWTs. Tokens can be revoked individually by their `jti` claim, or all the tokens of a user (identified by the `sub`
+/// claim) issued before a certain datetime can be revoked at once (e.g. when they log out everywhere or change their password).
+/// Diana provides [`MemoryRevocationStore`] and [`FileRevocationStore`], but you can implement this yourself to use something like a database.
+pub trait RevocationStore: fmt::Debug + Send + Sync {
+    /// Revokes the token with the given `jti`. The token's expiry is also given so that the revocation can be forgotten once the token
+    /// would have expired anyway.
+    fn revoke_token(&self, jti: &str, exp: u64) -> Result<()>;
+    /// Revokes all the tokens of the given user (their `sub` claim) that were issued before the given datetime (in seconds from Unix epoch).
+    fn revoke_user_tokens_issued_before(&self, sub: &str, issued_before: u64) -> Result<()>;
+    /// Checks if the token with the given `jti` has been revoked.
+    fn is_token_revoked(&self, jti: &str) -> Result<bool>;
+    /// Gets the datetime before which all tokens issued to the given user are revoked, if there is one.
+    fn get_user_revocation_time(&self, sub: &str) -> Result<Option<u64>>;
+}
+
+/// Checks if the given claims have been revoked in the given store, either directly by their `jti` or through their user (`sub`). If a
+/// user's tokens have been revoked and the token has no `iat` claim, it will be considered revoked, because we can't know when it was issued.
+pub fn is_revoked<T>(store: &dyn RevocationStore, claims: &Claims<T>) -> Result<bool> {
+    if let Some(jti) = &claims.registered.jti {
+        if store.is_token_revoked(jti)? {
+            return Ok(true);
+        }
+    }
+    if let Some(sub) = &claims.registered.sub {
+        if let Some(revocation_time) = store.get_user_revocation_time(sub)? {
+            return Ok(match claims.registered.iat {
+                Some(iat) => iat < revocation_time,
+                None => true,
+            });
+        }
+    }
+
+    Ok(false)
+}
+
+// The actual list of revocations, which is shared between the in-memory and file-backed stores
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct RevocationList {
+    // Revoked `jti`s mapped to the expiries of their tokens
+    tokens: HashMap<String, u64>,
+    // Users mapped to the datetimes before which their tokens are revoked
+    users: HashMap<String, u64>,
+}
+impl RevocationList {
+    // Forgets about the revoked tokens that have expired anyway
+    fn prune(&mut self, now: u64) {
+        self.tokens.retain(|_, exp| *exp >= now);
+    }
+    fn revoke_user_tokens_issued_before(&mut self, sub: &str, issued_before: u64) {
+        // We never bring the revocation time forward, as that would un-revoke tokens
+        let revocation_time = self.users.entry(sub.to_string()).or_insert(issued_before);
+        if issued_before > *revocation_time {
+            *revocation_time = issued_before;
+        }
+    }
+}
+
+/// A revocation store that's held entirely in memory. Revocations will be lost when the system restarts, and won't be shared between
+/// instances, so this is best for development or single-instance servers.
+#[derive(Debug)]
+pub struct MemoryRevocationStore {
+    list: RwLock<RevocationList>,
+    clock: Arc<dyn Clock>,
+}
+impl MemoryRevocationStore {
+    /// Creates a new empty in-memory revocation store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the clock that's used to decide when revoked tokens have expired and can be forgotten. This should be the same clock that
+    /// tokens are validated with. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+impl Default for MemoryRevocationStore {
+    fn default() -> Self {
+        Self {
+            list: RwLock::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+impl RevocationStore for MemoryRevocationStore {
+    fn revoke_token(&self, jti: &str, exp: u64) -> Result<()> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+        list.prune(self.clock.timestamp());
+        list.tokens.insert(jti.to_string(), exp);
+
+        Ok(())
+    }
+    fn revoke_user_tokens_issued_before(&self, sub: &str, issued_before: u64) -> Result<()> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+        list.revoke_user_tokens_issued_before(sub, issued_before);
+
+        Ok(())
+    }
+    fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        let list = self
+            .list
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+
+        Ok(list.tokens.contains_key(jti))
+    }
+    fn get_user_revocation_time(&self, sub: &str) -> Result<Option<u64>> {
+        let list = self
+            .list
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+
+        Ok(list.users.get(sub).copied())
+    }
+}
+
+/// A revocation store that's backed by a JSON file, so revocations will survive restarts. The file is read once when the store is created
+/// and then written to on every revocation, so lookups don't touch the filesystem. This won't pick up changes made to the file by other
+/// processes, so you should implement [`RevocationStore`] yourself with a database if you have several instances.
+#[derive(Debug)]
+pub struct FileRevocationStore {
+    path: PathBuf,
+    list: RwLock<RevocationList>,
+    clock: Arc<dyn Clock>,
+}
+impl FileRevocationStore {
+    /// Creates a new file-backed revocation store, reading any existing revocations from the given file. The file will be created on the
+    /// first revocation if it doesn't exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let list = match path.exists() {
+            true => serde_json::from_str(&fs::read_to_string(&path)?)?,
+            false => RevocationList::default(),
+        };
+
+        Ok(Self {
+            path,
+            list: RwLock::new(list),
+            clock: Arc::new(SystemClock),
+        })
+    }
+    /// Sets the clock that's used to decide when revoked tokens have expired and can be forgotten. This should be the same clock that
+    /// tokens are validated with. This is the system clock by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    // Writes the given list to the file, which should be done while the write lock is still held
+    fn persist(&self, list: &RevocationList) -> Result<()> {
+        fs::write(&self.path, serde_json::to_string(list)?)?;
+
+        Ok(())
+    }
+}
+impl RevocationStore for FileRevocationStore {
+    fn revoke_token(&self, jti: &str, exp: u64) -> Result<()> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+        list.prune(self.clock.timestamp());
+        list.tokens.insert(jti.to_string(), exp);
+
+        self.persist(&list)
+    }
+    fn revoke_user_tokens_issued_before(&self, sub: &str, issued_before: u64) -> Result<()> {
+        let mut list = self
+            .list
+            .write()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+        list.revoke_user_tokens_issued_before(sub, issued_before);
+
+        self.persist(&list)
+    }
+    fn is_token_revoked(&self, jti: &str) -> Result<bool> {
+        let list = self
+            .list
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+
+        Ok(list.tokens.contains_key(jti))
+    }
+    fn get_user_revocation_time(&self, sub: &str) -> Result<Option<u64>> {
+        let list = self
+            .list
+            .read()
+            .map_err(|_err| ErrorKind::MutexPoisoned("revocation_list".to_string()))?;
+
+        Ok(list.users.get(sub).copied())
+    }
+}
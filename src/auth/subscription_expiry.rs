@@ -0,0 +1,211 @@
+// This file contains the logic for ending subscriptions when the token their connection was authenticated with expires
+// WebSocket connections can last far longer than any token, so without this a client could keep receiving data long after it should have
+// had to log in again (or after its token had been revoked and had expired)
+
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextSubscribe};
+use async_graphql::futures_util::stream::BoxStream;
+use async_graphql::{Response, ServerError};
+use async_trait::async_trait;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
+
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::authenticator::Authenticator;
+use crate::auth::clock::Clock;
+use crate::auth::extraction::TokenExtractor;
+use crate::auth::failure::AuthFailure;
+use crate::auth::jwt::{Claims, UserClaims};
+use crate::auth::publish_auth::is_publish_token;
+use crate::errors::*;
+
+// Timers this long are never needed (tokens don't last anywhere near this long between refreshes), and tokens without an expiry (like API
+// keys) would otherwise overflow the timer
+const MAX_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What the subscriptions server does when the token a WebSocket connection was authenticated with expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubscriptionExpiry {
+    /// Subscriptions will carry on until the client ends them, no matter how long ago the token expired. This is how Diana behaved before
+    /// expiries were tracked.
+    Ignore,
+    /// Every subscription on the connection will be ended with an error when the token expires. This is the default.
+    #[default]
+    Terminate,
+    /// Clients can send a new token with the `refreshSubscriptionToken` mutation over the same connection before the old one expires, and
+    /// their subscriptions will carry on until the new one expires. The new token must be for the same subject (`sub`) as the old one, so
+    /// connections authenticated with tokens that don't have a subject can't be refreshed. Subscriptions will be ended with an error if the
+    /// token expires without being refreshed.
+    AllowRefresh,
+}
+
+/// The expiry of the token that a WebSocket connection to the subscriptions server was authenticated with. This is created by
+/// [`DianaHandler`](crate::DianaHandler) and put into the context of every subscription on the connection, you should only need to touch it
+/// if you're building a custom integration.
+#[derive(Clone)]
+pub struct ConnectionExpiry<T: UserClaims> {
+    policy: SubscriptionExpiry,
+    subject: Option<String>,
+    // This is shared with every stream on the connection, so that they're told about refreshes
+    exp: Arc<watch::Sender<u64>>,
+    exp_receiver: watch::Receiver<u64>,
+    authenticator: Arc<dyn Authenticator<T>>,
+    token_extractor: TokenExtractor,
+    clock: Arc<dyn Clock>,
+}
+impl<T: UserClaims> fmt::Debug for ConnectionExpiry<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionExpiry")
+            .field("policy", &self.policy)
+            .field("subject", &self.subject)
+            .field("exp", &self.get_exp())
+            .finish()
+    }
+}
+impl<T: UserClaims> ConnectionExpiry<T> {
+    /// Creates a new expiry tracker for a connection authenticated with the given claims. Refreshed tokens will be checked with the given
+    /// authenticator, and the given clock will be used to work out when the token expires (this should be the one the token was validated
+    /// with).
+    pub fn new(
+        policy: SubscriptionExpiry,
+        claims: &Claims<T>,
+        authenticator: Arc<dyn Authenticator<T>>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let (exp, exp_receiver) = watch::channel(claims.exp);
+        Self {
+            policy,
+            subject: claims.registered.sub.clone(),
+            exp: Arc::new(exp),
+            exp_receiver,
+            authenticator,
+            token_extractor: TokenExtractor::default(),
+            clock,
+        }
+    }
+    /// Sets where the authenticator looks for tokens, so that refreshed tokens can be given to it in the same place as the original one. By
+    /// default, they'll be given in the `Authorization` header with the `Bearer` scheme.
+    pub fn token_extractor(mut self, token_extractor: TokenExtractor) -> Self {
+        self.token_extractor = token_extractor;
+        self
+    }
+    /// Gets the current expiry of the connection, in seconds from Unix epoch.
+    pub fn get_exp(&self) -> u64 {
+        *self.exp_receiver.borrow()
+    }
+    /// Replaces the connection's token with the given one, returning its expiry. This will fail if refreshing isn't allowed, if the token
+    /// isn't valid, or if it's for a different subject than the connection's original token (or that token didn't have a subject). The claims
+    /// the connection's subscriptions were started with won't change.
+    pub async fn refresh(&self, token: &str) -> Result<u64> {
+        if self.policy != SubscriptionExpiry::AllowRefresh {
+            bail!(ErrorKind::SubscriptionRefreshNotAllowed);
+        }
+        // Without a subject, we'd have no way of telling whose token this is
+        let subject = match &self.subject {
+            Some(subject) => subject,
+            None => bail!(ErrorKind::InvalidConnectionToken(
+                "The connection's original token has no subject, so it can't be refreshed"
+                    .to_string()
+            )),
+        };
+        let parts = self.token_extractor.parts_with_token(token);
+        let claims = match self.authenticator.authenticate(&parts).await? {
+            AuthState::Authorised(AuthToken(claims, _)) => claims,
+            AuthState::InvalidToken(failure) => bail!(ErrorKind::InvalidConnectionToken(
+                failure.description().to_string()
+            )),
+            AuthState::RevokedToken => bail!(ErrorKind::InvalidConnectionToken(
+                AuthFailure::Revoked.description().to_string()
+            )),
+            AuthState::NoToken => bail!(ErrorKind::InvalidConnectionToken(
+                AuthFailure::MissingToken.description().to_string()
+            )),
+        };
+        // Tokens minted for publishing can't be used for anything else
+        if is_publish_token(&claims) {
+            bail!(ErrorKind::InvalidConnectionToken(
+                AuthFailure::InvalidAudience.description().to_string()
+            ));
+        }
+        // Otherwise anyone with a token could take over someone else's subscriptions
+        if claims.registered.sub.as_ref() != Some(subject) {
+            bail!(ErrorKind::InvalidConnectionToken(
+                "The token is for a different subject".to_string()
+            ));
+        }
+        self.exp.send_replace(claims.exp);
+
+        Ok(claims.exp)
+    }
+    /// Wraps the given stream of subscription responses so that it ends with an error when the connection's token expires. This does
+    /// nothing if expiries are being ignored.
+    pub fn guard_stream<'s>(&self, mut stream: BoxStream<'s, Response>) -> BoxStream<'s, Response> {
+        if self.policy == SubscriptionExpiry::Ignore {
+            return stream;
+        }
+        // The stream holds onto the sender so that it can always be told about refreshes
+        let exp = self.exp.clone();
+        let mut exp_receiver = self.exp_receiver.clone();
+        let clock = self.clock.clone();
+
+        Box::pin(async_stream::stream! {
+            let _exp = exp;
+            loop {
+                let now = clock.timestamp();
+                let current_exp = *exp_receiver.borrow();
+                if current_exp < now {
+                    yield Response::from_errors(vec![ServerError::new(AuthFailure::Expired.description(), None)]);
+                    break;
+                }
+                // We wake up a second after the expiry, because tokens are still valid during their `exp` second
+                let remaining = Duration::from_secs(current_exp.saturating_sub(now).saturating_add(1)).min(MAX_EXPIRY_CHECK_INTERVAL);
+                tokio::select! {
+                    item = stream.next() => match item {
+                        Some(item) => yield item,
+                        None => break,
+                    },
+                    // Either way, we go back around and check the expiry again
+                    _ = tokio::time::sleep(remaining) => (),
+                    _ = exp_receiver.changed() => (),
+                }
+            }
+        })
+    }
+}
+
+/// The `async_graphql` extension that makes subscriptions end when their connection's token expires. This is added to the schema for the
+/// subscriptions server automatically.
+pub struct SubscriptionExpiryExtension<T: UserClaims> {
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> Default for SubscriptionExpiryExtension<T> {
+    fn default() -> Self {
+        Self {
+            claims_type: PhantomData,
+        }
+    }
+}
+impl<T: UserClaims> ExtensionFactory for SubscriptionExpiryExtension<T> {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(Self::default())
+    }
+}
+#[async_trait]
+impl<T: UserClaims> Extension for SubscriptionExpiryExtension<T> {
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        let stream = next.run(ctx, stream);
+        // Connections that weren't authenticated with a token have nothing to expire
+        match ctx.data_opt::<ConnectionExpiry<T>>() {
+            Some(connection_expiry) => connection_expiry.guard_stream(stream),
+            None => stream,
+        }
+    }
+}
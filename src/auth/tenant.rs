@@ -0,0 +1,242 @@
+// This file contains the logic for hosting several tenants on one deployment, each with their own keys, block level and context data
+// The tenant is resolved from each request before it's authenticated, so that we know which keys to verify its token with
+
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::auth::core::AuthBlockLevel;
+use crate::auth::extraction::{AuthRequestParts, TokenExtraction, TokenExtractor};
+use crate::auth::jwks::JWTKeySource;
+use crate::auth::jwt::{Claims, JWTKeyConfig, JWTKeyMaterial, JWTValidation};
+
+/// The places a request's tenant can be found. A [`TenantResolver`] will try each of its sources in order until one of them names a
+/// tenant it knows about.
+#[derive(Debug, Clone)]
+pub enum TenantSource {
+    /// The `Host` header, which is matched against the hosts of each tenant (set with `TenantConfig::hosts()`). Any port is ignored.
+    /// Tenants resolved like this should have their own keys, because tokens verified with the keys in the options can't be told apart
+    /// unless they carry a tenant claim that's also a source (see [`TenantSource::UnverifiedClaim`]).
+    Host,
+    /// The first segment of the request's path (e.g. `acme` in `/acme/graphql`), which is matched against the IDs of the tenants.
+    /// Integrations must add the path to the [`AuthRequestParts`] for this to work (the inbuilt ones do). Like with `Host`, tenants
+    /// resolved like this should have their own keys.
+    PathPrefix,
+    /// The given claim (usually `tid`) in the request's JWT, which is matched against the IDs of the tenants. This can be a registered
+    /// claim or one of the user's claims. It's read *before* the token is verified (it's needed to know which key to verify it with), so a
+    /// token that lies about its tenant will only fail verification if that tenant has its own keys. To catch the rest, once a token has
+    /// been verified, this claim is checked again, and the token is rejected with
+    /// [`AuthFailure::InvalidClaims`](crate::AuthFailure::InvalidClaims) if it names a different tenant to the one the request was resolved
+    /// to (whichever source that came from). Tokens without this claim aren't affected.
+    UnverifiedClaim(String),
+}
+
+/// The configuration of a single tenant. Anything that isn't set here will fall back to the [`Options`](crate::Options).
+#[derive(Clone, Default)]
+pub struct TenantConfig {
+    hosts: Vec<String>,
+    jwt_key_source: Option<JWTKeySource>,
+    jwt_validation: Option<JWTValidation>,
+    authentication_block_state: Option<AuthBlockLevel>,
+    data: Option<Arc<dyn Any + Send + Sync>>,
+}
+impl fmt::Debug for TenantConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TenantConfig")
+            .field("hosts", &self.hosts)
+            .field("jwt_key_source", &self.jwt_key_source)
+            .field("jwt_validation", &self.jwt_validation)
+            .field(
+                "authentication_block_state",
+                &self.authentication_block_state,
+            )
+            .field("has_data", &self.data.is_some())
+            .finish()
+    }
+}
+impl TenantConfig {
+    /// Creates a new tenant configuration that uses everything from the options. You'll need to then call the other methods to set it up.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Defines the hosts that this tenant is served on, for [`TenantSource::Host`]. These are compared case-insensitively.
+    pub fn hosts(mut self, hosts: &[&str]) -> Self {
+        self.hosts = hosts.iter().map(|host| host.to_lowercase()).collect();
+        self
+    }
+    /// Defines this tenant's base64-encoded JWT secret, which will be used with `HS512`. For anything else, use `.jwt_key_source()`.
+    pub fn jwt_secret(self, jwt_secret: &str) -> Self {
+        self.jwt_key_source(JWTKeySource::Config(JWTKeyConfig {
+            algorithm: jsonwebtoken::Algorithm::HS512,
+            verification_key: JWTKeyMaterial::Base64Secret(jwt_secret.to_string()),
+            signing_key: None,
+        }))
+    }
+    /// Defines where the keys used to verify this tenant's JWTs come from.
+    pub fn jwt_key_source(mut self, jwt_key_source: JWTKeySource) -> Self {
+        self.jwt_key_source = Some(jwt_key_source);
+        self
+    }
+    /// Defines how this tenant's JWTs are validated (e.g. their accepted issuers and audiences).
+    pub fn jwt_validation(mut self, jwt_validation: JWTValidation) -> Self {
+        self.jwt_validation = Some(jwt_validation);
+        self
+    }
+    /// Defines the blocklevel for this tenant's requests. See [`AuthBlockLevel`] for more details.
+    pub fn auth_block_state(mut self, authentication_block_state: AuthBlockLevel) -> Self {
+        self.authentication_block_state = Some(authentication_block_state);
+        self
+    }
+    /// Defines data specific to this tenant (e.g. its database pool), which resolvers can get from the [`Tenant`] with `.data()`.
+    pub fn data<D: Any + Send + Sync>(mut self, data: D) -> Self {
+        self.data = Some(Arc::new(data));
+        self
+    }
+    /// Gets where the keys used to verify this tenant's JWTs come from, if it has its own.
+    pub fn get_jwt_key_source(&self) -> Option<&JWTKeySource> {
+        self.jwt_key_source.as_ref()
+    }
+    /// Gets how this tenant's JWTs are validated, if it's different from the options.
+    pub fn get_jwt_validation(&self) -> Option<&JWTValidation> {
+        self.jwt_validation.as_ref()
+    }
+    /// Gets the blocklevel for this tenant, if it's different from the options.
+    pub fn get_auth_block_state(&self) -> Option<AuthBlockLevel> {
+        self.authentication_block_state
+    }
+}
+
+/// A tenant that a request has been resolved to, which resolvers can get from the [`AuthState`](crate::AuthState) with `.tenant()`, or
+/// from the context of any request with `ctx.data::<Tenant>()`.
+#[derive(Clone)]
+pub struct Tenant {
+    id: String,
+    data: Option<Arc<dyn Any + Send + Sync>>,
+}
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tenant").field("id", &self.id).finish()
+    }
+}
+impl Tenant {
+    /// Creates a new tenant with the given ID and no data. You should only need this if you're writing a custom authenticator.
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            data: None,
+        }
+    }
+    /// Gets the ID of this tenant.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// Gets the data specific to this tenant, if it was set and is of the given type.
+    pub fn data<D: Any + Send + Sync>(&self) -> Option<&D> {
+        self.data.as_ref()?.downcast_ref::<D>()
+    }
+}
+
+/// The system that works out which tenant a request is for. If it can't find a tenant, the request will be handled with the
+/// [`Options`](crate::Options) as if there were no tenants at all.
+#[derive(Debug, Clone, Default)]
+pub struct TenantResolver {
+    sources: Vec<TenantSource>,
+    tenants: HashMap<String, TenantConfig>,
+}
+impl TenantResolver {
+    /// Creates a new resolver with no sources or tenants. You'll need to then call the other methods to set it up.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a place to look for the tenant. Sources are tried in the order they're added.
+    pub fn source(mut self, source: TenantSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+    /// Adds a tenant with the given ID.
+    pub fn tenant(mut self, id: &str, config: TenantConfig) -> Self {
+        self.tenants.insert(id.to_string(), config);
+        self
+    }
+    /// Gets the configurations of all the tenants, by their IDs.
+    pub fn get_tenants(&self) -> &HashMap<String, TenantConfig> {
+        &self.tenants
+    }
+    /// Works out which tenant the given request is for, returning its ID and configuration. The token extractor is used to find the token
+    /// for [`TenantSource::UnverifiedClaim`].
+    pub fn resolve<'a>(
+        &'a self,
+        parts: &AuthRequestParts,
+        token_extractor: &TokenExtractor,
+    ) -> Option<(&'a str, &'a TenantConfig)> {
+        self.sources.iter().find_map(|source| {
+            let id = match source {
+                TenantSource::Host => {
+                    let host = parts.get_header("host")?;
+                    // Strip the port, but be careful of IPv6 addresses, which are bracketed
+                    let host = match host.rsplit_once(':') {
+                        Some((host, port)) if !port.contains(']') => host,
+                        _ => host,
+                    }
+                    .to_lowercase();
+                    self.tenants
+                        .iter()
+                        .find(|(_, config)| config.hosts.contains(&host))
+                        .map(|(id, _)| id.to_string())?
+                }
+                TenantSource::PathPrefix => parts
+                    .get_path()?
+                    .split('/')
+                    .find(|segment| !segment.is_empty())?
+                    .to_string(),
+                TenantSource::UnverifiedClaim(claim) => match token_extractor.extract(parts) {
+                    TokenExtraction::Found(token) => get_unverified_claim(&token, claim)?,
+                    _ => return None,
+                },
+            };
+            self.tenants
+                .get_key_value(&id)
+                .map(|(id, config)| (id.as_str(), config))
+        })
+    }
+    /// Checks that the given verified claims don't name a different tenant to the given one in any of the claims used by
+    /// [`TenantSource::UnverifiedClaim`] sources. Claims that don't name a tenant at all will pass.
+    pub fn claims_match_tenant<T: Serialize>(&self, tenant_id: &str, claims: &Claims<T>) -> bool {
+        let claims = match serde_json::to_value(claims) {
+            Ok(claims) => claims,
+            Err(_) => return false,
+        };
+        self.sources.iter().all(|source| match source {
+            TenantSource::UnverifiedClaim(claim) => {
+                !matches!(get_claim(&claims, claim), Some(claimed_id) if claimed_id != tenant_id)
+            }
+            _ => true,
+        })
+    }
+    /// Creates the [`Tenant`] that resolvers will see for the tenant with the given ID, if it exists.
+    pub fn get_tenant(&self, id: &str) -> Option<Tenant> {
+        self.tenants.get(id).map(|config| Tenant {
+            id: id.to_string(),
+            data: config.data.clone(),
+        })
+    }
+}
+
+// Gets a string claim from a JWT's payload without verifying it
+// This must never be trusted for anything other than choosing how to verify the token
+fn get_unverified_claim(jwt: &str, claim: &str) -> Option<String> {
+    let payload = jwt.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let payload: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    get_claim(&payload, claim).map(|value| value.to_string())
+}
+// Gets a string claim from the payload of a JWT
+fn get_claim<'a>(payload: &'a serde_json::Value, claim: &str) -> Option<&'a str> {
+    // Diana puts users' claims in their own object, but identity providers put them at the top level
+    payload
+        .get(claim)
+        .or_else(|| payload.get("claims")?.get(claim))?
+        .as_str()
+}
@@ -0,0 +1,6 @@
+// The `diana` command-line tool, which creates secrets and tokens for any Diana system
+// If you want to print your schema too, call `diana::cli::run_with_options()` from a binary in your own project
+
+fn main() {
+    diana::cli::run()
+}
@@ -0,0 +1,217 @@
+// This file contains the logic for the `diana` command-line tool, which is only compiled with the `cli` feature
+// The binary itself can't know about the user's schema, so everything here is also usable from the user's own binary with their options
+
+use async_graphql::{ObjectType, Schema, SubscriptionType};
+use chrono::{TimeZone, Utc};
+use rand::{rngs::OsRng, RngCore};
+use std::any::Any;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use structopt::StructOpt;
+
+use crate::auth::clock::{Clock, SystemClock};
+use crate::auth::jwt::{
+    create_jwt_with_registered_claims, decode_time_str, get_jwt_secret,
+    validate_and_decode_jwt_with_reason, Claims, JWTValidation, RegisteredClaims, UserClaims,
+};
+use crate::errors::*;
+use crate::options::Options;
+
+/// The environment variable the secret will be read from if it isn't given with `--secret`.
+pub const JWT_SECRET_ENV_VAR: &str = "JWT_SECRET";
+
+/// The commands of the `diana` command-line tool. You should only need this if you want to parse and run commands yourself, otherwise
+/// [`run`] and [`run_with_options`] will do everything for you.
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "diana",
+    about = "Creates secrets and tokens for Diana systems, and prints their schemas."
+)]
+pub enum Command {
+    /// Generates a random base64-encoded secret for signing JWTs with HS512.
+    Secret {
+        /// The number of random bytes in the secret (HS512 should have at least 64).
+        #[structopt(long, default_value = "64")]
+        bytes: usize,
+    },
+    /// Creates a JWT signed with a secret. Use `--publisher` to create the token that the serverless system connects to the subscriptions
+    /// server with.
+    Token {
+        /// The base64-encoded secret to sign the token with. If this isn't given, it will be read from the `JWT_SECRET` environment variable.
+        #[structopt(long)]
+        secret: Option<String>,
+        /// A claim to put in the token, of the form `key=value`. This can be given more than once.
+        #[structopt(short, long = "claim", parse(try_from_str = parse_claim))]
+        claims: Vec<(String, String)>,
+        /// Adds the `role=graphql_server` claim, which is needed to publish to the subscriptions server. This is only for the default
+        /// `PublishAuth::Jwt` (the token you give to `.jwt_to_connect_to_subscriptions_server()`). With `PublishAuth::MintedJwt`, the
+        /// publisher mints its own tokens, and the subscriptions server will reject this one.
+        #[structopt(long)]
+        publisher: bool,
+        /// How long the token will be valid for (e.g. `1w`, `1h30m` or `P1D`).
+        #[structopt(long, default_value = "1h")]
+        exp: String,
+        /// The subject of the token (`sub`), usually the ID of the user it's for.
+        #[structopt(long)]
+        sub: Option<String>,
+        /// The issuer of the token (`iss`).
+        #[structopt(long)]
+        iss: Option<String>,
+    },
+    /// Verifies a JWT against a secret, and shows its claims and expiry if it's valid.
+    Decode {
+        /// The JWT to verify.
+        token: String,
+        /// The base64-encoded secret the token should have been signed with. If this isn't given, it will be read from the `JWT_SECRET`
+        /// environment variable.
+        #[structopt(long)]
+        secret: Option<String>,
+    },
+    /// Prints the SDL of the schema. This only works from a binary that calls `diana::cli::run_with_options()` with your options.
+    Sdl,
+}
+impl Command {
+    /// Parses a command from the given arguments (the first of which should be the name of the binary). Asking for help will return an
+    /// error with the help text.
+    pub fn parse_from<I>(args: I) -> Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        Self::from_iter_safe(args).map_err(|err| ErrorKind::InvalidCliArguments(err.message).into())
+    }
+    /// Runs the command, returning what should be printed. The SDL of the schema should be given if there is one, otherwise `sdl` will
+    /// fail.
+    pub fn execute(self, sdl: Option<String>) -> Result<String> {
+        match self {
+            Self::Secret { bytes } => {
+                let mut secret = vec![0u8; bytes];
+                OsRng.fill_bytes(&mut secret);
+                Ok(base64::encode(secret))
+            }
+            Self::Token {
+                secret,
+                claims,
+                publisher,
+                exp,
+                sub,
+                iss,
+            } => {
+                let secret = get_jwt_secret(get_secret_str(secret)?)?;
+                let mut claims: HashMap<String, String> = claims.into_iter().collect();
+                if publisher {
+                    claims.insert("role".to_string(), "graphql_server".to_string());
+                }
+                let registered_claims = RegisteredClaims {
+                    sub,
+                    iss,
+                    ..RegisteredClaims::default()
+                };
+                create_jwt_with_registered_claims(
+                    claims,
+                    registered_claims,
+                    &secret,
+                    decode_time_str(&exp)?,
+                )
+            }
+            Self::Decode { token, secret } => {
+                let secret = get_jwt_secret(get_secret_str(secret)?)?;
+                let claims: Claims<serde_json::Value> =
+                    validate_and_decode_jwt_with_reason(&token, &secret, &JWTValidation::default())
+                        .map_err(|failure| {
+                            ErrorKind::InvalidCliArguments(failure.description().to_string())
+                        })?;
+                let expiry = Utc
+                    .timestamp_opt(claims.exp as i64, 0)
+                    .single()
+                    .map(|expiry| expiry.to_rfc3339())
+                    .unwrap_or_else(|| claims.exp.to_string());
+                let remaining = claims.exp.saturating_sub(SystemClock.timestamp());
+
+                Ok(format!(
+                    "The token is valid.\nExpires: {} (in {} seconds)\nClaims: {}",
+                    expiry,
+                    remaining,
+                    serde_json::to_string_pretty(&claims)?
+                ))
+            }
+            Self::Sdl => sdl.ok_or_else(|| {
+                ErrorKind::InvalidCliArguments(
+                    "this binary doesn't know about your schema, call `diana::cli::run_with_options()` from your own binary to print it"
+                        .to_string(),
+                )
+                .into()
+            }),
+        }
+    }
+}
+
+// Parses a claim of the form `key=value`
+fn parse_claim(claim: &str) -> std::result::Result<(String, String), String> {
+    match claim.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!(
+            "claims must be of the form `key=value`, found '{}'",
+            claim
+        )),
+    }
+}
+// Gets the secret from the command line if it was given there, or from the environment otherwise
+fn get_secret_str(secret: Option<String>) -> Result<String> {
+    match secret {
+        Some(secret) => Ok(secret),
+        None => env::var(JWT_SECRET_ENV_VAR).map_err(|_err| {
+            ErrorKind::InvalidCliArguments(format!(
+                "no secret given, use `--secret` or set the `{}` environment variable",
+                JWT_SECRET_ENV_VAR
+            ))
+            .into()
+        }),
+    }
+}
+// Prints the output of a command, or its error (exiting with an error code)
+fn print_output(output: Result<String>) {
+    match output {
+        Ok(output) => println!("{}", output),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the `diana` command-line tool with the arguments the program was started with, printing the output. This is what the `diana`
+/// binary does, and it can't print a schema.
+pub fn run() {
+    print_output(Command::from_args().execute(None))
+}
+/// Runs the `diana` command-line tool with the arguments the program was started with and the schema in the given options, printing the
+/// output. You should call this from a binary in your own project if you want to print your schema.
+pub fn run_with_options<C, Q, M, S, T>(opts: &Options<C, Q, M, S, T>)
+where
+    C: Any + Send + Sync + Clone,
+    Q: Clone + ObjectType + 'static,
+    M: Clone + ObjectType + 'static,
+    S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
+{
+    print_output(Command::from_args().execute(Some(get_sdl(opts))))
+}
+/// Gets the SDL (Schema Definition Language) of the schema in the given options, including its subscriptions.
+pub fn get_sdl<C, Q, M, S, T>(opts: &Options<C, Q, M, S, T>) -> String
+where
+    C: Any + Send + Sync + Clone,
+    Q: Clone + ObjectType + 'static,
+    M: Clone + ObjectType + 'static,
+    S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
+{
+    Schema::build(
+        opts.schema.query_root.clone(),
+        opts.schema.mutation_root.clone(),
+        opts.schema.subscription_root.clone(),
+    )
+    .finish()
+    .sdl()
+}
@@ -3,8 +3,20 @@
 
 use async_graphql::{EmptySubscription, ObjectType, Request, Schema, SubscriptionType};
 use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::auth::core::{get_auth_verdict, get_token_state_from_header, AuthVerdict};
+use crate::auth::auth_state::{AuthState, AuthToken};
+use crate::auth::authenticator::{Authenticator, JWTAuthenticator};
+use crate::auth::core::{get_auth_verdict, AuthVerdict};
+use crate::auth::extraction::{AuthRequestParts, TokenExtraction};
+use crate::auth::failure::AuthFailure;
+use crate::auth::guards::SerializedClaims;
+use crate::auth::jwks::{JWTKeyProvider, JWTKeySource};
+use crate::auth::jwt::{JWTValidation, UserClaims};
+use crate::auth::publish_auth::{reject_publish_tokens, PublishAuth};
+use crate::auth::subscription_expiry::{ConnectionExpiry, SubscriptionExpiry};
+use crate::auth::tenant::Tenant;
 use crate::errors::*;
 use crate::graphql::{
     get_schema_for_subscriptions, get_schema_without_subscriptions, PublishMutation,
@@ -18,9 +30,9 @@ pub enum DianaResponse {
     /// The request was successful and the response is attached.
     /// Return a 200.
     Success(String),
-    /// The request was blocked (unauthorized).
-    /// Return a 403.
-    Blocked,
+    /// The request was blocked (unauthorized), for the attached reason.
+    /// Return the reason's status code (usually a 401) with its `WWW-Authenticate` header.
+    Blocked(AuthFailure),
     /// An error occurred on the server side and its body is encapsulated. Any GraphQL errors will be encapsulated in the `Success` variant's
     /// payload.
     /// Return a 500.
@@ -37,65 +49,281 @@ pub enum SysSchema {
 /// The core logic primitive that underlies Diana's systems. You should only use this if you need to support a platform other than the ones
 /// Diana has pre-built systems for (see the book).
 /// This is a struct so as to allow the caching of data that stay the same across requests, like the parsed and built schemas.
+/// The last type parameter is the type of the custom claims in users' JWTs, which will be inferred from the options.
 #[derive(Clone)]
-pub struct DianaHandler<C, Q, M, S>
+pub struct DianaHandler<C, Q, M, S, T = HashMap<String, String>>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     /// The options parsed in to the handler in `::new()`.You should only need to touch this if you're building a custom integration.
-    pub opts: Options<C, Q, M, S>,
+    pub opts: Options<C, Q, M, S, T>,
     /// The schema created for the queries/mutations system. This has the user's given query and mutation roots and no subscriptions at all.
     /// You should only need to touch this if you're building a custom integration.
     pub schema_without_subscriptions: Schema<Q, M, EmptySubscription>,
     /// The schema created for the subscriptions server. This has the user's given subscription root and internally used query/mutation roots
     /// for communication with the query/mutation system. You should only need to touch this if you're building a custom integration.
-    pub schema_for_subscriptions: Schema<SubscriptionQuery, PublishMutation, S>,
+    pub schema_for_subscriptions: Schema<SubscriptionQuery, PublishMutation<T>, S>,
+    /// The JWT keys created from the key source in the options. These are parsed once here so that keys don't have to be re-parsed on
+    /// every request. You should only need to touch this if you're building a custom integration.
+    pub jwt_keys: JWTKeyProvider,
+    /// The system that authenticates requests. This is the one in the options if there is one, or a [`JWTAuthenticator`] created from the
+    /// options otherwise. You should only need to touch this if you're building a custom integration.
+    pub authenticator: Arc<dyn Authenticator<T>>,
+    /// The systems that authenticate requests for tenants with their own JWT keys or validation settings, by their IDs. Other tenants
+    /// use the authenticator above. You should only need to touch this if you're building a custom integration.
+    pub tenant_authenticators: HashMap<String, Arc<dyn Authenticator<T>>>,
 }
-impl<C, Q, M, S> DianaHandler<C, Q, M, S>
+impl<C, Q, M, S, T> DianaHandler<C, Q, M, S, T>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     /// Creates a new instance of the handler with the given options.
-    pub fn new(opts: Options<C, Q, M, S>) -> Result<Self> {
+    /// This will fail if the JWT keys in the options can't be parsed (remote key sets won't be fetched until they're needed though), or if
+    /// the publisher is meant to mint its own tokens and there's no key it can sign them with.
+    pub fn new(opts: Options<C, Q, M, S, T>) -> Result<Self> {
+        let jwt_keys = JWTKeyProvider::from_source(&opts.jwt_key_source)?;
+        // If the user hasn't given their own authenticator, we'll validate JWTs
+        let authenticator = match &opts.authenticator {
+            Some(authenticator) => authenticator.clone(),
+            None => Self::create_jwt_authenticator(&opts, jwt_keys.clone(), &opts.jwt_validation),
+        };
+        // Tenants that change how JWTs are verified need their own authenticators (anything they don't set comes from the options)
+        let mut tenant_authenticators = HashMap::new();
+        if let Some(tenant_resolver) = &opts.tenant_resolver {
+            for (id, config) in tenant_resolver.get_tenants() {
+                if config.get_jwt_key_source().is_none() && config.get_jwt_validation().is_none() {
+                    continue;
+                }
+                let tenant_jwt_keys = match config.get_jwt_key_source() {
+                    Some(jwt_key_source) => JWTKeyProvider::from_source(jwt_key_source)?,
+                    None => jwt_keys.clone(),
+                };
+                let tenant_jwt_validation =
+                    config.get_jwt_validation().unwrap_or(&opts.jwt_validation);
+                tenant_authenticators.insert(
+                    id.to_string(),
+                    Self::create_jwt_authenticator(&opts, tenant_jwt_keys, tenant_jwt_validation),
+                );
+            }
+        }
         // TODO only create a schema for subscriptions if they're actually being used (will require broader logic changes)
         // Get the schema (this also creates a publisher to the subscriptions server and inserts context)
         // We deal with any errors directly with the serverless response enum
+        // If the publisher will be minting its own tokens, it signs them with the configured key unless it's been given its own
+        let publish_auth = match (&opts.publish_auth, &opts.jwt_key_source) {
+            (PublishAuth::MintedJwt(minted), JWTKeySource::Config(key_config))
+                if opts.subscriptions_server_data.is_some()
+                    && minted.get_signing_key().is_none() =>
+            {
+                PublishAuth::MintedJwt(minted.clone().signing_key(key_config.parse()?))
+            }
+            (publish_auth, _) => publish_auth.clone(),
+        };
+        if let PublishAuth::MintedJwt(minted) = &publish_auth {
+            let can_sign = minted.get_signing_key().map(|key| key.can_sign());
+            if opts.subscriptions_server_data.is_some() && can_sign != Some(true) {
+                bail!(ErrorKind::MissingJWTSigningKey);
+            }
+        }
         let schema_without_subscriptions = get_schema_without_subscriptions(
             opts.schema.clone(),
             opts.subscriptions_server_data.clone(),
+            publish_auth,
             opts.ctx.clone(),
+            opts.refresh_token_manager.clone(),
+            #[cfg(feature = "login")]
+            opts.login_manager.clone(),
         )?;
-        let schema_for_subscriptions =
-            get_schema_for_subscriptions(opts.schema.clone(), opts.ctx.clone());
+        let schema_for_subscriptions = get_schema_for_subscriptions::<C, Q, M, S, T>(
+            opts.schema.clone(),
+            opts.ctx.clone(),
+            opts.pubsub_backend.clone(),
+        );
 
         Ok(DianaHandler {
             opts,
             schema_without_subscriptions,
             schema_for_subscriptions,
+            jwt_keys,
+            authenticator,
+            tenant_authenticators,
         })
     }
+    // Creates the default authenticator, which validates JWTs with the given keys and the other settings in the options
+    fn create_jwt_authenticator(
+        opts: &Options<C, Q, M, S, T>,
+        jwt_keys: JWTKeyProvider,
+        jwt_validation: &JWTValidation,
+    ) -> Arc<dyn Authenticator<T>> {
+        let jwt_authenticator = JWTAuthenticator::new(jwt_keys, jwt_validation.clone())
+            .token_extractor(opts.token_extractor.clone());
+        let jwt_authenticator = match &opts.revocation_store {
+            Some(revocation_store) => jwt_authenticator.revocation_store(revocation_store.clone()),
+            None => jwt_authenticator,
+        };
+        Arc::new(jwt_authenticator)
+    }
+    /// Works out which tenant the given request is for, if there are tenants in the options and one can be found.
+    pub fn resolve_tenant(&self, parts: &AuthRequestParts) -> Option<Tenant> {
+        let tenant_resolver = self.opts.tenant_resolver.as_ref()?;
+        let (id, _) = tenant_resolver.resolve(parts, &self.opts.token_extractor)?;
+        tenant_resolver.get_tenant(id)
+    }
     /// Determines ahead of time whether or not a request is authenticated. This should be used in middleware if possible so we can avoid
     /// sending full payloads if the auth token isn't even valid.
-    /// This just takes the HTTP `Authorization` header and returns an [`AuthVerdict`].
-    pub fn is_authed<A: Into<String> + std::fmt::Display>(
+    /// This just takes the HTTP `Authorization` header and returns an [`AuthVerdict`]. If you've configured the token to be found anywhere
+    /// else (e.g. in a cookie), you should use `.is_authed_from_parts()` instead.
+    pub async fn is_authed<A: Into<String> + std::fmt::Display>(
         &self,
         raw_auth_header: Option<A>,
-    ) -> AuthVerdict {
+    ) -> AuthVerdict<T> {
         // This function accepts anything that can be turned into a string for convenience
         // Then we convert it into a definite Option<String>
         let auth_header = raw_auth_header.map(|x| x.to_string());
         // And then we get it as an Option<&str> (whic is what we need for slicing)
         let auth_header_str = auth_header.as_deref();
+        self.is_authed_from_parts(&AuthRequestParts::from_auth_header(auth_header_str))
+            .await
+    }
+    /// Determines ahead of time whether or not a request is authenticated, using the authenticator in the options (or JWTs by default).
+    /// If an API key authenticator has been set, API keys will be looked for first, and then the authenticator will be used if there isn't one.
+    /// Integrations should create the [`AuthRequestParts`] with all the headers, the path and the query string of the request.
+    /// If there are tenants, the request's tenant will pick the authenticator and block level, and valid JWTs will have it attached.
+    /// Tokens minted for publishing (with the [`PUBLISH_TOKEN_AUDIENCE`](crate::PUBLISH_TOKEN_AUDIENCE)) are rejected, because they can only
+    /// be used on the subscriptions server's publish endpoint. Use `.is_authed_for_publishing()` there instead.
+    pub async fn is_authed_from_parts(&self, parts: &AuthRequestParts) -> AuthVerdict<T> {
+        reject_publish_tokens(self.is_authed_for_publishing(parts).await)
+    }
+    /// Determines ahead of time whether or not a publish request to the subscriptions server's HTTP endpoint is authenticated. This is the
+    /// same as `.is_authed_from_parts()`, except that tokens minted for publishing are accepted. It should only be used for that endpoint.
+    pub async fn is_authed_for_publishing(&self, parts: &AuthRequestParts) -> AuthVerdict<T> {
+        let tenant = self
+            .opts
+            .tenant_resolver
+            .as_ref()
+            .and_then(|tenant_resolver| tenant_resolver.resolve(parts, &self.opts.token_extractor));
+        let block_level = tenant
+            .and_then(|(_, config)| config.get_auth_block_state())
+            .unwrap_or(self.opts.authentication_block_state);
+        let authenticator = tenant
+            .and_then(|(id, _)| self.tenant_authenticators.get(id))
+            .unwrap_or(&self.authenticator);
+        let api_key = match &self.opts.api_key_authenticator {
+            Some(api_key_authenticator) => api_key_authenticator.extract(parts),
+            None => TokenExtraction::Missing,
+        };
         // Get a verdict on whether or not the user should be allowed through
-        let token_state =
-            get_token_state_from_header(auth_header_str, self.opts.jwt_secret.clone());
-        get_auth_verdict(token_state, self.opts.authentication_block_state)
+        // Credentials of a kind this endpoint doesn't accept are treated as invalid tokens
+        let token_state = match (&self.opts.api_key_authenticator, api_key) {
+            (Some(api_key_authenticator), api_key @ TokenExtraction::Found(_))
+            | (Some(api_key_authenticator), api_key @ TokenExtraction::Invalid) => {
+                match block_level.accepts_api_keys() {
+                    true => api_key_authenticator.get_key_state(api_key),
+                    false => Ok(AuthState::InvalidToken(AuthFailure::WrongCredentialKind)),
+                }
+            }
+            _ => match authenticator.authenticate(parts).await {
+                Ok(AuthState::NoToken) => Ok(AuthState::NoToken),
+                Ok(_) if !block_level.accepts_jwts() => {
+                    Ok(AuthState::InvalidToken(AuthFailure::WrongCredentialKind))
+                }
+                // API keys aren't specific to tenants, so only tokens from the authenticator get the tenant attached
+                Ok(AuthState::Authorised(AuthToken(claims, None))) => {
+                    match (tenant, &self.opts.tenant_resolver) {
+                        // If two tenants share the keys in the options, a token for one would be verified for the other too
+                        (Some((id, _)), Some(tenant_resolver))
+                            if !tenant_resolver.claims_match_tenant(id, &claims) =>
+                        {
+                            Ok(AuthState::InvalidToken(AuthFailure::InvalidClaims))
+                        }
+                        (Some((id, _)), Some(tenant_resolver)) => Ok(AuthState::Authorised(
+                            AuthToken(claims, tenant_resolver.get_tenant(id)),
+                        )),
+                        _ => Ok(AuthState::Authorised(AuthToken(claims, None))),
+                    }
+                }
+                token_state => token_state,
+            },
+        };
+        get_auth_verdict(token_state, block_level)
+    }
+    /// Determines whether or not a WebSocket connection to the subscriptions server is authenticated, from the upgrade request and the
+    /// payload of the client's `connection_init` message (its connection parameters). Browsers can't set headers on WebSocket connections,
+    /// so any string parameters in that payload are treated as headers the upgrade request didn't have, and `authToken` or `token` is
+    /// treated as a bearer token (e.g. `{ "Authorization": "Bearer ..." }` or `{ "authToken": "..." }` will both work).
+    /// This uses the same authenticator and block level as any other request.
+    pub async fn is_authed_for_subscription(
+        &self,
+        parts: &AuthRequestParts,
+        connection_params: &serde_json::Value,
+    ) -> AuthVerdict<T> {
+        let mut parts = parts.clone();
+        if let Some(connection_params) = connection_params.as_object() {
+            for (name, value) in connection_params {
+                let (name, value) = match (name.as_str(), value.as_str()) {
+                    ("authToken", Some(token)) | ("token", Some(token)) => {
+                        ("Authorization", "Bearer ".to_string() + token)
+                    }
+                    (name, Some(value)) => (name, value.to_string()),
+                    _ => continue,
+                };
+                // The upgrade request takes precedence, which stops parameters from adding to anything it was sent with
+                if parts.get_header(name).is_none() {
+                    parts = parts.with_header(name, &value);
+                }
+            }
+        }
+
+        self.is_authed_from_parts(&parts).await
+    }
+    /// Creates the data that should be inserted into the context of every subscription on an authenticated WebSocket connection. This holds
+    /// the given authentication state (so that `get_auth_data_from_ctx` and the guards work in subscription resolvers), the request's
+    /// tenant, its parts, and the expiry of its token (unless expiries are ignored). Integrations should pass this to `async_graphql`'s WebSocket handling as the connection data.
+    pub fn get_subscription_data(
+        &self,
+        auth_state: AuthState<T>,
+        parts: &AuthRequestParts,
+    ) -> async_graphql::Data {
+        let mut data = async_graphql::Data::default();
+        // Only connections with tokens can expire, and refreshed tokens are checked by whatever checked the original one
+        if let AuthState::Authorised(AuthToken(claims, _)) = &auth_state {
+            if self.opts.subscription_expiry != SubscriptionExpiry::Ignore {
+                let tenant = self
+                    .opts
+                    .tenant_resolver
+                    .as_ref()
+                    .and_then(|tenant_resolver| {
+                        tenant_resolver.resolve(parts, &self.opts.token_extractor)
+                    });
+                let authenticator = tenant
+                    .and_then(|(id, _)| self.tenant_authenticators.get(id))
+                    .unwrap_or(&self.authenticator);
+                data.insert(
+                    ConnectionExpiry::new(
+                        self.opts.subscription_expiry,
+                        claims,
+                        authenticator.clone(),
+                        self.opts.jwt_validation.clock.clone(),
+                    )
+                    .token_extractor(self.opts.token_extractor.clone()),
+                );
+            }
+        }
+        data.insert(SerializedClaims::from_auth_state(&auth_state));
+        data.insert(auth_state);
+        if let Some(tenant) = self.resolve_tenant(parts) {
+            data.insert(tenant);
+        }
+        data.insert(parts.clone());
+        data
     }
     /// Runs a query or mutation (stateless) given the request body and the value of the HTTP `Authorization` header.
     /// This performs authorisation checks and runs the actual request. If you've already used `.is_authed()` to obtain an [`AuthVerdict`],
@@ -106,7 +334,7 @@ where
         &self,
         body: String,
         raw_auth_header: Option<A>,
-        given_auth_verdict: Option<AuthVerdict>,
+        given_auth_verdict: Option<AuthVerdict<T>>,
     ) -> DianaResponse {
         self.run_stateless_req(
             SysSchema::ForSubscriptions,
@@ -119,13 +347,15 @@ where
     /// Runs a query or mutation (stateless) given the request body and the value of the HTTP `Authorization` header.
     /// This performs authorisation checks and runs the actual request. If you've already used `.is_authed()` to obtain an [`AuthVerdict`],
     /// this can be provided as the third argument to avoid running auth checks twice.
+    /// If there's an [`AuthPolicy`](crate::AuthPolicy) in the options, it will be applied to the verdict once the request has been parsed,
+    /// so a verdict that blocks the request should still be provided if there's a policy.
     /// This will return a [`DianaResponse`] no matter what, which simplifies error handling significantly.
     /// This function is for the queries/mutations system only.
     pub async fn run_stateless_without_subscriptions<A: Into<String> + std::fmt::Display>(
         &self,
         body: String,
         raw_auth_header: Option<A>,
-        given_auth_verdict: Option<AuthVerdict>,
+        given_auth_verdict: Option<AuthVerdict<T>>,
     ) -> DianaResponse {
         self.run_stateless_req(
             SysSchema::WithoutSubscriptions,
@@ -135,6 +365,43 @@ where
         )
         .await
     }
+    /// Runs a query or mutation (stateless) given the request body and everything the token (and the tenant) could be in.
+    /// This is the same as `.run_stateless_for_subscriptions()`, but it should be used if you've configured the token to be found anywhere
+    /// other than the `Authorization` header, or if you're finding tenants from hosts or paths. If publish requests are signed with
+    /// [`PublishAuth::Hmac`], the signature in the parts is always checked against the body here, and any given verdict is ignored.
+    /// This function is for the subscriptions system only.
+    pub async fn run_stateless_for_subscriptions_from_parts(
+        &self,
+        body: String,
+        parts: &AuthRequestParts,
+        given_auth_verdict: Option<AuthVerdict<T>>,
+    ) -> DianaResponse {
+        self.run_stateless_req_from_parts(
+            SysSchema::ForSubscriptions,
+            body,
+            parts,
+            given_auth_verdict,
+        )
+        .await
+    }
+    /// Runs a query or mutation (stateless) given the request body and everything the token (and the tenant) could be in.
+    /// This is the same as `.run_stateless_without_subscriptions()`, but it should be used if you've configured the token to be found
+    /// anywhere other than the `Authorization` header, or if you're finding tenants from hosts or paths.
+    /// This function is for the queries/mutations system only.
+    pub async fn run_stateless_without_subscriptions_from_parts(
+        &self,
+        body: String,
+        parts: &AuthRequestParts,
+        given_auth_verdict: Option<AuthVerdict<T>>,
+    ) -> DianaResponse {
+        self.run_stateless_req_from_parts(
+            SysSchema::WithoutSubscriptions,
+            body,
+            parts,
+            given_auth_verdict,
+        )
+        .await
+    }
     // This is used internally to provide query/mutation running functionality to the systems for/without subscriptions
     // It is exposed to make testing easier, though users should not use it!
     #[doc(hidden)]
@@ -143,25 +410,72 @@ where
         which_schema: SysSchema,
         body: String,
         raw_auth_header: Option<A>,
-        given_auth_verdict: Option<AuthVerdict>,
+        given_auth_verdict: Option<AuthVerdict<T>>,
+    ) -> DianaResponse {
+        let auth_header = raw_auth_header.map(|x| x.to_string());
+        self.run_stateless_req_from_parts(
+            which_schema,
+            body,
+            &AuthRequestParts::from_auth_header(auth_header.as_deref()),
+            given_auth_verdict,
+        )
+        .await
+    }
+    // This is the same as the above, but with everything the token could be in
+    #[doc(hidden)]
+    pub async fn run_stateless_req_from_parts(
+        &self,
+        which_schema: SysSchema,
+        body: String,
+        parts: &AuthRequestParts,
+        given_auth_verdict: Option<AuthVerdict<T>>,
     ) -> DianaResponse {
         // Run authentication checks if we need to (they may have already been run in middleware)
-        let verdict = match given_auth_verdict {
-            Some(verdict) => verdict,
-            None => self.is_authed(raw_auth_header),
+        // Signed publish requests are checked here no matter what, because the signature covers the body
+        let verdict = match (&which_schema, &self.opts.publish_auth, given_auth_verdict) {
+            (SysSchema::ForSubscriptions, PublishAuth::Hmac(hmac), _) => hmac.verify(&body, parts),
+            (_, _, Some(verdict)) => verdict,
+            (SysSchema::ForSubscriptions, _, None) => self.is_authed_for_publishing(parts).await,
+            (SysSchema::WithoutSubscriptions, _, None) => self.is_authed_from_parts(parts).await,
+        };
+        // Minted publisher tokens have to have been minted for publishing, and not be too long-lived
+        // They can't be used for anything else, even if the verdict was reached elsewhere
+        let verdict = match (&which_schema, &self.opts.publish_auth) {
+            (SysSchema::ForSubscriptions, PublishAuth::MintedJwt(minted)) => {
+                minted.check_verdict(verdict)
+            }
+            (SysSchema::ForSubscriptions, _) => verdict,
+            (SysSchema::WithoutSubscriptions, _) => reject_publish_tokens(verdict),
+        };
+
+        // Deserialise that raw JSON request into an actual request with variables etc.
+        let gql_req = serde_json::from_str::<Request>(&body);
+        // Refine the verdict for the operation being run if there's a policy
+        // The schema for the subscriptions server is internal, so policies don't apply to it
+        let verdict = match (&self.opts.auth_policy, &which_schema, &gql_req) {
+            (Some(policy), SysSchema::WithoutSubscriptions, Ok(gql_req)) => {
+                policy.apply(&gql_req.query, gql_req.operation_name.as_deref(), verdict)
+            }
+            _ => verdict,
         };
 
         // Based on that verdict, maybe run the request
         match verdict {
             AuthVerdict::Allow(auth_data) => {
-                // Deserialise that raw JSON request into an actual request with variables etc.
-                let gql_req = serde_json::from_str::<Request>(&body);
                 let mut gql_req = match gql_req {
                     Ok(gql_req) => gql_req,
                     Err(err) => return DianaResponse::Error(err.to_string()),
                 };
-                // Insert the authentication data directly into that
-                gql_req = gql_req.data(auth_data);
+                // Insert the authentication data directly into that, along with a serialized copy for guards (which can't know the claims type)
+                gql_req = gql_req
+                    .data(SerializedClaims::from_auth_state(&auth_data))
+                    .data(auth_data);
+                // Requests without valid tokens still need to know their tenant (e.g. to log in)
+                if let Some(tenant) = self.resolve_tenant(parts) {
+                    gql_req = gql_req.data(tenant);
+                }
+                // Resolvers can read anything else about the request from its parts (e.g. the client's address for limiting logins)
+                gql_req = gql_req.data(parts.clone());
                 // Run the request with the correct schema
                 let res = match which_schema {
                     SysSchema::WithoutSubscriptions => {
@@ -180,7 +494,8 @@ where
 
                 DianaResponse::Success(res_str)
             }
-            AuthVerdict::Block => DianaResponse::Blocked,
+            AuthVerdict::Block(failure) => DianaResponse::Blocked(failure),
+            AuthVerdict::Revoked => DianaResponse::Blocked(AuthFailure::Revoked),
             AuthVerdict::Error(err) => DianaResponse::Error(err),
         }
     }
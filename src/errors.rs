@@ -39,10 +39,71 @@ error_chain! {
             display("failed to publish data to the subscriptions server, this is most likely due to an authentication failure")
         }
 
-        /// An invalid indicator string was used when trying to convert a timestring into a datetime.
-        InvalidDatetimeIntervalIndicator(indicator: String) {
+        /// An invalid indicator string was used when trying to convert a timestring into a datetime. The position is the index of the
+        /// offending character.
+        InvalidDatetimeIntervalIndicator(indicator: String, position: usize) {
             description("invalid indicator in timestring")
-            display("invalid indicator '{}' in timestring, must be one of: s, m, h, d, w, M, y", indicator)
+            display("invalid indicator '{}' at position {} in timestring, must be one of: s, m, h, d, w, M, y (or an ISO 8601 designator)", indicator, position)
+        }
+
+        /// A timestring was empty, or had no intervals in it (e.g. `P` or `PT`).
+        EmptyDuration {
+            description("empty timestring")
+            display("timestring didn't contain any intervals")
+        }
+
+        /// An interval indicator in a timestring wasn't preceded by a number (e.g. `m` rather than `1m`).
+        DurationMissingNumber(position: usize) {
+            description("interval indicator without number in timestring")
+            display("interval indicator at position {} in timestring has no number before it", position)
+        }
+
+        /// A number in a timestring wasn't followed by an interval indicator (e.g. the `30` in `1h30`).
+        DurationMissingIndicator(position: usize) {
+            description("number without interval indicator in timestring")
+            display("number at position {} in timestring has no interval indicator after it", position)
+        }
+
+        /// An ISO 8601 duration had a designator in the wrong place (e.g. `P1D1Y`, `P1H` or `P1DT`).
+        MisplacedDurationDesignator(designator: String, position: usize) {
+            description("misplaced designator in iso 8601 duration")
+            display("designator '{}' at position {} in iso 8601 duration is out of order or in the wrong section", designator, position)
+        }
+
+        /// A timestring or duration was too large to be represented.
+        DurationOutOfRange {
+            description("duration out of range")
+            display("duration was too large to be represented")
+        }
+
+        /// The key given for a JWT algorithm was of the wrong kind (e.g. a shared secret was given for an RSA algorithm).
+        InvalidJWTKeyForAlgorithm(algorithm: String) {
+            description("invalid key for jwt algorithm")
+            display("the given key can't be used with the jwt algorithm '{}'", algorithm)
+        }
+
+        /// There was an attempt to create a JWT with a secret that only has a public key.
+        MissingJWTSigningKey {
+            description("no signing key available to create jwt")
+            display("can't create a jwt without a private key or shared secret, only a public key was provided")
+        }
+
+        /// A remote JWKS document has never been fetched successfully, and the last attempt was too recent to try again yet.
+        JWKSUnavailable {
+            description("remote jwks unavailable")
+            display("the remote jwks hasn't been fetched successfully yet, and another attempt was made too recently to try again")
+        }
+
+        /// A refresh token was unknown, had expired, or belonged to a revoked family.
+        InvalidRefreshToken {
+            description("invalid refresh token")
+            display("the given refresh token is invalid, has expired, or has been revoked")
+        }
+
+        /// A refresh token that had already been used was presented again, so its whole family has been revoked.
+        RefreshTokenReused {
+            description("refresh token reused")
+            display("the given refresh token has already been used, so all tokens issued with it have been revoked")
         }
 
         /// There was an unauthorised access attempt.
@@ -51,6 +112,24 @@ error_chain! {
             display("unable to comply with request due to lack of valid and sufficient authentication")
         }
 
+        /// The username or password given to log in was wrong.
+        InvalidCredentials {
+            description("invalid username or password")
+            display("the given username or password is incorrect")
+        }
+
+        /// There have been too many failed attempts to log in to an account, or from an IP address, recently.
+        TooManyLoginAttempts {
+            description("too many failed login attempts")
+            display("there have been too many failed login attempts for this account or address, please try again later")
+        }
+
+        /// A password couldn't be hashed or verified, or a stored password hash was malformed.
+        PasswordHashingFailed(message: String) {
+            description("failed to hash or verify a password")
+            display("failed to hash or verify a password: {}", message)
+        }
+
         /// One or more required builder fields weren't set up.
         IncompleteBuilderFields {
             description("not all required builder fields were instantiated")
@@ -75,6 +154,40 @@ error_chain! {
             display("you tried to initialize the GraphQL playground in production, which is not supported due to authentication issues")
         }
 
+        /// The arguments given to the command-line tool were invalid, or the command couldn't do what they asked.
+        InvalidCliArguments(message: String) {
+            description("invalid command-line arguments")
+            display("{}", message)
+        }
+
+        /// The secret for signing publish requests wasn't valid base64, or was empty.
+        InvalidPublishSecret {
+            description("invalid secret for signing publish requests")
+            display("the secret for signing publish requests must be a non-empty base64-encoded string")
+        }
+
+        /// A WebSocket connection's token was refreshed, but the subscriptions server doesn't allow that.
+        SubscriptionRefreshNotAllowed {
+            description("refreshing the token of a subscriptions connection isn't allowed")
+            display("the subscriptions server doesn't allow the tokens of connections to be refreshed")
+        }
+        /// The token a WebSocket connection was refreshed with wasn't accepted.
+        InvalidConnectionToken(reason: String) {
+            description("invalid token given to refresh subscriptions connection")
+            display("the token given to refresh the connection wasn't accepted: {}", reason)
+        }
+
+        /// The backend that carries published data to subscriptions failed (e.g. its connection to Redis was lost).
+        PubSubBackendFailed(message: String) {
+            description("the pubsub backend failed")
+            display("the backend that carries published data to subscriptions failed: {}", message)
+        }
+        /// A subscription fell so far behind its channel that it missed some messages.
+        SubscriberLagged(missed: u64) {
+            description("subscription fell behind its channel")
+            display("lagged {} messages", missed)
+        }
+
         /// There was an error in one of the integrations.
         IntegrationError(message: String, integration_name: String) {
             description("an error occurred in one of Diana's integration libraries")
@@ -1,11 +1,19 @@
+use async_graphql::guard::Guard;
 use async_graphql::{EmptySubscription, Object as GQLObject, ObjectType, Schema, SubscriptionType};
 use std::any::Any;
-use std::sync::Mutex;
+use std::marker::PhantomData;
+use std::sync::Arc;
 
+use crate::auth::guards::ClaimsGuard;
+use crate::auth::jwt::UserClaims;
+#[cfg(feature = "login")]
+use crate::auth::login::LoginManager;
+use crate::auth::publish_auth::PublishAuth;
+use crate::auth::refresh::RefreshTokenManager;
+use crate::auth::subscription_expiry::{ConnectionExpiry, SubscriptionExpiryExtension};
 use crate::errors::*;
-use crate::graphql_utils::{get_auth_data_from_ctx, get_pubsub_from_ctx};
-use crate::is_authed;
-use crate::pubsub::{PubSub, Publisher};
+use crate::graphql_utils::get_pubsub_from_ctx;
+use crate::pubsub::{PubSubBackend, Publisher};
 
 // The base query type simply allows us to set up the subscriptions schema (has to have at least one query)
 #[derive(Default, Clone)]
@@ -20,32 +28,49 @@ impl SubscriptionQuery {
 
 // This mutation type is utilised by the subscriptions server to allow the publishing of data
 // We pass around the PubSub state internally to that GraphQL system (see get_schema_for_subscriptions)
-#[derive(Default, Clone)]
-pub struct PublishMutation;
+// This is generic over the user's claims type because that's what the authentication data in the context will be
+#[derive(Clone)]
+pub struct PublishMutation<T: UserClaims> {
+    claims_type: PhantomData<T>,
+}
+impl<T: UserClaims> Default for PublishMutation<T> {
+    fn default() -> Self {
+        Self {
+            claims_type: PhantomData,
+        }
+    }
+}
 #[GQLObject]
-impl PublishMutation {
+impl<T: UserClaims> PublishMutation<T> {
     // We accept string data because this is a highly generic type that serialises in the subscriptions handler
     // That may seem to subvert some of the purpose of GraphQL, but this resolver is to be INTERNALLY ONLY!
     // That provides a system-level data integrity guarantee, as only full mutations will call this, and through a PubSub abstraction
     // There should be very little reason for users to implement it themselves, but this type could easily be extended with custom logic
+    #[graphql(guard(ClaimsGuard(
+        predicate = r#"crate::claim_predicate!("role" == "graphql_server")"#
+    )))]
     async fn publish(
         &self,
         raw_ctx: &async_graphql::Context<'_>,
         channel: String,
         data: String,
     ) -> Result<bool> {
-        if is_authed!(
-            get_auth_data_from_ctx(raw_ctx)?,
-            {
-                "role" => "graphql_server"
-            }
-        ) {
-            let mut pubsub = get_pubsub_from_ctx(raw_ctx)?;
-            pubsub.publish(&channel, data);
-            Ok(true)
-        } else {
-            bail!(ErrorKind::Unauthorised)
-        }
+        // Only the serverless system can publish, which is enforced by the guard
+        let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+        pubsub.publish(&channel, data).await?;
+        Ok(true)
+    }
+    // This lets clients keep their subscriptions going past the expiry of the token their connection was authenticated with
+    // It only works over a WebSocket connection (that's where the connection's expiry is), and returns the new expiry
+    async fn refresh_subscription_token(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+        token: String,
+    ) -> Result<u64> {
+        let connection_expiry = raw_ctx
+            .data::<ConnectionExpiry<T>>()
+            .map_err(|_err| ErrorKind::GraphQLContextNotFound("connection_expiry".to_string()))?;
+        connection_expiry.refresh(&token).await
     }
 }
 
@@ -55,7 +80,7 @@ pub struct SubscriptionsServerInformation {
     pub hostname: String,
     pub port: String, // It'll be mixed in to create a URL, may as well start as a string
     pub endpoint: String,
-    pub jwt_to_connect: String, // This should be signed with the secret the subscriptions server knows
+    pub jwt_to_connect: Option<String>, // This should be signed with the secret the subscriptions server knows (it's not needed with HMAC)
 }
 
 // A type for the schema that the user will submit
@@ -71,16 +96,20 @@ where
     pub subscription_root: S,
 }
 
-pub fn get_schema_without_subscriptions<C, Q, M, S>(
+pub fn get_schema_without_subscriptions<C, Q, M, S, T>(
     user_schema: UserSchema<Q, M, S>,
     subscription_server_info: Option<SubscriptionsServerInformation>,
+    publish_auth: PublishAuth,
     user_ctx: C,
+    refresh_token_manager: Option<RefreshTokenManager<T>>,
+    #[cfg(feature = "login")] login_manager: Option<LoginManager<T>>,
 ) -> Result<Schema<Q, M, EmptySubscription>>
 where
     C: Any + Send + Sync,
     Q: ObjectType + 'static,
     M: ObjectType + 'static,
     S: SubscriptionType + 'static,
+    T: UserClaims,
 {
     // We just use an empty subscription root here because subscriptions are handled by the dedicated subscriptions server
     let schema = Schema::build(
@@ -90,42 +119,73 @@ where
     )
     // We add some custom user-defined context (e.g. a database connection pool)
     .data(user_ctx);
+    // If refresh tokens are being used, the manager needs to be available to the refresh mutation and the user's login logic
+    let schema = match refresh_token_manager {
+        Some(refresh_token_manager) => schema.data(refresh_token_manager),
+        None => schema,
+    };
+    // The same goes for the login manager and the login mutation
+    #[cfg(feature = "login")]
+    let schema = match login_manager {
+        Some(login_manager) => schema.data(login_manager),
+        None => schema,
+    };
 
     // Conditionally extend that schema with a publisher if we're using a subscriptions server
     let schema = match subscription_server_info {
-        Some(subscription_server_info) => schema
-            .data(Publisher::new(
-                subscription_server_info.hostname,
-                subscription_server_info.port,
-                subscription_server_info.endpoint,
-                subscription_server_info.jwt_to_connect,
-            )?)
-            .finish(),
+        Some(subscription_server_info) => {
+            let publisher = match (publish_auth, subscription_server_info.jwt_to_connect) {
+                (PublishAuth::Hmac(hmac), _) => Publisher::new_with_hmac(
+                    subscription_server_info.hostname,
+                    subscription_server_info.port,
+                    subscription_server_info.endpoint,
+                    hmac,
+                )?,
+                (PublishAuth::MintedJwt(minted), _) => Publisher::new_with_minted_tokens(
+                    subscription_server_info.hostname,
+                    subscription_server_info.port,
+                    subscription_server_info.endpoint,
+                    minted,
+                )?,
+                (PublishAuth::Jwt, Some(jwt_to_connect)) => Publisher::new(
+                    subscription_server_info.hostname,
+                    subscription_server_info.port,
+                    subscription_server_info.endpoint,
+                    jwt_to_connect,
+                )?,
+                (PublishAuth::Jwt, None) => bail!(ErrorKind::IncompleteBuilderFields),
+            };
+            schema.data(publisher).finish()
+        }
         None => schema.finish(),
     };
 
     Ok(schema)
 }
-pub fn get_schema_for_subscriptions<C, Q, M, S>(
+pub fn get_schema_for_subscriptions<C, Q, M, S, T>(
     user_schema: UserSchema<Q, M, S>,
     user_ctx: C,
-) -> Schema<SubscriptionQuery, PublishMutation, S>
+    pubsub: Arc<dyn PubSubBackend>,
+) -> Schema<SubscriptionQuery, PublishMutation<T>, S>
 where
     C: Any + Send + Sync,
     Q: ObjectType + 'static,
     M: ObjectType + 'static,
     S: SubscriptionType + 'static,
+    T: UserClaims,
 {
     // The schema for the subscriptions server should only have subscriptions, and a mutation to allow publishing
     // Unfortunately, we have to have at least one query, so we implement a meaningless one that isn't introspected
     Schema::build(
         SubscriptionQuery,
-        PublishMutation,
+        PublishMutation::default(),
         user_schema.subscription_root,
     )
     // We add some custom user-defined context (e.g. a database connection pool)
     .data(user_ctx)
-    // We add a mutable PubSub instance for managing subscriptions internally
-    .data(Mutex::new(PubSub::default())) // We add a PubSub instance to internally manage state in the serverful subscriptions system
+    // We add the PubSub backend that carries published data to subscriptions (this is the only state in the serverful subscriptions system)
+    .data(pubsub)
+    // Subscriptions are ended when their connection's token expires (if that's been set up)
+    .extension(SubscriptionExpiryExtension::<T>::default())
     .finish()
 }
@@ -1,10 +1,12 @@
 // Utility functions for GraphQL resolvers
-use std::sync::{Mutex, MutexGuard};
-use tokio_stream::Stream;
+use async_stream::stream;
+use std::sync::Arc;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::auth::auth_state::AuthState;
+use crate::auth::jwt::UserClaims;
 use crate::errors::*;
-use crate::pubsub::PubSub;
+use crate::pubsub::PubSubBackend;
 
 /// Checks to see if the given authentication state matches the series of given claims. This must be provided with the authentication state,
 /// a series of claims to check against, and code to execute if the user is authenticated. This will call [`bail!`] with an [`ErrorKind::Unauthorised`](crate::errors::ErrorKind::Unauthorised)
@@ -16,7 +18,7 @@ use crate::pubsub::PubSub;
 ///     errors::{Result, GQLResult},
 ///     graphql_utils::get_auth_data_from_ctx,
 ///     async_graphql::{Object as GQLObject},
-///     if_authed,
+///     if_authed, AuthState,
 /// };
 ///
 /// #[derive(Default, Clone)]
@@ -29,7 +31,7 @@ use crate::pubsub::PubSub;
 ///         channel: String,
 ///         data: String,
 ///     ) -> Result<bool> {
-///         let auth_state = get_auth_data_from_ctx(raw_ctx)?;
+///         let auth_state: &AuthState = get_auth_data_from_ctx(raw_ctx)?;
 ///         if_authed!(
 ///             auth_state,
 ///             {
@@ -69,8 +71,9 @@ macro_rules! if_authed(
      };
 );
 
-/// Checks to see if the given authentication state matches the series of given claims. This must be provided with the authentication state,
-/// a series of claims to check against. It will then return a boolean as to whether or not the user is authorized.
+/// Checks to see if the given authentication state matches the given claims. This must be provided with the authentication state and either
+/// a series of claims that must be exactly equal (`{ "role" => "admin" }`), or a claim predicate in the syntax of [`claim_predicate!`].
+/// It will then return a boolean as to whether or not the user is authorized.
 /// This should be used instead of [`if_authed!`].
 /// # Example
 /// This is a simplified version of the internal logic that publishes data to the subscriptions server.
@@ -79,7 +82,7 @@ macro_rules! if_authed(
 ///     errors::{Result, GQLResult, bail, ErrorKind},
 ///     graphql_utils::get_auth_data_from_ctx,
 ///     async_graphql::{Object as GQLObject},
-///     is_authed,
+///     is_authed, AuthState,
 /// };
 ///
 /// #[derive(Default, Clone)]
@@ -92,8 +95,9 @@ macro_rules! if_authed(
 ///         channel: String,
 ///         data: String,
 ///     ) -> Result<bool> {
+///         let auth_state: &AuthState = get_auth_data_from_ctx(raw_ctx)?;
 ///         if is_authed!(
-///             get_auth_data_from_ctx(raw_ctx)?,
+///             auth_state,
 ///             {
 ///                 "role" => "graphql_server"
 ///             }
@@ -109,6 +113,12 @@ macro_rules! if_authed(
 ///
 /// # fn main() {}
 /// ```
+/// With a claim predicate, that check could instead be something like this:
+/// ```
+/// # use diana::{is_authed, AuthState};
+/// # let auth_state: AuthState = AuthState::NoToken;
+/// is_authed!(auth_state, any("role" in ["admin", "editor"], "scopes" contains "publish"));
+/// ```
 #[macro_export]
 macro_rules! is_authed(
     ($auth_state:expr, { $($key:expr => $value:expr),+ }) => {
@@ -122,12 +132,93 @@ macro_rules! is_authed(
             $auth_state.has_claims(test_claims)
         }
      };
+    ($auth_state:expr, $($predicate:tt)+) => {
+        $auth_state.satisfies(&$crate::claim_predicate!($($predicate)+))
+    };
+);
+
+/// Creates a [`ClaimPredicate`](crate::ClaimPredicate) from a small syntax for conditions on claims. Claim names must be string literals,
+/// but values can be any expressions that can be turned into strings. The following are supported:
+/// - `"role" == "admin"` -- the claim is exactly equal to the value
+/// - `"role" != "admin"` -- the claim is not equal to the value (or doesn't exist)
+/// - `"role" in ["admin", "editor"]` -- the claim is equal to any of the values
+/// - `"scopes" contains "orders:read"` -- the claim is a list containing the value (or a space-separated string with it as a word)
+/// - `"scope" matches "orders:*"` -- the claim matches the pattern, in which `*` matches anything
+/// - `"scope" starts_with "orders:"` -- the claim starts with the prefix
+/// - `"tenant" exists` -- the claim exists
+/// - `not(...)`, `all(..., ...)` and `any(..., ...)` -- boolean combinations, which can be nested as deeply as you like
+///
+/// Any of these can also be wrapped in parentheses.
+/// # Example
+/// ```
+/// use diana::{claim_predicate, ClaimPredicate};
+///
+/// let predicate = claim_predicate!(all(
+///     any("role" == "admin", "permissions" contains "orders:write"),
+///     not("suspended" exists)
+/// ));
+/// # let _: ClaimPredicate = predicate;
+/// ```
+#[macro_export]
+macro_rules! claim_predicate(
+    // Splits comma-separated predicates (which can have any number of tokens each) into a list of predicates
+    (@list [$($done:expr,)*] [$($current:tt)*] , $($rest:tt)*) => {
+        $crate::claim_predicate!(@list [$($done,)* $crate::claim_predicate!($($current)*),] [] $($rest)*)
+    };
+    (@list [$($done:expr,)*] [$($current:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::claim_predicate!(@list [$($done,)*] [$($current)* $next] $($rest)*)
+    };
+    (@list [$($done:expr,)*] []) => {
+        ::std::vec![$($done),*]
+    };
+    (@list [$($done:expr,)*] [$($current:tt)+]) => {
+        ::std::vec![$($done,)* $crate::claim_predicate!($($current)+)]
+    };
+    // Boolean combinations
+    (all($($inner:tt)*)) => {
+        $crate::ClaimPredicate::All($crate::claim_predicate!(@list [] [] $($inner)*))
+    };
+    (any($($inner:tt)*)) => {
+        $crate::ClaimPredicate::Any($crate::claim_predicate!(@list [] [] $($inner)*))
+    };
+    (not($($inner:tt)+)) => {
+        $crate::ClaimPredicate::Not(::std::boxed::Box::new($crate::claim_predicate!($($inner)+)))
+    };
+    (($($inner:tt)+)) => {
+        $crate::claim_predicate!($($inner)+)
+    };
+    // Conditions on single claims
+    ($key:literal == $value:expr) => {
+        $crate::ClaimPredicate::Equals($key.to_string(), $value.to_string())
+    };
+    ($key:literal != $value:expr) => {
+        $crate::ClaimPredicate::Not(::std::boxed::Box::new($crate::ClaimPredicate::Equals($key.to_string(), $value.to_string())))
+    };
+    ($key:literal in [$($value:expr),* $(,)?]) => {
+        $crate::ClaimPredicate::AnyOf($key.to_string(), ::std::vec![$($value.to_string()),*])
+    };
+    ($key:literal contains $value:expr) => {
+        $crate::ClaimPredicate::Contains($key.to_string(), $value.to_string())
+    };
+    ($key:literal matches $value:expr) => {
+        $crate::ClaimPredicate::Matches($key.to_string(), $value.to_string())
+    };
+    ($key:literal starts_with $value:expr) => {
+        $crate::ClaimPredicate::StartsWith($key.to_string(), $value.to_string())
+    };
+    ($key:literal exists) => {
+        $crate::ClaimPredicate::Exists($key.to_string())
+    };
 );
 
 /// Gets a subscription stream to events published on a particular channel from the context of a GraphQL resolver.
 /// **This must only be used in subscriptions! It will not work anywhere else!**
 /// This returns a pre-created stream which you should manipulate if necessary.
-/// All data sent via the publisher from the queries/mutations system will land here **in string format**. Serialization is up to you.
+/// All data sent via the publisher from the queries/mutations system will land here **in string format**. Serialization is up to you, unless you use a
+/// [`Channel`](crate::Channel) instead.
+/// This stream can't carry errors, so if the subscription falls behind the channel with [`LagPolicy::Error`](crate::LagPolicy::Error), the
+/// missed messages will just be dropped, and if the PubSub backend fails, the stream will end (both will be logged with the
+/// [`log`](https://docs.rs/log) crate). If you'd like those errors to reach the client, use [`subscribe_to_channel_from_ctx`] instead.
 /// # Example
 /// ```
 /// use diana::{
@@ -173,36 +264,103 @@ pub fn get_stream_for_channel_from_ctx(
     channel: &str,
     raw_ctx: &async_graphql::Context<'_>,
 ) -> Result<impl Stream<Item = String>> {
-    // Get the PubSub mutably
-    let mut pubsub = get_pubsub_from_ctx(raw_ctx)?;
-    // Return a stream on the given channel
-    Ok(pubsub.subscribe(channel))
+    let pubsub = get_pubsub_from_ctx(raw_ctx)?.clone();
+    let channel = channel.to_string();
+    // Subscribing to the backend is asynchronous, so we only do it once the stream is first polled
+    Ok(stream! {
+        let stream = match pubsub.subscribe(&channel).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("couldn't subscribe to channel '{}': {}", channel, err);
+                return;
+            }
+        };
+        for await message in stream {
+            match message {
+                Ok(message) => yield message,
+                // The only errors are from falling behind, which we can't tell the client about here
+                Err(err) => log::warn!("subscription to channel '{}' dropped messages: {}", channel, err),
+            }
+        }
+    })
+}
+/// Subscribes to a particular channel from the context of a GraphQL resolver, returning a stream of the events published on it. This works
+/// like [`get_stream_for_channel_from_ctx`], except that it fails straight away if the PubSub backend can't be subscribed to, and that the
+/// stream can carry errors for the client if the subscription falls behind the channel (depending on the
+/// [`LagPolicy`](crate::LagPolicy) of the PubSub backend).
+/// **This must only be used in subscriptions! It will not work anywhere else!**
+/// # Example
+/// ```
+/// use diana::{
+///     stream,
+///     graphql_utils::subscribe_to_channel_from_ctx,
+///     errors::GQLResult,
+///     async_graphql::{Subscription as GQLSubscription, SimpleObject as GQLSimpleObject},
+/// };
+/// use tokio_stream::{Stream, StreamExt};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize, GQLSimpleObject)]
+/// struct User {
+///     username: String
+/// }
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     async fn new_users(
+///         &self,
+///         raw_ctx: &async_graphql::Context<'_>,
+///     ) -> impl Stream<Item = GQLResult<User>> {
+///         let stream_result = subscribe_to_channel_from_ctx("new_user", raw_ctx).await;
+///
+///         stream! {
+///             let stream = stream_result.map_err(|err| err.to_string())?;
+///             for await message in stream {
+///                 // This will be an error if the subscription fell too far behind (depending on the lag policy)
+///                 let message = message?;
+///                 let new_user: User = serde_json::from_str(&message).map_err(|_err| "couldn't serialize given data correctly".to_string())?;
+///                 yield Ok(new_user);
+///             }
+///         }
+///     }
+/// }
+/// # fn main() {}
+/// ```
+///
+pub async fn subscribe_to_channel_from_ctx(
+    channel: &str,
+    raw_ctx: &async_graphql::Context<'_>,
+) -> Result<impl Stream<Item = GQLResult<String>>> {
+    let pubsub = get_pubsub_from_ctx(raw_ctx)?;
+    // Return a stream on the given channel, with any errors (from falling behind) ready to be sent to the client
+    let stream = pubsub.subscribe(channel).await?;
+    Ok(stream.map(|message| message.map_err(|err| GQLError::new(err.to_string()))))
 }
 
 /// Gets authentication data from the context of a GraphQL resolver.
 /// This should only fail if the server is constructed without authentication middleware (which shouldn't be possible with the exposed API
-/// surface of this crate).
-pub fn get_auth_data_from_ctx<'a>(
+/// surface of this crate), or if the claims type you ask for isn't the one you set in your options.
+/// You'll usually need to annotate the type of the result (e.g. `let auth_state: &AuthState = get_auth_data_from_ctx(raw_ctx)?;`), and
+/// the claims will be of your own type if you've set one (e.g. `&AuthState<MyClaims>`).
+pub fn get_auth_data_from_ctx<'a, T: UserClaims>(
     raw_ctx: &'a async_graphql::Context<'_>,
-) -> Result<&'a AuthState> {
+) -> Result<&'a AuthState<T>> {
     let auth_state = raw_ctx
-        .data::<AuthState>()
+        .data::<AuthState<T>>()
         .map_err(|_err| ErrorKind::GraphQLContextNotFound("auth_state".to_string()))?;
 
     Ok(auth_state)
 }
-/// Gets the internal PubSub from the context of a GraphQL resolver. You should never need to use this.
+/// Gets the internal PubSub backend from the context of a GraphQL resolver. You should never need to use this.
 #[doc(hidden)]
 pub fn get_pubsub_from_ctx<'a>(
     raw_ctx: &'a async_graphql::Context<'_>,
-) -> Result<MutexGuard<'a, PubSub>> {
-    // We store the PubSub instance as a Mutex because we need it sent/synced between threads as a mutable
-    let pubsub_mutex = raw_ctx
-        .data::<Mutex<PubSub>>()
+) -> Result<&'a Arc<dyn PubSubBackend>> {
+    let pubsub = raw_ctx
+        .data::<Arc<dyn PubSubBackend>>()
         .map_err(|_err| ErrorKind::GraphQLContextNotFound("pubsub".to_string()))?;
-    let pubsub = pubsub_mutex
-        .lock()
-        .map_err(|_err| ErrorKind::MutexPoisoned("pubsub".to_string()))?;
 
     Ok(pubsub)
 }
@@ -1,5 +1,7 @@
 #![forbid(unsafe_code)]
 #![deny(missing_docs)]
+// `error_chain` needs more than the default recursion limit for the number of errors we have
+#![recursion_limit = "256"]
 
 /*!
 Diana is an out-of-the-box fully-fledged GraphQL system with inbuilt support for commonly-used features like subscriptions and authentication.
@@ -20,6 +22,11 @@ detailed explanations and tutorials can be found in the [book](https://arctic-he
 */
 
 mod auth;
+/// The module for the `diana` command-line tool, which can generate secrets, create and verify tokens, and print the SDL of your schema.
+/// This is only available with the `cli` feature. The `diana` binary can't know about your schema, so if you want to print it, call
+/// [`run_with_options`](crate::cli::run_with_options) from a binary in your own project.
+#[cfg(feature = "cli")]
+pub mod cli;
 mod diana_handler;
 /// The module for errors and results. This uses [error_chain] behind the scenes.
 /// You'll also find [`GQLResult`](crate::errors::GQLResult) and [`GQLError`](crate::errors::Error) in here, which may be useful in working
@@ -32,14 +39,61 @@ mod options;
 mod pubsub;
 
 // Public exports accessible from the root (everything the user will need)
+pub use crate::auth::api_keys::{
+    generate_api_key, hash_api_key, ApiKeyAuthenticator, ApiKeyRecord, ApiKeyStore,
+    FileApiKeyStore, MemoryApiKeyStore,
+};
 pub use crate::auth::auth_state::{AuthState, AuthToken};
+pub use crate::auth::authenticator::{Authenticator, IntrospectionAuthenticator, JWTAuthenticator};
+pub use crate::auth::claim_predicate::ClaimPredicate;
+pub use crate::auth::clock::{Clock, FakeClock, SystemClock};
 pub use crate::auth::core::{AuthBlockLevel, AuthVerdict};
+pub use crate::auth::duration::ParsedDuration;
+pub use crate::auth::extraction::{
+    AuthRequestParts, CsrfConfig, TokenExtraction, TokenExtractor, TokenSource,
+};
+pub use crate::auth::failure::AuthFailure;
+pub use crate::auth::guards::{
+    unauthorised_error, AuthenticatedGuard, ClaimsGuard, Guarded, UNAUTHORISED_ERROR_CODE,
+};
+pub use crate::auth::jwks::{JWTKeyProvider, JWTKeySet, JWTKeySource, RemoteJWKS};
 pub use crate::auth::jwt::{
-    create_jwt, decode_time_str, get_jwt_secret, validate_and_decode_jwt, Claims, JWTSecret,
+    create_jwt, create_jwt_with_clock, create_jwt_with_registered_claims, decode_time_str,
+    decode_time_str_with_clock, get_jwt_secret, validate_and_decode_jwt,
+    validate_and_decode_jwt_with_reason, validate_and_decode_jwt_with_validation, Claims,
+    JWTExpiry, JWTKeyConfig, JWTKeyMaterial, JWTSecret, JWTValidation, RegisteredClaims,
+    UserClaims,
+};
+#[cfg(feature = "login")]
+pub use crate::auth::login::{
+    hash_password, hash_password_async, verify_password, verify_password_async, CredentialVerifier,
+    LoginAttemptStore, LoginManager, LoginMutation, MemoryLoginAttemptStore, StoredCredentials,
+};
+pub use crate::auth::policy::{AuthPolicy, OperationKind, OperationRule};
+pub use crate::auth::publish_auth::{
+    HmacPublishAuth, MemoryNonceStore, MintedPublishAuth, NonceStore, PublishAuth,
+    PUBLISH_NONCE_HEADER, PUBLISH_SIGNATURE_HEADER, PUBLISH_TIMESTAMP_HEADER,
+    PUBLISH_TOKEN_AUDIENCE,
 };
+pub use crate::auth::refresh::{
+    MemoryRefreshTokenStore, RefreshMutation, RefreshTokenManager, RefreshTokenRecord,
+    RefreshTokenStore, TokenPair,
+};
+pub use crate::auth::revocation::{
+    is_revoked, FileRevocationStore, MemoryRevocationStore, RevocationStore,
+};
+pub use crate::auth::subscription_expiry::{
+    ConnectionExpiry, SubscriptionExpiry, SubscriptionExpiryExtension,
+};
+pub use crate::auth::tenant::{Tenant, TenantConfig, TenantResolver, TenantSource};
 pub use crate::diana_handler::{DianaHandler, DianaResponse, SysSchema};
 pub use crate::options::{Options, OptionsBuilder};
-pub use crate::pubsub::Publisher;
+#[cfg(feature = "redis")]
+pub use crate::pubsub::RedisPubSub;
+pub use crate::pubsub::{
+    Channel, InvalidMessagePolicy, LagCounts, LagPolicy, MemoryPubSub, PubSubBackend,
+    PubSubSettings, Publisher,
+};
 
 // Users shouldn't have to install `async_graphql` themselves for basic usage
 #[doc(no_inline)]
@@ -48,4 +102,6 @@ pub use async_graphql;
 #[doc(no_inline)]
 pub use async_stream::stream; // The `stream!` macro
 #[doc(no_inline)]
+pub use jsonwebtoken::Algorithm; // For configuring JWT algorithms
+#[doc(no_inline)]
 pub use tokio_stream::{Stream, StreamExt}; // For subscriptions
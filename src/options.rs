@@ -1,47 +1,107 @@
 // Contains the logic to actually create the GraphQL server that the user will use
 // This file does not include any logic for the subscriptions server
 
-// TODO convert the JWT to a fully-fledged secret straight away from here for efficiency
-
 use async_graphql::{ObjectType, SubscriptionType};
+use jsonwebtoken::Algorithm;
 use std::any::Any;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
 
+use crate::auth::api_keys::ApiKeyAuthenticator;
+use crate::auth::authenticator::Authenticator;
+use crate::auth::clock::Clock;
 use crate::auth::core::AuthBlockLevel;
+use crate::auth::extraction::TokenExtractor;
+use crate::auth::jwks::JWTKeySource;
+use crate::auth::jwt::{JWTKeyConfig, JWTKeyMaterial, JWTValidation, UserClaims};
+#[cfg(feature = "login")]
+use crate::auth::login::LoginManager;
+use crate::auth::policy::AuthPolicy;
+use crate::auth::publish_auth::PublishAuth;
+use crate::auth::refresh::RefreshTokenManager;
+use crate::auth::revocation::RevocationStore;
+use crate::auth::subscription_expiry::SubscriptionExpiry;
+use crate::auth::tenant::TenantResolver;
 use crate::errors::*;
 pub use crate::graphql::{SubscriptionsServerInformation, UserSchema};
+use crate::pubsub::{MemoryPubSub, PubSubBackend};
 
 /// The options for creating the normal server, subscriptions server, and serverless function.
 /// You should define your options in one file and then import them everywhere you need them.
 /// You should use `::builder()` to construct this.
+/// The last type parameter is the type of the custom claims in your users' JWTs, which is a map of strings by default. If you want to use
+/// your own type, create the builder with `OptionsBuilder::<C, Q, M, S, YourClaims>::new()` instead.
 #[derive(Clone)]
-pub struct Options<C, Q, M, S>
+pub struct Options<C, Q, M, S, T = HashMap<String, String>>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     /// Your custom context, often a database connection pool
     pub ctx: C,
     /// Data about the subscriptions server
     /// If you're not using subscriptions at all, the mechanics to connect to such a server will be disabled automatically.
     pub subscriptions_server_data: Option<SubscriptionsServerInformation>,
+    /// How the queries/mutations system authenticates itself to the subscriptions server when it publishes data. Both systems must use
+    /// the same setting. By default, this is with the JWT in the subscriptions server data.
+    pub publish_auth: PublishAuth,
     /// Your `async_graphql` schema. See the book for details on how to create a schema.
     pub schema: UserSchema<Q, M, S>,
-    /// The JWT secret for authenticating and generating client tokens and communications with the subscriptions server.
-    /// This should be stored in an environment variable and randomly generated (see the book).
-    pub jwt_secret: String,
+    /// Where the JWT keys for authenticating client tokens and communications with the subscriptions server come from.
+    /// Any secrets or private keys should be stored in environment variables, and shared secrets should be randomly generated (see the book).
+    pub jwt_key_source: JWTKeySource,
+    /// The settings used to validate the registered claims of JWTs (issuer, audience, etc.).
+    /// By default, any issuer and audience will be accepted.
+    pub jwt_validation: JWTValidation,
+    /// The store of revoked JWTs that will be checked whenever a client's token is validated.
+    /// If nothing is provided here, tokens will be valid until they expire.
+    pub revocation_store: Option<Arc<dyn RevocationStore>>,
+    /// The system that issues and rotates refresh tokens. This will be made available to your resolvers, and is needed if you want to use
+    /// [`RefreshMutation`](crate::RefreshMutation).
+    pub refresh_token_manager: Option<RefreshTokenManager<T>>,
+    /// The system that logs users in with their usernames and passwords. This will be made available to your resolvers, and is needed if
+    /// you want to use [`LoginMutation`](crate::LoginMutation).
+    #[cfg(feature = "login")]
+    pub login_manager: Option<LoginManager<T>>,
+    /// The system that finds tokens in requests. By default, this only looks at the `Authorization` header with the `Bearer` scheme.
+    pub token_extractor: TokenExtractor,
+    /// The system that verifies API keys, which can be used by machine-to-machine callers instead of JWTs.
+    /// If nothing is provided here, only JWTs will be accepted.
+    pub api_key_authenticator: Option<ApiKeyAuthenticator<T>>,
+    /// The system that authenticates requests. If nothing is provided here, JWTs will be validated with the keys, validation settings,
+    /// revocation store and token extractor above.
+    pub authenticator: Option<Arc<dyn Authenticator<T>>>,
     /// The blocking level that will be used for the GraphQL endpoint.
     /// See [`AuthBlockLevel`] for available blocklevels and their meanings.
     /// The default here is to block anything that is not authenticated.
     pub authentication_block_state: AuthBlockLevel,
+    /// The policy that refines the blocking level for each operation once requests have been parsed (e.g. to allow anonymous logins).
+    /// If nothing is provided here, the blocking level will apply to every operation.
+    pub auth_policy: Option<AuthPolicy>,
+    /// The system that works out which tenant each request is for, which picks the keys, blocking level and context data for it.
+    /// If nothing is provided here, there will only be one tenant, configured by everything else in here.
+    pub tenant_resolver: Option<TenantResolver>,
+    /// What the subscriptions server does when the token a WebSocket connection was authenticated with expires. By default, the
+    /// connection's subscriptions will be ended with an error.
+    pub subscription_expiry: SubscriptionExpiry,
+    /// The backend that carries published data to subscriptions on the subscriptions server. By default, this is held in memory, which
+    /// only works with a single instance of the subscriptions server.
+    pub pubsub_backend: Arc<dyn PubSubBackend>,
     /// The endpoint for the GraphiQL playground.
     /// If nothing is provided here, the playground will be disabled.
     /// Not supported in production
     pub playground_endpoint: Option<String>,
     /// The GraphQL endpoint location. By default `/graphql`.
     pub graphql_endpoint: String,
+    /// The type of the custom claims in users' JWTs. Tokens with claims that can't be deserialized into this will be treated as invalid.
+    /// This is set through the type parameters of the builder, you shouldn't need to touch it.
+    pub claims_type: PhantomData<T>,
 }
+// This is only implemented for the default claims type so that the types can be inferred without annotations
 impl<C, Q, M, S> Options<C, Q, M, S>
 where
     C: Any + Send + Sync + Clone,
@@ -49,7 +109,8 @@ where
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
 {
-    /// Creates a new builder-style struct to create the and instance of [`Options`].
+    /// Creates a new builder-style struct to create the and instance of [`Options`]. This will use the default claims type, if you want to
+    /// use your own, use `OptionsBuilder::new()` instead.
     pub fn builder() -> OptionsBuilder<C, Q, M, S> {
         OptionsBuilder::default()
     }
@@ -57,12 +118,13 @@ where
 
 /// A builder-style struct to create an instance of [`Options`] idiomatically.
 #[derive(Clone)]
-pub struct OptionsBuilder<C, Q, M, S>
+pub struct OptionsBuilder<C, Q, M, S, T = HashMap<String, String>>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     ctx: Option<C>,
     use_subscriptions_server: bool,
@@ -70,18 +132,36 @@ where
     subscriptions_server_port: Option<String>, // The real property actually does take an Option<String> for this one
     subscriptions_server_endpoint: Option<String>, // The real property actually does take an Option<String> for this one
     subscriptions_server_jwt_to_connect: Option<String>, // The real property actually does take an Option<String> for this one
+    publish_auth: PublishAuth,
     schema: Option<UserSchema<Q, M, S>>,
-    jwt_secret: Option<String>,
+    jwt_algorithm: Algorithm,
+    jwt_verification_key: Option<JWTKeyMaterial>,
+    jwt_signing_key: Option<JWTKeyMaterial>,
+    jwt_key_source: Option<JWTKeySource>,
+    jwt_validation: JWTValidation,
+    revocation_store: Option<Arc<dyn RevocationStore>>, // The real property actually does take an Option<Arc<dyn RevocationStore>> for this one
+    refresh_token_manager: Option<RefreshTokenManager<T>>, // The real property actually does take an Option<RefreshTokenManager<T>> for this one
+    #[cfg(feature = "login")]
+    login_manager: Option<LoginManager<T>>, // The real property actually does take an Option<LoginManager<T>> for this one
+    token_extractor: TokenExtractor,
+    api_key_authenticator: Option<ApiKeyAuthenticator<T>>, // The real property actually does take an Option<ApiKeyAuthenticator<T>> for this one
+    authenticator: Option<Arc<dyn Authenticator<T>>>, // The real property actually does take an Option<Arc<dyn Authenticator<T>>> for this one
     authentication_block_state: Option<AuthBlockLevel>,
+    auth_policy: Option<AuthPolicy>, // The real property actually does take an Option<AuthPolicy> for this one
+    tenant_resolver: Option<TenantResolver>, // The real property actually does take an Option<TenantResolver> for this one
+    subscription_expiry: SubscriptionExpiry,
+    pubsub_backend: Arc<dyn PubSubBackend>,
     playground_endpoint: Option<String>, // The real property actually does take an Option<String> for this one
     graphql_endpoint: Option<String>,
+    claims_type: PhantomData<T>,
 }
-impl<C, Q, M, S> Default for OptionsBuilder<C, Q, M, S>
+impl<C, Q, M, S, T> Default for OptionsBuilder<C, Q, M, S, T>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     fn default() -> Self {
         // By default, if we're in development we'll have a playground, and not in production
@@ -97,22 +177,41 @@ where
             subscriptions_server_port: None,
             subscriptions_server_endpoint: None,
             subscriptions_server_jwt_to_connect: None,
+            publish_auth: PublishAuth::default(), // Diana has always used JWTs for this
             schema: None,
-            jwt_secret: None,
+            jwt_algorithm: Algorithm::HS512, // This is what Diana has always used with shared secrets
+            jwt_verification_key: None,
+            jwt_signing_key: None,
+            jwt_key_source: None,
+            jwt_validation: JWTValidation::default(),
+            revocation_store: None,
+            refresh_token_manager: None,
+            #[cfg(feature = "login")]
+            login_manager: None,
+            token_extractor: TokenExtractor::default(),
+            api_key_authenticator: None,
+            authenticator: None,
             authentication_block_state: None,
+            auth_policy: None,
+            tenant_resolver: None,
+            subscription_expiry: SubscriptionExpiry::default(),
+            pubsub_backend: Arc::new(MemoryPubSub::default()),
             playground_endpoint,
             graphql_endpoint: Some("/graphql".to_string()),
+            claims_type: PhantomData,
         }
     }
 }
-impl<C, Q, M, S> OptionsBuilder<C, Q, M, S>
+impl<C, Q, M, S, T> OptionsBuilder<C, Q, M, S, T>
 where
     C: Any + Send + Sync + Clone,
     Q: Clone + ObjectType + 'static,
     M: Clone + ObjectType + 'static,
     S: Clone + SubscriptionType + 'static,
+    T: UserClaims,
 {
     /// Creates a new builder. You'll need to then call the other methods to set everything up.
+    /// The type of the custom claims in users' JWTs can be set with the last type parameter here.
     pub fn new() -> Self {
         Self::default()
     }
@@ -126,9 +225,111 @@ where
         self
     }
     /// Defines the JWT secret that will be used to authenticate client tokens and communication with the subscriptions server.
-    /// This should be randomly generated (see the book).
+    /// This should be randomly generated (see the book). This is a base64-encoded shared secret, which will be used with `HS512` unless
+    /// you set another algorithm with `.jwt_algorithm()`. If you want to use asymmetric keys, use `.jwt_public_key()` instead.
     pub fn jwt_secret(mut self, jwt_secret: &str) -> Self {
-        self.jwt_secret = Some(jwt_secret.to_string());
+        self.jwt_verification_key = Some(JWTKeyMaterial::Base64Secret(jwt_secret.to_string()));
+        self
+    }
+    /// Defines the algorithm that JWTs must be signed with. This defaults to `HS512`. Tokens signed with any other algorithm will be rejected.
+    pub fn jwt_algorithm(mut self, jwt_algorithm: Algorithm) -> Self {
+        self.jwt_algorithm = jwt_algorithm;
+        self
+    }
+    /// Defines the public key that will be used to verify client tokens and communication with the subscriptions server. This is an
+    /// alternative to `.jwt_secret()` for asymmetric algorithms, and means that systems that only verify tokens never need to hold anything
+    /// that can create them.
+    pub fn jwt_public_key(mut self, jwt_public_key: JWTKeyMaterial) -> Self {
+        self.jwt_verification_key = Some(jwt_public_key);
+        self
+    }
+    /// Defines the private key that will be used to sign tokens with an asymmetric algorithm. This is only needed on systems that create
+    /// tokens, and isn't needed at all if you're using a shared secret.
+    pub fn jwt_private_key(mut self, jwt_private_key: JWTKeyMaterial) -> Self {
+        self.jwt_signing_key = Some(jwt_private_key);
+        self
+    }
+    /// Defines where the keys used to verify JWTs come from, overriding anything set with `.jwt_secret()` or `.jwt_public_key()`. Use this
+    /// if you need to accept several keys at once (e.g. while rotating them), or if your keys are published as a JWKS by an identity provider.
+    /// See [`JWTKeySource`](crate::JWTKeySource) for the available sources.
+    pub fn jwt_key_source(mut self, jwt_key_source: JWTKeySource) -> Self {
+        self.jwt_key_source = Some(jwt_key_source);
+        self
+    }
+    /// Defines the issuers that JWTs will be accepted from. Tokens will have to have an `iss` claim that's one of these.
+    pub fn jwt_issuers(mut self, jwt_issuers: &[&str]) -> Self {
+        self.jwt_validation.issuers = Some(jwt_issuers.iter().map(|x| x.to_string()).collect());
+        self
+    }
+    /// Defines the audiences that JWTs will be accepted for. Tokens will have to have an `aud` claim that includes at least one of these.
+    /// You should set this to something unique to your API so that tokens minted for your other APIs can't be used on it.
+    pub fn jwt_audiences(mut self, jwt_audiences: &[&str]) -> Self {
+        self.jwt_validation.audiences = Some(jwt_audiences.iter().map(|x| x.to_string()).collect());
+        self
+    }
+    /// Defines the number of seconds of leeway allowed for clock skew when checking when JWTs expire, become valid and were issued.
+    /// This defaults to 0.
+    pub fn jwt_leeway(mut self, jwt_leeway: u64) -> Self {
+        self.jwt_validation.leeway = jwt_leeway;
+        self
+    }
+    /// Defines the clock that JWTs will be validated against, which is the system clock by default. You'll usually only want to set this
+    /// to a [`FakeClock`](crate::FakeClock) in tests. Anything you've created yourself (e.g. a [`RefreshTokenManager`] or revocation store)
+    /// should be given the same clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.jwt_validation.clock = clock;
+        self
+    }
+    /// Requires JWTs to have an `nbf` (not before) claim. This will always be checked if it's present, regardless of this setting.
+    pub fn require_jwt_nbf(mut self) -> Self {
+        self.jwt_validation.require_nbf = true;
+        self
+    }
+    /// Requires JWTs to have an `iat` (issued at) claim. This will always be checked if it's present, regardless of this setting.
+    pub fn require_jwt_iat(mut self) -> Self {
+        self.jwt_validation.require_iat = true;
+        self
+    }
+    /// Defines the store of revoked JWTs. Tokens that have been revoked in this store will be rejected even if they haven't expired yet.
+    /// You should keep a clone of the [`Arc`] so that you can revoke tokens (e.g. when a user logs out). See
+    /// [`RevocationStore`](crate::RevocationStore) for more details.
+    pub fn revocation_store(mut self, revocation_store: Arc<dyn RevocationStore>) -> Self {
+        self.revocation_store = Some(revocation_store);
+        self
+    }
+    /// Defines the system that issues and rotates refresh tokens. This will be made available to your resolvers (so you can start new token
+    /// families when users log in), and is needed if you want to use [`RefreshMutation`](crate::RefreshMutation).
+    pub fn refresh_token_manager(mut self, refresh_token_manager: RefreshTokenManager<T>) -> Self {
+        self.refresh_token_manager = Some(refresh_token_manager);
+        self
+    }
+    /// Defines the system that logs users in with their usernames and passwords. This will be made available to your resolvers, and is needed
+    /// if you want to use [`LoginMutation`](crate::LoginMutation).
+    #[cfg(feature = "login")]
+    pub fn login_manager(mut self, login_manager: LoginManager<T>) -> Self {
+        self.login_manager = Some(login_manager);
+        self
+    }
+    /// Defines where tokens will be looked for in requests (e.g. cookies, custom headers). By default, only the `Authorization` header with
+    /// the `Bearer` scheme will be used. See [`TokenExtractor`](crate::TokenExtractor) for more details.
+    pub fn token_extractor(mut self, token_extractor: TokenExtractor) -> Self {
+        self.token_extractor = token_extractor;
+        self
+    }
+    /// Defines the system that verifies API keys, which lets machine-to-machine callers authenticate with long-lived keys instead of JWTs.
+    /// A verified key authorises a request in exactly the same way as a valid JWT. See
+    /// [`ApiKeyAuthenticator`](crate::ApiKeyAuthenticator) for more details.
+    pub fn api_key_authenticator(mut self, api_key_authenticator: ApiKeyAuthenticator<T>) -> Self {
+        self.api_key_authenticator = Some(api_key_authenticator);
+        self
+    }
+    /// Defines the system that authenticates requests, replacing the default JWT validation. This can do anything asynchronously (e.g.
+    /// OAuth2 token introspection with [`IntrospectionAuthenticator`](crate::IntrospectionAuthenticator)). See
+    /// [`Authenticator`](crate::Authenticator) for more details.
+    /// If you're using the subscriptions server, it will use this too, so this must still accept the JWT for connecting to it (you can
+    /// wrap a [`JWTAuthenticator`](crate::JWTAuthenticator) to do that).
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator<T>>) -> Self {
+        self.authenticator = Some(authenticator);
         self
     }
     /// Defines the blocklevel for the GraphQL endpoint. See [`AuthBlockLevel`] for more details.
@@ -136,6 +337,32 @@ where
         self.authentication_block_state = Some(authentication_block_state);
         self
     }
+    /// Defines the policy that refines the blocklevel for each operation, like allowing anonymous logins or requiring claims for mutations.
+    /// See [`AuthPolicy`](crate::AuthPolicy) for more details.
+    pub fn auth_policy(mut self, auth_policy: AuthPolicy) -> Self {
+        self.auth_policy = Some(auth_policy);
+        self
+    }
+    /// Defines the tenants hosted on this deployment and how requests are matched to them. Each tenant can have its own JWT keys,
+    /// blocklevel and context data, and anything a tenant doesn't set will come from these options. See
+    /// [`TenantResolver`](crate::TenantResolver) for more details.
+    pub fn tenants(mut self, tenant_resolver: TenantResolver) -> Self {
+        self.tenant_resolver = Some(tenant_resolver);
+        self
+    }
+    /// Defines what the subscriptions server does when the token a WebSocket connection was authenticated with expires. By default, the
+    /// connection's subscriptions will be ended with an error. See [`SubscriptionExpiry`](crate::SubscriptionExpiry) for the alternatives.
+    pub fn subscription_expiry(mut self, subscription_expiry: SubscriptionExpiry) -> Self {
+        self.subscription_expiry = subscription_expiry;
+        self
+    }
+    /// Defines the backend that carries published data to subscriptions on the subscriptions server. By default, this is held in memory,
+    /// so if you want to run several instances of the subscriptions server, they'll need to share something like a
+    /// [`RedisPubSub`](crate::RedisPubSub) (with the `redis` feature).
+    pub fn pubsub_backend(mut self, pubsub_backend: Arc<dyn PubSubBackend>) -> Self {
+        self.pubsub_backend = pubsub_backend;
+        self
+    }
     /// Defines your custom schema.
     /// The query/mutation roots will be inserted into the queries/mutations server/function and the subscription root will be inserted
     /// into the subscriptions server. These should be specified using `async_graphql` as per the book.
@@ -183,6 +410,13 @@ where
         self.use_subscriptions_server = true;
         self
     }
+    /// Sets how the queries/mutations system authenticates itself to the subscriptions server when it publishes data. If you use
+    /// [`PublishAuth::Hmac`], you don't need to give a JWT with `.jwt_to_connect_to_subscriptions_server()`. This must be set the same way
+    /// on both systems.
+    pub fn publish_auth(mut self, publish_auth: PublishAuth) -> Self {
+        self.publish_auth = publish_auth;
+        self
+    }
     /// Defines the GraphiQL playground endpoint.
     /// In development, this is not required and will default to `/graphiql`.
     /// In production, if this has been set we'll throw an error at `.finish()`.
@@ -199,7 +433,7 @@ where
 
     /// Builds the final options, consuming `self`.
     /// This will return an error if you haven't set something required up.
-    pub fn finish(self) -> Result<Options<C, Q, M, S>> {
+    pub fn finish(self) -> Result<Options<C, Q, M, S, T>> {
         // If the playground has been enabled in production, throw
         if !cfg!(debug_assertions) && self.playground_endpoint.is_some() {
             bail!(ErrorKind::AttemptedPlaygroundInProduction);
@@ -218,21 +452,50 @@ where
                     endpoint: self
                         .subscriptions_server_endpoint
                         .ok_or(ErrorKind::IncompleteBuilderFields)?,
-                    jwt_to_connect: self
-                        .subscriptions_server_jwt_to_connect
-                        .ok_or(ErrorKind::IncompleteBuilderFields)?,
+                    // The JWT is only needed if requests aren't being signed instead
+                    jwt_to_connect: match (
+                        &self.publish_auth,
+                        self.subscriptions_server_jwt_to_connect,
+                    ) {
+                        (PublishAuth::Jwt, None) => bail!(ErrorKind::IncompleteBuilderFields),
+                        (_, jwt_to_connect) => jwt_to_connect,
+                    },
                 }),
                 false => None,
             },
+            publish_auth: self.publish_auth,
             schema: self.schema.ok_or(ErrorKind::IncompleteBuilderFields)?,
-            jwt_secret: self.jwt_secret.ok_or(ErrorKind::IncompleteBuilderFields)?,
+            jwt_key_source: match self.jwt_key_source {
+                Some(jwt_key_source) => jwt_key_source,
+                // If no other source has been given, we'll use the single key that's been configured
+                None => JWTKeySource::Config(JWTKeyConfig {
+                    algorithm: self.jwt_algorithm,
+                    verification_key: self
+                        .jwt_verification_key
+                        .ok_or(ErrorKind::IncompleteBuilderFields)?,
+                    signing_key: self.jwt_signing_key,
+                }),
+            },
+            jwt_validation: self.jwt_validation,
+            revocation_store: self.revocation_store, // This can be an option (revocation is optional)
+            refresh_token_manager: self.refresh_token_manager, // This can be an option (refresh tokens are optional)
+            #[cfg(feature = "login")]
+            login_manager: self.login_manager, // This can be an option (logins are optional)
+            token_extractor: self.token_extractor,
+            api_key_authenticator: self.api_key_authenticator, // This can be an option (API keys are optional)
+            authenticator: self.authenticator, // This can be an option (we'll use JWTs by default)
             authentication_block_state: self
                 .authentication_block_state
                 .ok_or(ErrorKind::IncompleteBuilderFields)?,
+            auth_policy: self.auth_policy, // This can be an option (policies are optional)
+            tenant_resolver: self.tenant_resolver, // This can be an option (tenants are optional)
+            subscription_expiry: self.subscription_expiry,
+            pubsub_backend: self.pubsub_backend,
             playground_endpoint: self.playground_endpoint, // This can be an option (we may not have a playground at all)
             graphql_endpoint: self
                 .graphql_endpoint
                 .ok_or(ErrorKind::IncompleteBuilderFields)?,
+            claims_type: PhantomData,
         };
 
         Ok(opts)
@@ -0,0 +1,176 @@
+// This file contains typed channels, which do the serialization that data needs to go through the subscriptions server
+// Channels are still just strings underneath, so typed and untyped publishing and subscribing can be mixed on the same channel
+
+use async_graphql::futures_util::stream::BoxStream;
+use async_stream::stream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::errors::*;
+use crate::graphql_utils::subscribe_to_channel_from_ctx;
+use crate::pubsub::Publisher;
+
+/// What a subscription on a [`Channel`] does with a message that can't be deserialized into the channel's type (e.g. because it was
+/// published by an older version of your code).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidMessagePolicy {
+    /// The message will be sent to the client as an error, and the subscription will end. This is the default.
+    #[default]
+    Error,
+    /// The message will be ignored.
+    Skip,
+    /// The message will be ignored, but a warning will be logged with the [`log`](https://docs.rs/log) crate.
+    Log,
+}
+
+/// A channel on the subscriptions server that carries data of a particular type, which is serialized as JSON when it's published and
+/// deserialized again for each subscription. You should declare each of your channels once and use that everywhere, so that publishers and
+/// subscribers can't disagree on its name or type.
+/// # Example
+/// ```
+/// use diana::{
+///     async_graphql::{Object as GQLObject, SimpleObject as GQLSimpleObject, Subscription as GQLSubscription},
+///     errors::GQLResult,
+///     Channel, Stream,
+/// };
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Serialize, Deserialize, GQLSimpleObject)]
+/// struct User {
+///     username: String,
+/// }
+/// const NEW_USERS: Channel<User> = Channel::new("new_user");
+///
+/// #[derive(Default, Clone)]
+/// pub struct Mutation {}
+/// #[GQLObject]
+/// impl Mutation {
+///     async fn add_user(&self, raw_ctx: &async_graphql::Context<'_>, username: String) -> GQLResult<bool> {
+///         // Your code to add the new user
+///         NEW_USERS.publish(raw_ctx, &User { username }).await.map_err(|err| err.to_string())?;
+///         Ok(true)
+///     }
+/// }
+///
+/// #[derive(Default, Clone)]
+/// pub struct Subscription;
+/// #[GQLSubscription]
+/// impl Subscription {
+///     async fn new_users(&self, raw_ctx: &async_graphql::Context<'_>) -> impl Stream<Item = GQLResult<User>> {
+///         NEW_USERS.subscribe(raw_ctx).await
+///     }
+/// }
+/// # fn main() {}
+/// ```
+pub struct Channel<T> {
+    name: Cow<'static, str>,
+    on_invalid: InvalidMessagePolicy,
+    data_type: PhantomData<fn() -> T>,
+}
+// These are implemented manually so that the data type doesn't need to implement them
+impl<T> Clone for Channel<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            on_invalid: self.on_invalid,
+            data_type: PhantomData,
+        }
+    }
+}
+impl<T> fmt::Debug for Channel<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("name", &self.name)
+            .field("on_invalid", &self.on_invalid)
+            .finish()
+    }
+}
+impl<T> Channel<T> {
+    /// Creates a new channel with the given name. This can be used in a `const`.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+            on_invalid: InvalidMessagePolicy::Error,
+            data_type: PhantomData,
+        }
+    }
+    /// Creates a new channel with a name that's only known at runtime (e.g. a channel for each user).
+    pub fn with_name(name: String) -> Self {
+        Self {
+            name: Cow::Owned(name),
+            on_invalid: InvalidMessagePolicy::Error,
+            data_type: PhantomData,
+        }
+    }
+    /// Sets what subscriptions will do with messages that can't be deserialized. By default, they'll end with an error.
+    pub const fn on_invalid(mut self, on_invalid: InvalidMessagePolicy) -> Self {
+        self.on_invalid = on_invalid;
+        self
+    }
+    /// Gets the name of the channel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+impl<T: Serialize> Channel<T> {
+    /// Serializes the given data and publishes it on this channel with the [`Publisher`] in the context of a GraphQL resolver. This will
+    /// fail if the queries/mutations system hasn't been set up to use subscriptions, or if the publisher does.
+    pub async fn publish(&self, raw_ctx: &async_graphql::Context<'_>, data: &T) -> Result<()> {
+        let publisher = raw_ctx
+            .data::<Publisher>()
+            .map_err(|_err| ErrorKind::GraphQLContextNotFound("publisher".to_string()))?;
+        let data = serde_json::to_string(data)?;
+        publisher.publish(&self.name, data).await
+    }
+}
+impl<T: DeserializeOwned + Send + 'static> Channel<T> {
+    /// Gets a stream of the data published on this channel from the context of a GraphQL resolver.
+    /// **This must only be used in subscriptions! It will not work anywhere else!**
+    /// If the stream can't be created, it'll just give a single error.
+    pub async fn subscribe(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> BoxStream<'static, GQLResult<T>> {
+        let stream_result = subscribe_to_channel_from_ctx(&self.name, raw_ctx).await;
+        let name = self.name.to_string();
+        let on_invalid = self.on_invalid;
+
+        Box::pin(stream! {
+            let stream = match stream_result {
+                Ok(stream) => stream,
+                Err(err) => {
+                    yield Err(GQLError::new(err.to_string()));
+                    return;
+                }
+            };
+            for await message in stream {
+                // Errors from falling behind are already meant for the client
+                let message = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        yield Err(err);
+                        continue;
+                    }
+                };
+                let err = match serde_json::from_str(&message) {
+                    Ok(data) => {
+                        yield Ok(data);
+                        continue;
+                    }
+                    Err(err) => err,
+                };
+                match on_invalid {
+                    InvalidMessagePolicy::Error => {
+                        yield Err(GQLError::new(format!("couldn't deserialize message on channel '{}': {}", name, err)));
+                        break;
+                    }
+                    InvalidMessagePolicy::Skip => (),
+                    InvalidMessagePolicy::Log => log::warn!("skipped message on channel '{}' that couldn't be deserialized: {}", name, err),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,433 @@
+// This module defines a simple publish-subscribe structure, though one designed to run across the web
+// The publishing and subscribing are done on different servers/functions
+
+use async_graphql::futures_util::stream::BoxStream;
+use async_stream::stream;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{channel as create_channel, Receiver, Sender};
+
+use crate::auth::publish_auth::{HmacPublishAuth, MintedPublishAuth};
+use crate::errors::*;
+
+mod channel;
+#[cfg(feature = "redis")]
+mod redis_backend;
+pub use channel::{Channel, InvalidMessagePolicy};
+#[cfg(feature = "redis")]
+pub use redis_backend::RedisPubSub;
+
+// The number of messages each channel holds for subscribers that haven't caught up yet, unless that's been configured
+const DEFAULT_CHANNEL_CAPACITY: usize = 5;
+
+#[derive(Serialize)]
+struct GQLQueryBody<T: Serialize> {
+    query: String,
+    variables: T,
+}
+
+#[derive(Deserialize)]
+struct GQLPublishResponse {
+    data: PublishResponse,
+}
+#[derive(Deserialize)]
+struct PublishResponse {
+    publish: bool,
+}
+
+/// The system that publishes data from the queries/mutations system to the subscriptions server.
+/// These communications are secured by a JWT (given or minted) or HMAC signatures, as specified in [`Options`](crate::Options).
+/// This is automatically created from the [`Options`](crate::Options) and passed to all resolvers. You should never need to manually create it.
+pub struct Publisher {
+    client: Client,
+    address: String,
+    credentials: PublisherCredentials,
+}
+// The ways the publisher can authenticate itself to the subscriptions server
+enum PublisherCredentials {
+    Token(String),
+    MintedToken(MintedPublishAuth),
+    Hmac(HmacPublishAuth),
+}
+impl Publisher {
+    /// Creates a new publisher. This is done for you when you create the queries/mutations system, so you should never need to call this.
+    pub fn new(hostname: String, port: String, endpoint: String, token: String) -> Result<Self> {
+        Self::new_with_credentials(hostname, port, endpoint, PublisherCredentials::Token(token))
+    }
+    /// Creates a new publisher that mints its own short-lived tokens, which must have a signing key. This is done for you when you create
+    /// the queries/mutations system, so you should never need to call this.
+    pub fn new_with_minted_tokens(
+        hostname: String,
+        port: String,
+        endpoint: String,
+        minted: MintedPublishAuth,
+    ) -> Result<Self> {
+        Self::new_with_credentials(
+            hostname,
+            port,
+            endpoint,
+            PublisherCredentials::MintedToken(minted),
+        )
+    }
+    /// Creates a new publisher that signs its requests instead of sending a JWT. This is done for you when you create the
+    /// queries/mutations system, so you should never need to call this.
+    pub fn new_with_hmac(
+        hostname: String,
+        port: String,
+        endpoint: String,
+        hmac: HmacPublishAuth,
+    ) -> Result<Self> {
+        Self::new_with_credentials(hostname, port, endpoint, PublisherCredentials::Hmac(hmac))
+    }
+    fn new_with_credentials(
+        hostname: String,
+        port: String,
+        endpoint: String,
+        credentials: PublisherCredentials,
+    ) -> Result<Self> {
+        let address = format!(
+            "{hostname}:{port}{endpoint}", // The endpoint should start with '/'
+            hostname = hostname,
+            port = port,
+            endpoint = endpoint
+        );
+
+        let client = Client::new();
+
+        Ok(Self {
+            client,
+            address,
+            credentials,
+        })
+    }
+
+    /// Sends the given data to the subscriptions server on the given channel. In-depth information about this process is available in the book.
+    /// You should use [serde] to serialize anything sent here as a string (this won't be done for you). It should then be deserialized in the
+    /// appropriate subscription (which will listen for messages from here indirectly). A [`Channel`] will do all that for you.
+    /// This function will return an error if the subscriptions server was unavailable or didn't correctly acknowledge the request.
+    /// # Example
+    /// ```
+    /// use diana::{
+    ///     async_graphql::{Object as GQLObject, InputObject as GQLInputObject, SimpleObject as GQLSimpleObject},
+    ///     errors::GQLResult,
+    ///     Publisher,
+    /// };
+    /// use serde::Serialize;
+    ///
+    /// #[derive(Serialize, GQLSimpleObject)]
+    /// struct User {
+    ///     username: String,
+    /// }
+    /// #[derive(Serialize, GQLInputObject)]
+    /// struct UserInput {
+    ///     username: String,
+    /// }
+    ///
+    /// #[derive(Default, Clone)]
+    /// pub struct Mutation {}
+    /// #[GQLObject]
+    /// impl Mutation {
+    ///     async fn add_user(
+    ///         &self,
+    ///         ctx: &async_graphql::Context<'_>,
+    ///         new_user: UserInput,
+    ///     ) -> GQLResult<User> {
+    ///         // Your code to add the new user
+    ///
+    ///         // Notify the subscriptions server that a new user has been added
+    ///         let publisher = ctx.data::<Publisher>()?;
+    ///         let user_json = serde_json::to_string(&new_user).unwrap(); // GraphQL has already checked for ill-formation
+    ///         publisher.publish("new_user", user_json.to_string()).await?;
+    ///
+    ///         Ok(User {
+    ///             username: new_user.username
+    ///         }) // In reality, you'd probably return the user that's just been created
+    ///     }
+    /// }
+    ///
+    /// # fn main() {}
+    /// ```
+    pub async fn publish(&self, channel: &str, data: String) -> Result<()> {
+        let client = &self.client;
+
+        // Create the query body with a HashMap of variables
+        let mut variables = HashMap::new();
+        variables.insert("channel", channel.to_string());
+        variables.insert("data", data);
+
+        let body = GQLQueryBody {
+            query: "
+                mutation PublishData($channel: String!, $data: String!) {
+                    publish(
+                        channel: $channel,
+                        data: $data
+                    )
+                }
+            "
+            .to_string(),
+            variables,
+        };
+
+        // We serialize the body ourselves so that exactly what's sent can be signed
+        let body = serde_json::to_string(&body)?;
+        let req = client
+            .post(&self.address)
+            .header("Content-Type", "application/json");
+        let req = match &self.credentials {
+            PublisherCredentials::Token(token) => {
+                req.header("Authorization", "Bearer ".to_string() + token)
+            }
+            PublisherCredentials::MintedToken(minted) => req.header(
+                "Authorization",
+                "Bearer ".to_string() + &minted.get_token()?,
+            ),
+            PublisherCredentials::Hmac(hmac) => hmac
+                .sign(&body)
+                .into_iter()
+                .fold(req, |req, (name, value)| req.header(name, value)),
+        };
+        let res = req
+            .body(body)
+            .send()
+            .await
+            .map_err(|_| ErrorKind::SubscriptionDataPublishFailed)?;
+
+        // Handle if the request wasn't successful on an HTTP level
+        if res.status().to_string() != "200 OK" {
+            bail!(ErrorKind::SubscriptionDataPublishFailed)
+        }
+
+        // Get the body out (data still stringified though, that's handled by resolvers)
+        let body: GQLPublishResponse = serde_json::from_str(
+            &res.text()
+                .await
+                .map_err(|_| ErrorKind::SubscriptionDataPublishFailed)?,
+        )
+        .map_err(|_| ErrorKind::SubscriptionDataPublishFailed)?;
+
+        // Confirm nothing's gone wrong on a GraphQL level (basically only if we got `false` instead of `true`)
+        match body.data.publish {
+            true => Ok(()),
+            _ => bail!(ErrorKind::SubscriptionDataPublishFailed),
+        }
+    }
+}
+
+// Everything from here down operates solely on the subscriptions server, and is stateful!
+// Do NOT import these mechanisms in the serverless system!
+
+/// The system that carries published data to the subscriptions on the subscriptions server. Diana uses [`MemoryPubSub`] by default, which
+/// only works if there's a single instance of the subscriptions server. If you want to run several behind a load balancer, they'll all need
+/// to share a backend like [`RedisPubSub`] (with the `redis` feature), or you can implement this yourself for your own message broker.
+#[async_trait]
+pub trait PubSubBackend: fmt::Debug + Send + Sync {
+    /// Sends the given data to every subscriber on the given channel. It doesn't matter if there aren't any.
+    async fn publish(&self, channel: &str, data: String) -> Result<()>;
+    /// Gets a stream of all the data published on the given channel from now on. This should end if the channel is closed. If the
+    /// subscriber falls behind, this can contain errors (see [`LagPolicy`]).
+    async fn subscribe(&self, channel: &str) -> Result<BoxStream<'static, Result<String>>>;
+    /// Closes the given channel, ending the streams of all its subscribers. This doesn't need to be explicitly called normally.
+    async fn close(&self, channel: &str) -> Result<()>;
+    /// Gets how many times subscribers have fallen behind on each channel, and how many messages they've missed. Channels that have never
+    /// had a subscriber fall behind won't be included. By default, this doesn't count anything.
+    fn lag_counts(&self) -> HashMap<String, LagCounts> {
+        HashMap::new()
+    }
+}
+
+/// What a subscription does when it falls so far behind its channel that the channel has run out of space to hold the messages it
+/// hasn't received yet. Either way, the oldest of those messages will be missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LagPolicy {
+    /// The missed messages will be dropped silently, and the subscription will carry on with the oldest message it can still get. This is
+    /// the default.
+    #[default]
+    Drop,
+    /// The client will be sent an error saying how many messages were missed, and the subscription will then carry on.
+    Error,
+    /// The subscription will end.
+    Close,
+}
+
+/// The number of times subscribers have fallen behind on a channel, and the total number of messages they've missed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LagCounts {
+    /// The number of times a subscriber has fallen behind.
+    pub events: u64,
+    /// The total number of messages that subscribers have missed.
+    pub messages: u64,
+}
+
+/// The settings for the channels of a PubSub backend. Each channel holds a certain number of messages (its capacity) for subscribers that
+/// haven't received them yet, and any subscriber that falls further behind than that will miss messages. Larger capacities use more
+/// memory, but let subscribers cope with bigger bursts of messages.
+#[derive(Debug, Clone)]
+pub struct PubSubSettings {
+    capacity: usize,
+    channel_capacities: HashMap<String, usize>,
+    lag_policy: LagPolicy,
+}
+impl Default for PubSubSettings {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CHANNEL_CAPACITY,
+            channel_capacities: HashMap::new(),
+            lag_policy: LagPolicy::default(),
+        }
+    }
+}
+impl PubSubSettings {
+    /// Creates the default settings, which give every channel a capacity of 5 messages and drop missed messages silently.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the capacity of every channel that doesn't have its own. This must be at least 1, and smaller values will be treated as 1.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+    /// Sets the capacity of the given channel, overriding the capacity for every other channel. This must be at least 1, and smaller
+    /// values will be treated as 1.
+    pub fn channel_capacity(mut self, channel: &str, capacity: usize) -> Self {
+        self.channel_capacities
+            .insert(channel.to_string(), capacity);
+        self
+    }
+    /// Sets what subscriptions do when they fall behind their channels. By default, missed messages will be dropped silently.
+    pub fn lag_policy(mut self, lag_policy: LagPolicy) -> Self {
+        self.lag_policy = lag_policy;
+        self
+    }
+    /// Gets the capacity of the given channel.
+    pub fn get_capacity(&self, channel: &str) -> usize {
+        // Tokio's broadcast channels can't have no capacity
+        self.channel_capacities
+            .get(channel)
+            .copied()
+            .unwrap_or(self.capacity)
+            .max(1)
+    }
+    /// Gets what subscriptions do when they fall behind their channels.
+    pub fn get_lag_policy(&self) -> LagPolicy {
+        self.lag_policy
+    }
+}
+
+// The lag counts of a backend's channels, which are shared with all their subscribers' streams
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LagCounter(Arc<Mutex<HashMap<String, LagCounts>>>);
+impl LagCounter {
+    fn record(&self, channel: &str, missed: u64) {
+        // Poisoning can only happen if another subscriber panicked while counting, and the counts are still valid
+        let mut counts = match self.0.lock() {
+            Ok(counts) => counts,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let counts = counts.entry(channel.to_string()).or_default();
+        counts.events += 1;
+        counts.messages += missed;
+    }
+    pub(crate) fn get(&self) -> HashMap<String, LagCounts> {
+        match self.0.lock() {
+            Ok(counts) => counts.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+// Turns a receiver for a channel into a stream of its messages, which ends when the channel is closed
+// Subscribers that fall too far behind will miss the oldest messages, and what happens then depends on the lag policy
+pub(crate) fn stream_from_receiver(
+    mut receiver: Receiver<String>,
+    channel: &str,
+    lag_policy: LagPolicy,
+    lag_counter: LagCounter,
+) -> BoxStream<'static, Result<String>> {
+    let channel = channel.to_string();
+    Box::pin(stream! {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => yield Ok(message),
+                Err(RecvError::Lagged(missed)) => {
+                    lag_counter.record(&channel, missed);
+                    match lag_policy {
+                        LagPolicy::Drop => continue,
+                        LagPolicy::Error => yield Err(ErrorKind::SubscriberLagged(missed).into()),
+                        LagPolicy::Close => break,
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+/// A PubSub backend that's held entirely in memory using Tokio's broadcast channels. Published data will only reach subscriptions on the same
+/// instance of the subscriptions server, so this is best for development or single-instance servers. This is the default.
+#[derive(Debug, Default)]
+pub struct MemoryPubSub {
+    // A hash map of channels to their Tokio broadcasters
+    channels: Mutex<HashMap<String, Sender<String>>>,
+    settings: PubSubSettings,
+    lag_counter: LagCounter,
+}
+impl MemoryPubSub {
+    /// Creates a new in-memory PubSub backend without any channels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the capacities of the backend's channels and what subscribers do when they fall behind. This only affects channels created
+    /// after it's called, so it should be called straight away.
+    pub fn settings(mut self, settings: PubSubSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+    // Gets a channel or creates a new one if needed
+    fn get_channel(&self, channel: &str) -> Result<Sender<String>> {
+        let mut channels = self
+            .channels
+            .lock()
+            .map_err(|_err| ErrorKind::MutexPoisoned("pubsub".to_string()))?;
+        let channel_sender = channels
+            .entry(channel.to_string())
+            .or_insert_with(|| create_channel(self.settings.get_capacity(channel)).0);
+
+        Ok(channel_sender.clone()) // Broadcast can be multi-producer
+    }
+}
+#[async_trait]
+impl PubSubBackend for MemoryPubSub {
+    // Creates a new sender for a given channel name if one doesn't exist and then sends a message using it
+    async fn publish(&self, channel: &str, data: String) -> Result<()> {
+        let channel_sender = self.get_channel(channel)?;
+        // This will fail only if there are now receivers, but we don't care if that's the case
+        let _ = channel_sender.send(data);
+        Ok(())
+    }
+    async fn subscribe(&self, channel: &str) -> Result<BoxStream<'static, Result<String>>> {
+        let channel_sender = self.get_channel(channel)?;
+        Ok(stream_from_receiver(
+            channel_sender.subscribe(),
+            channel,
+            self.settings.get_lag_policy(),
+            self.lag_counter.clone(),
+        ))
+    }
+    // Drops the handle to a sender for the given channel
+    // All receiver calls after this point will result in a closed channel error
+    async fn close(&self, channel: &str) -> Result<()> {
+        self.channels
+            .lock()
+            .map_err(|_err| ErrorKind::MutexPoisoned("pubsub".to_string()))?
+            .remove(channel);
+        Ok(())
+    }
+    fn lag_counts(&self) -> HashMap<String, LagCounts> {
+        self.lag_counter.get()
+    }
+}
@@ -0,0 +1,272 @@
+// This file contains a PubSub backend that uses Redis, so that data published to any instance of the subscriptions server reaches them all
+// Each instance listens on Redis for a channel once, and then passes its messages on to its own subscribers with Tokio's broadcast channels
+
+use async_graphql::futures_util::stream::{BoxStream, Stream};
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::Client;
+use std::collections::HashMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::broadcast::{channel as create_channel, Receiver, Sender};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+use super::{stream_from_receiver, LagCounter, LagCounts, PubSubBackend, PubSubSettings};
+use crate::errors::*;
+
+// A channel that this instance is listening to on Redis
+struct RedisChannel {
+    // This tells subscribers' streams apart from those of an earlier listener on the same channel
+    id: u64,
+    sender: Sender<String>,
+    // The task that passes on messages from Redis, which holds the connection for this channel
+    listener: JoinHandle<()>,
+}
+type RedisChannels = Arc<Mutex<HashMap<String, RedisChannel>>>;
+
+// A subscriber's stream, which stops this instance listening to its channel on Redis if it's the last one left when it's dropped
+// This can't hold onto the sender to check that, otherwise the streams wouldn't end when the listener does
+struct SubscriberStream {
+    // This is only `None` while it's being dropped
+    stream: Option<BoxStream<'static, Result<String>>>,
+    channel: String,
+    channel_id: u64,
+    channels: RedisChannels,
+}
+impl Stream for SubscriberStream {
+    type Item = Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match &mut self.stream {
+            Some(stream) => stream.as_mut().poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+impl Drop for SubscriberStream {
+    fn drop(&mut self) {
+        // Our receiver has to be gone before we can count the ones that are left
+        self.stream = None;
+        if let Ok(mut channels) = self.channels.lock() {
+            let is_last = matches!(
+                channels.get(&self.channel),
+                Some(redis_channel) if redis_channel.id == self.channel_id && redis_channel.sender.receiver_count() == 0
+            );
+            if is_last {
+                // Dropping the connection unsubscribes it
+                if let Some(redis_channel) = channels.remove(&self.channel) {
+                    redis_channel.listener.abort();
+                }
+            }
+        }
+    }
+}
+
+/// A PubSub backend that uses [Redis pub/sub](https://redis.io/topics/pubsub), which lets you run several instances of the subscriptions
+/// server behind a load balancer. Every instance must use the same Redis server. This is only available with the `redis` feature.
+/// Each instance opens one connection to Redis for publishing, and one for each channel that it has subscribers on (which is closed again
+/// once the last of them unsubscribes). Like Redis pub/sub itself, this doesn't store messages, so anything published while an instance's
+/// connection to Redis is down won't reach it.
+pub struct RedisPubSub {
+    client: Client,
+    // This is only connected when something is first published, and it's dropped if it fails so that the next publish reconnects
+    publish_connection: AsyncMutex<Option<MultiplexedConnection>>,
+    channels: RedisChannels,
+    // The ID of the next channel that's listened to
+    next_channel_id: AtomicU64,
+    settings: PubSubSettings,
+    lag_counter: LagCounter,
+}
+impl fmt::Debug for RedisPubSub {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The connection info may have a password in it
+        f.debug_struct("RedisPubSub")
+            .field("address", &self.client.get_connection_info().addr)
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+impl RedisPubSub {
+    /// Creates a new Redis PubSub backend for the Redis server at the given URL (e.g. `redis://127.0.0.1:6379`). This won't connect to Redis
+    /// until something is published or subscribed to, but it will fail if the URL is invalid.
+    pub fn new(url: &str) -> Result<Self> {
+        let client =
+            Client::open(url).map_err(|err| ErrorKind::PubSubBackendFailed(err.to_string()))?;
+
+        Ok(Self {
+            client,
+            publish_connection: AsyncMutex::new(None),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            next_channel_id: AtomicU64::new(0),
+            settings: PubSubSettings::default(),
+            lag_counter: LagCounter::default(),
+        })
+    }
+    /// Sets the capacities of the channels on this instance and what subscribers do when they fall behind. This only affects channels
+    /// that this instance starts listening to after it's called, so it should be called straight away.
+    pub fn settings(mut self, settings: PubSubSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+    // Turns a receiver for the channel with the given ID into a stream for a subscriber
+    fn get_stream(
+        &self,
+        channel: &str,
+        channel_id: u64,
+        receiver: Receiver<String>,
+    ) -> BoxStream<'static, Result<String>> {
+        let stream = stream_from_receiver(
+            receiver,
+            channel,
+            self.settings.get_lag_policy(),
+            self.lag_counter.clone(),
+        );
+        Box::pin(SubscriberStream {
+            stream: Some(stream),
+            channel: channel.to_string(),
+            channel_id,
+            channels: self.channels.clone(),
+        })
+    }
+    // Gets the connection for publishing, connecting if we aren't already
+    async fn get_publish_connection(&self) -> Result<MultiplexedConnection> {
+        let mut publish_connection = self.publish_connection.lock().await;
+        if let Some(connection) = &*publish_connection {
+            // The multiplexed connection can be shared, so this is cheap
+            return Ok(connection.clone());
+        }
+        let connection = self
+            .client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|err| ErrorKind::PubSubBackendFailed(err.to_string()))?;
+        *publish_connection = Some(connection.clone());
+
+        Ok(connection)
+    }
+    // Starts listening to the given channel on Redis, passing its messages on to the given sender
+    async fn listen(&self, channel: &str, sender: Sender<String>) -> Result<JoinHandle<()>> {
+        let mut pubsub = self
+            .client
+            .get_async_connection()
+            .await
+            .map_err(|err| ErrorKind::PubSubBackendFailed(err.to_string()))?
+            .into_pubsub();
+        pubsub
+            .subscribe(channel)
+            .await
+            .map_err(|err| ErrorKind::PubSubBackendFailed(err.to_string()))?;
+
+        let channels = self.channels.clone();
+        let channel = channel.to_string();
+        Ok(tokio::spawn(async move {
+            let mut messages = pubsub.into_on_message();
+            while let Some(message) = messages.next().await {
+                // Anything that isn't a string can't have come from Diana
+                if let Ok(data) = message.get_payload::<String>() {
+                    // This will fail only if there are no receivers, but we don't care if that's the case
+                    let _ = sender.send(data);
+                }
+            }
+            // The connection's gone, so we forget the channel (ending its streams), and the next subscriber will reconnect
+            if let Ok(mut channels) = channels.lock() {
+                if matches!(channels.get(&channel), Some(redis_channel) if redis_channel.sender.same_channel(&sender))
+                {
+                    channels.remove(&channel);
+                }
+            }
+        }))
+    }
+}
+#[async_trait]
+impl PubSubBackend for RedisPubSub {
+    async fn publish(&self, channel: &str, data: String) -> Result<()> {
+        let mut connection = self.get_publish_connection().await?;
+        let mut res = redis::cmd("PUBLISH")
+            .arg(channel)
+            .arg(&data)
+            .query_async::<_, i64>(&mut connection)
+            .await;
+        // If the connection has gone (e.g. Redis restarted), we forget it and try once more with a new one
+        if matches!(&res, Err(err) if err.is_io_error() || err.is_connection_dropped()) {
+            *self.publish_connection.lock().await = None;
+            let mut connection = self.get_publish_connection().await?;
+            res = redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg(&data)
+                .query_async::<_, i64>(&mut connection)
+                .await;
+        }
+        res.map_err(|err| ErrorKind::PubSubBackendFailed(err.to_string()))?;
+
+        Ok(())
+    }
+    async fn subscribe(&self, channel: &str) -> Result<BoxStream<'static, Result<String>>> {
+        // We subscribe while we hold the lock, so that the last subscriber can't stop us listening to the channel in the meantime
+        let get_existing = || -> Result<Option<(u64, Receiver<String>)>> {
+            let channels = self
+                .channels
+                .lock()
+                .map_err(|_err| ErrorKind::MutexPoisoned("redis_pubsub".to_string()))?;
+            Ok(channels
+                .get(channel)
+                .map(|redis_channel| (redis_channel.id, redis_channel.sender.subscribe())))
+        };
+        if let Some((id, receiver)) = get_existing()? {
+            return Ok(self.get_stream(channel, id, receiver));
+        }
+
+        // We can't hold the lock while we connect, so someone else might have started listening in the meantime
+        let (sender, receiver) = create_channel(self.settings.get_capacity(channel));
+        let listener = self.listen(channel, sender.clone()).await?;
+        let mut channels = self
+            .channels
+            .lock()
+            .map_err(|_err| ErrorKind::MutexPoisoned("redis_pubsub".to_string()))?;
+        let (id, receiver) = match channels.get(channel) {
+            Some(redis_channel) => {
+                listener.abort();
+                (redis_channel.id, redis_channel.sender.subscribe())
+            }
+            None => {
+                let id = self.next_channel_id.fetch_add(1, Ordering::Relaxed);
+                channels.insert(
+                    channel.to_string(),
+                    RedisChannel {
+                        id,
+                        sender,
+                        listener,
+                    },
+                );
+                (id, receiver)
+            }
+        };
+        // The lock has to be released first, because the stream takes it when it's dropped
+        drop(channels);
+
+        Ok(self.get_stream(channel, id, receiver))
+    }
+    // This only stops this instance listening to the channel, other instances will carry on
+    async fn close(&self, channel: &str) -> Result<()> {
+        let redis_channel = self
+            .channels
+            .lock()
+            .map_err(|_err| ErrorKind::MutexPoisoned("redis_pubsub".to_string()))?
+            .remove(channel);
+        // Dropping the connection unsubscribes it, and dropping the sender ends the streams
+        if let Some(redis_channel) = redis_channel {
+            redis_channel.listener.abort();
+        }
+
+        Ok(())
+    }
+    // These are only the subscribers on this instance
+    fn lag_counts(&self) -> HashMap<String, LagCounts> {
+        self.lag_counter.get()
+    }
+}
@@ -0,0 +1,217 @@
+use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject};
+use diana::{
+    create_jwt, decode_time_str, get_jwt_secret, ApiKeyAuthenticator, AuthBlockLevel, AuthFailure,
+    AuthRequestParts, AuthState, AuthVerdict, DianaHandler, FileApiKeyStore, MemoryApiKeyStore,
+    Options, TokenExtraction,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+
+fn get_claims() -> HashMap<String, String> {
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), "service".to_string());
+    claims
+}
+
+fn get_authenticator() -> ApiKeyAuthenticator {
+    ApiKeyAuthenticator::new(Arc::new(MemoryApiKeyStore::new()))
+}
+
+fn get_diana_handler(
+    api_key_authenticator: ApiKeyAuthenticator,
+    auth_block_level: AuthBlockLevel,
+) -> DianaHandler<(), Query, EmptyMutation, EmptySubscription> {
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(auth_block_level)
+        .jwt_secret(JWT_SECRET)
+        .api_key_authenticator(api_key_authenticator)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+fn get_api_key_parts(key: &str) -> AuthRequestParts {
+    AuthRequestParts::new().with_header("X-Api-Key", key)
+}
+
+fn get_jwt_parts() -> AuthRequestParts {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let exp = decode_time_str("1m").unwrap();
+    let jwt = create_jwt(get_claims(), &secret, exp).unwrap();
+    AuthRequestParts::from_auth_header(Some(&format!("Bearer {}", jwt)))
+}
+
+// Tests for `ApiKeyAuthenticator`
+#[test]
+fn authorises_valid_key_with_its_claims() {
+    let authenticator = get_authenticator();
+    let key = authenticator
+        .create_key("billing", get_claims(), Some("billing-service"), None)
+        .unwrap();
+    let auth_state = authenticator
+        .get_key_state(authenticator.extract(&get_api_key_parts(&key)))
+        .unwrap();
+    let claims = auth_state.get_claims().unwrap();
+    assert_eq!(claims.claims, get_claims());
+    assert_eq!(claims.registered.sub, Some("billing-service".to_string()));
+    // This is what `is_authed!` uses, so it should keep working
+    let mut test_claims = HashMap::new();
+    test_claims.insert("role", "service");
+    assert!(auth_state.has_claims(test_claims));
+}
+#[test]
+fn returns_invalid_token_on_unknown_key() {
+    let authenticator = get_authenticator();
+    let auth_state = authenticator
+        .get_key_state(TokenExtraction::Found("thisisnotakey".to_string()))
+        .unwrap();
+    if !matches!(
+        auth_state,
+        AuthState::InvalidToken(AuthFailure::UnknownCredentials)
+    ) {
+        panic!(
+            "Didn't return correct AuthState variant. Expected AuthState::InvalidToken(AuthFailure::UnknownCredentials), got {:?}",
+            auth_state
+        )
+    }
+}
+#[test]
+fn returns_invalid_token_on_expired_key() {
+    let authenticator = get_authenticator();
+    let key = authenticator
+        .create_key("expired", get_claims(), None, Some(1))
+        .unwrap();
+    let auth_state = authenticator
+        .get_key_state(TokenExtraction::Found(key))
+        .unwrap();
+    assert!(auth_state.is_invalid());
+}
+#[test]
+fn returns_invalid_token_on_revoked_key() {
+    let authenticator = get_authenticator();
+    let key = authenticator
+        .create_key("revoked", get_claims(), None, None)
+        .unwrap();
+    assert!(authenticator.revoke_key(&key).unwrap());
+    let auth_state = authenticator
+        .get_key_state(TokenExtraction::Found(key))
+        .unwrap();
+    assert!(auth_state.is_invalid());
+}
+#[test]
+fn persists_keys_to_file() {
+    let path = std::env::temp_dir().join(format!("diana-api-keys-{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let key = {
+        let authenticator: ApiKeyAuthenticator =
+            ApiKeyAuthenticator::new(Arc::new(FileApiKeyStore::new(&path).unwrap()));
+        authenticator
+            .create_key("persisted", get_claims(), None, None)
+            .unwrap()
+    };
+    // The file should only ever have the key's hash
+    assert!(!fs::read_to_string(&path).unwrap().contains(&key));
+    let authenticator: ApiKeyAuthenticator =
+        ApiKeyAuthenticator::new(Arc::new(FileApiKeyStore::new(&path).unwrap()));
+    let auth_state = authenticator
+        .get_key_state(TokenExtraction::Found(key))
+        .unwrap();
+    assert!(auth_state.is_valid());
+    fs::remove_file(&path).unwrap();
+}
+
+// Tests for accepting API keys and JWTs at different block levels
+#[tokio::test]
+async fn accepts_both_for_block_unauthenticated() {
+    let authenticator = get_authenticator();
+    let key = authenticator
+        .create_key("both", get_claims(), None, None)
+        .unwrap();
+    let diana_handler = get_diana_handler(authenticator, AuthBlockLevel::BlockUnauthenticated);
+    for parts in &[get_api_key_parts(&key), get_jwt_parts()] {
+        let verdict = diana_handler.is_authed_from_parts(parts).await;
+        if !matches!(verdict, AuthVerdict::Allow(ref auth_state) if auth_state.is_valid()) {
+            panic!(
+                "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow with a valid token, got {:?}",
+                verdict
+            )
+        }
+    }
+    let verdict = diana_handler
+        .is_authed_from_parts(&get_api_key_parts("thisisnotakey"))
+        .await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::UnknownCredentials)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::UnknownCredentials), got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn blocks_api_key_for_require_jwt() {
+    let authenticator = get_authenticator();
+    let key = authenticator
+        .create_key("jwt-only", get_claims(), None, None)
+        .unwrap();
+    let diana_handler = get_diana_handler(authenticator, AuthBlockLevel::RequireJwt);
+    let verdict = diana_handler
+        .is_authed_from_parts(&get_api_key_parts(&key))
+        .await;
+    if !matches!(
+        verdict,
+        AuthVerdict::Block(AuthFailure::WrongCredentialKind)
+    ) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::WrongCredentialKind), got {:?}",
+            verdict
+        )
+    }
+    let verdict = diana_handler.is_authed_from_parts(&get_jwt_parts()).await;
+    if !matches!(verdict, AuthVerdict::Allow(_)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn blocks_jwt_for_require_api_key() {
+    let authenticator = get_authenticator();
+    let key = authenticator
+        .create_key("api-key-only", get_claims(), None, None)
+        .unwrap();
+    let diana_handler = get_diana_handler(authenticator, AuthBlockLevel::RequireApiKey);
+    let verdict = diana_handler.is_authed_from_parts(&get_jwt_parts()).await;
+    if !matches!(
+        verdict,
+        AuthVerdict::Block(AuthFailure::WrongCredentialKind)
+    ) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::WrongCredentialKind), got {:?}",
+            verdict
+        )
+    }
+    let verdict = diana_handler
+        .is_authed_from_parts(&get_api_key_parts(&key))
+        .await;
+    if !matches!(verdict, AuthVerdict::Allow(_)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        )
+    }
+}
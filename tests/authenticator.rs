@@ -0,0 +1,207 @@
+use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject};
+use async_trait::async_trait;
+use diana::{
+    errors::Result, AuthBlockLevel, AuthFailure, AuthRequestParts, AuthState, AuthToken,
+    AuthVerdict, Authenticator, Claims, DianaHandler, IntrospectionAuthenticator, Options,
+    RegisteredClaims,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+const ACTIVE_TOKEN: &str = "activetoken";
+// This is `client:secret` in base64
+const CLIENT_AUTH_HEADER: &str = "Basic Y2xpZW50OnNlY3JldA==";
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+
+fn get_diana_handler(
+    authenticator: Arc<dyn Authenticator>,
+) -> DianaHandler<(), Query, EmptyMutation, EmptySubscription> {
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .authenticator(authenticator)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+// A stand-in for an in-house session service, which looks up session IDs from a cookie
+#[derive(Debug)]
+struct SessionAuthenticator {
+    sessions: HashMap<String, String>,
+}
+#[async_trait]
+impl Authenticator for SessionAuthenticator {
+    async fn authenticate(&self, parts: &AuthRequestParts) -> Result<AuthState> {
+        let session_id = match parts.get_cookie("session") {
+            Some(session_id) => session_id,
+            None => return Ok(AuthState::NoToken),
+        };
+        match self.sessions.get(session_id) {
+            Some(user_id) => {
+                let mut claims = HashMap::new();
+                claims.insert("role".to_string(), "user".to_string());
+                Ok(AuthState::Authorised(AuthToken(
+                    Claims {
+                        exp: u64::MAX,
+                        registered: RegisteredClaims {
+                            sub: Some(user_id.to_string()),
+                            ..RegisteredClaims::default()
+                        },
+                        claims,
+                    },
+                    None,
+                )))
+            }
+            None => Ok(AuthState::InvalidToken(AuthFailure::UnknownCredentials)),
+        }
+    }
+}
+
+// A minimal stand-in for an OAuth2 authorization server's introspection endpoint
+// This only says `ACTIVE_TOKEN` is active, and only if the client authenticated properly
+async fn start_introspection_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // Read until we have the headers and the whole body
+            let mut req = Vec::new();
+            let mut req_buf = [0; 4096];
+            let req = loop {
+                let num_bytes = socket.read(&mut req_buf).await.unwrap();
+                req.extend_from_slice(&req_buf[..num_bytes]);
+                let req_str = String::from_utf8_lossy(&req).to_string();
+                if let Some((headers, body)) = req_str.split_once("\r\n\r\n") {
+                    let content_length = headers
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(|len| len.parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= content_length || num_bytes == 0 {
+                        break req_str;
+                    }
+                }
+            };
+            let client_authenticated = req.lines().any(|line| {
+                matches!(line.split_once(": "), Some((name, value)) if name.eq_ignore_ascii_case("authorization") && value == CLIENT_AUTH_HEADER)
+            });
+            let body = match (
+                client_authenticated,
+                req.contains(&format!("token={}", ACTIVE_TOKEN)),
+            ) {
+                (false, _) => {
+                    let res = "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+                    let _ = socket.write_all(res.as_bytes()).await;
+                    continue;
+                }
+                (true, true) => "{\"active\":true,\"sub\":\"user-1\",\"scope\":\"read write\",\"client_id\":\"app\",\"exp\":4102444800}",
+                (true, false) => "{\"active\":false}",
+            };
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(res.as_bytes()).await;
+        }
+    });
+
+    format!("http://{}/introspect", addr)
+}
+
+// Tests for custom authenticators
+#[tokio::test]
+async fn uses_custom_authenticator() {
+    let mut sessions = HashMap::new();
+    sessions.insert("session-1".to_string(), "user-1".to_string());
+    let diana_handler = get_diana_handler(Arc::new(SessionAuthenticator { sessions }));
+
+    let parts = AuthRequestParts::new().with_header("Cookie", "session=session-1");
+    let verdict = diana_handler.is_authed_from_parts(&parts).await;
+    if !matches!(verdict, AuthVerdict::Allow(AuthState::Authorised(ref token)) if token.0.registered.sub == Some("user-1".to_string()))
+    {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow with the session's user, got {:?}",
+            verdict
+        )
+    }
+    let parts = AuthRequestParts::new().with_header("Cookie", "session=session-2");
+    let verdict = diana_handler.is_authed_from_parts(&parts).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::UnknownCredentials)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::UnknownCredentials), got {:?}",
+            verdict
+        )
+    }
+}
+
+// Tests for `IntrospectionAuthenticator`
+#[tokio::test]
+async fn authorises_active_token_with_introspection() {
+    let endpoint = start_introspection_server().await;
+    let diana_handler = get_diana_handler(Arc::new(
+        IntrospectionAuthenticator::new(&endpoint).client_credentials("client", "secret"),
+    ));
+    let verdict = diana_handler
+        .is_authed(Some(format!("Bearer {}", ACTIVE_TOKEN)))
+        .await;
+    let auth_state = match verdict {
+        AuthVerdict::Allow(auth_state) => auth_state,
+        _ => panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        ),
+    };
+    let claims = auth_state.get_claims().unwrap();
+    assert_eq!(claims.exp, 4_102_444_800);
+    assert_eq!(claims.registered.sub, Some("user-1".to_string()));
+    assert_eq!(claims.claims.get("scope"), Some(&"read write".to_string()));
+    assert_eq!(claims.claims.get("active"), None);
+}
+#[tokio::test]
+async fn blocks_inactive_token_with_introspection() {
+    let endpoint = start_introspection_server().await;
+    let diana_handler = get_diana_handler(Arc::new(
+        IntrospectionAuthenticator::new(&endpoint).client_credentials("client", "secret"),
+    ));
+    let verdict = diana_handler.is_authed(Some("Bearer inactivetoken")).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::UnknownCredentials)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::UnknownCredentials), got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn returns_error_on_failed_introspection() {
+    let endpoint = start_introspection_server().await;
+    // Without client credentials, the endpoint will refuse to answer
+    let diana_handler = get_diana_handler(Arc::new(IntrospectionAuthenticator::new(&endpoint)));
+    let verdict = diana_handler
+        .is_authed(Some(format!("Bearer {}", ACTIVE_TOKEN)))
+        .await;
+    if !matches!(verdict, AuthVerdict::Error(_)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Error, got {:?}",
+            verdict
+        )
+    }
+}
@@ -0,0 +1,181 @@
+use async_graphql::{
+    Object as GQLObject, Request, SimpleObject as GQLSimpleObject, Subscription as GQLSubscription,
+};
+use diana::{
+    errors::GQLResult, graphql_utils::get_stream_for_channel_from_ctx, stream, AuthBlockLevel,
+    AuthRequestParts, AuthState, Channel, DianaHandler, InvalidMessagePolicy, LagPolicy,
+    MemoryPubSub, Options, PubSubBackend, PubSubSettings, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+#[derive(Serialize, Deserialize, GQLSimpleObject)]
+struct User {
+    username: String,
+}
+const USERS: Channel<User> = Channel::new("user");
+const SKIPPED_USERS: Channel<User> = Channel::new("user").on_invalid(InvalidMessagePolicy::Skip);
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+#[derive(Clone)]
+struct Mutation {}
+#[GQLObject]
+impl Mutation {
+    async fn add_user(&self, raw_ctx: &async_graphql::Context<'_>) -> GQLResult<bool> {
+        let user = User {
+            username: "user".to_string(),
+        };
+        USERS
+            .publish(raw_ctx, &user)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(true)
+    }
+}
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn users(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> impl Stream<Item = GQLResult<User>> {
+        USERS.subscribe(raw_ctx).await
+    }
+    async fn skipped_users(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> impl Stream<Item = GQLResult<User>> {
+        SKIPPED_USERS.subscribe(raw_ctx).await
+    }
+    // This is what subscriptions looked like before typed channels
+    async fn raw_users(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> impl Stream<Item = GQLResult<String>> {
+        let stream_result =
+            get_stream_for_channel_from_ctx("user", raw_ctx).map_err(|err| err.to_string());
+        stream! {
+            let stream = stream_result?;
+            for await message in stream {
+                yield Ok(message);
+            }
+        }
+    }
+}
+
+fn get_diana_handler(pubsub: Arc<MemoryPubSub>) -> DianaHandler<(), Query, Mutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .pubsub_backend(pubsub)
+        .schema(Query {}, Mutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+// Gets the next response on a subscription, or `None` if there isn't one yet
+async fn get_next<S: Stream<Item = async_graphql::Response> + Unpin>(
+    stream: &mut S,
+) -> Option<async_graphql::Response> {
+    tokio::time::timeout(Duration::from_millis(100), stream.next())
+        .await
+        .ok()
+        .flatten()
+}
+// Subscribes with the given query, and then publishes the given messages on the channel, returning everything the subscription gets
+async fn get_responses(query: &str, messages: &[&str]) -> Vec<String> {
+    get_responses_with_pubsub(Arc::new(MemoryPubSub::new()), query, messages).await
+}
+async fn get_responses_with_pubsub(
+    pubsub: Arc<MemoryPubSub>,
+    query: &str,
+    messages: &[&str],
+) -> Vec<String> {
+    let diana_handler = get_diana_handler(pubsub.clone());
+    let data = diana_handler.get_subscription_data(AuthState::NoToken, &AuthRequestParts::new());
+    let mut stream = diana_handler
+        .schema_for_subscriptions
+        .execute_stream_with_session_data(Request::new(query), Arc::new(data));
+    // The subscription only starts listening once it's first polled
+    assert!(get_next(&mut stream).await.is_none());
+
+    for message in messages {
+        pubsub.publish("user", message.to_string()).await.unwrap();
+    }
+    let mut responses = Vec::new();
+    while let Some(res) = get_next(&mut stream).await {
+        responses.push(match res.errors.first() {
+            Some(err) => err.message.clone(),
+            None => res.data.to_string(),
+        });
+    }
+    responses
+}
+
+#[tokio::test]
+async fn deserializes_messages() {
+    let responses = get_responses(
+        "subscription { users { username } }",
+        &["{\"username\": \"user\"}"],
+    )
+    .await;
+    assert_eq!(responses, vec!["{users: {username: \"user\"}}"]);
+}
+#[tokio::test]
+async fn ends_subscriptions_on_invalid_messages_by_default() {
+    let responses = get_responses(
+        "subscription { users { username } }",
+        &["not json", "{\"username\": \"user\"}"],
+    )
+    .await;
+    assert_eq!(responses.len(), 1);
+    assert!(responses[0].starts_with("couldn't deserialize message on channel 'user'"));
+}
+#[tokio::test]
+async fn skips_invalid_messages_if_configured() {
+    let responses = get_responses(
+        "subscription { skippedUsers { username } }",
+        &["{\"name\": \"user\"}", "{\"username\": \"user\"}"],
+    )
+    .await;
+    assert_eq!(responses, vec!["{skippedUsers: {username: \"user\"}}"]);
+}
+#[tokio::test]
+async fn drops_lag_errors_from_untyped_streams() {
+    let pubsub = MemoryPubSub::new().settings(
+        PubSubSettings::new()
+            .capacity(1)
+            .lag_policy(LagPolicy::Error),
+    );
+    let responses = get_responses_with_pubsub(
+        Arc::new(pubsub),
+        "subscription { rawUsers }",
+        &["1", "2", "3"],
+    )
+    .await;
+    assert_eq!(responses, vec!["{rawUsers: \"3\"}"]);
+}
+#[tokio::test]
+async fn needs_publisher_to_publish() {
+    let diana_handler = get_diana_handler(Arc::new(MemoryPubSub::new()));
+    let res = diana_handler
+        .schema_without_subscriptions
+        .execute(Request::new("mutation { addUser }"))
+        .await;
+    assert_eq!(
+        res.errors[0].message,
+        "required graphql context element 'publisher' not found"
+    );
+}
@@ -0,0 +1,103 @@
+use diana::{claim_predicate, is_authed, AuthState, AuthToken, ClaimPredicate, Claims};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TestClaims {
+    role: String,
+    permissions: Vec<String>,
+    scope: String,
+}
+
+fn get_auth_state(role: &str) -> AuthState<TestClaims> {
+    AuthState::Authorised(AuthToken(
+        Claims {
+            exp: u64::MAX,
+            registered: Default::default(),
+            claims: TestClaims {
+                role: role.to_string(),
+                permissions: vec!["orders:read".to_string(), "orders:write".to_string()],
+                scope: "profile email".to_string(),
+            },
+        },
+        None,
+    ))
+}
+
+// Tests for single conditions
+#[test]
+fn matches_equality() {
+    let auth_state = get_auth_state("editor");
+    assert!(is_authed!(auth_state, "role" == "editor"));
+    assert!(!is_authed!(auth_state, "role" == "admin"));
+    assert!(is_authed!(auth_state, "role" != "admin"));
+    // The old syntax should still work
+    assert!(is_authed!(auth_state, { "role" => "editor" }));
+}
+#[test]
+fn matches_any_of() {
+    let auth_state = get_auth_state("editor");
+    assert!(is_authed!(auth_state, "role" in ["admin", "editor"]));
+    assert!(!is_authed!(auth_state, "role" in ["admin", "owner"]));
+}
+#[test]
+fn matches_list_membership() {
+    let auth_state = get_auth_state("editor");
+    assert!(is_authed!(auth_state, "permissions" contains "orders:write"));
+    assert!(!is_authed!(auth_state, "permissions" contains "orders:delete"));
+    // Space-separated strings should be treated as lists
+    assert!(is_authed!(auth_state, "scope" contains "email"));
+    assert!(!is_authed!(auth_state, "scope" contains "mail"));
+}
+#[test]
+fn matches_wildcards_and_prefixes() {
+    let auth_state = get_auth_state("editor");
+    assert!(is_authed!(auth_state, "permissions" starts_with "orders:"));
+    assert!(!is_authed!(auth_state, "permissions" starts_with "users:"));
+    assert!(is_authed!(auth_state, "permissions" matches "orders:*"));
+    assert!(is_authed!(auth_state, "role" matches "*dit*"));
+    assert!(is_authed!(auth_state, "role" matches "e*r"));
+    assert!(!is_authed!(auth_state, "role" matches "edit"));
+    assert!(!is_authed!(auth_state, "role" matches "*x*"));
+}
+#[test]
+fn matches_existence() {
+    let auth_state = get_auth_state("editor");
+    assert!(is_authed!(auth_state, "role" exists));
+    assert!(!is_authed!(auth_state, "tenant" exists));
+    assert!(is_authed!(auth_state, not("tenant" exists)));
+}
+// Tests for combinations
+#[test]
+fn matches_nested_combinations() {
+    let auth_state = get_auth_state("editor");
+    assert!(is_authed!(
+        auth_state,
+        all(
+            any("role" == "admin", "permissions" contains "orders:write"),
+            not("role" in ["viewer", "guest"]),
+        )
+    ));
+    assert!(!is_authed!(
+        auth_state,
+        all(("role" == "editor"), any("role" == "admin", not("scope" contains "profile")))
+    ));
+    assert!(is_authed!(auth_state, all()));
+    assert!(!is_authed!(auth_state, any()));
+}
+#[test]
+fn builds_predicates_with_macro() {
+    let role = "admin".to_string();
+    assert_eq!(
+        claim_predicate!(any("role" == role, not("scope" exists))),
+        ClaimPredicate::Any(vec![
+            ClaimPredicate::Equals("role".to_string(), "admin".to_string()),
+            ClaimPredicate::Not(Box::new(ClaimPredicate::Exists("scope".to_string()))),
+        ])
+    );
+}
+#[test]
+fn never_matches_without_valid_token() {
+    let auth_state: AuthState<TestClaims> = AuthState::NoToken;
+    assert!(!is_authed!(auth_state, not("role" exists)));
+    assert!(!auth_state.satisfies(&ClaimPredicate::All(Vec::new())));
+}
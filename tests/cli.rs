@@ -0,0 +1,81 @@
+#![cfg(feature = "cli")]
+
+use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject};
+use diana::{
+    cli::{get_sdl, Command},
+    get_jwt_secret, validate_and_decode_jwt, AuthBlockLevel, Claims, Options,
+};
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+fn run(args: &[&str]) -> diana::errors::Result<String> {
+    Command::parse_from([&["diana"], args].concat())?.execute(None)
+}
+
+// Tests for the commands
+#[test]
+fn generates_secrets_usable_with_get_jwt_secret() {
+    let secret = run(&["secret"]).unwrap();
+    assert_eq!(base64::decode(&secret).unwrap().len(), 64);
+    assert!(get_jwt_secret(secret).is_ok());
+    assert_ne!(run(&["secret"]).unwrap(), run(&["secret"]).unwrap());
+}
+#[test]
+fn mints_and_decodes_publisher_tokens() {
+    let token = run(&[
+        "token",
+        "--secret",
+        JWT_SECRET,
+        "--publisher",
+        "--claim",
+        "instance=eu-1",
+        "--exp",
+        "1w",
+    ])
+    .unwrap();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let claims: Claims = validate_and_decode_jwt(&token, &secret).unwrap();
+    assert_eq!(
+        claims.claims.get("role"),
+        Some(&"graphql_server".to_string())
+    );
+    assert_eq!(claims.claims.get("instance"), Some(&"eu-1".to_string()));
+
+    let output = run(&["decode", &token, "--secret", JWT_SECRET]).unwrap();
+    assert!(output.starts_with("The token is valid."));
+    assert!(output.contains("\"role\": \"graphql_server\""));
+}
+#[test]
+fn rejects_invalid_claims_and_tokens() {
+    assert!(Command::parse_from(["diana", "token", "--claim", "nokey"]).is_err());
+    let other_secret = run(&["secret"]).unwrap();
+    let token = run(&["token", "--secret", &other_secret]).unwrap();
+    assert!(run(&["decode", &token, "--secret", JWT_SECRET]).is_err());
+}
+
+// Tests for printing the schema
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn api_version(&self) -> &str {
+        "0.1.0"
+    }
+}
+
+#[test]
+fn prints_sdl_only_with_a_schema() {
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let sdl = Command::parse_from(["diana", "sdl"])
+        .unwrap()
+        .execute(Some(get_sdl(&opts)))
+        .unwrap();
+    assert!(sdl.contains("apiVersion: String!"));
+    assert!(run(&["sdl"]).is_err());
+}
@@ -0,0 +1,171 @@
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use diana::{
+    create_jwt_with_clock, decode_time_str_with_clock, errors::ErrorKind, get_jwt_secret,
+    validate_and_decode_jwt_with_validation, ApiKeyAuthenticator, AuthFailure, AuthState, Claims,
+    FakeClock, JWTValidation, MemoryApiKeyStore, MemoryRefreshTokenStore, MemoryRevocationStore,
+    ParsedDuration, RefreshTokenManager, RegisteredClaims, RevocationStore, TokenExtraction,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+// All the tests start in 2001, long before the system clock, so nothing here depends on the real time
+fn get_clock() -> Arc<FakeClock> {
+    let start = NaiveDate::from_ymd_opt(2001, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    Arc::new(FakeClock::new(Utc.from_utc_datetime(&start)))
+}
+
+fn get_jwt(clock: &FakeClock, registered_claims: RegisteredClaims) -> String {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    create_jwt_with_clock(
+        HashMap::<String, String>::new(),
+        registered_claims,
+        &secret,
+        Duration::minutes(1),
+        clock,
+    )
+    .unwrap()
+}
+
+fn is_valid(jwt: &str, validation: &JWTValidation) -> bool {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    validate_and_decode_jwt_with_validation::<HashMap<String, String>>(jwt, &secret, validation)
+        .is_some()
+}
+
+// Tests for JWTs
+#[test]
+fn creates_jwt_with_clock() {
+    let clock = get_clock();
+    let jwt = get_jwt(&clock, RegisteredClaims::default());
+    let validation = JWTValidation {
+        clock: clock.clone(),
+        ..JWTValidation::default()
+    };
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let claims: Claims =
+        validate_and_decode_jwt_with_validation(&jwt, &secret, &validation).unwrap();
+    assert_eq!(claims.registered.iat, Some(978_307_200));
+    assert_eq!(claims.exp, 978_307_260);
+}
+#[test]
+fn decodes_time_strs_with_clock() {
+    let clock = get_clock();
+    assert_eq!(
+        decode_time_str_with_clock("1m", clock.as_ref()).unwrap(),
+        978_307_260
+    );
+    let datetime = ParsedDuration::parse("P1M")
+        .unwrap()
+        .calendar_aware(true)
+        .from_now_with_clock(clock.as_ref())
+        .unwrap();
+    assert_eq!(datetime.timestamp(), 978_307_200 + 31 * 24 * 60 * 60);
+}
+#[test]
+fn expires_jwt_with_clock_and_leeway() {
+    let clock = get_clock();
+    let jwt = get_jwt(&clock, RegisteredClaims::default());
+    let validation = JWTValidation {
+        clock: clock.clone(),
+        ..JWTValidation::default()
+    };
+    let lenient_validation = JWTValidation {
+        leeway: 30,
+        clock: clock.clone(),
+        ..JWTValidation::default()
+    };
+    assert!(is_valid(&jwt, &validation));
+    clock.advance(Duration::seconds(61));
+    assert!(!is_valid(&jwt, &validation));
+    assert!(is_valid(&jwt, &lenient_validation));
+    clock.advance(Duration::seconds(30));
+    assert!(!is_valid(&jwt, &lenient_validation));
+}
+#[test]
+fn checks_nbf_and_iat_with_clock() {
+    let clock = get_clock();
+    let now = 978_307_200;
+    let validation = JWTValidation {
+        clock: clock.clone(),
+        ..JWTValidation::default()
+    };
+    let future_nbf_jwt = get_jwt(
+        &clock,
+        RegisteredClaims {
+            nbf: Some(now + 10),
+            ..RegisteredClaims::default()
+        },
+    );
+    let future_iat_jwt = get_jwt(
+        &clock,
+        RegisteredClaims {
+            iat: Some(now + 10),
+            ..RegisteredClaims::default()
+        },
+    );
+    assert!(!is_valid(&future_nbf_jwt, &validation));
+    assert!(!is_valid(&future_iat_jwt, &validation));
+    clock.advance(Duration::seconds(10));
+    assert!(is_valid(&future_nbf_jwt, &validation));
+    assert!(is_valid(&future_iat_jwt, &validation));
+}
+// Tests for stateful systems
+#[test]
+fn expires_refresh_tokens_with_clock() {
+    let clock = get_clock();
+    let manager: RefreshTokenManager = RefreshTokenManager::new(
+        get_jwt_secret(JWT_SECRET.to_string()).unwrap(),
+        Arc::new(MemoryRefreshTokenStore::new().clock(clock.clone())),
+    )
+    .refresh_token_lifetime(std::time::Duration::from_secs(60))
+    .clock(clock.clone());
+    let token_pair = manager.issue(HashMap::new(), Some("user-1")).unwrap();
+    assert_eq!(token_pair.refresh_token_exp, 978_307_260);
+    // The token should still be stored even though it's long expired by the system clock
+    let token_pair = manager.refresh(&token_pair.refresh_token).unwrap();
+    clock.advance(Duration::seconds(61));
+    let res = manager.refresh(&token_pair.refresh_token);
+    if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::InvalidRefreshToken)) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::InvalidRefreshToken, got {:?}",
+            res
+        )
+    }
+}
+#[test]
+fn forgets_revocations_and_expires_api_keys_with_clock() {
+    let clock = get_clock();
+    let store = MemoryRevocationStore::new().clock(clock.clone());
+    store.revoke_token("token-1", 978_307_260).unwrap();
+    // Revoking another token prunes the expired ones
+    store.revoke_token("token-2", u64::MAX).unwrap();
+    assert!(store.is_token_revoked("token-1").unwrap());
+    clock.advance(Duration::seconds(61));
+    store.revoke_token("token-2", u64::MAX).unwrap();
+    assert!(!store.is_token_revoked("token-1").unwrap());
+
+    let authenticator: ApiKeyAuthenticator =
+        ApiKeyAuthenticator::new(Arc::new(MemoryApiKeyStore::new())).clock(clock.clone());
+    let key = authenticator
+        .create_key("test", HashMap::new(), None, Some(978_307_400))
+        .unwrap();
+    let state = authenticator
+        .get_key_state(TokenExtraction::Found(key.clone()))
+        .unwrap();
+    assert!(matches!(state, AuthState::Authorised(_)));
+    clock.advance(Duration::days(1));
+    let state = authenticator
+        .get_key_state(TokenExtraction::Found(key))
+        .unwrap();
+    if !matches!(state, AuthState::InvalidToken(AuthFailure::Expired)) {
+        panic!(
+            "Didn't return correct AuthState. Expected AuthState::InvalidToken(AuthFailure::Expired), got {:?}",
+            state
+        )
+    }
+}
@@ -1,9 +1,14 @@
 use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject};
 use diana::{
-    create_jwt, decode_time_str, get_jwt_secret, AuthBlockLevel, AuthVerdict, DianaHandler,
-    DianaResponse, Options, SysSchema,
+    create_jwt, create_jwt_with_registered_claims, decode_time_str, get_jwt_secret,
+    graphql_utils::get_auth_data_from_ctx, AuthBlockLevel, AuthFailure, AuthRequestParts,
+    AuthState, AuthVerdict, CsrfConfig, DianaHandler, DianaResponse, MemoryRevocationStore,
+    Options, OptionsBuilder, RegisteredClaims, RevocationStore, SysSchema, TokenExtractor,
+    TokenSource,
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Clone)]
 struct Context {
@@ -19,6 +24,23 @@ impl Query {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct TestClaims {
+    role: String,
+    permissions: Vec<String>,
+}
+
+#[derive(Clone)]
+struct TypedClaimsQuery {}
+#[GQLObject]
+impl TypedClaimsQuery {
+    async fn permissions(&self, raw_ctx: &async_graphql::Context<'_>) -> Option<Vec<String>> {
+        let auth_state: &AuthState<TestClaims> = get_auth_data_from_ctx(raw_ctx).ok()?;
+        let claims = auth_state.get_claims().ok()?;
+        Some(claims.claims.permissions.clone())
+    }
+}
+
 const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
 const SIMPLE_QUERY: &str = "{\"query\": \"query { query }\"}";
 const SIMPLE_QUERY_RES: &str = "{\"data\":{\"query\":true}}";
@@ -99,10 +121,10 @@ fn returns_valid_handler_with_no_subscriptions() {
     }
 }
 // Tests for `.is_authed()`
-#[test]
-fn allows_user_if_token_valid_for_block_unauthenticated_block_state() {
+#[tokio::test]
+async fn allows_user_if_token_valid_for_block_unauthenticated_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::BlockUnauthenticated)).unwrap();
-    let verdict = diana_handler.is_authed(get_valid_auth_header());
+    let verdict = diana_handler.is_authed(get_valid_auth_header()).await;
     if !matches!(verdict, AuthVerdict::Allow(_)) {
         panic!(
             "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
@@ -110,32 +132,32 @@ fn allows_user_if_token_valid_for_block_unauthenticated_block_state() {
         )
     }
 }
-#[test]
-fn blocks_user_if_token_invalid_for_block_unauthenticated_block_state() {
+#[tokio::test]
+async fn blocks_user_if_token_invalid_for_block_unauthenticated_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::BlockUnauthenticated)).unwrap();
-    let verdict = diana_handler.is_authed(get_invalid_auth_header());
-    if !matches!(verdict, AuthVerdict::Block) {
+    let verdict = diana_handler.is_authed(get_invalid_auth_header()).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::Malformed)) {
         panic!(
-            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block, got {:?}",
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::Malformed), got {:?}",
             verdict
         )
     }
 }
-#[test]
-fn blocks_user_if_token_missing_for_block_unauthenticated_block_state() {
+#[tokio::test]
+async fn blocks_user_if_token_missing_for_block_unauthenticated_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::BlockUnauthenticated)).unwrap();
-    let verdict = diana_handler.is_authed(Option::<String>::None);
-    if !matches!(verdict, AuthVerdict::Block) {
+    let verdict = diana_handler.is_authed(Option::<String>::None).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::MissingToken)) {
         panic!(
-            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block, got {:?}",
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::MissingToken), got {:?}",
             verdict
         )
     }
 }
-#[test]
-fn allows_user_if_token_valid_for_allow_all_block_state() {
+#[tokio::test]
+async fn allows_user_if_token_valid_for_allow_all_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowAll)).unwrap();
-    let verdict = diana_handler.is_authed(get_valid_auth_header());
+    let verdict = diana_handler.is_authed(get_valid_auth_header()).await;
     if !matches!(verdict, AuthVerdict::Allow(_)) {
         panic!(
             "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
@@ -143,10 +165,10 @@ fn allows_user_if_token_valid_for_allow_all_block_state() {
         )
     }
 }
-#[test]
-fn allow_user_if_token_invalid_for_allow_all_block_state() {
+#[tokio::test]
+async fn allow_user_if_token_invalid_for_allow_all_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowAll)).unwrap();
-    let verdict = diana_handler.is_authed(get_invalid_auth_header());
+    let verdict = diana_handler.is_authed(get_invalid_auth_header()).await;
     if !matches!(verdict, AuthVerdict::Allow(_)) {
         panic!(
             "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
@@ -154,10 +176,10 @@ fn allow_user_if_token_invalid_for_allow_all_block_state() {
         )
     }
 }
-#[test]
-fn allow_user_if_token_missing_for_allow_all_block_state() {
+#[tokio::test]
+async fn allow_user_if_token_missing_for_allow_all_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowAll)).unwrap();
-    let verdict = diana_handler.is_authed(Option::<String>::None);
+    let verdict = diana_handler.is_authed(Option::<String>::None).await;
     if !matches!(verdict, AuthVerdict::Allow(_)) {
         panic!(
             "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
@@ -165,10 +187,10 @@ fn allow_user_if_token_missing_for_allow_all_block_state() {
         )
     }
 }
-#[test]
-fn allows_user_if_token_valid_for_allow_missing_block_state() {
+#[tokio::test]
+async fn allows_user_if_token_valid_for_allow_missing_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowMissing)).unwrap();
-    let verdict = diana_handler.is_authed(get_valid_auth_header());
+    let verdict = diana_handler.is_authed(get_valid_auth_header()).await;
     if !matches!(verdict, AuthVerdict::Allow(_)) {
         panic!(
             "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
@@ -176,27 +198,225 @@ fn allows_user_if_token_valid_for_allow_missing_block_state() {
         )
     }
 }
-#[test]
-fn blocks_user_if_token_invalid_for_allow_missing_block_state() {
+#[tokio::test]
+async fn blocks_user_if_token_invalid_for_allow_missing_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowMissing)).unwrap();
-    let verdict = diana_handler.is_authed(get_invalid_auth_header());
-    if !matches!(verdict, AuthVerdict::Block) {
+    let verdict = diana_handler.is_authed(get_invalid_auth_header()).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::Malformed)) {
         panic!(
-            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block, got {:?}",
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::Malformed), got {:?}",
             verdict
         )
     }
 }
-#[test]
-fn allows_user_if_token_missing_for_allow_missing_block_state() {
+#[tokio::test]
+async fn allows_user_if_token_missing_for_allow_missing_block_state() {
     let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowMissing)).unwrap();
-    let verdict = diana_handler.is_authed(Option::<String>::None);
+    let verdict = diana_handler.is_authed(Option::<String>::None).await;
+    if !matches!(verdict, AuthVerdict::Allow(_)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn blocks_user_if_token_for_other_audience() {
+    let opts = Options::builder()
+        .ctx(Context {
+            prop: "connection".to_string(),
+        })
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .jwt_audiences(&["orders"])
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let exp = decode_time_str("1m").unwrap();
+    let get_auth_header = |aud: &str| {
+        let registered_claims = RegisteredClaims {
+            aud: Some(vec![aud.to_string()]),
+            ..RegisteredClaims::default()
+        };
+        let jwt = create_jwt_with_registered_claims(
+            HashMap::<String, String>::new(),
+            registered_claims,
+            &secret,
+            exp,
+        )
+        .unwrap();
+        Some("Bearer ".to_string() + &jwt)
+    };
+
+    let verdict = diana_handler.is_authed(get_auth_header("orders")).await;
     if !matches!(verdict, AuthVerdict::Allow(_)) {
         panic!(
             "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
             verdict
         )
     }
+    let verdict = diana_handler.is_authed(get_auth_header("billing")).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidAudience)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::InvalidAudience), got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn blocks_user_if_token_revoked() {
+    let revocation_store = Arc::new(MemoryRevocationStore::new());
+    let get_opts = |auth_block_level| {
+        Options::builder()
+            .ctx(Context {
+                prop: "connection".to_string(),
+            })
+            .auth_block_state(auth_block_level)
+            .jwt_secret(JWT_SECRET)
+            .revocation_store(revocation_store.clone())
+            .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+            .finish()
+            .unwrap()
+    };
+    let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowMissing)).unwrap();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let exp = decode_time_str("1m").unwrap();
+    let registered_claims = RegisteredClaims {
+        jti: Some("token-1".to_string()),
+        ..RegisteredClaims::default()
+    };
+    let jwt = create_jwt_with_registered_claims(
+        HashMap::<String, String>::new(),
+        registered_claims,
+        &secret,
+        exp,
+    )
+    .unwrap();
+    let auth_header = Some("Bearer ".to_string() + &jwt);
+
+    let verdict = diana_handler.is_authed(auth_header.clone()).await;
+    if !matches!(verdict, AuthVerdict::Allow(_)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        )
+    }
+    revocation_store.revoke_token("token-1", exp).unwrap();
+    let verdict = diana_handler.is_authed(auth_header.clone()).await;
+    if !matches!(verdict, AuthVerdict::Revoked) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Revoked, got {:?}",
+            verdict
+        )
+    }
+    // Revoked tokens should still be let through with the state attached if we allow everything
+    let diana_handler = DianaHandler::new(get_opts(AuthBlockLevel::AllowAll)).unwrap();
+    let verdict = diana_handler.is_authed(auth_header).await;
+    if !matches!(verdict, AuthVerdict::Allow(ref auth_state) if auth_state.is_revoked()) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow with a revoked token, got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn allows_user_with_token_in_cookie() {
+    let opts = Options::builder()
+        .ctx(Context {
+            prop: "connection".to_string(),
+        })
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .token_extractor(TokenExtractor::new().with_source(TokenSource::Cookie {
+            name: "token".to_string(),
+            csrf: Some(CsrfConfig::default()),
+        }))
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let jwt = get_valid_auth_header().unwrap().replace("Bearer ", "");
+    let parts = AuthRequestParts::new()
+        .with_header("Cookie", &format!("token={}; csrf_token=xyz", jwt))
+        .with_header("X-CSRF-Token", "xyz");
+
+    let verdict = diana_handler.is_authed_from_parts(&parts).await;
+    if !matches!(verdict, AuthVerdict::Allow(ref auth_state) if auth_state.is_valid()) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow with a valid token, got {:?}",
+            verdict
+        )
+    }
+    // The `Authorization` header shouldn't be looked at anymore
+    let verdict = diana_handler.is_authed(get_valid_auth_header()).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::MissingToken)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::MissingToken), got {:?}",
+            verdict
+        )
+    }
+}
+#[tokio::test]
+async fn decodes_custom_claims_type() {
+    let opts = OptionsBuilder::<
+        Context,
+        TypedClaimsQuery,
+        EmptyMutation,
+        EmptySubscription,
+        TestClaims,
+    >::new()
+    .ctx(Context {
+        prop: "connection".to_string(),
+    })
+    .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+    .jwt_secret(JWT_SECRET)
+    .schema(TypedClaimsQuery {}, EmptyMutation {}, EmptySubscription {})
+    .finish()
+    .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let exp = decode_time_str("1m").unwrap();
+    let claims = TestClaims {
+        role: "editor".to_string(),
+        permissions: vec!["read".to_string(), "write".to_string()],
+    };
+    let auth_header = "Bearer ".to_string() + &create_jwt(claims.clone(), &secret, exp).unwrap();
+
+    let verdict = diana_handler.is_authed(Some(auth_header.clone())).await;
+    match verdict {
+        AuthVerdict::Allow(auth_state) => {
+            assert_eq!(auth_state.get_claims().unwrap().claims, claims);
+            assert!(auth_state.has_claims([("role", "editor")].iter().cloned().collect()));
+            assert!(!auth_state.has_claims([("role", "admin")].iter().cloned().collect()));
+        }
+        _ => panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        ),
+    }
+    // The typed claims should make it all the way to the resolvers
+    let res = diana_handler
+        .run_stateless_req(
+            SysSchema::WithoutSubscriptions,
+            "{\"query\": \"query { permissions }\"}".to_string(),
+            Some(auth_header),
+            None,
+        )
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val == "{\"data\":{\"permissions\":[\"read\",\"write\"]}}")
+    {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+    // Tokens with claims that don't fit the type should be treated as invalid
+    let verdict = diana_handler.is_authed(get_valid_auth_header()).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidClaims)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::InvalidClaims), got {:?}",
+            verdict
+        )
+    }
 }
 // Tests for `.run_stateless_req()` (internal function that underlies other simpler querying logic)
 #[tokio::test]
@@ -240,7 +460,7 @@ async fn returns_blocked_on_invalid_auth_and_valid_body() {
             None,
         )
         .await;
-    if !matches!(res.clone(), DianaResponse::Blocked) {
+    if !matches!(res.clone(), DianaResponse::Blocked(AuthFailure::Malformed)) {
         panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked, got {:?}", res)
     }
 }
@@ -255,7 +475,7 @@ async fn returns_blocked_on_invalid_auth_and_invalid_body() {
             None,
         )
         .await;
-    if !matches!(res.clone(), DianaResponse::Blocked) {
+    if !matches!(res.clone(), DianaResponse::Blocked(AuthFailure::Malformed)) {
         panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked, got {:?}", res)
     }
 }
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use diana::{
+    create_jwt, errors::ErrorKind, get_jwt_secret, validate_and_decode_jwt, Claims, ParsedDuration,
+};
+use std::collections::HashMap;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+fn datetime(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+    let naive = NaiveDate::from_ymd_opt(year, month, day)
+        .unwrap()
+        .and_hms_opt(hour, min, sec)
+        .unwrap();
+    Utc.from_utc_datetime(&naive)
+}
+
+// Tests for parsing
+#[test]
+fn parses_shorthand_and_iso_durations() {
+    let expected = Duration::days(32) + Duration::hours(3) + Duration::minutes(4);
+    assert_eq!(
+        ParsedDuration::parse("1M2d3h4m")
+            .unwrap()
+            .to_chrono()
+            .unwrap(),
+        expected
+    );
+    assert_eq!(
+        ParsedDuration::parse("P1M2DT3H4M")
+            .unwrap()
+            .to_chrono()
+            .unwrap(),
+        expected
+    );
+    assert_eq!(
+        "P1Y2W".parse::<ParsedDuration>().unwrap().to_std().unwrap(),
+        std::time::Duration::from_secs((365 + 14) * 24 * 60 * 60)
+    );
+    assert_eq!(
+        ParsedDuration::parse("PT90S").unwrap().to_std().unwrap(),
+        std::time::Duration::from_secs(90)
+    );
+}
+#[test]
+fn returns_errors_with_positions() {
+    let cases = vec![
+        ("m", ErrorKind::DurationMissingNumber(0)),
+        ("1h30", ErrorKind::DurationMissingIndicator(2)),
+        (
+            "1q",
+            ErrorKind::InvalidDatetimeIntervalIndicator("q".to_string(), 1),
+        ),
+        ("", ErrorKind::EmptyDuration),
+        ("P", ErrorKind::EmptyDuration),
+        (
+            "P1D1Y",
+            ErrorKind::MisplacedDurationDesignator("Y".to_string(), 4),
+        ),
+        (
+            "P1DT",
+            ErrorKind::MisplacedDurationDesignator("T".to_string(), 3),
+        ),
+        (
+            "PT1D",
+            ErrorKind::MisplacedDurationDesignator("D".to_string(), 3),
+        ),
+        (
+            "PT0.5S",
+            ErrorKind::InvalidDatetimeIntervalIndicator(".".to_string(), 3),
+        ),
+        ("99999999999999999999s", ErrorKind::DurationOutOfRange),
+        ("9999999999999999y", ErrorKind::DurationOutOfRange),
+    ];
+    for (time_str, expected) in cases {
+        let err = ParsedDuration::parse(time_str)
+            .and_then(|duration| duration.to_chrono())
+            .unwrap_err();
+        // `ErrorKind` doesn't implement `PartialEq`, so we compare the messages
+        assert_eq!(
+            err.kind().to_string(),
+            expected.to_string(),
+            "wrong error for {:?}",
+            time_str
+        );
+    }
+}
+// Tests for datetime arithmetic
+#[test]
+fn uses_fixed_months_by_default() {
+    let start = datetime(2024, 1, 31, 12, 0, 0);
+    let end = ParsedDuration::parse("1M").unwrap().after(start).unwrap();
+    assert_eq!(end, datetime(2024, 3, 1, 12, 0, 0));
+}
+#[test]
+fn uses_calendar_months_when_asked() {
+    let start = datetime(2024, 1, 31, 12, 0, 0);
+    let duration = ParsedDuration::parse("P1MT1H")
+        .unwrap()
+        .calendar_aware(true);
+    // There's no February 31st, so this should go to the end of February (which is the 29th in a leap year)
+    assert_eq!(
+        duration.after(start).unwrap(),
+        datetime(2024, 2, 29, 13, 0, 0)
+    );
+    let duration = ParsedDuration::parse("1y").unwrap().calendar_aware(true);
+    assert_eq!(
+        duration.after(start).unwrap(),
+        datetime(2025, 1, 31, 12, 0, 0)
+    );
+    let duration = ParsedDuration::parse("11M").unwrap().calendar_aware(true);
+    assert_eq!(
+        duration.after(start).unwrap(),
+        datetime(2024, 12, 31, 12, 0, 0)
+    );
+}
+// Tests for creating JWTs with durations
+#[test]
+fn creates_jwt_with_duration() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let expected_exp = (Utc::now() + Duration::hours(1)).timestamp() as u64;
+    for jwt in [
+        create_jwt(
+            HashMap::<String, String>::new(),
+            &secret,
+            ParsedDuration::parse("1h").unwrap(),
+        ),
+        create_jwt(
+            HashMap::<String, String>::new(),
+            &secret,
+            Duration::hours(1),
+        ),
+    ] {
+        let claims: Claims = validate_and_decode_jwt(&jwt.unwrap(), &secret).unwrap();
+        // Allow for the clock ticking over between creating the tokens and checking them
+        assert!(claims.exp >= expected_exp && claims.exp <= expected_exp + 1);
+    }
+}
@@ -0,0 +1,162 @@
+use diana::{AuthRequestParts, CsrfConfig, TokenExtraction, TokenExtractor, TokenSource};
+
+fn get_cookie_extractor() -> TokenExtractor {
+    TokenExtractor::new().with_source(TokenSource::Cookie {
+        name: "token".to_string(),
+        csrf: Some(CsrfConfig::default()),
+    })
+}
+
+// Tests for the `Authorization` header
+#[test]
+fn extracts_bearer_token_case_insensitively() {
+    let extractor = TokenExtractor::default();
+    for header in &["Bearer abc", "bearer abc", "BEARER  abc "] {
+        let parts = AuthRequestParts::new().with_header("authorization", header);
+        assert_eq!(
+            extractor.extract(&parts),
+            TokenExtraction::Found("abc".to_string())
+        );
+    }
+}
+#[test]
+fn returns_missing_on_wrong_scheme() {
+    let extractor = TokenExtractor::default();
+    let parts = AuthRequestParts::from_auth_header(Some("Basic abc"));
+    assert_eq!(extractor.extract(&parts), TokenExtraction::Missing);
+    let parts = AuthRequestParts::from_auth_header(Some("Bearer"));
+    assert_eq!(extractor.extract(&parts), TokenExtraction::Missing);
+}
+// Tests for custom headers
+#[test]
+fn extracts_token_from_custom_header() {
+    let extractor =
+        TokenExtractor::new().with_source(TokenSource::Header("X-Api-Token".to_string()));
+    let parts = AuthRequestParts::new().with_header("x-api-token", "abc");
+    assert_eq!(
+        extractor.extract(&parts),
+        TokenExtraction::Found("abc".to_string())
+    );
+}
+// Tests for cookies
+#[test]
+fn extracts_token_from_cookie_with_valid_csrf() {
+    let parts = AuthRequestParts::new()
+        .with_header("Cookie", "other=1; token=abc")
+        .with_header("Cookie", "csrf_token=xyz")
+        .with_header("X-CSRF-Token", "xyz");
+    assert_eq!(
+        get_cookie_extractor().extract(&parts),
+        TokenExtraction::Found("abc".to_string())
+    );
+}
+#[test]
+fn returns_invalid_on_mismatched_csrf() {
+    let parts = AuthRequestParts::new()
+        .with_header("Cookie", "token=abc; csrf_token=xyz")
+        .with_header("X-CSRF-Token", "xya");
+    assert_eq!(
+        get_cookie_extractor().extract(&parts),
+        TokenExtraction::Invalid
+    );
+}
+#[test]
+fn returns_invalid_on_missing_csrf_header() {
+    let parts = AuthRequestParts::new().with_header("Cookie", "token=abc; csrf_token=xyz");
+    assert_eq!(
+        get_cookie_extractor().extract(&parts),
+        TokenExtraction::Invalid
+    );
+}
+#[test]
+fn extracts_token_from_cookie_without_csrf() {
+    let extractor = TokenExtractor::new().with_source(TokenSource::Cookie {
+        name: "token".to_string(),
+        csrf: None,
+    });
+    let parts = AuthRequestParts::new().with_header("Cookie", "token=abc");
+    assert_eq!(
+        extractor.extract(&parts),
+        TokenExtraction::Found("abc".to_string())
+    );
+}
+// Tests for query strings
+#[test]
+fn extracts_percent_decoded_token_from_query() {
+    let extractor =
+        TokenExtractor::new().with_source(TokenSource::QueryParam("access_token".to_string()));
+    let parts = AuthRequestParts::new().with_query("foo=bar&access_token=a%2Eb%2ec");
+    assert_eq!(
+        extractor.extract(&parts),
+        TokenExtraction::Found("a.b.c".to_string())
+    );
+}
+// Tests for WebSocket `connection_init` payloads
+#[test]
+fn extracts_token_from_init_payload() {
+    let extractor =
+        TokenExtractor::new().with_source(TokenSource::InitPayload("Authorization".to_string()));
+    for payload in &[
+        serde_json::json!({ "Authorization": "Bearer abc" }),
+        serde_json::json!({ "Authorization": "abc" }),
+    ] {
+        let parts = AuthRequestParts::new().with_init_payload(payload.clone());
+        assert_eq!(
+            extractor.extract(&parts),
+            TokenExtraction::Found("abc".to_string())
+        );
+    }
+}
+// Tests for multiple sources
+#[test]
+fn uses_first_source_with_token() {
+    let extractor = TokenExtractor::default()
+        .with_source(TokenSource::Header("X-Api-Token".to_string()))
+        .with_source(TokenSource::QueryParam("access_token".to_string()));
+    let parts = AuthRequestParts::new()
+        .with_header("X-Api-Token", "header")
+        .with_query("access_token=query");
+    assert_eq!(
+        extractor.extract(&parts),
+        TokenExtraction::Found("header".to_string())
+    );
+    let parts = AuthRequestParts::new()
+        .with_header("X-Api-Token", "")
+        .with_query("access_token=query");
+    assert_eq!(
+        extractor.extract(&parts),
+        TokenExtraction::Found("query".to_string())
+    );
+}
+#[test]
+fn knows_which_headers_it_reads() {
+    let extractor = get_cookie_extractor()
+        .with_source(TokenSource::AuthorizationHeader("Bearer".to_string()))
+        .with_source(TokenSource::Header("X-Api-Token".to_string()));
+    for header in &["authorization", "Cookie", "x-csrf-token", "X-API-TOKEN"] {
+        assert!(extractor.reads_header(header));
+    }
+    assert!(!extractor.reads_header("User-Agent"));
+}
+#[test]
+fn creates_parts_that_it_extracts_from() {
+    let token = "abc.def+ghi/=";
+    for source in [
+        TokenSource::AuthorizationHeader("Token".to_string()),
+        TokenSource::Header("X-Api-Token".to_string()),
+        TokenSource::Cookie {
+            name: "token".to_string(),
+            csrf: Some(CsrfConfig::default()),
+        },
+        TokenSource::QueryParam("token".to_string()),
+        TokenSource::InitPayload("authToken".to_string()),
+    ] {
+        let extractor = TokenExtractor::new()
+            .with_source(source)
+            .with_source(TokenSource::AuthorizationHeader("Bearer".to_string()));
+        assert_eq!(
+            extractor.extract(&extractor.parts_with_token(token)),
+            TokenExtraction::Found(token.to_string())
+        );
+    }
+}
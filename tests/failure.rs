@@ -0,0 +1,70 @@
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use diana::{
+    create_jwt_with_clock, get_jwt_secret, validate_and_decode_jwt_with_reason, AuthFailure,
+    FakeClock, JWTValidation, RegisteredClaims,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+const OTHER_JWT_SECRET: &str = "thisisanotherterriblesecretthatshouldalsoberandomlygenerated";
+
+fn get_clock() -> Arc<FakeClock> {
+    let start = NaiveDate::from_ymd_opt(2001, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    Arc::new(FakeClock::new(Utc.from_utc_datetime(&start)))
+}
+
+fn get_reason(jwt: &str, secret_str: &str, validation: &JWTValidation) -> Option<AuthFailure> {
+    let secret = get_jwt_secret(secret_str.to_string()).unwrap();
+    validate_and_decode_jwt_with_reason::<HashMap<String, String>>(jwt, &secret, validation).err()
+}
+
+// Tests for failure reasons
+#[test]
+fn distinguishes_expired_from_invalid_tokens() {
+    let clock = get_clock();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let jwt = create_jwt_with_clock(
+        HashMap::<String, String>::new(),
+        RegisteredClaims::default(),
+        &secret,
+        Duration::minutes(1),
+        &*clock,
+    )
+    .unwrap();
+    let validation = JWTValidation {
+        clock: clock.clone(),
+        ..JWTValidation::default()
+    };
+    assert_eq!(get_reason(&jwt, JWT_SECRET, &validation), None);
+    assert_eq!(
+        get_reason(&jwt, OTHER_JWT_SECRET, &validation),
+        Some(AuthFailure::InvalidSignature)
+    );
+    assert_eq!(
+        get_reason("thisisaninvalidjwt", JWT_SECRET, &validation),
+        Some(AuthFailure::Malformed)
+    );
+    clock.advance(Duration::seconds(61));
+    assert_eq!(
+        get_reason(&jwt, JWT_SECRET, &validation),
+        Some(AuthFailure::Expired)
+    );
+}
+#[test]
+fn builds_www_authenticate_headers() {
+    assert_eq!(AuthFailure::MissingToken.www_authenticate(), "Bearer");
+    assert_eq!(
+        AuthFailure::Expired.www_authenticate(),
+        "Bearer error=\"invalid_token\", error_description=\"The token has expired\""
+    );
+    assert_eq!(AuthFailure::InvalidRequest.status_code(), 400);
+    assert_eq!(
+        AuthFailure::InvalidRequest.error_code(),
+        Some("invalid_request")
+    );
+    assert_eq!(AuthFailure::Revoked.status_code(), 401);
+}
@@ -0,0 +1,236 @@
+// The guard derive needs this in scope
+use async_graphql::guard::Guard;
+use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject, ObjectType};
+use diana::{
+    claim_predicate, create_jwt, decode_time_str, errors::ErrorKind, get_jwt_secret,
+    AuthBlockLevel, AuthenticatedGuard, ClaimsGuard, DianaHandler, DianaResponse, Guarded, Options,
+    SysSchema,
+};
+use std::collections::HashMap;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+#[derive(Clone)]
+struct Admin {}
+#[GQLObject]
+impl Admin {
+    async fn users(&self) -> i32 {
+        42
+    }
+}
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn public(&self) -> bool {
+        true
+    }
+    #[graphql(guard(AuthenticatedGuard()))]
+    async fn me(&self) -> bool {
+        true
+    }
+    #[graphql(guard(ClaimsGuard(
+        predicate = r#"claim_predicate!("role" in ["admin", "editor"])"#
+    )))]
+    async fn edit(&self) -> bool {
+        true
+    }
+    async fn admin(&self) -> Guarded<Admin, ClaimsGuard> {
+        Guarded::new(
+            Admin {},
+            ClaimsGuard::new(claim_predicate!("role" == "admin")),
+        )
+    }
+}
+
+// A query root that can only be used to resolve entities through federation
+#[derive(Clone)]
+struct EntityQuery {}
+#[GQLObject]
+impl EntityQuery {
+    #[graphql(entity)]
+    async fn find_admin_by_id(&self, id: i32) -> Admin {
+        let _ = id;
+        Admin {}
+    }
+}
+
+fn get_diana_handler() -> DianaHandler<(), Query, EmptyMutation, EmptySubscription> {
+    get_diana_handler_with_query(Query {})
+}
+fn get_diana_handler_with_query<Q: Clone + ObjectType + 'static>(
+    query: Q,
+) -> DianaHandler<(), Q, EmptyMutation, EmptySubscription> {
+    let opts = Options::builder()
+        .ctx(())
+        .subscriptions_server_hostname("http://localhost")
+        .subscriptions_server_port("9002")
+        .subscriptions_server_endpoint("/graphql")
+        .jwt_to_connect_to_subscriptions_server("SUBSCRIPTIONS_SERVER_PUBLISH_JWT")
+        .auth_block_state(AuthBlockLevel::AllowMissing)
+        .jwt_secret(JWT_SECRET)
+        .schema(query, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+fn get_auth_header(role: &str) -> Option<String> {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), role.to_string());
+    let exp = decode_time_str("1m").unwrap();
+    let jwt = create_jwt(claims, &secret, exp).unwrap();
+    Some("Bearer ".to_string() + &jwt)
+}
+
+// Runs the given query and returns the response as JSON
+async fn run_query<Q: Clone + ObjectType + 'static>(
+    diana_handler: &DianaHandler<(), Q, EmptyMutation, EmptySubscription>,
+    which_schema: SysSchema,
+    query: &str,
+    auth_header: Option<String>,
+) -> serde_json::Value {
+    let body = serde_json::json!({ "query": query }).to_string();
+    let res = diana_handler
+        .run_stateless_req(which_schema, body, auth_header, None)
+        .await;
+    match res {
+        DianaResponse::Success(res) => serde_json::from_str(&res).unwrap(),
+        _ => panic!(
+            "Didn't return correct DianaResponse. Expected DianaResponse::Success, got {:?}",
+            res
+        ),
+    }
+}
+
+// Checks that a response was denied with the consistent unauthorised error
+fn assert_unauthorised(res: &serde_json::Value) {
+    assert_eq!(
+        res["errors"][0]["message"],
+        ErrorKind::Unauthorised.to_string()
+    );
+    assert_eq!(res["errors"][0]["extensions"]["code"], "UNAUTHORISED");
+}
+
+// Tests for field guards
+#[tokio::test]
+async fn allows_authenticated_users_through_guard() {
+    let diana_handler = get_diana_handler();
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { public me }",
+        get_auth_header("viewer"),
+    )
+    .await;
+    assert_eq!(res["data"]["me"], true);
+}
+#[tokio::test]
+async fn blocks_anonymous_users_with_guard() {
+    let diana_handler = get_diana_handler();
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { me }",
+        None,
+    )
+    .await;
+    assert_unauthorised(&res);
+    // Unguarded fields should still be accessible
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { public }",
+        None,
+    )
+    .await;
+    assert_eq!(res["data"]["public"], true);
+}
+#[tokio::test]
+async fn enforces_claims_with_guard() {
+    let diana_handler = get_diana_handler();
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { edit }",
+        get_auth_header("editor"),
+    )
+    .await;
+    assert_eq!(res["data"]["edit"], true);
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { edit }",
+        get_auth_header("viewer"),
+    )
+    .await;
+    assert_unauthorised(&res);
+}
+// Tests for object guards
+#[tokio::test]
+async fn enforces_claims_on_guarded_object() {
+    let diana_handler = get_diana_handler();
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { admin { users } }",
+        get_auth_header("admin"),
+    )
+    .await;
+    assert_eq!(res["data"]["admin"]["users"], 42);
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        "query { admin { users } }",
+        get_auth_header("editor"),
+    )
+    .await;
+    assert_unauthorised(&res);
+}
+#[tokio::test]
+async fn enforces_claims_on_guarded_entities() {
+    let diana_handler = get_diana_handler_with_query(Guarded::new(
+        EntityQuery {},
+        ClaimsGuard::new(claim_predicate!("role" == "admin")),
+    ));
+    let query = "query { _entities(representations: [{ __typename: \"Admin\", id: 1 }]) { ... on Admin { users } } }";
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        query,
+        get_auth_header("admin"),
+    )
+    .await;
+    assert_eq!(res["data"]["_entities"][0]["users"], 42);
+    let res = run_query(
+        &diana_handler,
+        SysSchema::WithoutSubscriptions,
+        query,
+        get_auth_header("editor"),
+    )
+    .await;
+    assert_unauthorised(&res);
+}
+// Tests for the built-in publish mutation
+#[tokio::test]
+async fn blocks_publish_without_server_role() {
+    let diana_handler = get_diana_handler();
+    let res = run_query(
+        &diana_handler,
+        SysSchema::ForSubscriptions,
+        "mutation { publish(channel: \"test\", data: \"data\") }",
+        get_auth_header("admin"),
+    )
+    .await;
+    assert_unauthorised(&res);
+    let res = run_query(
+        &diana_handler,
+        SysSchema::ForSubscriptions,
+        "mutation { publish(channel: \"test\", data: \"data\") }",
+        get_auth_header("graphql_server"),
+    )
+    .await;
+    assert_eq!(res["data"]["publish"], true);
+}
@@ -0,0 +1,408 @@
+use diana::{
+    create_jwt, decode_time_str, Algorithm, JWTKeyConfig, JWTKeyMaterial, JWTKeyProvider,
+    JWTKeySet, JWTKeySource, JWTSecret, JWTValidation,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const OLD_JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+const NEW_JWT_SECRET: &str = "thisisanotherterriblesecretthatshouldalsoberandomlygenerated";
+
+fn get_hs512_secret(secret_str: &str, key_id: Option<&str>) -> JWTSecret {
+    let secret = JWTKeyConfig {
+        algorithm: Algorithm::HS512,
+        verification_key: JWTKeyMaterial::Base64Secret(secret_str.to_string()),
+        signing_key: None,
+    }
+    .parse()
+    .unwrap();
+    match key_id {
+        Some(key_id) => secret.with_key_id(key_id),
+        None => secret,
+    }
+}
+fn get_jwt(secret: &JWTSecret) -> String {
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), "test".to_string());
+    create_jwt(claims, secret, decode_time_str("1m").unwrap()).unwrap()
+}
+// Creates a JWKS document with the given shared secrets (these are all alphanumeric, so they're already valid base64url)
+fn get_hs512_jwks(secrets: &[(&str, &str)]) -> String {
+    let keys: Vec<String> = secrets
+        .iter()
+        .map(|(key_id, secret_str)| {
+            format!(
+                "{{\"kty\":\"oct\",\"kid\":\"{}\",\"alg\":\"HS512\",\"k\":\"{}\"}}",
+                key_id, secret_str
+            )
+        })
+        .collect();
+    format!("{{\"keys\":[{}]}}", keys.join(","))
+}
+
+// Setting the JWKS to this will make the server stop responding
+const HANGING_JWKS: &str = "hang";
+
+// A minimal stand-in for an identity provider that serves whatever JWKS is currently set and counts how many times it's been fetched
+async fn start_jwks_server(jwks: Arc<Mutex<String>>, fetches: Arc<AtomicUsize>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut req_buf = [0; 4096];
+            let _ = socket.read(&mut req_buf).await; // We don't care what the request actually is
+            fetches.fetch_add(1, Ordering::SeqCst);
+            let body = jwks.lock().unwrap().clone();
+            // This is an identity provider that's stopped responding, but hasn't closed the connection
+            if body == HANGING_JWKS {
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                    drop(socket);
+                });
+                continue;
+            }
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(res.as_bytes()).await;
+        }
+    });
+
+    format!("http://{}/.well-known/jwks.json", addr)
+}
+
+// Tests for `JWTKeySet`
+#[test]
+fn selects_key_by_kid() {
+    let old_secret = get_hs512_secret(OLD_JWT_SECRET, Some("old"));
+    let new_secret = get_hs512_secret(NEW_JWT_SECRET, Some("new"));
+    let key_set = JWTKeySet::new()
+        .with_secret(old_secret.clone())
+        .with_secret(new_secret.clone());
+
+    // Both keys should be accepted during a rotation window
+    assert!(key_set
+        .validate_and_decode_jwt::<HashMap<String, String>>(
+            &get_jwt(&old_secret),
+            &JWTValidation::default()
+        )
+        .is_some());
+    assert!(key_set
+        .validate_and_decode_jwt::<HashMap<String, String>>(
+            &get_jwt(&new_secret),
+            &JWTValidation::default()
+        )
+        .is_some());
+    // A token that claims to use one key but is signed with another should be rejected
+    let impostor_secret = get_hs512_secret(NEW_JWT_SECRET, Some("old"));
+    assert!(key_set
+        .validate_and_decode_jwt::<HashMap<String, String>>(
+            &get_jwt(&impostor_secret),
+            &JWTValidation::default()
+        )
+        .is_none());
+}
+#[test]
+fn tries_all_keys_if_no_kid() {
+    let key_set = JWTKeySet::new()
+        .with_secret(get_hs512_secret(OLD_JWT_SECRET, Some("old")))
+        .with_secret(get_hs512_secret(NEW_JWT_SECRET, Some("new")));
+    let jwt = get_jwt(&get_hs512_secret(NEW_JWT_SECRET, None));
+    assert!(key_set.has_key_for_jwt(&jwt));
+    assert!(key_set
+        .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+        .is_some());
+}
+#[test]
+fn reports_unknown_kid() {
+    let key_set = JWTKeySet::new().with_secret(get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    let jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("unknown")));
+    assert!(!key_set.has_key_for_jwt(&jwt));
+    assert!(key_set
+        .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+        .is_none());
+}
+#[test]
+fn validates_asymmetric_tokens_from_jwks() {
+    let key_set =
+        JWTKeySet::from_jwks(&fs::read_to_string("tests/keys/jwks.json").unwrap()).unwrap();
+    for (algorithm, key_name) in &[
+        (Algorithm::RS256, "rsa"),
+        (Algorithm::ES256, "ec"),
+        (Algorithm::EdDSA, "ed"),
+    ] {
+        let private_key = fs::read(format!("tests/keys/{}_private.pem", key_name)).unwrap();
+        let public_key = fs::read(format!("tests/keys/{}_public.pem", key_name)).unwrap();
+        let signing_secret = JWTKeyConfig {
+            algorithm: *algorithm,
+            verification_key: JWTKeyMaterial::Pem(public_key),
+            signing_key: Some(JWTKeyMaterial::Pem(private_key)),
+        }
+        .parse()
+        .unwrap()
+        .with_key_id(&format!("{}-1", key_name));
+        assert!(key_set
+            .validate_and_decode_jwt::<HashMap<String, String>>(
+                &get_jwt(&signing_secret),
+                &JWTValidation::default()
+            )
+            .is_some());
+    }
+}
+#[test]
+fn returns_error_on_invalid_jwks() {
+    let key_set = JWTKeySet::from_jwks("{\"keys\": \"notalist\"}");
+    if key_set.is_ok() {
+        panic!("Expected Err, found {:?}", key_set);
+    }
+}
+// Tests for `JWTKeyProvider`
+#[tokio::test]
+async fn loads_keys_from_jwks_file() {
+    let provider =
+        JWTKeyProvider::from_source(&JWTKeySource::File(PathBuf::from("tests/keys/jwks.json")))
+            .unwrap();
+    let jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, None));
+    // None of those keys are shared secrets, so this should be rejected rather than causing an error
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_none());
+}
+#[tokio::test]
+async fn fetches_and_caches_remote_jwks() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks, fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(3600),
+    })
+    .unwrap();
+
+    let jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+#[tokio::test]
+async fn refreshes_remote_jwks_on_unknown_kid() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks.clone(), fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(0),
+    })
+    .unwrap();
+
+    let old_jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    let new_jwt = get_jwt(&get_hs512_secret(NEW_JWT_SECRET, Some("new")));
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&old_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    // The identity provider starts rotating, both keys should now be accepted
+    *jwks.lock().unwrap() = get_hs512_jwks(&[("new", NEW_JWT_SECRET), ("old", OLD_JWT_SECRET)]);
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&new_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&old_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+}
+#[tokio::test]
+async fn limits_remote_jwks_refreshes() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks, fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(3600),
+    })
+    .unwrap();
+
+    // Tokens with made-up key IDs shouldn't be able to force us to fetch the keys over and over again
+    for _ in 0..5 {
+        let jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("madeup")));
+        assert!(provider
+            .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+            .await
+            .unwrap()
+            .is_none());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+#[tokio::test]
+async fn only_refreshes_remote_jwks_for_unknown_kids() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks, fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(0),
+    })
+    .unwrap();
+
+    // Neither of these names a key ID, so fetching the keys again couldn't help
+    let jwt_without_kid = get_jwt(&get_hs512_secret(NEW_JWT_SECRET, None));
+    for jwt in ["notatoken", jwt_without_kid.as_str()] {
+        assert!(provider
+            .validate_and_decode_jwt::<HashMap<String, String>>(jwt, &JWTValidation::default())
+            .await
+            .unwrap()
+            .is_none());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+#[tokio::test]
+async fn keeps_remote_jwks_if_refresh_fails() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks.clone(), fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(0),
+    })
+    .unwrap();
+
+    let old_jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    let new_jwt = get_jwt(&get_hs512_secret(NEW_JWT_SECRET, Some("new")));
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&old_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    // The identity provider breaks, so the token with the unknown key should just be invalid
+    *jwks.lock().unwrap() = "not json".to_string();
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&new_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&old_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+}
+#[tokio::test]
+async fn limits_failed_remote_jwks_fetches() {
+    let jwks = Arc::new(Mutex::new("not json".to_string()));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks, fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(3600),
+    })
+    .unwrap();
+
+    let jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    for _ in 0..3 {
+        assert!(provider
+            .validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &JWTValidation::default())
+            .await
+            .is_err());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+#[tokio::test]
+async fn shares_remote_jwks_fetches_between_requests() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks, fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(3600),
+    })
+    .unwrap();
+
+    let jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    let validation = JWTValidation::default();
+    let validations = (0..10)
+        .map(|_| provider.validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &validation));
+    for res in async_graphql::futures_util::future::join_all(validations).await {
+        assert!(res.unwrap().is_some());
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+}
+#[tokio::test]
+async fn uses_cached_remote_jwks_while_refreshing() {
+    let jwks = Arc::new(Mutex::new(get_hs512_jwks(&[("old", OLD_JWT_SECRET)])));
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let url = start_jwks_server(jwks.clone(), fetches.clone()).await;
+    let provider = JWTKeyProvider::from_source(&JWTKeySource::Url {
+        url,
+        refresh_interval: Duration::from_secs(3600),
+        min_refresh_interval: Duration::from_secs(0),
+    })
+    .unwrap();
+
+    let old_jwt = get_jwt(&get_hs512_secret(OLD_JWT_SECRET, Some("old")));
+    let new_jwt = get_jwt(&get_hs512_secret(NEW_JWT_SECRET, Some("new")));
+    assert!(provider
+        .validate_and_decode_jwt::<HashMap<String, String>>(&old_jwt, &JWTValidation::default())
+        .await
+        .unwrap()
+        .is_some());
+    // The identity provider hangs in the middle of a refresh
+    *jwks.lock().unwrap() = HANGING_JWKS.to_string();
+    let refreshing_provider = provider.clone();
+    let refreshing_jwt = new_jwt.clone();
+    tokio::spawn(async move {
+        refreshing_provider
+            .validate_and_decode_jwt::<HashMap<String, String>>(
+                &refreshing_jwt,
+                &JWTValidation::default(),
+            )
+            .await
+    });
+    while fetches.load(Ordering::SeqCst) < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Other requests shouldn't have to wait for it, even if they'd like the keys refreshed too
+    for jwt in [&old_jwt, &new_jwt] {
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            provider
+                .validate_and_decode_jwt::<HashMap<String, String>>(jwt, &JWTValidation::default()),
+        )
+        .await;
+        if res.is_err() {
+            panic!("Validation waited for the hanging refresh");
+        }
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+}
@@ -1,6 +1,11 @@
 use chrono::{Duration, Utc};
-use diana::{create_jwt, decode_time_str, get_jwt_secret, validate_and_decode_jwt};
+use diana::{
+    create_jwt, create_jwt_with_registered_claims, decode_time_str, get_jwt_secret,
+    validate_and_decode_jwt, validate_and_decode_jwt_with_validation, Algorithm, Claims,
+    JWTKeyConfig, JWTKeyMaterial, JWTValidation, RegisteredClaims,
+};
 use std::collections::HashMap;
+use std::fs;
 
 const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
 const NUM_INTERVAL_LENGTHS_TO_TEST: i64 = 1000;
@@ -90,7 +95,8 @@ fn returns_valid_jwt() {
         panic!("Expected Ok, found {:?}", jwt);
     }
 
-    let extracted_claims = validate_and_decode_jwt(&jwt.unwrap(), &secret);
+    let extracted_claims: Option<Claims> =
+        validate_and_decode_jwt::<HashMap<String, String>>(&jwt.unwrap(), &secret);
     assert_eq!(extracted_claims.unwrap().claims, claims);
 }
 // Tests for `validate_and_decode_jwt` (the basic one is done with `create_jwt`)
@@ -98,8 +104,258 @@ fn returns_valid_jwt() {
 fn returns_error_if_jwt_invalid() {
     let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
 
-    let extracted_claims = validate_and_decode_jwt("thisisaninvalidjwt", &secret);
+    let extracted_claims: Option<Claims> = validate_and_decode_jwt("thisisaninvalidjwt", &secret);
     if !matches!(extracted_claims, None) {
         panic!("Expected None, found {:?}", extracted_claims);
     }
 }
+// Tests for asymmetric algorithms
+fn get_key_config(algorithm: Algorithm, key_name: &str, with_private_key: bool) -> JWTKeyConfig {
+    let read_key = |kind: &str| {
+        fs::read(format!("tests/keys/{}_{}.pem", key_name, kind)).expect("Couldn't read test key!")
+    };
+    JWTKeyConfig {
+        algorithm,
+        verification_key: JWTKeyMaterial::Pem(read_key("public")),
+        signing_key: match with_private_key {
+            true => Some(JWTKeyMaterial::Pem(read_key("private"))),
+            false => None,
+        },
+    }
+}
+#[test]
+fn returns_valid_jwt_for_asymmetric_algorithms() {
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), "test".to_string());
+    for (algorithm, key_name) in &[
+        (Algorithm::RS256, "rsa"),
+        (Algorithm::PS512, "rsa"),
+        (Algorithm::ES256, "ec"),
+        (Algorithm::EdDSA, "ed"),
+    ] {
+        let signing_secret = get_key_config(*algorithm, key_name, true).parse().unwrap();
+        let verifying_secret = get_key_config(*algorithm, key_name, false).parse().unwrap();
+        let exp = decode_time_str("1w").unwrap();
+        let jwt = create_jwt(claims.clone(), &signing_secret, exp).unwrap();
+
+        let extracted_claims: Option<Claims> =
+            validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &verifying_secret);
+        assert_eq!(extracted_claims.unwrap().claims, claims);
+    }
+}
+#[test]
+fn returns_error_on_create_without_private_key() {
+    let secret = get_key_config(Algorithm::RS256, "rsa", false)
+        .parse()
+        .unwrap();
+    let jwt = create_jwt(
+        HashMap::<String, String>::new(),
+        &secret,
+        decode_time_str("1w").unwrap(),
+    );
+    if jwt.is_ok() {
+        panic!("Expected Err, found {:?}", jwt);
+    }
+}
+#[test]
+fn returns_error_on_key_for_wrong_algorithm() {
+    let key_config = JWTKeyConfig {
+        algorithm: Algorithm::RS256,
+        verification_key: JWTKeyMaterial::Base64Secret(JWT_SECRET.to_string()),
+        signing_key: None,
+    };
+    let secret = key_config.parse();
+    if secret.is_ok() {
+        panic!("Expected Err, found {:?}", secret);
+    }
+}
+#[test]
+fn returns_none_if_jwt_signed_with_other_algorithm() {
+    let exp = decode_time_str("1w").unwrap();
+    let hs256_secret = JWTKeyConfig {
+        algorithm: Algorithm::HS256,
+        verification_key: JWTKeyMaterial::Base64Secret(JWT_SECRET.to_string()),
+        signing_key: None,
+    }
+    .parse()
+    .unwrap();
+    let hs512_secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let jwt = create_jwt(HashMap::<String, String>::new(), &hs256_secret, exp).unwrap();
+    let extracted_claims: Option<Claims> =
+        validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &hs512_secret);
+    if extracted_claims.is_some() {
+        panic!("Expected None, found {:?}", extracted_claims);
+    }
+
+    let ec_secret = get_key_config(Algorithm::ES256, "ec", true)
+        .parse()
+        .unwrap();
+    let rsa_secret = get_key_config(Algorithm::RS256, "rsa", false)
+        .parse()
+        .unwrap();
+    let jwt = create_jwt(HashMap::<String, String>::new(), &ec_secret, exp).unwrap();
+    let extracted_claims: Option<Claims> =
+        validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &rsa_secret);
+    if extracted_claims.is_some() {
+        panic!("Expected None, found {:?}", extracted_claims);
+    }
+}
+// Tests for registered claims
+fn get_registered_claims_jwt(registered_claims: RegisteredClaims) -> String {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let exp = decode_time_str("1w").unwrap();
+    create_jwt_with_registered_claims(
+        HashMap::<String, String>::new(),
+        registered_claims,
+        &secret,
+        exp,
+    )
+    .unwrap()
+}
+#[test]
+fn returns_registered_claims() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let jwt = get_registered_claims_jwt(RegisteredClaims {
+        iss: Some("https://auth.example.com".to_string()),
+        sub: Some("user-1".to_string()),
+        aud: Some(vec!["orders".to_string(), "billing".to_string()]),
+        jti: Some("token-1".to_string()),
+        ..RegisteredClaims::default()
+    });
+
+    let extracted_claims: Claims = validate_and_decode_jwt(&jwt, &secret).unwrap();
+    assert_eq!(
+        extracted_claims.registered.iss,
+        Some("https://auth.example.com".to_string())
+    );
+    assert_eq!(extracted_claims.registered.sub, Some("user-1".to_string()));
+    assert_eq!(
+        extracted_claims.registered.aud,
+        Some(vec!["orders".to_string(), "billing".to_string()])
+    );
+    assert_eq!(extracted_claims.registered.jti, Some("token-1".to_string()));
+    // This should be set automatically
+    assert!(extracted_claims.registered.iat.is_some());
+}
+#[test]
+fn validates_issuer_and_audience() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let validation = JWTValidation {
+        issuers: Some(vec!["https://auth.example.com".to_string()]),
+        audiences: Some(vec!["orders".to_string()]),
+        ..JWTValidation::default()
+    };
+
+    let valid_jwt = get_registered_claims_jwt(RegisteredClaims {
+        iss: Some("https://auth.example.com".to_string()),
+        aud: Some(vec!["orders".to_string()]),
+        ..RegisteredClaims::default()
+    });
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &valid_jwt,
+            &secret,
+            &validation
+        )
+        .is_some()
+    );
+    // A token minted for another API shouldn't be accepted
+    let other_audience_jwt = get_registered_claims_jwt(RegisteredClaims {
+        iss: Some("https://auth.example.com".to_string()),
+        aud: Some(vec!["billing".to_string()]),
+        ..RegisteredClaims::default()
+    });
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &other_audience_jwt,
+            &secret,
+            &validation
+        )
+        .is_none()
+    );
+    let other_issuer_jwt = get_registered_claims_jwt(RegisteredClaims {
+        iss: Some("https://evil.example.com".to_string()),
+        aud: Some(vec!["orders".to_string()]),
+        ..RegisteredClaims::default()
+    });
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &other_issuer_jwt,
+            &secret,
+            &validation
+        )
+        .is_none()
+    );
+    // If we require an issuer and audience, they have to be present
+    let bare_jwt = get_registered_claims_jwt(RegisteredClaims::default());
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &bare_jwt,
+            &secret,
+            &validation
+        )
+        .is_none()
+    );
+}
+#[test]
+fn validates_nbf_with_leeway() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let jwt = get_registered_claims_jwt(RegisteredClaims {
+        nbf: Some((Utc::now() + Duration::seconds(30)).timestamp() as u64),
+        ..RegisteredClaims::default()
+    });
+
+    assert!(validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &secret).is_none());
+    let lenient_validation = JWTValidation {
+        leeway: 60,
+        ..JWTValidation::default()
+    };
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &jwt,
+            &secret,
+            &lenient_validation
+        )
+        .is_some()
+    );
+}
+#[test]
+fn requires_nbf_if_configured() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let validation = JWTValidation {
+        require_nbf: true,
+        require_iat: true,
+        ..JWTValidation::default()
+    };
+
+    let jwt = get_registered_claims_jwt(RegisteredClaims::default());
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &jwt,
+            &secret,
+            &validation
+        )
+        .is_none()
+    );
+    let jwt = get_registered_claims_jwt(RegisteredClaims {
+        nbf: Some(Utc::now().timestamp() as u64),
+        ..RegisteredClaims::default()
+    });
+    assert!(
+        validate_and_decode_jwt_with_validation::<HashMap<String, String>>(
+            &jwt,
+            &secret,
+            &validation
+        )
+        .is_some()
+    );
+}
+#[test]
+fn rejects_iat_in_future() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let jwt = get_registered_claims_jwt(RegisteredClaims {
+        iat: Some((Utc::now() + Duration::hours(1)).timestamp() as u64),
+        ..RegisteredClaims::default()
+    });
+    assert!(validate_and_decode_jwt::<HashMap<String, String>>(&jwt, &secret).is_none());
+}
@@ -0,0 +1,252 @@
+#![cfg(feature = "login")]
+
+use async_graphql::{EmptySubscription, MergedObject, Object as GQLObject};
+use async_trait::async_trait;
+use chrono::Duration as ChronoDuration;
+use diana::{
+    errors::{ErrorKind, Result},
+    get_jwt_secret, hash_password, validate_and_decode_jwt, verify_password, AuthBlockLevel,
+    AuthPolicy, AuthRequestParts, Claims, CredentialVerifier, DianaHandler, DianaResponse,
+    FakeClock, LoginManager, LoginMutation, MemoryLoginAttemptStore, OperationKind, Options,
+    StoredCredentials,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+// A user database with a single user, who's an admin
+#[derive(Debug)]
+struct Users {
+    password_hash: String,
+}
+#[async_trait]
+impl CredentialVerifier for Users {
+    async fn find_credentials(&self, username: &str) -> Result<Option<StoredCredentials>> {
+        Ok(match username {
+            "alice" => Some(StoredCredentials {
+                user_id: "user-1".to_string(),
+                password_hash: self.password_hash.clone(),
+                data: serde_json::json!({ "role": "admin" }),
+            }),
+            _ => None,
+        })
+    }
+}
+
+fn get_manager(clock: Arc<FakeClock>) -> LoginManager {
+    let users = Users {
+        password_hash: hash_password("correct horse battery staple").unwrap(),
+    };
+    LoginManager::new(
+        Arc::new(users),
+        get_jwt_secret(JWT_SECRET.to_string()).unwrap(),
+        |credentials: &StoredCredentials| {
+            let mut claims = HashMap::new();
+            let role = credentials.data["role"].as_str().unwrap_or("user");
+            claims.insert("role".to_string(), role.to_string());
+            Ok(claims)
+        },
+    )
+    .max_account_failures(3)
+    .max_ip_failures(5)
+    .lockout_window(Duration::from_secs(60))
+    .attempt_store(Arc::new(
+        MemoryLoginAttemptStore::new().clock(clock.clone()),
+    ))
+    .clock(clock)
+}
+
+fn assert_login_error<T: std::fmt::Debug>(res: Result<T>, expected: ErrorKind) {
+    if !matches!(res, Err(ref err) if err.kind().to_string() == expected.to_string()) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::{:?}, got {:?}",
+            expected, res
+        )
+    }
+}
+
+// Tests for password hashing
+#[test]
+fn hashes_and_verifies_passwords() {
+    let hash = hash_password("hunter2").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    // Every hash should have its own salt
+    assert_ne!(hash, hash_password("hunter2").unwrap());
+    assert!(verify_password("hunter2", &hash).unwrap());
+    assert!(!verify_password("hunter3", &hash).unwrap());
+    assert!(verify_password("hunter2", "thisisnotahash").is_err());
+}
+// Tests for `LoginManager`
+#[tokio::test]
+async fn logs_in_with_claims_from_callback() {
+    let manager = get_manager(Arc::new(FakeClock::default()));
+    let token = manager
+        .login("alice", "correct horse battery staple", None)
+        .await
+        .unwrap();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let claims: Claims = validate_and_decode_jwt(&token, &secret).unwrap();
+    assert_eq!(claims.claims.get("role"), Some(&"admin".to_string()));
+    assert_eq!(claims.registered.sub, Some("user-1".to_string()));
+    assert_login_error(
+        manager.login("alice", "wrong", None).await,
+        ErrorKind::InvalidCredentials,
+    );
+    assert_login_error(
+        manager
+            .login("bob", "correct horse battery staple", None)
+            .await,
+        ErrorKind::InvalidCredentials,
+    );
+}
+#[tokio::test]
+async fn locks_accounts_after_too_many_failures() {
+    let clock = Arc::new(FakeClock::default());
+    let manager = get_manager(clock.clone());
+    for _ in 0..3 {
+        assert!(manager.login("Alice", "wrong", None).await.is_err());
+    }
+    // Even the right password shouldn't work now, whatever the case of the username
+    assert_login_error(
+        manager
+            .login("alice", "correct horse battery staple", None)
+            .await,
+        ErrorKind::TooManyLoginAttempts,
+    );
+    clock.advance(ChronoDuration::seconds(61));
+    assert!(manager
+        .login("alice", "correct horse battery staple", None)
+        .await
+        .is_ok());
+}
+#[tokio::test]
+async fn locks_ip_addresses_across_accounts() {
+    let manager = get_manager(Arc::new(FakeClock::default()));
+    for username in ["bob", "carol", "dave", "erin", "frank"] {
+        assert!(manager
+            .login(username, "wrong", Some("192.0.2.1"))
+            .await
+            .is_err());
+    }
+    assert_login_error(
+        manager
+            .login("alice", "correct horse battery staple", Some("192.0.2.1"))
+            .await,
+        ErrorKind::TooManyLoginAttempts,
+    );
+    assert!(manager
+        .login("alice", "correct horse battery staple", Some("192.0.2.2"))
+        .await
+        .is_ok());
+}
+#[tokio::test]
+async fn treats_unknown_users_like_wrong_passwords() {
+    let manager = get_manager(Arc::new(FakeClock::default()));
+    // Otherwise attackers could find out which usernames exist, and guess the passwords of those that don't without limit
+    for _ in 0..3 {
+        assert_login_error(
+            manager.login("bob", "wrong", Some("192.0.2.1")).await,
+            ErrorKind::InvalidCredentials,
+        );
+    }
+    assert_login_error(
+        manager.login("bob", "wrong", Some("192.0.2.2")).await,
+        ErrorKind::TooManyLoginAttempts,
+    );
+    for username in ["carol", "dave"] {
+        assert_login_error(
+            manager.login(username, "wrong", Some("192.0.2.1")).await,
+            ErrorKind::InvalidCredentials,
+        );
+    }
+    assert_login_error(
+        manager
+            .login("alice", "correct horse battery staple", Some("192.0.2.1"))
+            .await,
+        ErrorKind::TooManyLoginAttempts,
+    );
+}
+#[tokio::test]
+async fn limits_concurrent_guesses() {
+    let manager = get_manager(Arc::new(FakeClock::default()));
+    let guesses = (0..10).map(|_| manager.login("alice", "wrong", None));
+    let results = async_graphql::futures_util::future::join_all(guesses).await;
+    // Only as many guesses as the limit should ever have been checked, however they were sent
+    let checked = results
+        .iter()
+        .filter(
+            |res| matches!(res, Err(err) if matches!(err.kind(), ErrorKind::InvalidCredentials)),
+        )
+        .count();
+    assert_eq!(checked, 3);
+    assert_login_error(
+        manager
+            .login("alice", "correct horse battery staple", None)
+            .await,
+        ErrorKind::TooManyLoginAttempts,
+    );
+}
+#[tokio::test]
+async fn does_not_count_successful_logins() {
+    let manager = get_manager(Arc::new(FakeClock::default()));
+    for _ in 0..6 {
+        assert!(manager
+            .login("alice", "correct horse battery staple", Some("192.0.2.1"))
+            .await
+            .is_ok());
+    }
+}
+
+// Tests for `LoginMutation`
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+#[derive(MergedObject, Default, Clone)]
+struct Mutation(LoginMutation);
+
+#[tokio::test]
+async fn logs_in_through_mutation() {
+    let manager = get_manager(Arc::new(FakeClock::default())).client_ip_header("X-Forwarded-For");
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .auth_policy(AuthPolicy::new().anonymous_fields(OperationKind::Mutation, &["login"]))
+        .jwt_secret(JWT_SECRET)
+        .login_manager(manager)
+        .schema(Query {}, Mutation::default(), EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let body = |password: &str| {
+        format!(
+            "{{\"query\": \"mutation {{ login(username: \\\"alice\\\", password: \\\"{}\\\") }}\"}}",
+            password
+        )
+    };
+    let parts = AuthRequestParts::new().with_header("X-Forwarded-For", "192.0.2.1, 10.0.0.1");
+
+    let res = diana_handler
+        .run_stateless_without_subscriptions_from_parts(
+            body("correct horse battery staple"),
+            &parts,
+            None,
+        )
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.contains("login") && !val.contains("errors"))
+    {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success with a token, got {:?}", res)
+    }
+    let res = diana_handler
+        .run_stateless_without_subscriptions_from_parts(body("wrong"), &parts, None)
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.contains("incorrect")) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success with errors, got {:?}", res)
+    }
+}
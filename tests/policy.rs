@@ -0,0 +1,160 @@
+use async_graphql::{EmptySubscription, Object as GQLObject};
+use diana::{
+    claim_predicate, create_jwt, get_jwt_secret, AuthBlockLevel, AuthFailure, AuthPolicy,
+    AuthState, AuthVerdict, DianaHandler, DianaResponse, OperationKind, OperationRule, Options,
+    SysSchema,
+};
+use std::collections::HashMap;
+
+#[derive(Clone)]
+struct Context {}
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn me(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+struct Mutation {}
+#[GQLObject]
+impl Mutation {
+    async fn login(&self) -> bool {
+        true
+    }
+    async fn delete_account(&self) -> bool {
+        true
+    }
+}
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+fn get_handler(
+    auth_block_level: AuthBlockLevel,
+    policy: AuthPolicy,
+) -> DianaHandler<Context, Query, Mutation, EmptySubscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(auth_block_level)
+        .auth_policy(policy)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, Mutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+fn get_auth_header(role: &str) -> Option<String> {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), role.to_string());
+    let jwt = create_jwt(claims, &secret, chrono::Duration::minutes(1)).unwrap();
+    Some("Bearer ".to_string() + &jwt)
+}
+
+async fn run(
+    handler: &DianaHandler<Context, Query, Mutation, EmptySubscription>,
+    body: &str,
+    auth_header: Option<String>,
+) -> DianaResponse {
+    handler
+        .run_stateless_req(
+            SysSchema::WithoutSubscriptions,
+            body.to_string(),
+            auth_header,
+            None,
+        )
+        .await
+}
+
+// Tests for `AuthPolicy::apply()`
+#[test]
+fn allows_anonymous_fields_only_if_every_field_is() {
+    let policy = AuthPolicy::new()
+        .anonymous_fields(OperationKind::Mutation, &["login"])
+        .allow_introspection();
+    let blocked = || AuthVerdict::<HashMap<String, String>>::Block(AuthFailure::MissingToken);
+    let verdict = policy.apply("mutation Login { login }", None, blocked());
+    if !matches!(verdict, AuthVerdict::Allow(AuthState::NoToken)) {
+        panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow(AuthState::NoToken), got {:?}", verdict)
+    }
+    let verdict = policy.apply(
+        "query { ...Schema } fragment Schema on Query { __schema { queryType { name } } }",
+        None,
+        blocked(),
+    );
+    if !matches!(verdict, AuthVerdict::Allow(AuthState::NoToken)) {
+        panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow(AuthState::NoToken), got {:?}", verdict)
+    }
+    // Naming an operation after an anonymous field shouldn't help, and neither should mixing fields
+    for query in [
+        "mutation login { deleteAccount }",
+        "mutation { login deleteAccount }",
+        "query { login }",
+    ] {
+        let verdict = policy.apply(query, None, blocked());
+        if !matches!(verdict, AuthVerdict::Block(AuthFailure::MissingToken)) {
+            panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::MissingToken), got {:?}", verdict)
+        }
+    }
+}
+#[test]
+fn uses_the_requested_operation() {
+    let policy = AuthPolicy::new().anonymous_fields(OperationKind::Mutation, &["login"]);
+    let query = "mutation Login { login } mutation Delete { deleteAccount }";
+    let blocked = || AuthVerdict::<HashMap<String, String>>::Block(AuthFailure::Expired);
+    let verdict = policy.apply(query, Some("Login"), blocked());
+    if !matches!(
+        verdict,
+        AuthVerdict::Allow(AuthState::InvalidToken(AuthFailure::Expired))
+    ) {
+        panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow(AuthState::InvalidToken(AuthFailure::Expired)), got {:?}", verdict)
+    }
+    for operation_name in [Some("Delete"), None] {
+        let verdict = policy.apply(query, operation_name, blocked());
+        if !matches!(verdict, AuthVerdict::Block(AuthFailure::Expired)) {
+            panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::Expired), got {:?}", verdict)
+        }
+    }
+}
+// Tests for policies in `DianaHandler`
+#[tokio::test]
+async fn allows_anonymous_login_when_blocking_unauthenticated() {
+    let policy = AuthPolicy::new().anonymous_fields(OperationKind::Mutation, &["login"]);
+    let handler = get_handler(AuthBlockLevel::BlockUnauthenticated, policy);
+    let res = run(&handler, "{\"query\": \"mutation { login }\"}", None).await;
+    if !matches!(res, DianaResponse::Success(ref body) if body == "{\"data\":{\"login\":true}}") {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+    let res = run(&handler, "{\"query\": \"query { me }\"}", None).await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::MissingToken)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::MissingToken), got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn requires_claims_for_mutations_and_leaves_queries_open() {
+    let policy = AuthPolicy::new()
+        .queries(OperationRule::Anonymous)
+        .mutations(OperationRule::Claims(claim_predicate!("role" == "admin")));
+    let handler = get_handler(AuthBlockLevel::AllowMissing, policy);
+    let res = run(&handler, "{\"query\": \"query { me }\"}", None).await;
+    if !matches!(res, DianaResponse::Success(_)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+    let mutation = "{\"query\": \"mutation { deleteAccount }\"}";
+    let res = run(&handler, mutation, None).await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::MissingToken)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::MissingToken), got {:?}", res)
+    }
+    let res = run(&handler, mutation, get_auth_header("user")).await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::InsufficientClaims)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::InsufficientClaims), got {:?}", res)
+    }
+    let res = run(&handler, mutation, get_auth_header("admin")).await;
+    if !matches!(res, DianaResponse::Success(_)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+}
@@ -0,0 +1,300 @@
+use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject};
+use chrono::Duration as ChronoDuration;
+use diana::{
+    create_jwt, create_jwt_with_registered_claims, errors::ErrorKind, get_jwt_secret,
+    validate_and_decode_jwt, AuthBlockLevel, AuthFailure, AuthRequestParts, AuthState, AuthVerdict,
+    Claims, DianaHandler, DianaResponse, FakeClock, HmacPublishAuth, MemoryNonceStore,
+    MintedPublishAuth, Options, PublishAuth, RegisteredClaims, PUBLISH_TOKEN_AUDIENCE,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const PUBLISH_SECRET: &str = "dGhpc2lzYW5vdGhlcnRlcnJpYmxlc2VjcmV0Zm9ycHVibGlzaGluZw==";
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+const PUBLISH_MUTATION: &str =
+    "{\"query\": \"mutation { publish(channel: \\\"channel\\\", data: \\\"data\\\") }\"}";
+
+type Verdict = AuthVerdict<HashMap<String, String>>;
+
+fn get_hmac(clock: Arc<FakeClock>) -> HmacPublishAuth {
+    HmacPublishAuth::new(PUBLISH_SECRET)
+        .unwrap()
+        .max_age(Duration::from_secs(30))
+        .nonce_store(Arc::new(MemoryNonceStore::new().clock(clock.clone())))
+        .clock(clock)
+}
+fn get_parts(headers: Vec<(&'static str, String)>) -> AuthRequestParts {
+    headers
+        .into_iter()
+        .fold(AuthRequestParts::new(), |parts, (name, value)| {
+            parts.with_header(name, &value)
+        })
+}
+
+// Tests for signing and verifying
+#[test]
+fn verifies_signed_requests_once() {
+    let hmac = get_hmac(Arc::new(FakeClock::default()));
+    let parts = get_parts(hmac.sign("body"));
+    let verdict: Verdict = hmac.verify("body", &parts);
+    if !matches!(verdict, AuthVerdict::Allow(ref auth_state) if auth_state.get_claims().unwrap().claims.get("role") == Some(&"graphql_server".to_string()))
+    {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Allow with the publishing role, got {:?}", verdict)
+    }
+    let verdict: Verdict = hmac.verify("body", &parts);
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::Replayed)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::Replayed), got {:?}", verdict)
+    }
+}
+#[test]
+fn rejects_tampered_and_unsigned_requests() {
+    let hmac = get_hmac(Arc::new(FakeClock::default()));
+    let verdict: Verdict = hmac.verify("tampered", &get_parts(hmac.sign("body")));
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidSignature)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::InvalidSignature), got {:?}", verdict)
+    }
+    let other_hmac = HmacPublishAuth::new("b3RoZXJzZWNyZXQ=").unwrap();
+    let verdict: Verdict = hmac.verify("body", &get_parts(other_hmac.sign("body")));
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidSignature)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::InvalidSignature), got {:?}", verdict)
+    }
+    let verdict: Verdict = hmac.verify("body", &AuthRequestParts::new());
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::MissingToken)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::MissingToken), got {:?}", verdict)
+    }
+}
+#[test]
+fn rejects_stale_requests() {
+    let clock = Arc::new(FakeClock::default());
+    let hmac = get_hmac(clock.clone());
+    let parts = get_parts(hmac.sign("body"));
+    clock.advance(ChronoDuration::seconds(31));
+    let verdict: Verdict = hmac.verify("body", &parts);
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::Expired)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::Expired), got {:?}", verdict)
+    }
+    let parts = get_parts(hmac.sign("body"));
+    clock.advance(ChronoDuration::seconds(-31));
+    let verdict: Verdict = hmac.verify("body", &parts);
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::NotYetValid)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::NotYetValid), got {:?}", verdict)
+    }
+}
+#[test]
+fn rejects_invalid_secrets() {
+    for secret in ["", "not base64!"] {
+        let res = HmacPublishAuth::new(secret);
+        if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::InvalidPublishSecret)) {
+            panic!(
+                "Didn't return correct error. Expected ErrorKind::InvalidPublishSecret, got {:?}",
+                res
+            )
+        }
+    }
+}
+
+// Tests for minting tokens
+#[test]
+fn mints_and_renews_publisher_tokens() {
+    let clock = Arc::new(FakeClock::default());
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let minted = MintedPublishAuth::new()
+        .signing_key(secret.clone())
+        .lifetime(Duration::from_secs(60))
+        .renew_before(Duration::from_secs(10))
+        .clock(clock.clone());
+    let token = minted.get_token().unwrap();
+    let claims: Claims = validate_and_decode_jwt(&token, &secret).unwrap();
+    assert_eq!(
+        claims.claims.get("role"),
+        Some(&"graphql_server".to_string())
+    );
+    assert_eq!(
+        claims.registered.aud,
+        Some(vec![PUBLISH_TOKEN_AUDIENCE.to_string()])
+    );
+    assert_eq!(claims.exp - claims.registered.iat.unwrap(), 60);
+
+    clock.advance(ChronoDuration::seconds(49));
+    assert_eq!(minted.get_token().unwrap(), token);
+    clock.advance(ChronoDuration::seconds(1));
+    assert_ne!(minted.get_token().unwrap(), token);
+}
+#[test]
+fn fails_to_mint_without_signing_key() {
+    let res = MintedPublishAuth::new().get_token();
+    if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::MissingJWTSigningKey)) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::MissingJWTSigningKey, got {:?}",
+            res
+        )
+    }
+}
+
+// Tests for the subscriptions server
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn publishes_signed_requests_without_a_jwt() {
+    let hmac = get_hmac(Arc::new(FakeClock::default()));
+    let opts = Options::builder()
+        .ctx(())
+        .subscriptions_server_hostname("http://localhost")
+        .subscriptions_server_port("9002")
+        .subscriptions_server_endpoint("/graphql")
+        .publish_auth(PublishAuth::Hmac(hmac.clone()))
+        .auth_block_state(AuthBlockLevel::AllowAll)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let parts = get_parts(hmac.sign(PUBLISH_MUTATION));
+
+    // A verdict from the usual checks can't get around the signature
+    let res = diana_handler
+        .run_stateless_for_subscriptions_from_parts(
+            PUBLISH_MUTATION.to_string(),
+            &AuthRequestParts::new(),
+            Some(AuthVerdict::Allow(AuthState::NoToken)),
+        )
+        .await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::MissingToken)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::MissingToken), got {:?}", res)
+    }
+    let res = diana_handler
+        .run_stateless_for_subscriptions_from_parts(PUBLISH_MUTATION.to_string(), &parts, None)
+        .await;
+    if !matches!(res, DianaResponse::Success(ref val) if val == "{\"data\":{\"publish\":true}}") {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+    let res = diana_handler
+        .run_stateless_for_subscriptions_from_parts(PUBLISH_MUTATION.to_string(), &parts, None)
+        .await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::Replayed)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::Replayed), got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn only_accepts_short_lived_publisher_tokens() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let minted = MintedPublishAuth::new().lifetime(Duration::from_secs(60));
+    let opts = Options::builder()
+        .ctx(())
+        .subscriptions_server_hostname("http://localhost")
+        .subscriptions_server_port("9002")
+        .subscriptions_server_endpoint("/graphql")
+        .publish_auth(PublishAuth::MintedJwt(minted.clone()))
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let publish = |token: String| {
+        diana_handler.run_stateless_for_subscriptions(
+            PUBLISH_MUTATION.to_string(),
+            Some("Bearer ".to_string() + &token),
+            None,
+        )
+    };
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), "graphql_server".to_string());
+
+    let res = publish(minted.signing_key(secret.clone()).get_token().unwrap()).await;
+    if !matches!(res, DianaResponse::Success(ref val) if val == "{\"data\":{\"publish\":true}}") {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+    // A hand-made token isn't for publishing
+    let res =
+        publish(create_jwt(claims.clone(), &secret, ChronoDuration::seconds(60)).unwrap()).await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::InvalidAudience)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::InvalidAudience), got {:?}", res)
+    }
+    // And even one that's for publishing can't last too long
+    let registered_claims = RegisteredClaims {
+        aud: Some(vec![PUBLISH_TOKEN_AUDIENCE.to_string()]),
+        ..RegisteredClaims::default()
+    };
+    let token = create_jwt_with_registered_claims(
+        claims,
+        registered_claims,
+        &secret,
+        ChronoDuration::weeks(1),
+    )
+    .unwrap();
+    let res = publish(token).await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::InvalidClaims)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::InvalidClaims), got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn only_accepts_publisher_tokens_for_publishing() {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let minted = MintedPublishAuth::new().signing_key(secret);
+    let opts = Options::builder()
+        .ctx(())
+        .subscriptions_server_hostname("http://localhost")
+        .subscriptions_server_port("9002")
+        .subscriptions_server_endpoint("/graphql")
+        .publish_auth(PublishAuth::MintedJwt(minted.clone()))
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let token = minted.get_token().unwrap();
+    let parts = AuthRequestParts::from_auth_header(Some(&("Bearer ".to_string() + &token)));
+
+    let res = diana_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"query { query }\"}".to_string(),
+            Some("Bearer ".to_string() + &token),
+            None,
+        )
+        .await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::InvalidAudience)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::InvalidAudience), got {:?}", res)
+    }
+    // Even a verdict that was reached for publishing can't be used for queries
+    let verdict = diana_handler.is_authed_for_publishing(&parts).await;
+    let res = diana_handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"query { query }\"}".to_string(),
+            Option::<String>::None,
+            Some(verdict),
+        )
+        .await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::InvalidAudience)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::InvalidAudience), got {:?}", res)
+    }
+    let verdict: Verdict = diana_handler.is_authed_from_parts(&parts).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidAudience)) {
+        panic!("Didn't return correct AuthVerdict variant. Expected AuthVerdict::Block(AuthFailure::InvalidAudience), got {:?}", verdict)
+    }
+    let verdict: Verdict = diana_handler
+        .is_authed_for_subscription(
+            &AuthRequestParts::new(),
+            &serde_json::json!({ "authToken": token }),
+        )
+        .await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidAudience)) {
+        panic!("Didn't return correct AuthVerdict variant. Expected AuthVerdict::Block(AuthFailure::InvalidAudience), got {:?}", verdict)
+    }
+    // It still works for publishing though
+    let res = diana_handler
+        .run_stateless_for_subscriptions_from_parts(PUBLISH_MUTATION.to_string(), &parts, None)
+        .await;
+    if !matches!(res, DianaResponse::Success(ref val) if val == "{\"data\":{\"publish\":true}}") {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+}
@@ -0,0 +1,266 @@
+use diana::{
+    errors::{ErrorKind, Result},
+    LagCounts, LagPolicy, MemoryPubSub, PubSubBackend, PubSubSettings, Stream, StreamExt,
+};
+use std::time::Duration;
+
+// Gets the next message on a stream, or `None` if there isn't one yet (or the stream has ended)
+async fn get_next<S: Stream<Item = Result<String>> + Unpin>(stream: &mut S) -> Option<String> {
+    tokio::time::timeout(Duration::from_millis(500), stream.next())
+        .await
+        .ok()
+        .flatten()
+        .map(|message| message.unwrap())
+}
+
+// These tests are run against every backend
+async fn publishes_to_all_subscribers(pubsub: &dyn PubSubBackend) {
+    let mut first = pubsub.subscribe("channel").await.unwrap();
+    let mut second = pubsub.subscribe("channel").await.unwrap();
+    let mut other = pubsub.subscribe("other_channel").await.unwrap();
+    pubsub.publish("channel", "data".to_string()).await.unwrap();
+
+    assert_eq!(get_next(&mut first).await, Some("data".to_string()));
+    assert_eq!(get_next(&mut second).await, Some("data".to_string()));
+    assert_eq!(get_next(&mut other).await, None);
+}
+async fn closing_ends_streams(pubsub: &dyn PubSubBackend) {
+    let mut stream = pubsub.subscribe("channel").await.unwrap();
+    pubsub.close("channel").await.unwrap();
+    assert_eq!(get_next(&mut stream).await, None);
+    // The channel can still be used again
+    let mut stream = pubsub.subscribe("channel").await.unwrap();
+    pubsub.publish("channel", "data".to_string()).await.unwrap();
+    assert_eq!(get_next(&mut stream).await, Some("data".to_string()));
+}
+
+#[tokio::test]
+async fn memory_publishes_to_all_subscribers() {
+    publishes_to_all_subscribers(&MemoryPubSub::new()).await;
+}
+#[tokio::test]
+async fn memory_closing_ends_streams() {
+    closing_ends_streams(&MemoryPubSub::new()).await;
+}
+
+// Tests for channel capacities and falling behind
+async fn publish_all(pubsub: &MemoryPubSub, channel: &str, messages: &[&str]) {
+    for message in messages {
+        pubsub.publish(channel, message.to_string()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn uses_channel_capacities() {
+    let pubsub =
+        MemoryPubSub::new().settings(PubSubSettings::new().capacity(1).channel_capacity("big", 3));
+    let mut big = pubsub.subscribe("big").await.unwrap();
+    let mut small = pubsub.subscribe("small").await.unwrap();
+    publish_all(&pubsub, "big", &["1", "2", "3"]).await;
+    publish_all(&pubsub, "small", &["1", "2", "3"]).await;
+
+    for message in ["1", "2", "3"] {
+        assert_eq!(get_next(&mut big).await, Some(message.to_string()));
+    }
+    // The oldest messages are dropped by default
+    assert_eq!(get_next(&mut small).await, Some("3".to_string()));
+    assert!(!pubsub.lag_counts().contains_key("big"));
+    assert_eq!(
+        pubsub.lag_counts().get("small"),
+        Some(&LagCounts {
+            events: 1,
+            messages: 2
+        })
+    );
+}
+#[tokio::test]
+async fn sends_lag_errors_if_configured() {
+    let pubsub = MemoryPubSub::new().settings(
+        PubSubSettings::new()
+            .capacity(1)
+            .lag_policy(LagPolicy::Error),
+    );
+    let mut stream = pubsub.subscribe("channel").await.unwrap();
+    publish_all(&pubsub, "channel", &["1", "2", "3"]).await;
+
+    let res = stream.next().await;
+    if !matches!(res, Some(Err(ref err)) if matches!(err.kind(), ErrorKind::SubscriberLagged(2))) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::SubscriberLagged(2), got {:?}",
+            res
+        )
+    }
+    assert_eq!(res.unwrap().unwrap_err().to_string(), "lagged 2 messages");
+    // The subscription carries on afterwards
+    assert_eq!(get_next(&mut stream).await, Some("3".to_string()));
+}
+#[tokio::test]
+async fn closes_lagging_streams_if_configured() {
+    let pubsub = MemoryPubSub::new().settings(
+        PubSubSettings::new()
+            .capacity(1)
+            .lag_policy(LagPolicy::Close),
+    );
+    let mut stream = pubsub.subscribe("channel").await.unwrap();
+    publish_all(&pubsub, "channel", &["1", "2", "3"]).await;
+    assert_eq!(get_next(&mut stream).await, None);
+    assert_eq!(pubsub.lag_counts()["channel"].events, 1);
+}
+#[test]
+fn treats_zero_capacities_as_one() {
+    let settings = PubSubSettings::new()
+        .capacity(0)
+        .channel_capacity("channel", 0);
+    assert_eq!(settings.get_capacity("channel"), 1);
+    assert_eq!(settings.get_capacity("other_channel"), 1);
+}
+
+// The Redis tests need `redis-server` to be installed, and will be skipped if it isn't
+#[cfg(feature = "redis")]
+mod redis {
+    use diana::{PubSubBackend, RedisPubSub};
+    use std::net::TcpListener;
+    use std::process::{Child, Command, Stdio};
+    use std::time::Duration;
+
+    // A Redis server on a free port, which is killed when this is dropped
+    struct RedisServer {
+        process: Child,
+        port: u16,
+        url: String,
+    }
+    impl RedisServer {
+        fn spawn() -> Option<Self> {
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            Self::spawn_on(port)
+        }
+        fn spawn_on(port: u16) -> Option<Self> {
+            let process = Command::new("redis-server")
+                .args([
+                    "--port",
+                    &port.to_string(),
+                    "--save",
+                    "",
+                    "--appendonly",
+                    "no",
+                ])
+                .stdout(Stdio::null())
+                .spawn();
+            let process = match process {
+                Ok(process) => process,
+                Err(_) => {
+                    eprintln!("Skipping Redis tests, couldn't start `redis-server`");
+                    return None;
+                }
+            };
+            let server = Self {
+                process,
+                port,
+                url: format!("redis://127.0.0.1:{}", port),
+            };
+            // Wait for the server to start accepting connections
+            for _ in 0..50 {
+                if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                    return Some(server);
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            panic!("Redis server didn't start");
+        }
+    }
+    impl Drop for RedisServer {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_publishes_to_all_subscribers() {
+        if let Some(server) = RedisServer::spawn() {
+            super::publishes_to_all_subscribers(&RedisPubSub::new(&server.url).unwrap()).await;
+        }
+    }
+    #[tokio::test]
+    async fn redis_closing_ends_streams() {
+        if let Some(server) = RedisServer::spawn() {
+            super::closing_ends_streams(&RedisPubSub::new(&server.url).unwrap()).await;
+        }
+    }
+    #[tokio::test]
+    async fn redis_publishes_across_instances() {
+        if let Some(server) = RedisServer::spawn() {
+            // These would be on different subscriptions servers
+            let first = RedisPubSub::new(&server.url).unwrap();
+            let second = RedisPubSub::new(&server.url).unwrap();
+            let mut stream = second.subscribe("channel").await.unwrap();
+            first.publish("channel", "data".to_string()).await.unwrap();
+            assert_eq!(super::get_next(&mut stream).await, Some("data".to_string()));
+        }
+    }
+    #[tokio::test]
+    async fn redis_reconnects_for_publishing() {
+        if let Some(server) = RedisServer::spawn() {
+            let pubsub = RedisPubSub::new(&server.url).unwrap();
+            pubsub.publish("channel", "data".to_string()).await.unwrap();
+            // Redis restarts, which drops the connection we were publishing with
+            let port = server.port;
+            drop(server);
+            let _server = RedisServer::spawn_on(port).unwrap();
+            let mut stream = pubsub.subscribe("channel").await.unwrap();
+            pubsub.publish("channel", "data".to_string()).await.unwrap();
+            assert_eq!(super::get_next(&mut stream).await, Some("data".to_string()));
+        }
+    }
+    #[tokio::test]
+    async fn redis_stops_listening_without_subscribers() {
+        if let Some(server) = RedisServer::spawn() {
+            let pubsub = RedisPubSub::new(&server.url).unwrap();
+            let first = pubsub.subscribe("channel").await.unwrap();
+            let second = pubsub.subscribe("channel").await.unwrap();
+            // Publishing directly to Redis tells us how many connections are listening to the channel
+            let connection = ::redis::Client::open(server.url.as_str())
+                .unwrap()
+                .get_multiplexed_tokio_connection()
+                .await
+                .unwrap();
+            let get_listeners = || {
+                let mut connection = connection.clone();
+                async move {
+                    // The connection is closed in the background, so we give it a moment
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    ::redis::cmd("PUBLISH")
+                        .arg("channel")
+                        .arg("data")
+                        .query_async::<_, i64>(&mut connection)
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_eq!(get_listeners().await, 1);
+            drop(first);
+            assert_eq!(get_listeners().await, 1);
+            drop(second);
+            assert_eq!(get_listeners().await, 0);
+            // The channel can still be subscribed to again
+            let mut stream = pubsub.subscribe("channel").await.unwrap();
+            pubsub.publish("channel", "data".to_string()).await.unwrap();
+            assert_eq!(super::get_next(&mut stream).await, Some("data".to_string()));
+        }
+    }
+    #[test]
+    fn rejects_invalid_urls() {
+        let res = RedisPubSub::new("not a url");
+        if !matches!(res, Err(ref err) if matches!(err.kind(), diana::errors::ErrorKind::PubSubBackendFailed(_)))
+        {
+            panic!(
+                "Didn't return correct error. Expected ErrorKind::PubSubBackendFailed, got {:?}",
+                res
+            )
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use async_graphql::{EmptySubscription, MergedObject, Object as GQLObject};
+use chrono::Duration as ChronoDuration;
+use diana::{
+    errors::ErrorKind, get_jwt_secret, is_revoked, validate_and_decode_jwt, AuthBlockLevel, Claims,
+    Clock, DianaHandler, DianaResponse, FakeClock, MemoryRefreshTokenStore, MemoryRevocationStore,
+    Options, RefreshMutation, RefreshTokenManager, RefreshTokenRecord, RefreshTokenStore,
+    SysSchema,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+fn get_claims() -> HashMap<String, String> {
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), "test".to_string());
+    claims
+}
+
+fn get_manager() -> RefreshTokenManager {
+    RefreshTokenManager::new(
+        get_jwt_secret(JWT_SECRET.to_string()).unwrap(),
+        Arc::new(MemoryRefreshTokenStore::new()),
+    )
+}
+
+// Tests for `RefreshTokenManager`
+#[test]
+fn issues_valid_access_token() {
+    let manager = get_manager().access_token_lifetime(Duration::from_secs(60));
+    let token_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let claims: Claims = validate_and_decode_jwt(&token_pair.access_token, &secret).unwrap();
+    assert_eq!(claims.claims, get_claims());
+    assert_eq!(claims.registered.sub, Some("user-1".to_string()));
+    assert!(claims.registered.jti.is_some());
+    assert_eq!(claims.exp, token_pair.access_token_exp);
+    assert!(token_pair.refresh_token_exp > token_pair.access_token_exp);
+}
+#[test]
+fn rotates_refresh_token_on_refresh() {
+    let manager = get_manager();
+    let first_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let second_pair = manager.refresh(&first_pair.refresh_token).unwrap();
+    assert_ne!(first_pair.refresh_token, second_pair.refresh_token);
+    assert_ne!(first_pair.access_token, second_pair.access_token);
+    // The new refresh token should work in turn
+    assert!(manager.refresh(&second_pair.refresh_token).is_ok());
+}
+#[test]
+fn returns_error_on_unknown_refresh_token() {
+    let manager = get_manager();
+    let res = manager.refresh("thisisnotarefreshtoken");
+    if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::InvalidRefreshToken)) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::InvalidRefreshToken, got {:?}",
+            res
+        )
+    }
+}
+#[test]
+fn revokes_family_on_reuse() {
+    let revocation_store = Arc::new(MemoryRevocationStore::new());
+    let manager = get_manager().revocation_store(revocation_store.clone());
+    let first_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let second_pair = manager.refresh(&first_pair.refresh_token).unwrap();
+
+    // Using the first refresh token again should be detected as reuse
+    let res = manager.refresh(&first_pair.refresh_token);
+    if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::RefreshTokenReused)) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::RefreshTokenReused, got {:?}",
+            res
+        )
+    }
+    // And that should have revoked the whole family, including the legitimate client's latest tokens
+    let res = manager.refresh(&second_pair.refresh_token);
+    if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::InvalidRefreshToken)) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::InvalidRefreshToken, got {:?}",
+            res
+        )
+    }
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    for access_token in &[first_pair.access_token, second_pair.access_token] {
+        let claims: Claims = validate_and_decode_jwt(access_token, &secret).unwrap();
+        assert!(is_revoked(revocation_store.as_ref(), &claims).unwrap());
+    }
+}
+#[test]
+fn does_not_affect_other_families_on_reuse() {
+    let manager = get_manager();
+    let first_family_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let second_family_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    manager.refresh(&first_family_pair.refresh_token).unwrap();
+    assert!(manager.refresh(&first_family_pair.refresh_token).is_err());
+    assert!(manager.refresh(&second_family_pair.refresh_token).is_ok());
+}
+#[test]
+fn revokes_family_on_logout() {
+    let manager = get_manager();
+    let first_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let second_pair = manager.refresh(&first_pair.refresh_token).unwrap();
+    manager.revoke(&second_pair.refresh_token).unwrap();
+    // Logging out isn't a sign of theft, so the token should just be invalid
+    let res = manager.refresh(&second_pair.refresh_token);
+    if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::InvalidRefreshToken)) {
+        panic!(
+            "Didn't return correct error. Expected ErrorKind::InvalidRefreshToken, got {:?}",
+            res
+        )
+    }
+}
+#[test]
+fn does_not_treat_expired_tokens_as_reused() {
+    let clock = Arc::new(FakeClock::default());
+    let manager = get_manager()
+        .refresh_token_lifetime(Duration::from_secs(60))
+        .clock(clock.clone());
+    let first_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let second_pair = manager.refresh(&first_pair.refresh_token).unwrap();
+    clock.advance(ChronoDuration::seconds(61));
+
+    // A client that presents its expired token more than once hasn't had it stolen
+    for _ in 0..2 {
+        let res = manager.refresh(&second_pair.refresh_token);
+        if !matches!(res, Err(ref err) if matches!(err.kind(), ErrorKind::InvalidRefreshToken)) {
+            panic!(
+                "Didn't return correct error. Expected ErrorKind::InvalidRefreshToken, got {:?}",
+                res
+            )
+        }
+    }
+}
+
+// Tests for `MemoryRefreshTokenStore`
+fn get_record(family_id: &str, exp: u64) -> RefreshTokenRecord {
+    RefreshTokenRecord {
+        family_id: family_id.to_string(),
+        claims: serde_json::Value::Null,
+        sub: None,
+        exp,
+        used: false,
+        access_token_jti: "jti".to_string(),
+        access_token_exp: exp,
+    }
+}
+#[test]
+fn forgets_revoked_families_once_their_tokens_expire() {
+    let clock = Arc::new(FakeClock::default());
+    let store = MemoryRefreshTokenStore::new().clock(clock.clone());
+    let now = clock.timestamp();
+    store
+        .insert_token("first", get_record("family-1", now + 60))
+        .unwrap();
+    store
+        .insert_token("second", get_record("family-1", now + 120))
+        .unwrap();
+    store.revoke_family("family-1").unwrap();
+
+    // The family has to be remembered until its latest token expires
+    clock.advance(ChronoDuration::seconds(90));
+    store
+        .insert_token("other", get_record("family-2", now + 1000))
+        .unwrap();
+    assert!(store.is_family_revoked("family-1").unwrap());
+    clock.advance(ChronoDuration::seconds(60));
+    store
+        .insert_token("another", get_record("family-2", now + 1000))
+        .unwrap();
+    assert!(!store.is_family_revoked("family-1").unwrap());
+}
+
+// Tests for `RefreshMutation`
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+#[derive(Default, Clone)]
+struct UserMutation {}
+#[GQLObject]
+impl UserMutation {
+    async fn mutation(&self) -> bool {
+        true
+    }
+}
+#[derive(MergedObject, Default, Clone)]
+struct Mutation(UserMutation, RefreshMutation);
+
+#[tokio::test]
+async fn refreshes_tokens_through_mutation() {
+    let manager = get_manager();
+    let token_pair = manager.issue(get_claims(), Some("user-1")).unwrap();
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(AuthBlockLevel::AllowMissing)
+        .jwt_secret(JWT_SECRET)
+        .refresh_token_manager(manager)
+        .schema(Query {}, Mutation::default(), EmptySubscription {})
+        .finish()
+        .unwrap();
+    let diana_handler = DianaHandler::new(opts).unwrap();
+    let body = format!(
+        "{{\"query\": \"mutation {{ refreshToken(refreshToken: \\\"{}\\\") {{ accessToken refreshToken }} }}\"}}",
+        token_pair.refresh_token
+    );
+
+    let res = diana_handler
+        .run_stateless_req(
+            SysSchema::WithoutSubscriptions,
+            body.clone(),
+            Option::<String>::None,
+            None,
+        )
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.contains("accessToken") && !val.contains("errors"))
+    {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success with tokens, got {:?}", res)
+    }
+    // The same refresh token can't be used twice
+    let res = diana_handler
+        .run_stateless_req(
+            SysSchema::WithoutSubscriptions,
+            body,
+            Option::<String>::None,
+            None,
+        )
+        .await;
+    if !matches!(res.clone(), DianaResponse::Success(val) if val.contains("errors")) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success with errors, got {:?}", res)
+    }
+}
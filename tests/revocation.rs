@@ -0,0 +1,105 @@
+use diana::{
+    is_revoked, Claims, FileRevocationStore, MemoryRevocationStore, RegisteredClaims,
+    RevocationStore,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+const EXP: u64 = 4_102_444_800; // 2100-01-01, so nothing expires during the tests
+
+fn get_claims(jti: Option<&str>, sub: Option<&str>, iat: Option<u64>) -> Claims {
+    Claims {
+        exp: EXP,
+        registered: RegisteredClaims {
+            jti: jti.map(|jti| jti.to_string()),
+            sub: sub.map(|sub| sub.to_string()),
+            iat,
+            ..RegisteredClaims::default()
+        },
+        claims: HashMap::new(),
+    }
+}
+
+fn get_temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "diana-revocation-{}-{}.json",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+// Tests for `is_revoked` with the in-memory store
+#[test]
+fn revokes_tokens_by_jti() {
+    let store = MemoryRevocationStore::new();
+    store.revoke_token("token-1", EXP).unwrap();
+    assert!(is_revoked(&store, &get_claims(Some("token-1"), None, None)).unwrap());
+    assert!(!is_revoked(&store, &get_claims(Some("token-2"), None, None)).unwrap());
+    assert!(!is_revoked(&store, &get_claims(None, None, None)).unwrap());
+}
+#[test]
+fn revokes_user_tokens_issued_before_time() {
+    let store = MemoryRevocationStore::new();
+    store
+        .revoke_user_tokens_issued_before("user-1", 1000)
+        .unwrap();
+    assert!(is_revoked(&store, &get_claims(None, Some("user-1"), Some(999))).unwrap());
+    assert!(!is_revoked(&store, &get_claims(None, Some("user-1"), Some(1000))).unwrap());
+    assert!(!is_revoked(&store, &get_claims(None, Some("user-2"), Some(999))).unwrap());
+    // We can't know when a token without `iat` was issued, so it should be considered revoked
+    assert!(is_revoked(&store, &get_claims(None, Some("user-1"), None)).unwrap());
+}
+#[test]
+fn never_brings_user_revocation_time_forward() {
+    let store = MemoryRevocationStore::new();
+    store
+        .revoke_user_tokens_issued_before("user-1", 2000)
+        .unwrap();
+    store
+        .revoke_user_tokens_issued_before("user-1", 1000)
+        .unwrap();
+    assert_eq!(
+        store.get_user_revocation_time("user-1").unwrap(),
+        Some(2000)
+    );
+}
+#[test]
+fn forgets_expired_revocations() {
+    let store = MemoryRevocationStore::new();
+    store.revoke_token("expired", 1).unwrap();
+    store.revoke_token("token-1", EXP).unwrap();
+    assert!(!store.is_token_revoked("expired").unwrap());
+    assert!(store.is_token_revoked("token-1").unwrap());
+}
+// Tests for the file-backed store
+#[test]
+fn persists_revocations_to_file() {
+    let path = get_temp_path("persists");
+    {
+        let store = FileRevocationStore::new(&path).unwrap();
+        store.revoke_token("token-1", EXP).unwrap();
+        store
+            .revoke_user_tokens_issued_before("user-1", 1000)
+            .unwrap();
+    }
+    // A new store from the same file should know about everything revoked before
+    let store = FileRevocationStore::new(&path).unwrap();
+    assert!(is_revoked(&store, &get_claims(Some("token-1"), None, None)).unwrap());
+    assert!(is_revoked(&store, &get_claims(None, Some("user-1"), Some(999))).unwrap());
+    assert!(!is_revoked(
+        &store,
+        &get_claims(Some("token-2"), Some("user-2"), Some(999))
+    )
+    .unwrap());
+    fs::remove_file(&path).unwrap();
+}
+#[test]
+fn returns_error_on_invalid_file() {
+    let path = get_temp_path("invalid");
+    fs::write(&path, "thisisnotjson").unwrap();
+    assert!(FileRevocationStore::new(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
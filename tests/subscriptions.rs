@@ -0,0 +1,454 @@
+use async_graphql::futures_util::stream;
+use async_graphql::{EmptyMutation, Object as GQLObject, Request, Subscription as GQLSubscription};
+use chrono::{Duration as ChronoDuration, NaiveDate, TimeZone, Utc};
+use diana::{
+    create_jwt, create_jwt_with_clock, get_jwt_secret, graphql_utils::get_auth_data_from_ctx,
+    AuthBlockLevel, AuthFailure, AuthRequestParts, AuthState, AuthVerdict, Claims,
+    ConnectionExpiry, CsrfConfig, DianaHandler, FakeClock, JWTAuthenticator, JWTKeyProvider,
+    JWTKeySet, JWTValidation, Options, RegisteredClaims, SubscriptionExpiry, TokenExtractor,
+    TokenSource,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt};
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn query(&self) -> bool {
+        true
+    }
+}
+#[derive(Clone)]
+struct Subscription {}
+#[GQLSubscription]
+impl Subscription {
+    async fn role(
+        &self,
+        raw_ctx: &async_graphql::Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = String>> {
+        let auth_state: &AuthState =
+            get_auth_data_from_ctx(raw_ctx).map_err(|err| err.to_string())?;
+        let role = match auth_state.get_claims() {
+            Ok(claims) => claims.claims.get("role").cloned().unwrap_or_default(),
+            Err(_) => "anonymous".to_string(),
+        };
+        Ok(tokio_stream::once(role))
+    }
+    async fn forever(&self) -> impl Stream<Item = bool> {
+        tokio_stream::pending()
+    }
+}
+
+fn get_diana_handler(
+    auth_block_level: AuthBlockLevel,
+) -> DianaHandler<(), Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(auth_block_level)
+        .jwt_secret(JWT_SECRET)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+fn get_jwt(role: &str) -> String {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let mut claims = HashMap::new();
+    claims.insert("role".to_string(), role.to_string());
+    create_jwt(claims, &secret, chrono::Duration::minutes(1)).unwrap()
+}
+
+// Tests for authenticating connections
+#[tokio::test]
+async fn authenticates_connections_from_upgrade_requests_or_params() {
+    let diana_handler = get_diana_handler(AuthBlockLevel::BlockUnauthenticated);
+    let verdict = diana_handler
+        .is_authed_for_subscription(&AuthRequestParts::new(), &serde_json::Value::Null)
+        .await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::MissingToken)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::MissingToken), got {:?}", verdict)
+    }
+    let parts =
+        AuthRequestParts::from_auth_header(Some(&("Bearer ".to_string() + &get_jwt("admin"))));
+    let verdict = diana_handler
+        .is_authed_for_subscription(&parts, &serde_json::Value::Null)
+        .await;
+    if !matches!(verdict, AuthVerdict::Allow(AuthState::Authorised(_))) {
+        panic!(
+            "Didn't return correct verdict. Expected AuthVerdict::Allow with a token, got {:?}",
+            verdict
+        )
+    }
+    for connection_params in [
+        serde_json::json!({ "Authorization": "Bearer ".to_string() + &get_jwt("admin") }),
+        serde_json::json!({ "authToken": get_jwt("admin") }),
+    ] {
+        let verdict = diana_handler
+            .is_authed_for_subscription(&AuthRequestParts::new(), &connection_params)
+            .await;
+        if !matches!(verdict, AuthVerdict::Allow(AuthState::Authorised(_))) {
+            panic!(
+                "Didn't return correct verdict. Expected AuthVerdict::Allow with a token, got {:?}",
+                verdict
+            )
+        }
+    }
+    let verdict = diana_handler
+        .is_authed_for_subscription(
+            &AuthRequestParts::new(),
+            &serde_json::json!({ "authToken": "thisisaninvalidjwt" }),
+        )
+        .await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::Malformed)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::Malformed), got {:?}", verdict)
+    }
+}
+#[tokio::test]
+async fn upgrade_request_takes_precedence_over_params() {
+    let diana_handler = get_diana_handler(AuthBlockLevel::BlockUnauthenticated);
+    let parts = AuthRequestParts::from_auth_header(Some("Bearer thisisaninvalidjwt"));
+    let verdict = diana_handler
+        .is_authed_for_subscription(
+            &parts,
+            &serde_json::json!({ "authToken": get_jwt("admin") }),
+        )
+        .await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::Malformed)) {
+        panic!("Didn't return correct verdict. Expected AuthVerdict::Block(AuthFailure::Malformed), got {:?}", verdict)
+    }
+}
+
+// Tests for the data in the context of subscriptions
+#[tokio::test]
+async fn subscriptions_get_auth_state() {
+    let diana_handler = get_diana_handler(AuthBlockLevel::AllowAll);
+    let get_role = |auth_state: AuthState| {
+        let data = diana_handler.get_subscription_data(auth_state, &AuthRequestParts::new());
+        let mut stream = diana_handler
+            .schema_for_subscriptions
+            .execute_stream_with_session_data(
+                Request::new("subscription { role }"),
+                Arc::new(data),
+            );
+        async move { stream.next().await.unwrap().data.to_string() }
+    };
+
+    let verdict = diana_handler
+        .is_authed_for_subscription(
+            &AuthRequestParts::new(),
+            &serde_json::json!({ "authToken": get_jwt("admin") }),
+        )
+        .await;
+    let auth_state = match verdict {
+        AuthVerdict::Allow(auth_state) => auth_state,
+        _ => panic!(
+            "Didn't return correct verdict. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        ),
+    };
+    assert_eq!(get_role(auth_state).await, "{role: \"admin\"}");
+    assert_eq!(get_role(AuthState::NoToken).await, "{role: \"anonymous\"}");
+}
+
+// Tests for ending subscriptions when tokens expire
+fn get_expiring_diana_handler(
+    subscription_expiry: SubscriptionExpiry,
+    clock: Arc<FakeClock>,
+) -> DianaHandler<(), Query, EmptyMutation, Subscription> {
+    get_expiring_diana_handler_with_extractor(subscription_expiry, clock, TokenExtractor::default())
+}
+fn get_expiring_diana_handler_with_extractor(
+    subscription_expiry: SubscriptionExpiry,
+    clock: Arc<FakeClock>,
+    token_extractor: TokenExtractor,
+) -> DianaHandler<(), Query, EmptyMutation, Subscription> {
+    let opts = Options::builder()
+        .ctx(())
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .token_extractor(token_extractor)
+        .clock(clock)
+        .subscription_expiry(subscription_expiry)
+        .schema(Query {}, EmptyMutation {}, Subscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+fn get_jwt_for_subject(sub: &str, exp: ChronoDuration, clock: &FakeClock) -> String {
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let registered_claims = RegisteredClaims {
+        sub: Some(sub.to_string()),
+        ..RegisteredClaims::default()
+    };
+    create_jwt_with_clock(
+        HashMap::<String, String>::new(),
+        registered_claims,
+        &secret,
+        exp,
+        clock,
+    )
+    .unwrap()
+}
+async fn get_connection_data(
+    diana_handler: &DianaHandler<(), Query, EmptyMutation, Subscription>,
+    token: String,
+) -> Arc<async_graphql::Data> {
+    let verdict = diana_handler
+        .is_authed_for_subscription(
+            &AuthRequestParts::new(),
+            &serde_json::json!({ "authToken": token }),
+        )
+        .await;
+    match verdict {
+        AuthVerdict::Allow(auth_state) => {
+            Arc::new(diana_handler.get_subscription_data(auth_state, &AuthRequestParts::new()))
+        }
+        _ => panic!(
+            "Didn't return correct verdict. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        ),
+    }
+}
+// Gets the next response on a subscription, or `None` if there isn't one yet
+async fn get_next(
+    diana_handler: &DianaHandler<(), Query, EmptyMutation, Subscription>,
+    query: &str,
+    data: Arc<async_graphql::Data>,
+) -> Option<async_graphql::Response> {
+    let mut stream = diana_handler
+        .schema_for_subscriptions
+        .execute_stream_with_session_data(Request::new(query), data);
+    tokio::time::timeout(Duration::from_millis(100), stream.next())
+        .await
+        .ok()
+        .flatten()
+}
+
+#[tokio::test]
+async fn ends_subscriptions_when_tokens_expire() {
+    let clock = Arc::new(FakeClock::default());
+    let diana_handler = get_expiring_diana_handler(SubscriptionExpiry::Terminate, clock.clone());
+    let data = get_connection_data(
+        &diana_handler,
+        get_jwt_for_subject("user", ChronoDuration::seconds(60), &clock),
+    )
+    .await;
+
+    let res = get_next(&diana_handler, "subscription { forever }", data.clone()).await;
+    if res.is_some() {
+        panic!(
+            "Didn't keep subscription open. Expected no response, got {:?}",
+            res
+        )
+    }
+    clock.advance(ChronoDuration::seconds(61));
+    let res = get_next(&diana_handler, "subscription { forever }", data.clone()).await;
+    if !matches!(res, Some(ref res) if res.errors.len() == 1 && res.errors[0].message == AuthFailure::Expired.description())
+    {
+        panic!(
+            "Didn't end subscription. Expected an expiry error, got {:?}",
+            res
+        )
+    }
+    // Tokens can't be refreshed unless that's been allowed
+    let res = get_next(
+        &diana_handler,
+        &format!(
+            "mutation {{ refreshSubscriptionToken(token: \"{}\") }}",
+            get_jwt_for_subject("user", ChronoDuration::seconds(60), &clock)
+        ),
+        data,
+    )
+    .await;
+    if !matches!(res, Some(ref res) if res.errors.len() == 1) {
+        panic!("Didn't refuse refresh. Expected an error, got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn ignores_expiry_if_configured() {
+    let clock = Arc::new(FakeClock::default());
+    let diana_handler = get_expiring_diana_handler(SubscriptionExpiry::Ignore, clock.clone());
+    let data = get_connection_data(
+        &diana_handler,
+        get_jwt_for_subject("user", ChronoDuration::seconds(60), &clock),
+    )
+    .await;
+
+    clock.advance(ChronoDuration::seconds(61));
+    let res = get_next(&diana_handler, "subscription { forever }", data).await;
+    if res.is_some() {
+        panic!(
+            "Didn't keep subscription open. Expected no response, got {:?}",
+            res
+        )
+    }
+}
+#[tokio::test]
+async fn refreshes_connection_tokens_for_the_same_subject() {
+    let clock = Arc::new(FakeClock::default());
+    let diana_handler = get_expiring_diana_handler(SubscriptionExpiry::AllowRefresh, clock.clone());
+    let data = get_connection_data(
+        &diana_handler,
+        get_jwt_for_subject("user", ChronoDuration::seconds(60), &clock),
+    )
+    .await;
+    let refresh = |token: String| {
+        let query = format!(
+            "mutation {{ refreshSubscriptionToken(token: \"{}\") }}",
+            token
+        );
+        let data = data.clone();
+        let diana_handler = &diana_handler;
+        async move { get_next(diana_handler, &query, data).await }
+    };
+
+    let res = refresh(get_jwt_for_subject(
+        "someone_else",
+        ChronoDuration::seconds(120),
+        &clock,
+    ))
+    .await;
+    if !matches!(res, Some(ref res) if res.errors.len() == 1) {
+        panic!("Didn't refuse refresh. Expected an error, got {:?}", res)
+    }
+    let res = refresh(get_jwt_for_subject(
+        "user",
+        ChronoDuration::seconds(120),
+        &clock,
+    ))
+    .await;
+    if !matches!(res, Some(ref res) if res.errors.is_empty()) {
+        panic!("Didn't refresh token. Expected no errors, got {:?}", res)
+    }
+
+    clock.advance(ChronoDuration::seconds(61));
+    let res = get_next(&diana_handler, "subscription { forever }", data.clone()).await;
+    if res.is_some() {
+        panic!(
+            "Didn't keep subscription open. Expected no response, got {:?}",
+            res
+        )
+    }
+    clock.advance(ChronoDuration::seconds(60));
+    let res = get_next(&diana_handler, "subscription { forever }", data).await;
+    if !matches!(res, Some(ref res) if res.errors.len() == 1 && res.errors[0].message == AuthFailure::Expired.description())
+    {
+        panic!(
+            "Didn't end subscription. Expected an expiry error, got {:?}",
+            res
+        )
+    }
+}
+#[tokio::test]
+async fn refuses_refresh_without_subject() {
+    let clock = Arc::new(FakeClock::default());
+    let diana_handler = get_expiring_diana_handler(SubscriptionExpiry::AllowRefresh, clock.clone());
+    let secret = get_jwt_secret(JWT_SECRET.to_string()).unwrap();
+    let get_jwt_without_subject = |exp: ChronoDuration| {
+        create_jwt_with_clock(
+            HashMap::<String, String>::new(),
+            RegisteredClaims::default(),
+            &secret,
+            exp,
+            clock.as_ref(),
+        )
+        .unwrap()
+    };
+    let data = get_connection_data(
+        &diana_handler,
+        get_jwt_without_subject(ChronoDuration::seconds(60)),
+    )
+    .await;
+
+    // Otherwise any token without a subject could take over the connection
+    let query = format!(
+        "mutation {{ refreshSubscriptionToken(token: \"{}\") }}",
+        get_jwt_without_subject(ChronoDuration::seconds(120))
+    );
+    let res = get_next(&diana_handler, &query, data).await;
+    if !matches!(res, Some(ref res) if res.errors.len() == 1 && res.errors[0].message.contains("has no subject"))
+    {
+        panic!("Didn't refuse refresh. Expected an error, got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn refreshes_connection_tokens_from_configured_source() {
+    let clock = Arc::new(FakeClock::default());
+    let diana_handler = get_expiring_diana_handler_with_extractor(
+        SubscriptionExpiry::AllowRefresh,
+        clock.clone(),
+        TokenExtractor::new().with_source(TokenSource::Cookie {
+            name: "token".to_string(),
+            csrf: Some(CsrfConfig::default()),
+        }),
+    );
+    let parts = AuthRequestParts::new()
+        .with_header(
+            "Cookie",
+            &format!(
+                "token={}; csrf_token=abc",
+                get_jwt_for_subject("user", ChronoDuration::seconds(60), &clock)
+            ),
+        )
+        .with_header("X-CSRF-Token", "abc");
+    let verdict = diana_handler
+        .is_authed_for_subscription(&parts, &serde_json::Value::Null)
+        .await;
+    let data = match verdict {
+        AuthVerdict::Allow(auth_state) => {
+            Arc::new(diana_handler.get_subscription_data(auth_state, &parts))
+        }
+        _ => panic!(
+            "Didn't return correct verdict. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        ),
+    };
+
+    // The new token should be checked as if it had arrived in a cookie too
+    let query = format!(
+        "mutation {{ refreshSubscriptionToken(token: \"{}\") }}",
+        get_jwt_for_subject("user", ChronoDuration::seconds(120), &clock)
+    );
+    let res = get_next(&diana_handler, &query, data).await;
+    if !matches!(res, Some(ref res) if res.errors.is_empty()) {
+        panic!("Didn't refresh token. Expected no errors, got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn keeps_subscriptions_open_for_tokens_without_expiry() {
+    // API keys and introspected tokens without an `exp` have the largest possible expiry
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let clock = Arc::new(FakeClock::new(Utc.from_utc_datetime(&epoch)));
+    let claims = Claims {
+        exp: u64::MAX,
+        registered: RegisteredClaims::default(),
+        claims: HashMap::<String, String>::new(),
+    };
+    let authenticator = JWTAuthenticator::new(
+        JWTKeyProvider::Static(JWTKeySet::new()),
+        JWTValidation::default(),
+    );
+    let connection_expiry = ConnectionExpiry::new(
+        SubscriptionExpiry::Terminate,
+        &claims,
+        Arc::new(authenticator),
+        clock,
+    );
+
+    let mut stream = connection_expiry.guard_stream(Box::pin(stream::pending()));
+    let res = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+    if let Ok(res) = res {
+        panic!(
+            "Didn't keep subscription open. Expected no response, got {:?}",
+            res
+        )
+    }
+}
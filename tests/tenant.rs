@@ -0,0 +1,198 @@
+use async_graphql::{EmptyMutation, EmptySubscription, Object as GQLObject};
+use diana::{
+    create_jwt, get_jwt_secret, graphql_utils::get_auth_data_from_ctx, AuthBlockLevel, AuthFailure,
+    AuthRequestParts, AuthState, AuthVerdict, DianaHandler, DianaResponse, Options, Tenant,
+    TenantConfig, TenantResolver, TenantSource, TokenExtractor,
+};
+use std::collections::HashMap;
+
+#[derive(Clone)]
+struct Context {}
+
+// The data each tenant has, which would usually be something like a database pool
+struct TenantDatabase {
+    name: String,
+}
+
+#[derive(Clone)]
+struct Query {}
+#[GQLObject]
+impl Query {
+    async fn database(&self, raw_ctx: &async_graphql::Context<'_>) -> Option<String> {
+        let tenant = raw_ctx.data::<Tenant>().ok()?;
+        Some(tenant.data::<TenantDatabase>()?.name.clone())
+    }
+    async fn verified_tenant(&self, raw_ctx: &async_graphql::Context<'_>) -> Option<String> {
+        let auth_state: &AuthState = get_auth_data_from_ctx(raw_ctx).ok()?;
+        Some(auth_state.tenant()?.id().to_string())
+    }
+}
+
+const JWT_SECRET: &str = "thisisaterriblesecretthatshouldberandomlygeneratedseethebook";
+const ACME_JWT_SECRET: &str = "thisisanotherterriblesecretthatshouldalsoberandomlygenerated";
+
+fn get_resolver(source: TenantSource) -> TenantResolver {
+    TenantResolver::new()
+        .source(source)
+        .tenant(
+            "acme",
+            TenantConfig::new()
+                .hosts(&["acme.example.com"])
+                .jwt_secret(ACME_JWT_SECRET)
+                .data(TenantDatabase {
+                    name: "acme_db".to_string(),
+                }),
+        )
+        .tenant(
+            "globex",
+            TenantConfig::new()
+                .hosts(&["globex.example.com"])
+                .auth_block_state(AuthBlockLevel::AllowMissing)
+                .data(TenantDatabase {
+                    name: "globex_db".to_string(),
+                }),
+        )
+}
+
+fn get_handler(
+    tenant_resolver: TenantResolver,
+) -> DianaHandler<Context, Query, EmptyMutation, EmptySubscription> {
+    let opts = Options::builder()
+        .ctx(Context {})
+        .auth_block_state(AuthBlockLevel::BlockUnauthenticated)
+        .jwt_secret(JWT_SECRET)
+        .tenants(tenant_resolver)
+        .schema(Query {}, EmptyMutation {}, EmptySubscription {})
+        .finish()
+        .unwrap();
+    DianaHandler::new(opts).unwrap()
+}
+
+fn get_jwt(secret: &str, tenant_id: &str) -> String {
+    let secret = get_jwt_secret(secret.to_string()).unwrap();
+    let mut claims = HashMap::new();
+    claims.insert("tid".to_string(), tenant_id.to_string());
+    create_jwt(claims, &secret, chrono::Duration::minutes(1)).unwrap()
+}
+
+// Tests for `TenantResolver`
+#[test]
+fn resolves_tenants_from_hosts_paths_and_claims() {
+    let extractor = TokenExtractor::default();
+    let resolve = |source: TenantSource, parts: AuthRequestParts| {
+        get_resolver(source)
+            .resolve(&parts, &extractor)
+            .map(|(id, _)| id.to_string())
+    };
+    let by_host = AuthRequestParts::new().with_header("Host", "ACME.example.com:8080");
+    assert_eq!(
+        resolve(TenantSource::Host, by_host),
+        Some("acme".to_string())
+    );
+    let by_path = AuthRequestParts::new().with_path("/globex/graphql");
+    assert_eq!(
+        resolve(TenantSource::PathPrefix, by_path),
+        Some("globex".to_string())
+    );
+    let by_claim = AuthRequestParts::from_auth_header(Some(&format!(
+        "Bearer {}",
+        get_jwt(JWT_SECRET, "acme")
+    )));
+    assert_eq!(
+        resolve(TenantSource::UnverifiedClaim("tid".to_string()), by_claim),
+        Some("acme".to_string())
+    );
+    let unknown = AuthRequestParts::new()
+        .with_header("Host", "initech.example.com")
+        .with_path("/initech/graphql");
+    assert_eq!(resolve(TenantSource::Host, unknown.clone()), None);
+    assert_eq!(resolve(TenantSource::PathPrefix, unknown), None);
+}
+// Tests for tenants in `DianaHandler`
+#[tokio::test]
+async fn verifies_tokens_with_tenant_keys() {
+    let handler = get_handler(get_resolver(TenantSource::UnverifiedClaim(
+        "tid".to_string(),
+    )));
+    // A token that claims to be for a tenant must be signed with that tenant's key
+    let forged = format!("Bearer {}", get_jwt(JWT_SECRET, "acme"));
+    let verdict = handler
+        .is_authed_from_parts(&AuthRequestParts::from_auth_header(Some(&forged)))
+        .await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidSignature)) {
+        panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::InvalidSignature), got {:?}", verdict)
+    }
+    let valid = format!("Bearer {}", get_jwt(ACME_JWT_SECRET, "acme"));
+    let verdict = handler
+        .is_authed_from_parts(&AuthRequestParts::from_auth_header(Some(&valid)))
+        .await;
+    match verdict {
+        AuthVerdict::Allow(auth_state) => {
+            assert_eq!(auth_state.tenant().map(|tenant| tenant.id()), Some("acme"))
+        }
+        _ => panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        ),
+    }
+    // Tenants without their own keys use the ones in the options
+    let valid = format!("Bearer {}", get_jwt(JWT_SECRET, "globex"));
+    let res = handler
+        .run_stateless_without_subscriptions(
+            "{\"query\": \"query { database verifiedTenant }\"}".to_string(),
+            Some(valid),
+            None,
+        )
+        .await;
+    if !matches!(res, DianaResponse::Success(ref body) if body == "{\"data\":{\"database\":\"globex_db\",\"verifiedTenant\":\"globex\"}}")
+    {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn uses_tenant_block_levels_and_data() {
+    let handler = get_handler(get_resolver(TenantSource::Host));
+    let body = "{\"query\": \"query { database verifiedTenant }\"}".to_string();
+    let globex = AuthRequestParts::new().with_header("Host", "globex.example.com");
+    let res = handler
+        .run_stateless_without_subscriptions_from_parts(body.clone(), &globex, None)
+        .await;
+    if !matches!(res, DianaResponse::Success(ref body) if body == "{\"data\":{\"database\":\"globex_db\",\"verifiedTenant\":null}}")
+    {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Success, got {:?}", res)
+    }
+    let acme = AuthRequestParts::new().with_header("Host", "acme.example.com");
+    let res = handler
+        .run_stateless_without_subscriptions_from_parts(body, &acme, None)
+        .await;
+    if !matches!(res, DianaResponse::Blocked(AuthFailure::MissingToken)) {
+        panic!("Didn't return correct DianaResponse variant. Expected DianaResponse::Blocked(AuthFailure::MissingToken), got {:?}", res)
+    }
+}
+#[tokio::test]
+async fn rejects_tokens_for_other_tenants() {
+    // Neither of these tenants has its own keys, so only the tenant claim can tell their tokens apart
+    let resolver = get_resolver(TenantSource::Host)
+        .source(TenantSource::UnverifiedClaim("tid".to_string()))
+        .tenant(
+            "initech",
+            TenantConfig::new().hosts(&["initech.example.com"]),
+        );
+    let handler = get_handler(resolver);
+    let auth_header = format!("Bearer {}", get_jwt(JWT_SECRET, "initech"));
+    let parts = AuthRequestParts::from_auth_header(Some(&auth_header))
+        .with_header("Host", "globex.example.com");
+    let verdict = handler.is_authed_from_parts(&parts).await;
+    if !matches!(verdict, AuthVerdict::Block(AuthFailure::InvalidClaims)) {
+        panic!("Didn't return correct AuthVerdict response. Expected AuthVerdict::Block(AuthFailure::InvalidClaims), got {:?}", verdict)
+    }
+    let parts = AuthRequestParts::from_auth_header(Some(&auth_header))
+        .with_header("Host", "initech.example.com");
+    let verdict = handler.is_authed_from_parts(&parts).await;
+    if !matches!(verdict, AuthVerdict::Allow(_)) {
+        panic!(
+            "Didn't return correct AuthVerdict response. Expected AuthVerdict::Allow, got {:?}",
+            verdict
+        )
+    }
+}